@@ -0,0 +1,83 @@
+use std::alloc::Layout;
+
+use btree::{node::bench::Leaf, page::Pool, tree::BTree};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+fn random_keys(rng: &mut StdRng, count: usize, key_len: usize) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|_| (0..key_len).map(|_| rng.gen::<u8>()).collect())
+        .collect()
+}
+
+// Looks up every key of a full leaf in random order, with binary search and with the
+// linear scan that it replaced.
+fn leaf_find(c: &mut Criterion) {
+    let val_layout = Layout::from_size_align(8, 8).unwrap();
+    let mut group = c.benchmark_group("leaf_find");
+    for &key_len in &[8, 16, 64] {
+        let pool = Pool::new();
+        let mut rng = StdRng::seed_from_u64(key_len as u64);
+        let keys = random_keys(&mut rng, 4096, key_len);
+        let (leaf, count) = Leaf::fill(&pool, val_layout, &keys);
+        let mut lookups = keys[..count].to_vec();
+        lookups.shuffle(&mut rng);
+
+        group.bench_with_input(
+            BenchmarkId::new("linear", key_len),
+            &lookups,
+            |b, lookups| {
+                b.iter(|| {
+                    for key in lookups {
+                        black_box(leaf.find_linear(key));
+                    }
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("binary", key_len),
+            &lookups,
+            |b, lookups| {
+                b.iter(|| {
+                    for key in lookups {
+                        black_box(leaf.find(key));
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+// Inserts and looks up random keys through the whole tree.
+fn tree(c: &mut Criterion) {
+    let val_layout = Layout::from_size_align(8, 8).unwrap();
+    let mut rng = StdRng::seed_from_u64(0);
+    let keys = random_keys(&mut rng, 10_000, 16);
+    let pool = Pool::new();
+
+    c.bench_function("tree_insert", |b| {
+        b.iter(|| {
+            let mut tree = BTree::new(val_layout, &pool);
+            for key in &keys {
+                tree.insert(key, &[0; 8]).unwrap();
+            }
+            tree
+        })
+    });
+
+    let mut tree = BTree::new(val_layout, &pool);
+    for key in &keys {
+        tree.insert(key, &[0; 8]).unwrap();
+    }
+    c.bench_function("tree_get", |b| {
+        b.iter(|| {
+            for key in &keys {
+                black_box(tree.get(key));
+            }
+        })
+    });
+}
+
+criterion_group!(benches, leaf_find, tree);
+criterion_main!(benches);
@@ -0,0 +1,30 @@
+use std::cmp::Ordering;
+
+/// Defines the order of the keys in a tree.
+///
+/// `compare` must be a total order. Keys that compare as `Equal` are treated as the same key,
+/// even if their bytes differ.
+pub trait KeyComparator {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// Orders keys bytewise. This is the default. Tree operations are generic over the
+/// comparator, so it compiles down to plain slice comparisons.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Bytewise;
+
+impl KeyComparator for Bytewise {
+    #[inline]
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+impl<F> KeyComparator for F
+where
+    F: Fn(&[u8], &[u8]) -> Ordering,
+{
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self(a, b)
+    }
+}
@@ -0,0 +1,881 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+
+use parking_lot::{
+    ArcRwLockReadGuard, ArcRwLockWriteGuard, Mutex, RawRwLock, RwLock, RwLockWriteGuard,
+};
+
+use crate::{
+    comparator::{Bytewise, KeyComparator},
+    entry::{PageEntry, ValueFormat, ValuesIterator},
+    node::{check_entry_len, entry_size, InnerEntry, InnerNode, Node, NodeId, Rebalance, Split},
+    page::Pool,
+    tree::prefix_successor,
+};
+
+type Latch<'a> = Arc<RwLock<Node<'a>>>;
+type ReadGuard<'a> = ArcRwLockReadGuard<RawRwLock, Node<'a>>;
+type WriteGuard<'a> = ArcRwLockWriteGuard<RawRwLock, Node<'a>>;
+type WritePath<'t, 'a> = (
+    Option<RwLockWriteGuard<'t, NodeId>>,
+    Vec<(WriteGuard<'a>, usize)>,
+    WriteGuard<'a>,
+);
+
+/// A B+ tree in memory that can be read and updated from many threads at once through a
+/// shared reference. It holds the same nodes as a BTree, but each of them is behind its
+/// own read/write latch.
+///
+/// Operations take latches on the way down from the root, and release a node's latch once
+/// they hold its child's, so that they only block each other where their paths meet. An
+/// update takes a read latch on every inner node and a write latch on the leaf, which is
+/// enough as long as the leaf does not split or underflow. Otherwise it starts over from
+/// the root with write latches, and keeps the latches of the ancestors that the split or
+/// merge may reach.
+///
+/// Latches are always taken from the root down, and from left to right within a level, so
+/// that operations can not deadlock.
+pub struct ConcurrentBTree<'a, C = Bytewise> {
+    // Latched for writing by an update that may replace the root.
+    root: RwLock<NodeId>,
+    nodes: LatchTable<'a>,
+    // The length of the longest key that was ever inserted, which bounds the length of the
+    // keys in inner nodes. It decides whether an update that changes a node's children
+    // can reach its parent.
+    max_key_len: AtomicUsize,
+    val_format: ValueFormat,
+    pool: &'a Pool,
+    cmp: C,
+}
+
+/// Owns the latched nodes of a ConcurrentBTree. A node can only be reached through its
+/// parent's latch, so the table's own lock is only held long enough to look up a latch.
+struct LatchTable<'a> {
+    slots: Mutex<LatchSlots<'a>>,
+}
+
+struct LatchSlots<'a> {
+    // None while the id is free.
+    latches: Vec<Option<Latch<'a>>>,
+    // Ids of removed nodes, which are reused before the table grows.
+    free: Vec<NodeId>,
+}
+
+impl<'a> LatchTable<'a> {
+    fn get(&self, id: NodeId) -> Latch<'a> {
+        let slots = self.slots.lock();
+        match slots.latches.get(index(id)) {
+            Some(Some(latch)) => latch.clone(),
+            _ => panic!("{:?} is not in the node table", id),
+        }
+    }
+
+    fn insert(&self, node: Node<'a>) -> NodeId {
+        let latch = Some(Arc::new(RwLock::new(node)));
+        let mut slots = self.slots.lock();
+        match slots.free.pop() {
+            Some(id) => {
+                slots.latches[index(id)] = latch;
+                id
+            }
+            None => {
+                slots.latches.push(latch);
+                NodeId::new(slots.latches.len() as u64).unwrap()
+            }
+        }
+    }
+
+    /// Removes a node whose latch is held by the caller. The node is dropped, and its page
+    /// returned to the pool, once the caller releases the latch.
+    fn remove(&self, id: NodeId) {
+        let mut slots = self.slots.lock();
+        slots.latches[index(id)] = None;
+        slots.free.push(id);
+    }
+}
+
+fn index(id: NodeId) -> usize {
+    id.get() as usize - 1
+}
+
+impl<'a> ConcurrentBTree<'a> {
+    pub fn new(val_format: impl Into<ValueFormat>, pool: &'a Pool) -> ConcurrentBTree<'a> {
+        ConcurrentBTree::with_comparator(val_format, pool, Bytewise)
+    }
+
+    /// Returns an iterator over the keys that start with `prefix`, in order, along with
+    /// their values. Only bytewise order keeps such keys next to each other.
+    pub fn prefix(&self, prefix: &[u8]) -> Range<'_, 'a> {
+        let end = match prefix_successor(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.range((Bound::Included(prefix.to_vec()), end))
+    }
+}
+
+impl<'a, C: KeyComparator> ConcurrentBTree<'a, C> {
+    pub fn with_comparator(
+        val_format: impl Into<ValueFormat>,
+        pool: &'a Pool,
+        cmp: C,
+    ) -> ConcurrentBTree<'a, C> {
+        let nodes = LatchTable {
+            slots: Mutex::new(LatchSlots {
+                latches: Vec::new(),
+                free: Vec::new(),
+            }),
+        };
+        let root = nodes.insert(Node::new_leaf(pool));
+        ConcurrentBTree {
+            root: RwLock::new(root),
+            nodes,
+            max_key_len: AtomicUsize::new(0),
+            val_format: val_format.into(),
+            pool,
+            cmp,
+        }
+    }
+
+    /// Returns the values of `key`. They hold a read latch on the key's leaf, which keeps
+    /// updates out of it until they are dropped, so a thread must drop them before it uses
+    /// the tree again.
+    pub fn get(&self, key: &[u8]) -> Option<Values<'a>> {
+        let leaf = self.read_leaf(Some(key));
+        let entry = leaf.as_leaf().find(key, &self.cmp)? as *const PageEntry;
+        Some(Values {
+            _leaf: leaf,
+            entry,
+            val_format: self.val_format,
+        })
+    }
+
+    /// Returns an iterator over every key in the tree, in order, along with its values.
+    pub fn scan(&self) -> Range<'_, 'a, C> {
+        self.range::<&[u8], _>(..)
+    }
+
+    /// Returns an iterator over the keys within `range`, in order, along with their values.
+    /// It only latches a leaf while it copies entries out of it, so it sees the updates made
+    /// to the leaves ahead of it while it runs, but not the ones behind it.
+    pub fn range<K, R>(&self, range: R) -> Range<'_, 'a, C>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let mut range = Range {
+            tree: self,
+            entries: VecDeque::new(),
+            start: range.start_bound().map(|k| k.as_ref().to_vec()),
+            end: range.end_bound().map(|k| k.as_ref().to_vec()),
+            done: false,
+        };
+        range.fill();
+        range
+    }
+
+    /// Adds `val` to the values of `key`. Fails with InvalidInput, and leaves the tree as it
+    /// was, if the key and all of its values would no longer fit in half a page, or if `val`
+    /// is not the size of a Fixed value.
+    pub fn insert(&self, key: &[u8], val: &[u8]) -> io::Result<()> {
+        self.val_format.check(val)?;
+        // A key that is too long even for a single value must not raise max_key_len, which
+        // would make every later split hold on to more latches.
+        let val_len = self.val_format.encoded_len(val.len());
+        check_entry_len(entry_size(self.val_format, key.len(), val_len))?;
+        self.max_key_len
+            .fetch_max(key.len(), atomic::Ordering::Relaxed);
+        let mut leaf = self.write_leaf(key);
+        let leaf_node = leaf.as_leaf_mut();
+        check_entry_len(leaf_node.entry_len_with(self.val_format, key, val, &self.cmp))?;
+        let inserted = leaf_node.try_insert(self.val_format, key, val, &self.cmp);
+        if inserted.is_none() {
+            drop(leaf);
+            return self.insert_with_split(key, val);
+        }
+        Ok(())
+    }
+
+    /// Removes `key` and all of its values. Returns whether the key was found.
+    pub fn remove(&self, key: &[u8]) -> bool {
+        let mut leaf = self.write_leaf(key);
+        let removed = leaf.as_leaf_mut().remove(key, &self.cmp);
+        let underfull = leaf.is_underfull();
+        drop(leaf);
+        if removed && underfull {
+            self.rebalance(key);
+        }
+        removed
+    }
+
+    /// Removes one value of `key` that equals `val`, and the key itself if that was its
+    /// last value. Returns whether the value was found.
+    pub fn remove_value(&self, key: &[u8], val: &[u8]) -> bool {
+        let mut leaf = self.write_leaf(key);
+        let removed = leaf
+            .as_leaf_mut()
+            .remove_value(self.val_format, key, val, &self.cmp);
+        let underfull = leaf.is_underfull();
+        drop(leaf);
+        if removed && underfull {
+            self.rebalance(key);
+        }
+        removed
+    }
+
+    /// Inserts a value into a leaf that may have to be split. The latches of the nodes on
+    /// the way to it are kept from the highest one that is too full to take another child.
+    fn insert_with_split(&self, key: &[u8], val: &[u8]) -> io::Result<()> {
+        let (root, mut path, mut guard) =
+            self.write_path(key, |n, _, max_key_len| n.can_fit_child(max_key_len));
+
+        // Other updates may have added values to the key since it was last checked.
+        let leaf = guard.as_leaf_mut();
+        check_entry_len(leaf.entry_len_with(self.val_format, key, val, &self.cmp))?;
+        let Split {
+            mut pivot,
+            right: mut right_node,
+        } = match leaf.insert_or_split(self.val_format, key, val, &self.cmp) {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+
+        // Link the new leaf in between the split leaf and its old successor. No one else
+        // can reach it before it is linked in.
+        right_node.as_leaf_mut().set_next(leaf.next());
+        let mut right = self.nodes.insert(right_node);
+        leaf.set_next(Some(right));
+        drop(guard);
+
+        while let Some((mut parent, idx)) = path.pop() {
+            match parent.as_inner_mut().insert_or_split(idx, pivot, right) {
+                Some(split) => {
+                    pivot = split.pivot;
+                    right = self.nodes.insert(split.right);
+                }
+                None => return Ok(()),
+            }
+        }
+
+        // When the root splits, the tree grows by one level: the new root's only entry
+        // separates the old root from its new sibling.
+        let mut root = root.expect("Root must stay latched while it may split");
+        let mut new_root = Node::new_inner(self.pool);
+        new_root
+            .as_inner_mut()
+            .insert_entry(InnerEntry {
+                left: *root,
+                right,
+                key: pivot,
+            })
+            .expect("Inner node must have capacity for entries after split");
+        *root = self.nodes.insert(new_root);
+        Ok(())
+    }
+
+    /// Restores the fill of the leaf that contains `key`, and of its ancestors, if it is
+    /// underfull. The latches of the nodes on the way to it are kept from the highest one
+    /// that would underflow if it lost a child.
+    fn rebalance(&self, key: &[u8]) {
+        // The root is replaced once it is left with a single child.
+        let (root, mut path, mut guard) = self.write_path(key, |n, is_root, max_key_len| {
+            n.can_lose_child(max_key_len) && !(is_root && n.child_count() <= 2)
+        });
+
+        while let Some((mut parent, idx)) = path.pop() {
+            if !guard.is_underfull() {
+                return;
+            }
+
+            // Pair the node with its right sibling, or its left one if it is the last
+            // child. Its own latch is released first, so that the pair can be latched from
+            // left to right. Only scans can get to it in the meantime.
+            drop(guard);
+            let parent_node = parent.as_inner();
+            let left_idx = if idx + 1 < parent_node.child_count() {
+                idx
+            } else {
+                idx - 1
+            };
+            let right_id = parent_node.child(left_idx + 1);
+            let mut left = self.nodes.get(parent_node.child(left_idx)).write_arc();
+            let mut right = self.nodes.get(right_id).write_arc();
+            let pivot = parent_node.key(left_idx).to_vec();
+            let max_pivot_len = parent_node.max_key_len(left_idx);
+
+            let result = match (&mut *left, &mut *right) {
+                (Node::LeafNode(l), Node::LeafNode(r)) => {
+                    l.merge_or_rebalance(r, self.val_format, max_pivot_len)
+                }
+                (Node::InnerNode(l), Node::InnerNode(r)) => {
+                    l.merge_or_rebalance(&pivot, r, max_pivot_len)
+                }
+                _ => panic!("Siblings must be on the same level"),
+            };
+            let parent_node = parent.as_inner_mut();
+            match result {
+                Rebalance::Merged => {
+                    parent_node.remove_child(left_idx + 1);
+                    self.nodes.remove(right_id);
+                }
+                Rebalance::Rebalanced(pivot) => {
+                    parent_node
+                        .set_key(left_idx, pivot)
+                        .expect("Rebalancing must pick a pivot that fits in the parent");
+                    return;
+                }
+                Rebalance::Unchanged => return,
+            }
+            guard = parent;
+        }
+        drop(guard);
+
+        // When the root is left with a single child, the tree shrinks by one level.
+        if let Some(mut root) = root {
+            loop {
+                let old_root = self.nodes.get(*root).write_arc();
+                let child = match &*old_root {
+                    Node::InnerNode(n) if n.child_count() == 1 => n.child(0),
+                    _ => break,
+                };
+                self.nodes.remove(*root);
+                *root = child;
+            }
+        }
+    }
+
+    /// Latches the nodes on the way to the leaf whose key range contains `key` for writing,
+    /// along with the root id. A change to the leaf stops at the last inner node for which
+    /// `stops` holds, given whether it is the root and the longest key that a change below
+    /// can move into it, so the latches above that node are released on the way down.
+    ///
+    /// Returns the root's latch if it is still held, each latched inner node with the index
+    /// of the child on the way, and the leaf.
+    fn write_path<F>(&self, key: &[u8], stops: F) -> WritePath<'_, 'a>
+    where
+        F: Fn(&InnerNode<'a>, bool, usize) -> bool,
+    {
+        loop {
+            // No key in the tree is longer than the bound once the root is latched, except
+            // for the ones that other updates add to the leaves ahead of this one.
+            let mut root = Some(self.root.write());
+            let max_key_len = self.max_key_len.load(atomic::Ordering::Relaxed);
+            let mut path = Vec::new();
+            let mut guard = self.nodes.get(*root.as_deref().unwrap()).write_arc();
+            while let Node::InnerNode(n) = &*guard {
+                let is_root = root.is_some() && path.is_empty();
+                if stops(n, is_root, max_key_len) {
+                    root = None;
+                    path.clear();
+                }
+                let idx = n.child_index(key, &self.cmp);
+                let child = self.nodes.get(n.child(idx)).write_arc();
+                path.push((guard, idx));
+                guard = child;
+            }
+
+            // Every key in the leaf was counted in the bound before it was added, which
+            // happened before the leaf was latched here. If one of them is longer than the
+            // bound that the latches were released by, a change may reach further up than
+            // they were kept, so start over with the new bound.
+            if self.max_key_len.load(atomic::Ordering::Relaxed) <= max_key_len {
+                return (root, path, guard);
+            }
+        }
+    }
+
+    /// Returns a read latch on the leaf whose key range contains `key`, or the leftmost
+    /// leaf for None.
+    fn read_leaf(&self, key: Option<&[u8]>) -> ReadGuard<'a> {
+        let root = self.root.read();
+        let mut guard = self.nodes.get(*root).read_arc();
+        drop(root);
+        while let Node::InnerNode(n) = &*guard {
+            let child = match key {
+                Some(key) => n.child(n.child_index(key, &self.cmp)),
+                None => n.child(0),
+            };
+            guard = self.nodes.get(child).read_arc();
+        }
+        guard
+    }
+
+    /// Returns a write latch on the leaf whose key range contains `key`. Inner nodes are
+    /// only latched for reading on the way to it.
+    fn write_leaf(&self, key: &[u8]) -> WriteGuard<'a> {
+        // The leaf's parent, or the root if it is the leaf, stays latched until the leaf is
+        // latched for writing, so that it can not be split or merged in the meantime.
+        let mut root = Some(self.root.read());
+        let mut parent = None;
+        let mut latch = self.nodes.get(*root.as_deref().unwrap());
+        loop {
+            let guard = latch.read_arc();
+            let child = match &*guard {
+                Node::InnerNode(n) => n.child(n.child_index(key, &self.cmp)),
+                Node::LeafNode(_) => {
+                    drop(guard);
+                    let leaf = latch.write_arc();
+                    drop((parent, root));
+                    return leaf;
+                }
+            };
+            latch = self.nodes.get(child);
+            parent = Some(guard);
+            drop(root.take());
+        }
+    }
+}
+
+/// The values of a key in a ConcurrentBTree, returned by [`ConcurrentBTree::get`]. They
+/// hold a read latch on the key's leaf until they are dropped.
+pub struct Values<'a> {
+    // Keeps the leaf latched.
+    _leaf: ReadGuard<'a>,
+    // Points into the leaf's page, which can not change while its latch is held.
+    entry: *const PageEntry,
+    val_format: ValueFormat,
+}
+
+impl<'a> Values<'a> {
+    pub fn iter(&self) -> ValuesIterator<'_> {
+        unsafe { &*self.entry }.values_iter(self.val_format)
+    }
+}
+
+/// An ordered iterator over the keys of a ConcurrentBTree and their values, created by
+/// [`ConcurrentBTree::scan`], [`ConcurrentBTree::range`] and [`ConcurrentBTree::prefix`].
+///
+/// Keys and values are copied out of a leaf when the iterator gets to it, and no latch is
+/// held between calls to `next`, so the thread that owns the iterator can update the tree
+/// while it runs. Once the copied entries run out, the iterator finds its way back from the
+/// root to the leaf after the last key it returned, since that leaf may have been split or
+/// merged in the meantime.
+pub struct Range<'t, 'a, C = Bytewise> {
+    tree: &'t ConcurrentBTree<'a, C>,
+    entries: VecDeque<(Vec<u8>, Vec<Vec<u8>>)>,
+    // Excludes the last key that was copied, once there is one.
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    // Set once the end of the range is reached.
+    done: bool,
+}
+
+impl<'t, 'a, C: KeyComparator> Range<'t, 'a, C> {
+    /// Copies the next entries within range, from the first leaf after `start` that has any.
+    /// The leaves are walked from left to right through the `next` links in their page
+    /// headers, latching each leaf before it releases the one before it.
+    fn fill(&mut self) {
+        let mut leaf = match &self.start {
+            Bound::Included(k) | Bound::Excluded(k) => self.tree.read_leaf(Some(k)),
+            Bound::Unbounded => self.tree.read_leaf(None),
+        };
+        while !self.copy_entries(&leaf) && self.entries.is_empty() {
+            match leaf.as_leaf().next() {
+                Some(id) => leaf = self.tree.nodes.get(id).read_arc(),
+                None => break,
+            }
+        }
+        if self.entries.is_empty() {
+            self.done = true;
+        }
+    }
+
+    /// Copies the entries of `leaf` that are within range. Returns whether it got past the
+    /// end of the range.
+    fn copy_entries(&mut self, leaf: &ReadGuard<'a>) -> bool {
+        let cmp = &self.tree.cmp;
+        for (_, entry) in leaf.as_leaf().scan() {
+            let entry = unsafe { &*entry };
+            let key = entry.key();
+            let after_start = match &self.start {
+                Bound::Included(start) => cmp.compare(key, start) != Ordering::Less,
+                Bound::Excluded(start) => cmp.compare(key, start) == Ordering::Greater,
+                Bound::Unbounded => true,
+            };
+            if !after_start {
+                continue;
+            }
+
+            let before_end = match &self.end {
+                Bound::Included(end) => cmp.compare(key, end) != Ordering::Greater,
+                Bound::Excluded(end) => cmp.compare(key, end) == Ordering::Less,
+                Bound::Unbounded => true,
+            };
+            if !before_end {
+                self.done = true;
+                return true;
+            }
+            let vals = entry
+                .values_iter(self.tree.val_format)
+                .map(|val| val.to_vec())
+                .collect();
+            self.start = Bound::Excluded(key.to_vec());
+            self.entries.push_back((key.to_vec(), vals));
+        }
+        false
+    }
+}
+
+impl<'t, 'a, C: KeyComparator> Iterator for Range<'t, 'a, C> {
+    type Item = (Vec<u8>, Vec<Vec<u8>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.entries.is_empty() && !self.done {
+            self.fill();
+        }
+        self.entries.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+    use std::collections::BTreeMap;
+    use std::convert::TryInto;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::node::MAX_ENTRY_LEN;
+
+    type Model = BTreeMap<Vec<u8>, Vec<u64>>;
+
+    fn val_layout() -> Layout {
+        Layout::from_size_align(8, 8).unwrap()
+    }
+
+    // Long keys, so that a few hundred of them split the root more than once.
+    fn key(thread: u8, k: u32) -> Vec<u8> {
+        let mut key = vec![k as u8; 100 + (k as usize * 37) % 400];
+        key[0] = thread;
+        key[1..5].copy_from_slice(&k.to_be_bytes());
+        key
+    }
+
+    fn get_u64s<C: KeyComparator>(tree: &ConcurrentBTree<C>, key: &[u8]) -> Option<Vec<u64>> {
+        let values = tree.get(key)?;
+        let mut vals = values
+            .iter()
+            .map(|v| u64::from_le_bytes(v.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        vals.sort_unstable();
+        Some(vals)
+    }
+
+    fn contents<C: KeyComparator>(tree: &ConcurrentBTree<C>) -> Model {
+        tree.scan()
+            .map(|(key, vals)| {
+                let mut vals = vals
+                    .iter()
+                    .map(|v| u64::from_le_bytes(v[..].try_into().unwrap()))
+                    .collect::<Vec<_>>();
+                vals.sort_unstable();
+                (key, vals)
+            })
+            .collect()
+    }
+
+    /// Makes a random update to one of the first `keys` keys of `thread`, in `tree` and
+    /// in `model`.
+    fn update(tree: &ConcurrentBTree, thread: u8, keys: u32, model: &mut Model, rng: &mut StdRng) {
+        let key = key(thread, rng.gen_range(0, keys));
+        match rng.gen_range(0, 10) {
+            0..=5 => {
+                let val = rng.gen_range(0, 4u64);
+                tree.insert(&key, &val.to_le_bytes()).unwrap();
+                let vals = model.entry(key).or_default();
+                vals.push(val);
+                vals.sort_unstable();
+            }
+            6 | 7 => {
+                assert_eq!(model.remove(&key).is_some(), tree.remove(&key));
+            }
+            _ => {
+                let val = rng.gen_range(0, 4u64);
+                let removed = tree.remove_value(&key, &val.to_le_bytes());
+                let vals = model.entry(key.clone()).or_default();
+                let idx = vals.iter().position(|&v| v == val);
+                assert_eq!(idx.is_some(), removed);
+                if let Some(idx) = idx {
+                    vals.remove(idx);
+                }
+                if vals.is_empty() {
+                    model.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Checks the order of the keys and the shape of a tree that no one else is using.
+    fn check_invariants(tree: &ConcurrentBTree) {
+        let mut leaves = Vec::new();
+        check_node(tree, *tree.root.read(), None, None, 1, &mut leaves);
+        assert!(leaves.iter().all(|&(_, depth)| depth == leaves[0].1));
+
+        let mut linked = Vec::new();
+        let mut next = Some(leaves[0].0);
+        while let Some(id) = next {
+            linked.push(id);
+            next = tree.nodes.get(id).read().as_leaf().next();
+        }
+        assert_eq!(leaves.iter().map(|&(id, _)| id).collect::<Vec<_>>(), linked);
+    }
+
+    fn check_node(
+        tree: &ConcurrentBTree,
+        id: NodeId,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+        depth: usize,
+        leaves: &mut Vec<(NodeId, usize)>,
+    ) {
+        let in_bounds =
+            |key: &[u8]| lower.is_none_or(|lower| key >= lower) && upper.is_none_or(|u| key < u);
+        let latch = tree.nodes.get(id);
+        let node = latch.read();
+        match &*node {
+            Node::LeafNode(n) => {
+                assert!(n
+                    .scan()
+                    .all(|(_, entry)| in_bounds(unsafe { &*entry }.key())));
+                leaves.push((id, depth));
+            }
+            Node::InnerNode(n) => {
+                let count = n.child_count();
+                assert!(count > 1 || depth > 1);
+                for i in 0..count {
+                    let lower = if i == 0 { lower } else { Some(n.key(i - 1)) };
+                    let upper = if i + 1 == count {
+                        upper
+                    } else {
+                        Some(n.key(i))
+                    };
+                    assert!(lower.is_none_or(in_bounds));
+                    check_node(tree, n.child(i), lower, upper, depth + 1, leaves);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_updates_match_model() {
+        let pool = Pool::new();
+        let tree = ConcurrentBTree::new(val_layout(), &pool);
+        let mut rng = StdRng::seed_from_u64(51);
+        let mut model = Model::new();
+        for i in 0..3000 {
+            update(&tree, 0, 150, &mut model, &mut rng);
+            if i % 500 == 0 {
+                check_invariants(&tree);
+            }
+        }
+        check_invariants(&tree);
+        assert_eq!(model, contents(&tree));
+        for (key, vals) in &model {
+            assert_eq!(Some(vals), get_u64s(&tree, key).as_ref());
+        }
+        assert_eq!(None, get_u64s(&tree, &key(1, 0)));
+
+        let prefix = key(0, 7)[..5].to_vec();
+        let expected = model
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix));
+        assert!(tree
+            .prefix(&prefix)
+            .map(|(k, _)| k)
+            .eq(expected.map(|(k, _)| k.clone())));
+
+        // Removing everything collapses the tree back into a single leaf.
+        for key in model.keys() {
+            assert!(tree.remove(key));
+        }
+        check_invariants(&tree);
+        assert_eq!(0, tree.scan().count());
+        assert!(matches!(
+            &*tree.nodes.get(*tree.root.read()).read(),
+            Node::LeafNode(_)
+        ));
+    }
+
+    #[test]
+    fn test_entry_size_limit() {
+        let pool = Pool::new();
+        let tree = ConcurrentBTree::new(ValueFormat::Variable, &pool);
+        for k in 0..200 {
+            tree.insert(&key(0, k), &[0; 100]).unwrap();
+        }
+
+        // Values are added to one key until the next one no longer fits, and the tree stays
+        // as it was before the insert that failed.
+        let k = key(0, 100);
+        let mut count = 1;
+        let err = loop {
+            match tree.insert(&k, &[1; 1000]) {
+                Ok(()) => count += 1,
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert_eq!(count, tree.get(&k).unwrap().iter().count());
+        let vals_len = 102 + (count - 1) * 1002;
+        assert!(entry_size(ValueFormat::Variable, k.len(), vals_len + 1002) > MAX_ENTRY_LEN);
+
+        // A key that is too long for any value is rejected before it can make splits take
+        // more latches.
+        let max_key_len = tree.max_key_len.load(atomic::Ordering::Relaxed);
+        let err = tree.insert(&[7; MAX_ENTRY_LEN], &[]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert_eq!(
+            max_key_len,
+            tree.max_key_len.load(atomic::Ordering::Relaxed)
+        );
+        check_invariants(&tree);
+        assert_eq!(200, tree.scan().count());
+
+        // Fixed values must have the size of their layout.
+        let tree = ConcurrentBTree::new(val_layout(), &pool);
+        for val in [&[1; 7][..], &[1; 9]] {
+            let err = tree.insert(&k, val).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+        assert!(tree.get(&k).is_none());
+        tree.insert(&k, &[1; 8]).unwrap();
+    }
+
+    // A range holds no latches between items, so the thread that iterates over the tree can
+    // update it along the way, even the leaf that the range is in.
+    #[test]
+    fn test_update_while_iterating() {
+        let pool = Pool::new();
+        let tree = ConcurrentBTree::new(val_layout(), &pool);
+        let mut model = Model::new();
+        for k in 0..150 {
+            tree.insert(&key(0, k), &0u64.to_le_bytes()).unwrap();
+            model.insert(key(0, k), vec![0]);
+        }
+
+        let mut seen = Vec::new();
+        for (k, vals) in tree.scan() {
+            assert_eq!(
+                model[&k],
+                vals.iter()
+                    .map(|v| u64::from_le_bytes(v[..].try_into().unwrap()))
+                    .collect::<Vec<_>>()
+            );
+            seen.push(k.clone());
+            // Grow the current key, which splits leaves as they fill up, and remove every
+            // other key behind it, which merges them as they empty.
+            tree.insert(&k, &1u64.to_le_bytes()).unwrap();
+            model.get_mut(&k).unwrap().push(1);
+            if seen.len() % 2 == 0 {
+                let behind = &seen[seen.len() - 2];
+                assert!(tree.remove(behind));
+                model.remove(behind);
+            }
+        }
+
+        check_invariants(&tree);
+        assert_eq!((0..150).map(|k| key(0, k)).collect::<Vec<_>>(), seen);
+        assert_eq!(model, contents(&tree));
+    }
+
+    // Writers update disjoint sets of keys, so that each of them can keep a model of its
+    // own keys, while they split and merge the nodes that they share. Readers look up keys
+    // and scan the tree at the same time.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_concurrent_updates() {
+        const WRITERS: u8 = 4;
+        let pool = Pool::new();
+        let tree = ConcurrentBTree::new(val_layout(), &pool);
+        let done = AtomicBool::new(false);
+
+        let models = thread::scope(|s| {
+            for seed in 0..2 {
+                let (tree, done) = (&tree, &done);
+                s.spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(seed);
+                    while !done.load(atomic::Ordering::Relaxed) {
+                        let key = key(rng.gen_range(0, WRITERS), rng.gen_range(0, 150));
+                        if let Some(values) = tree.get(&key) {
+                            assert!(values.iter().count() > 0);
+                        }
+                        let keys = tree.range(key..).take(20).map(|(k, _)| k);
+                        let keys = keys.collect::<Vec<_>>();
+                        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+                    }
+                });
+            }
+
+            let writers = (0..WRITERS)
+                .map(|thread| {
+                    let tree = &tree;
+                    s.spawn(move || {
+                        let mut rng = StdRng::seed_from_u64(100 + thread as u64);
+                        let mut model = Model::new();
+                        for _ in 0..4000 {
+                            update(tree, thread, 1500, &mut model, &mut rng);
+                        }
+                        // Shrink the tree again while the others are still using it.
+                        let keys = model.keys().cloned().collect::<Vec<_>>();
+                        for (i, key) in keys.iter().enumerate() {
+                            if i % 10 != 0 {
+                                assert!(tree.remove(key));
+                                model.remove(key);
+                            }
+                        }
+                        model
+                    })
+                })
+                .collect::<Vec<_>>();
+            let models = writers
+                .into_iter()
+                .map(|writer| writer.join().unwrap())
+                .collect::<Vec<_>>();
+            done.store(true, atomic::Ordering::Relaxed);
+            models
+        });
+
+        check_invariants(&tree);
+        let model = models.into_iter().flatten().collect::<Model>();
+        assert_eq!(model, contents(&tree));
+    }
+
+    // Every thread inserts its own keys, and checks that it can find each of them, along
+    // with every key inserted before it, while the others keep splitting the tree.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_concurrent_inserts() {
+        const THREADS: u8 = 8;
+        const KEYS: u32 = 400;
+        let pool = Pool::new();
+        let tree = ConcurrentBTree::new(val_layout(), &pool);
+        thread::scope(|s| {
+            for thread in 0..THREADS {
+                let tree = &tree;
+                s.spawn(move || {
+                    for k in 0..KEYS {
+                        tree.insert(&key(thread, k), &(k as u64).to_le_bytes())
+                            .unwrap();
+                        let found = get_u64s(tree, &key(thread, k / 2));
+                        assert_eq!(Some(vec![(k / 2) as u64]), found);
+                    }
+                });
+            }
+        });
+
+        check_invariants(&tree);
+        assert_eq!(THREADS as usize * KEYS as usize, tree.scan().count());
+        for thread in 0..THREADS {
+            for k in 0..KEYS {
+                assert_eq!(Some(vec![k as u64]), get_u64s(&tree, &key(thread, k)));
+            }
+        }
+    }
+}
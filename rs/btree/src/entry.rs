@@ -1,7 +1,11 @@
 use core::fmt;
 use std::{
     alloc::Layout,
+    borrow::Cow,
+    convert::TryInto,
+    io,
     mem::{align_of, size_of},
+    ops::Range,
 };
 
 use crate::util::pad_for;
@@ -14,8 +18,8 @@ use crate::util::pad_for;
 //
 // Since the keys inside an index page are never referenced directly by a pointer or any other
 // physical identifier, the entry pointers can be rearranged to keep them ordered by keys.
-// TODO: we should benchmark the performance of keeping keys ordered versus keeping entries internal to
-// a page unordered and always performing a linear scan (and potentially a sort).
+// Keeping the key pointers ordered lets lookups binary search them instead of performing a linear
+// scan; benches/leaf_search.rs compares the two.
 // Another option would be keeping elements unsorted but including a "next element" pointer
 // like InnoDB does. Again, these are things that need thorough benchmarking before we settle on an
 // option.
@@ -34,10 +38,6 @@ pub(crate) struct EntryRef {
 }
 
 impl EntryRef {
-    pub(crate) fn new(offset: u16, length: u16) -> EntryRef {
-        EntryRef { offset, length }
-    }
-
     #[inline]
     pub(crate) fn layout() -> Layout {
         ENTRY_REF_LAYOUT
@@ -49,6 +49,78 @@ impl EntryRef {
     }
 }
 
+/// How the values of a tree are stored. Either way, a key can have many values.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ValueFormat {
+    /// Every value has the same layout, such as a row id, and is aligned within its page.
+    Fixed(Layout),
+    /// Each value has its own length, such as a row payload, which is stored in front of
+    /// it. Values are not aligned. A key and all of its values must fit in half a page,
+    /// or inserting them fails.
+    Variable,
+}
+
+// The length prefix of a value in the Variable format.
+const VALUE_LEN_SIZE: usize = size_of::<u16>();
+
+impl ValueFormat {
+    /// The alignment of the values within a page.
+    pub(crate) fn align(self) -> usize {
+        match self {
+            ValueFormat::Fixed(layout) => layout.align(),
+            ValueFormat::Variable => 1,
+        }
+    }
+
+    /// Fails with InvalidInput if `val` does not have the size of a Fixed layout.
+    pub(crate) fn check(self, val: &[u8]) -> io::Result<()> {
+        match self {
+            ValueFormat::Fixed(layout) if val.len() != layout.size() => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Value of {} bytes does not match the layout of {} bytes",
+                    val.len(),
+                    layout.size()
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The number of bytes that a value of `len` bytes takes up in an entry.
+    pub(crate) fn encoded_len(self, len: usize) -> usize {
+        match self {
+            ValueFormat::Fixed(_) => len,
+            ValueFormat::Variable => VALUE_LEN_SIZE + len,
+        }
+    }
+
+    /// Returns `val` as it is stored in an entry.
+    pub(crate) fn encode(self, val: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            ValueFormat::Fixed(layout) => {
+                debug_assert_eq!(val.len(), layout.size());
+                Cow::Borrowed(val)
+            }
+            ValueFormat::Variable => {
+                let len: u16 = val.len().try_into().unwrap_or_else(|_| {
+                    panic!("Value of {} bytes is too long", val.len());
+                });
+                let mut encoded = Vec::with_capacity(VALUE_LEN_SIZE + val.len());
+                encoded.extend_from_slice(&len.to_ne_bytes());
+                encoded.extend_from_slice(val);
+                Cow::Owned(encoded)
+            }
+        }
+    }
+}
+
+impl From<Layout> for ValueFormat {
+    fn from(layout: Layout) -> ValueFormat {
+        ValueFormat::Fixed(layout)
+    }
+}
+
 // TODO: Embed the header in an entry like we do with `page::header::Header` in `page::Page`.
 // Possible optimization: allocate some fixed number of value slots for each entry and
 // keep track of how many are free. This way, we minimize the frequency of allocating
@@ -56,7 +128,9 @@ impl EntryRef {
 #[repr(packed, C)]
 pub struct PageEntry {
     pub(crate) key_len: u16,
-    pub(crate) val_count: u16, // TODO: Do we need to know the length of each element? The total length?
+    pub(crate) val_count: u16,
+    // The key, followed by padding to align the values, followed by the values, which are
+    // each prefixed with their length in the Variable format.
     pub(crate) data: [u8],
 }
 
@@ -65,23 +139,37 @@ impl PageEntry {
         &self.data[0..(self.key_len as usize)]
     }
 
-    pub(crate) fn values_buffer(&self, val_layout: Layout) -> &[u8] {
-        &self.data[self.values_offset(val_layout)..]
+    /// The values as they are stored, which can be copied into another entry as is.
+    pub(crate) fn values_buffer(&self, val_format: ValueFormat) -> &[u8] {
+        &self.data[self.values_offset(val_format)..]
     }
 
-    pub(crate) fn values_iter(&self, val_layout: Layout) -> ValuesIterator {
+    pub(crate) fn values_iter(&self, val_format: ValueFormat) -> ValuesIterator<'_> {
         ValuesIterator {
-            layout: val_layout,
+            format: val_format,
             data: &self.data,
-            offset: self.values_offset(val_layout),
+            offset: self.values_offset(val_format),
+            remaining: self.val_count,
+        }
+    }
+
+    /// Returns the range of `data` that holds the first value that equals `val`, including
+    /// its length prefix.
+    pub(crate) fn find_value(&self, val_format: ValueFormat, val: &[u8]) -> Option<Range<usize>> {
+        let mut values = self.values_iter(val_format);
+        loop {
+            let start = values.offset;
+            if values.next()? == val {
+                return Some(start..values.offset);
+            }
         }
     }
 
-    fn values_offset(&self, val_layout: Layout) -> usize {
+    fn values_offset(&self, val_format: ValueFormat) -> usize {
         self.key_len as usize
             + pad_for(
                 PAGE_ENTRY_HEADER_SIZE + self.key_len as usize,
-                val_layout.align(),
+                val_format.align(),
             )
     }
 }
@@ -89,36 +177,43 @@ impl PageEntry {
 impl fmt::Debug for PageEntry {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("PageEntry")
-            .field("key_len", &self.key_len)
-            .field("val_count", &self.val_count)
+            .field("key_len", &{ self.key_len })
+            .field("val_count", &{ self.val_count })
             .field("data", &&self.data)
             .finish()
     }
 }
 
 pub struct ValuesIterator<'a> {
-    layout: Layout,
+    format: ValueFormat,
     data: &'a [u8],
     offset: usize,
+    remaining: u16,
 }
 
 impl<'a> Iterator for ValuesIterator<'a> {
     type Item = &'a [u8];
 
     fn next(&mut self) -> Option<Self::Item> {
-        let size = self.layout.size();
-        let offset = self.offset;
-        let end = offset + size;
-        if end > self.data.len() {
+        if self.remaining == 0 {
             return None;
         }
-
-        self.offset += size;
-        Some(&self.data[offset..end])
+        let (start, len) = match self.format {
+            ValueFormat::Fixed(layout) => (self.offset, layout.size()),
+            ValueFormat::Variable => {
+                let prefix = &self.data[self.offset..self.offset + VALUE_LEN_SIZE];
+                let len = u16::from_ne_bytes(prefix.try_into().unwrap());
+                (self.offset + VALUE_LEN_SIZE, len as usize)
+            }
+        };
+
+        self.remaining -= 1;
+        self.offset = start + len;
+        Some(&self.data[start..start + len])
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        let size = self.data.len() / self.layout.size();
+        let size = self.remaining as usize;
         (size, Some(size))
     }
 }
@@ -0,0 +1,11 @@
+pub mod comparator;
+pub mod concurrent;
+pub mod entry;
+pub mod node;
+pub mod page;
+mod pager;
+#[cfg(test)]
+mod test_util;
+pub mod tree;
+mod util;
+mod wal;
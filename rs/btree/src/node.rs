@@ -1,24 +1,624 @@
 use std::alloc::Layout;
+use std::borrow::Cow;
+use std::cell::{Cell, OnceCell};
+use std::cmp::Ordering;
 use std::convert::TryInto;
-use std::intrinsics::copy_nonoverlapping;
+use std::io;
 use std::mem::{self, align_of, size_of};
-use std::ops::{Deref, DerefMut};
-use std::ptr::NonNull;
+use std::num::NonZeroU64;
+use std::ops::{Deref, DerefMut, Index, IndexMut};
+use std::ptr::copy_nonoverlapping;
 use std::{ptr, slice};
 
-use crate::entry::{EntryRef, PageEntry, PAGE_ENTRY_HEADER_ALIGN, PAGE_ENTRY_HEADER_SIZE};
-use crate::page::{Allocation, Header, Page, Pool};
+use crate::comparator::KeyComparator;
+use crate::entry::{
+    EntryRef, PageEntry, ValueFormat, PAGE_ENTRY_HEADER_ALIGN, PAGE_ENTRY_HEADER_SIZE,
+};
+use crate::page::{Allocation, Header, Page, Pool, PAGE_SIZE};
+use crate::pager::{Meta, Pager};
 use crate::util::{pad_for, round_to};
+use crate::wal::{Record, Wal};
 
+/// Identifies a node in its tree's NodeTable. Ids start at 1, so they can also be stored
+/// as a NonZeroU64. For a disk-backed tree, the id is the number of the node's page.
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 #[repr(transparent)]
 pub struct NodeId(u64);
 
+impl NodeId {
+    pub(crate) fn new(id: u64) -> Option<NodeId> {
+        NonZeroU64::new(id).map(NodeId::from)
+    }
+
+    pub(crate) fn get(self) -> u64 {
+        self.0
+    }
+
+    fn index(self) -> usize {
+        self.0 as usize - 1
+    }
+}
+
+impl From<NodeId> for NonZeroU64 {
+    fn from(id: NodeId) -> NonZeroU64 {
+        NonZeroU64::new(id.0).expect("Node ids start at 1")
+    }
+}
+
+impl From<NonZeroU64> for NodeId {
+    fn from(id: NonZeroU64) -> NodeId {
+        NodeId(id.get())
+    }
+}
+
+/// Owns every node of a tree. Nodes refer to each other by NodeId instead of by address, so
+/// a node can be moved or replaced without leaving dangling references in its parent.
+///
+/// For a disk-backed tree, the table is also its buffer pool. A NodeId is then the number of
+/// the node's page in the file, and only some of the nodes are kept in memory. The others
+/// are read back in when they are accessed, which can happen through a shared reference.
+/// Nodes are only written back and evicted by `evict` and `flush`, which take a mutable
+/// reference, so that no borrow of a node can outlive it.
+///
+/// Every change to a disk-backed table is logged to its Wal by `commit`, at the end of the
+/// update that made it. A changed node is logged as a whole the first time after each
+/// flush, and with the record passed to `describe`, if any, after that.
+pub(crate) struct NodeTable<'a> {
+    slots: Vec<Slot<'a>>,
+    // Ids of removed nodes, which are reused before the table grows.
+    free: Vec<NodeId>,
+    store: Option<Store<'a>>,
+}
+
+struct Slot<'a> {
+    // Empty while the node is free, or only on disk.
+    node: OnceCell<Node<'a>>,
+    // Set whenever the node is accessed, and cleared when the clock hand passes it.
+    referenced: Cell<bool>,
+    // Whether the node has changed since it was last written to disk.
+    dirty: bool,
+    // Whether the id is in the table's free list.
+    free: bool,
+    // Whether the node has changed in the current update, and how, if it was described.
+    touched: bool,
+    record: Option<Record>,
+    // Whether an image of the node has been logged since the last flush.
+    logged: bool,
+}
+
+impl<'a> Slot<'a> {
+    fn new(node: Option<Node<'a>>) -> Slot<'a> {
+        Slot {
+            dirty: node.is_some(),
+            free: false,
+            touched: false,
+            record: None,
+            logged: false,
+            referenced: Cell::new(node.is_some()),
+            node: node.map_or_else(OnceCell::new, OnceCell::from),
+        }
+    }
+}
+
+/// The files behind a disk-backed NodeTable, and the state of the cache in front of them.
+struct Store<'a> {
+    pager: Pager,
+    wal: Wal,
+    pool: &'a Pool,
+    // The number of nodes that `evict` keeps in memory.
+    capacity: usize,
+    resident: Cell<usize>,
+    // Where the clock hand of `evict` points to in `slots`.
+    hand: usize,
+    // Ids in `free` before this index are already linked into the free list on disk.
+    free_synced: usize,
+    // The nodes changed and freed by the current update, in order.
+    touched: Vec<NodeId>,
+    freed: Vec<NodeId>,
+}
+
+// The kinds of page in a disk-backed tree's file, stored in their headers.
+const FREE_PAGE: u8 = 0;
+const LEAF_PAGE: u8 = 1;
+const INNER_PAGE: u8 = 2;
+
+impl<'a> NodeTable<'a> {
+    pub(crate) fn new() -> NodeTable<'a> {
+        NodeTable {
+            slots: Vec::new(),
+            free: Vec::new(),
+            store: None,
+        }
+    }
+
+    /// Creates a table for the nodes in a file with `page_count` node pages, of which the
+    /// ones in `free` are free. Nodes are read from the file as they are needed.
+    pub(crate) fn open(
+        pager: Pager,
+        wal: Wal,
+        pool: &'a Pool,
+        page_count: u64,
+        free: Vec<NodeId>,
+    ) -> io::Result<NodeTable<'a>> {
+        let mut slots = (0..page_count).map(|_| Slot::new(None)).collect::<Vec<_>>();
+        for id in &free {
+            match slots.get_mut(id.index()) {
+                Some(slot) if !slot.free => slot.free = true,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Free list contains {:?} more than once, or past the end",
+                            id
+                        ),
+                    ))
+                }
+            }
+        }
+        Ok(NodeTable {
+            slots,
+            store: Some(Store {
+                pager,
+                wal,
+                pool,
+                capacity: DEFAULT_CACHE_CAPACITY,
+                resident: Cell::new(0),
+                hand: 0,
+                free_synced: free.len(),
+                touched: Vec::new(),
+                freed: Vec::new(),
+            }),
+            free,
+        })
+    }
+
+    pub(crate) fn insert(&mut self, node: Node<'a>) -> NodeId {
+        if let Some(store) = &mut self.store {
+            store.resident.set(store.resident.get() + 1);
+        }
+        let id = match self.free.pop() {
+            Some(id) => {
+                if let Some(store) = &mut self.store {
+                    store.free_synced = store.free_synced.min(self.free.len());
+                }
+                self.slots[id.index()] = Slot::new(Some(node));
+                id
+            }
+            None => {
+                self.slots.push(Slot::new(Some(node)));
+                NodeId(self.slots.len() as u64)
+            }
+        };
+        self.touch(id);
+        id
+    }
+
+    /// Removes a node from the table, and returns its page to the pool. Its id may be
+    /// handed out again by a later insert.
+    pub(crate) fn remove(&mut self, id: NodeId) {
+        let slot = &mut self.slots[id.index()];
+        if slot.free || (slot.node.get().is_none() && self.store.is_none()) {
+            panic!("{:?} is not in the node table", id);
+        }
+        if let (Some(_), Some(store)) = (slot.node.take(), &self.store) {
+            store.resident.set(store.resident.get() - 1);
+        }
+        slot.dirty = false;
+        slot.free = true;
+        self.free.push(id);
+        if let Some(store) = &mut self.store {
+            store.freed.push(id);
+        }
+    }
+
+    /// Borrows two different nodes mutably at the same time.
+    pub(crate) fn pair_mut(&mut self, a: NodeId, b: NodeId) -> (&mut Node<'a>, &mut Node<'a>) {
+        assert_ne!(a, b);
+        // Make sure both are in memory.
+        let _ = (&self[a], &self[b]);
+        self.touch(a);
+        self.touch(b);
+        let (a, b) = (a.index(), b.index());
+        let (low, high) = self.slots.split_at_mut(a.max(b));
+        let (a, b) = if a < b {
+            (&mut low[a], &mut high[0])
+        } else {
+            (&mut high[0], &mut low[b])
+        };
+        (a.node.get_mut().unwrap(), b.node.get_mut().unwrap())
+    }
+
+    /// Describes the change that the current update made to node `id`, so that it can be
+    /// logged as such instead of as an image of the node. Only holds if the node is not
+    /// borrowed mutably again during the update.
+    pub(crate) fn describe<F: FnOnce() -> Record>(&mut self, id: NodeId, record: F) {
+        if self.store.is_some() {
+            self.slots[id.index()].record = Some(record());
+        }
+    }
+
+    /// Logs the changes made by the current update, which leaves the tree with `root`.
+    pub(crate) fn commit(&mut self, root: NodeId) -> io::Result<()> {
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+        let mut records = store
+            .freed
+            .drain(..)
+            .map(|id| Record::Free { id })
+            .collect::<Vec<_>>();
+        for id in store.touched.drain(..) {
+            let slot = &mut self.slots[id.index()];
+            let record = slot.record.take();
+            if !mem::replace(&mut slot.touched, false) || slot.free {
+                continue;
+            }
+            // Records are numbered from the LSN after the last one written.
+            let lsn = store.wal.lsn() + records.len() as u64 + 1;
+            let page = disk_page(slot.node.get_mut().unwrap());
+            page.header_mut().lsn = lsn;
+            records.push(match record {
+                Some(record) if slot.logged => record,
+                _ => Record::Image {
+                    id,
+                    page: page.as_bytes().to_vec(),
+                },
+            });
+            slot.logged = true;
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+        records.push(Record::Commit { root });
+        store.wal.append(&records).map(|_| ())
+    }
+
+    /// Restores node `id` from an image of its page during recovery. The node is taken
+    /// off the free list if it is on it.
+    pub(crate) fn restore(&mut self, id: NodeId, image: &[u8]) -> io::Result<()> {
+        let store = self
+            .store
+            .as_mut()
+            .expect("Only a disk-backed table is recovered");
+        if image.len() != PAGE_SIZE {
+            return Err(invalid_log(id));
+        }
+        let mut page = store.pool.get();
+        page.as_bytes_mut().copy_from_slice(image);
+        if !page.header().is_valid() {
+            store.pool.check_in(page);
+            return Err(invalid_log(id));
+        }
+        let node = node_from_page(page, store.pool).ok_or_else(|| invalid_log(id))?;
+
+        while self.slots.len() < id.index() + 1 {
+            self.slots.push(Slot::new(None));
+        }
+        let slot = &mut self.slots[id.index()];
+        if slot.free {
+            let idx = self.free.iter().rposition(|&free| free == id).unwrap();
+            self.free.remove(idx);
+            store.free_synced = store.free_synced.min(idx);
+            slot.free = false;
+        }
+        if slot.node.take().is_none() {
+            store.resident.set(store.resident.get() + 1);
+        }
+        slot.node = OnceCell::from(node);
+        slot.dirty = true;
+        Ok(())
+    }
+
+    /// Applies `change` to node `id` during recovery, unless the node already has the
+    /// change that was logged with `lsn`.
+    pub(crate) fn redo<F, R>(&mut self, id: NodeId, lsn: u64, change: F) -> Option<R>
+    where
+        F: FnOnce(&mut Node<'a>) -> R,
+    {
+        let _ = &self[id];
+        let slot = &mut self.slots[id.index()];
+        let node = slot.node.get_mut().unwrap();
+        if disk_page(node).header().lsn >= lsn {
+            return None;
+        }
+        let result = change(node);
+        disk_page(node).header_mut().lsn = lsn;
+        slot.dirty = true;
+        Some(result)
+    }
+
+    /// Forgets the changes made during recovery, which are already in the log.
+    pub(crate) fn forget_changes(&mut self) {
+        if let Some(store) = &mut self.store {
+            for id in store.touched.drain(..) {
+                self.slots[id.index()].touched = false;
+                self.slots[id.index()].record = None;
+            }
+            store.freed.clear();
+        }
+    }
+
+    /// Sets the number of nodes that a disk-backed table keeps in memory.
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        if let Some(store) = &mut self.store {
+            store.capacity = capacity;
+        }
+    }
+
+    /// Writes back and drops nodes that have not been accessed recently, until no more
+    /// than the capacity are left in memory. Nodes that fail to be written back are kept,
+    /// and the error is reported by the next flush instead.
+    pub(crate) fn evict(&mut self) {
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => return,
+        };
+        // Two turns of the clock are enough to clear every referenced bit along the way.
+        let mut steps = 2 * self.slots.len();
+        while store.resident.get() > store.capacity && steps > 0 {
+            steps -= 1;
+            store.hand = (store.hand + 1) % self.slots.len();
+            let slot = &mut self.slots[store.hand];
+            let node = match slot.node.get_mut() {
+                Some(node) => node,
+                None => continue,
+            };
+            if slot.referenced.replace(false) {
+                continue;
+            }
+            if slot.dirty {
+                if store
+                    .write_node(NodeId(store.hand as u64 + 1), node)
+                    .is_err()
+                {
+                    continue;
+                }
+                slot.dirty = false;
+            }
+            slot.node.take();
+            store.resident.set(store.resident.get() - 1);
+        }
+    }
+
+    /// Writes every changed node and the free list to disk, followed by the Meta that
+    /// describes them, and waits for the writes to complete. This is a checkpoint, after
+    /// which the log starts over. Nodes stay in memory.
+    pub(crate) fn flush(&mut self, root: NodeId, val_format: ValueFormat) -> io::Result<()> {
+        let store = match &mut self.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+        for (idx, slot) in self.slots.iter_mut().enumerate() {
+            if let (true, Some(node)) = (slot.dirty, slot.node.get_mut()) {
+                store.write_node(NodeId(idx as u64 + 1), node)?;
+                slot.dirty = false;
+            }
+        }
+
+        // Each free page links to the one freed before it.
+        let mut page = store.pool.get();
+        page.header_mut().kind = FREE_PAGE;
+        let free = &self.free;
+        for idx in store.free_synced..free.len() {
+            page.header_mut().next = idx.checked_sub(1).map(|prev| free[prev].into());
+            store.pager.write_page(free[idx].into(), &page)?;
+        }
+        store.pool.check_in(page);
+        store.free_synced = self.free.len();
+
+        // The pages must be on disk before the Meta that refers to them, and the Meta must
+        // be before the log is reset.
+        store.pager.sync()?;
+        store.pager.write_meta(&Meta {
+            root: root.into(),
+            page_count: self.slots.len() as u64,
+            free_head: self.free.last().map(|&id| id.into()),
+            val_format,
+            lsn: store.wal.lsn(),
+        })?;
+        store.pager.sync()?;
+        store.wal.reset(&Record::Checkpoint {
+            root,
+            page_count: self.slots.len() as u64,
+            free: self.free.clone(),
+        })?;
+        self.slots.iter_mut().for_each(|slot| slot.logged = false);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    /// The number of nodes in memory.
+    #[cfg(test)]
+    pub(crate) fn resident(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.node.get().is_some())
+            .count()
+    }
+
+    fn slot(&self, id: NodeId) -> &Slot<'a> {
+        self.slots
+            .get(id.index())
+            .unwrap_or_else(|| panic!("{:?} is not in the node table", id))
+    }
+
+    // Marks node `id` as changed by the current update.
+    fn touch(&mut self, id: NodeId) {
+        let slot = &mut self.slots[id.index()];
+        slot.dirty = true;
+        if let Some(store) = &mut self.store {
+            slot.record = None;
+            if !mem::replace(&mut slot.touched, true) {
+                store.touched.push(id);
+            }
+        }
+    }
+}
+
+/// Reads the free list of a tree's file, which starts at `head` and is linked through the
+/// page headers, into a stack of free ids.
+pub(crate) fn read_free_list(
+    pager: &Pager,
+    pool: &Pool,
+    page_count: u64,
+    head: Option<NonZeroU64>,
+) -> io::Result<Vec<NodeId>> {
+    // The list is linked from the most recently freed page, which is the top of the stack.
+    let mut free = Vec::new();
+    let mut next = head;
+    let mut page = pool.get();
+    while let Some(id) = next {
+        if free.len() as u64 >= page_count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Free list contains a cycle",
+            ));
+        }
+        pager.read_page(id, &mut page)?;
+        if page.header().kind != FREE_PAGE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Page {} is on the free list, but in use", id),
+            ));
+        }
+        free.push(NodeId::from(id));
+        next = page.header().next;
+    }
+    pool.check_in(page);
+    free.reverse();
+    Ok(free)
+}
+
+impl<'a> Store<'a> {
+    /// Reads node `id` from disk.
+    ///
+    /// # Panics
+    ///
+    /// When the node can not be read. It is needed to carry on with the operation at hand,
+    /// which has no way to report the error.
+    fn read_node(&self, id: NodeId) -> Node<'a> {
+        let mut page = self.pool.get();
+        if let Err(err) = self.pager.read_page(id.into(), &mut page) {
+            panic!("Failed to read {:?}: {}", id, err);
+        }
+        self.resident.set(self.resident.get() + 1);
+        node_from_page(page, self.pool)
+            .unwrap_or_else(|| panic!("{:?} is not in the node table", id))
+    }
+
+    fn write_node(&mut self, id: NodeId, node: &mut Node<'a>) -> io::Result<()> {
+        let page = disk_page(node);
+        // The log must be on disk up to the last change to the page before the page is.
+        self.wal.sync(page.header().lsn)?;
+        self.pager.write_page(id.into(), page)
+    }
+}
+
+/// Returns the node's page, with its kind set in its header, as it is stored on disk.
+fn disk_page<'n>(node: &'n mut Node) -> &'n mut Page {
+    let (kind, page) = match node {
+        Node::LeafNode(n) => (LEAF_PAGE, &mut **n),
+        Node::InnerNode(n) => (INNER_PAGE, n.page_mut()),
+    };
+    page.header_mut().kind = kind;
+    page
+}
+
+fn node_from_page<'a>(page: Page, pool: &'a Pool) -> Option<Node<'a>> {
+    match page.header().kind {
+        LEAF_PAGE => Some(Node::LeafNode(LeafNode::from_page(page, pool))),
+        INNER_PAGE => Some(Node::InnerNode(InnerNode::from_page(page, pool))),
+        _ => {
+            pool.check_in(page);
+            None
+        }
+    }
+}
+
+fn invalid_log(id: NodeId) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Log has an invalid image of {:?}", id),
+    )
+}
+
+impl<'a> Index<NodeId> for NodeTable<'a> {
+    type Output = Node<'a>;
+
+    fn index(&self, id: NodeId) -> &Node<'a> {
+        let slot = self.slot(id);
+        slot.referenced.set(true);
+        slot.node.get_or_init(|| match &self.store {
+            Some(store) if !slot.free => store.read_node(id),
+            _ => panic!("{:?} is not in the node table", id),
+        })
+    }
+}
+
+impl<'a> IndexMut<NodeId> for NodeTable<'a> {
+    fn index_mut(&mut self, id: NodeId) -> &mut Node<'a> {
+        // Make sure it is in memory.
+        let _ = &self[id];
+        self.touch(id);
+        self.slots[id.index()].node.get_mut().unwrap()
+    }
+}
+
+/// The number of nodes that a disk-backed tree keeps in memory by default, 16MiB worth of
+/// pages.
+const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+// Space in a page that is available to a node.
+const NODE_CAPACITY: usize = PAGE_SIZE - size_of::<Header>();
+
+// The most bytes of a page that a key and its values may take up in a leaf. Any two-way
+// split of a full node and an entry of at most this size, or of a full inner node and a key
+// that came from such an entry, leaves both halves small enough to fit in a page.
+pub(crate) const MAX_ENTRY_LEN: usize = NODE_CAPACITY / 2 - INNER_SLOT_SIZE;
+
+/// Fails with InvalidInput for an entry that would take up `len` bytes of a page, if that
+/// is more than an entry may take up.
+pub(crate) fn check_entry_len(len: usize) -> io::Result<()> {
+    if len > MAX_ENTRY_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Entry of {} bytes is larger than the limit of {} bytes",
+                len, MAX_ENTRY_LEN
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The outcome of rebalancing a node with its right sibling.
+pub(crate) enum Rebalance {
+    /// Everything was moved into the left node, and the right node is now empty.
+    Merged,
+    /// The contents were redistributed between both nodes, and the key that separates them
+    /// in their parent must be replaced with this one.
+    Rebalanced(Vec<u8>),
+    /// Neither was possible without a pivot that is too long for the parent.
+    Unchanged,
+}
+
 pub(crate) enum Node<'a> {
     LeafNode(LeafNode<'a>),
     InnerNode(InnerNode<'a>),
 }
 
+/// The result of splitting a full node: the new right sibling, and the smallest key in its
+/// subtree, which separates it from the node that was split.
+pub(crate) struct Split<'a> {
+    pub(crate) pivot: Vec<u8>,
+    pub(crate) right: Node<'a>,
+}
+
 impl<'a> Node<'a> {
     pub(crate) fn new_leaf(pool: &'a Pool) -> Node<'a> {
         Node::LeafNode(LeafNode::new(pool))
@@ -28,22 +628,35 @@ impl<'a> Node<'a> {
         Node::InnerNode(InnerNode::new(pool))
     }
 
-    // Does it make sense to extract find/insert to a trait?
-    pub(crate) fn find(&self, key: &[u8]) -> Option<&PageEntry> {
+    /// Whether the node uses less than a quarter of its page, and should be merged with or
+    /// refilled from a sibling.
+    pub(crate) fn is_underfull(&self) -> bool {
+        let used = match self {
+            Node::LeafNode(n) => n.used_len(),
+            Node::InnerNode(n) => n.used_len(),
+        };
+        used < NODE_CAPACITY / 4
+    }
+
+    pub(crate) fn as_leaf(&self) -> &LeafNode<'a> {
         match self {
-            Node::LeafNode(n) => n.find(key),
-            Node::InnerNode(n) => n.find(key),
+            Node::LeafNode(n) => n,
+            _ => {
+                panic!("Caller assumes that node is LeafNode")
+            }
         }
     }
 
-    pub(crate) fn insert(&mut self, val_layout: Layout, key: &[u8], val: &[u8]) -> Option<()> {
+    pub(crate) fn as_leaf_mut(&mut self) -> &mut LeafNode<'a> {
         match self {
-            Node::LeafNode(n) => n.insert(val_layout, key, val),
-            Node::InnerNode(n) => n.insert(val_layout, key, val),
+            Node::LeafNode(n) => n,
+            _ => {
+                panic!("Caller assumes that node is LeafNode")
+            }
         }
     }
 
-    pub(crate) fn as_inner_mut(&mut self) -> &mut InnerNode<'a> {
+    pub(crate) fn as_inner(&self) -> &InnerNode<'a> {
         match self {
             Node::InnerNode(n) => n,
             _ => {
@@ -52,11 +665,11 @@ impl<'a> Node<'a> {
         }
     }
 
-    pub(crate) fn as_leaf_mut(&mut self) -> &mut LeafNode<'a> {
+    pub(crate) fn as_inner_mut(&mut self) -> &mut InnerNode<'a> {
         match self {
-            Node::LeafNode(n) => n,
+            Node::InnerNode(n) => n,
             _ => {
-                panic!("Caller assumes that node is LeafNode")
+                panic!("Caller assumes that node is InnerNode")
             }
         }
     }
@@ -84,36 +697,68 @@ impl<'a> TryInto<LeafNode<'a>> for Node<'a> {
     }
 }
 
-// TODO: Consider a locking scheme for nodes. Where should the lock live?
+// Nodes have no locks of their own. A ConcurrentBTree keeps each node behind a latch in
+// its table, next to the node, and a BTree relies on the borrow checker instead.
 pub(crate) struct LeafNode<'a> {
     page: Option<Page>, // Always Some<Page> until dropped.
     pool: &'a Pool,
 }
 
 impl<'a> LeafNode<'a> {
-    pub(crate) fn new(pool: &'a Pool) -> LeafNode {
+    pub(crate) fn new(pool: &'a Pool) -> LeafNode<'a> {
+        LeafNode::from_page(pool.get(), pool)
+    }
+
+    /// Wraps a page that already holds a leaf, such as one read back from disk.
+    pub(crate) fn from_page(page: Page, pool: &'a Pool) -> LeafNode<'a> {
         LeafNode {
-            page: Some(pool.get()),
+            page: Some(page),
             pool,
         }
     }
 
-    // TODO: add versions of search functions that take a key comparator
-    // for more complex keys that do not have byte-wise comparison semantics.
-    // We should leave the raw versions that perform byte comparisons so that
-    // the client code can use this (faster) version whenever possible.
+    // Operations that compare keys take the tree's comparator. They are generic over it, so
+    // that the default bytewise comparator compiles down to plain slice comparisons.
+
+    pub(crate) fn find<C: KeyComparator>(&self, key: &[u8], cmp: &C) -> Option<&PageEntry> {
+        self.find_entry(key, cmp)
+            .map(|(_, entry)| unsafe { &*entry })
+    }
+
+    /// Bytes of the page that the entry for `key` would take up with `val` added to it.
+    pub(crate) fn entry_len_with<C: KeyComparator>(
+        &self,
+        val_format: ValueFormat,
+        key: &[u8],
+        val: &[u8],
+        cmp: &C,
+    ) -> usize {
+        let vals_len = self
+            .find(key, cmp)
+            .map_or(0, |entry| entry.values_buffer(val_format).len());
+        entry_size(
+            val_format,
+            key.len(),
+            vals_len + val_format.encoded_len(val.len()),
+        )
+    }
+
+    /// The leaf that follows this one in key order, linked through the page header.
+    pub(crate) fn next(&self) -> Option<NodeId> {
+        self.header().next.map(NodeId::from)
+    }
 
-    pub(crate) fn find(&self, key: &[u8]) -> Option<&PageEntry> {
-        self.find_entry(key).map(|(_, entry)| unsafe { &*entry })
+    pub(crate) fn set_next(&mut self, next: Option<NodeId>) {
+        self.header_mut().next = next.map(NonZeroU64::from);
     }
 
-    pub(crate) fn scan(&self) -> PageEntryIter {
+    pub(crate) fn scan(&self) -> PageEntryIter<'_> {
         PageEntryIter {
             ref_iter: self.scan_entry_refs(),
         }
     }
 
-    fn scan_entry_refs(&self) -> EntryRefIter {
+    fn scan_entry_refs(&self) -> EntryRefIter<'_> {
         EntryRefIter {
             page: self.page.as_ref().unwrap(),
             offset: size_of::<Header>(),
@@ -121,11 +766,56 @@ impl<'a> LeafNode<'a> {
         }
     }
 
-    fn find_entry(&self, key: &[u8]) -> Option<(*mut EntryRef, *mut PageEntry)> {
-        // TODO: Allow this linear scan to be replaced by binary search.
+    fn find_entry<C: KeyComparator>(
+        &self,
+        key: &[u8],
+        cmp: &C,
+    ) -> Option<(*mut EntryRef, *mut PageEntry)> {
+        self.search(key, cmp).ok().map(|idx| self.entry_ptrs(idx))
+    }
+
+    /// Like `find_entry`, but the pointers may be written through.
+    fn find_entry_mut<C: KeyComparator>(
+        &mut self,
+        key: &[u8],
+        cmp: &C,
+    ) -> Option<(*mut EntryRef, *mut PageEntry)> {
+        let idx = self.search(key, cmp).ok()?;
+        Some(self.entry_ptrs_mut(idx))
+    }
+
+    /// Pointers to EntryRef `idx` and its entry that may be written through. Unlike the ones
+    /// from `entry_ptrs`, they are derived from a mutable borrow of the page.
+    fn entry_ptrs_mut(&mut self, idx: usize) -> (*mut EntryRef, *mut PageEntry) {
+        let (offset, length) = {
+            let entry_ref = &self.entry_refs()[idx];
+            (entry_ref.offset as usize, entry_ref.length as usize)
+        };
+        unsafe {
+            let entry_ref_ptr = self.offset_ptr_unchecked_mut(
+                size_of::<Header>() + idx * size_of::<EntryRef>(),
+                size_of::<EntryRef>(),
+            ) as *mut EntryRef;
+            let entry_ptr = self.offset_ptr_unchecked_mut(offset, length) as *mut PageEntry;
+            (entry_ref_ptr, entry_ptr)
+        }
+    }
+
+    /// Pointers to EntryRef `idx` and its entry, for reading or comparing only.
+    fn entry_ptrs(&self, idx: usize) -> (*mut EntryRef, *mut PageEntry) {
+        let entry_ref = &self.entry_refs()[idx];
+        (
+            entry_ref as *const EntryRef as *mut EntryRef,
+            self.entry(entry_ref) as *const PageEntry as *mut PageEntry,
+        )
+    }
+
+    /// The entry lookup that binary search replaced, kept as a baseline for benchmarks and
+    /// as a reference for tests. It only supports bytewise key equality.
+    fn find_entry_linear(&self, key: &[u8]) -> Option<(*mut EntryRef, *mut PageEntry)> {
         self.scan()
             .find(|(_entry_ref_ptr, entry_ptr)| {
-                let entry = unsafe { &mut *(*entry_ptr as *mut PageEntry) };
+                let entry = unsafe { &*(*entry_ptr) };
                 entry.key_len as usize == key.len() && &entry.data[0..entry.key_len as usize] == key
             })
             .map(|(entry_ref_ptr, entry_ptr)| {
@@ -133,34 +823,280 @@ impl<'a> LeafNode<'a> {
             })
     }
 
-    pub(crate) fn insert(&mut self, val_layout: Layout, key: &[u8], val: &[u8]) -> Option<()> {
-        debug_assert_eq!(val.len(), val_layout.size());
+    /// Binary searches the EntryRefs, which are kept in key order, for `key`. Returns the
+    /// index of its EntryRef if it is present, or the index at which to insert one if not.
+    fn search<C: KeyComparator>(&self, key: &[u8], cmp: &C) -> Result<usize, usize> {
+        self.entry_refs()
+            .binary_search_by(|entry_ref| cmp.compare(self.entry(entry_ref).key(), key))
+    }
 
-        if let Some((entry_ref_ptr, old_entry_ptr)) = self.find_entry(key) {
-            self.insert_extend(val_layout, entry_ref_ptr, old_entry_ptr, val)
-        } else {
-            self.insert_initial(val_layout, key, val, 1)
+    fn entry_refs(&self) -> &[EntryRef] {
+        unsafe {
+            // SAFETY: The EntryRefs are packed from the end of the header to free_start,
+            // and the header size is a multiple of their alignment.
+            let ptr = self.offset_ptr_unchecked(size_of::<Header>(), 0) as *const EntryRef;
+            slice::from_raw_parts(ptr, self.entry_count())
+        }
+    }
+
+    fn entry(&self, entry_ref: &EntryRef) -> &PageEntry {
+        unsafe {
+            let ptr =
+                self.offset_ptr_unchecked(entry_ref.offset as usize, entry_ref.length as usize);
+            &*(ptr as *const PageEntry)
+        }
+    }
+
+    pub(crate) fn insert<C: KeyComparator>(
+        &mut self,
+        val_format: ValueFormat,
+        key: &[u8],
+        val: &[u8],
+        cmp: &C,
+    ) -> Option<()> {
+        let val = val_format.encode(val);
+        match self.search(key, cmp) {
+            Ok(idx) => {
+                let (entry_ref_ptr, old_entry_ptr) = self.entry_ptrs_mut(idx);
+                self.insert_extend(entry_ref_ptr, old_entry_ptr, &val)
+            }
+            Err(idx) => self.insert_initial(val_format, idx, key, &val, 1),
+        }
+    }
+
+    /// Inserts a value if it fits in the node without splitting it. Returns None without
+    /// modifying the node's entries if it does not.
+    pub(crate) fn try_insert<C: KeyComparator>(
+        &mut self,
+        val_format: ValueFormat,
+        key: &[u8],
+        val: &[u8],
+        cmp: &C,
+    ) -> Option<()> {
+        if self.insert(val_format, key, val, cmp).is_some() {
+            return Some(());
+        }
+        // Removed entries and values leave garbage behind, which may be enough to make room.
+        self.compact();
+        if self.insert(val_format, key, val, cmp).is_some() {
+            return Some(());
+        }
+
+        // Adding a value to an existing entry copies it, so the entry briefly takes up space
+        // twice. A large entry, which variable-size values can make, may only grow if it is
+        // taken out of the page and written back in one piece.
+        let idx = self.search(key, cmp).ok()?;
+        let (entry_ref_ptr, entry_ptr) = self.entry_ptrs_mut(idx);
+        let val_count = unsafe { &*entry_ptr }.val_count;
+        let mut vals = unsafe { &*entry_ptr }.values_buffer(val_format).to_vec();
+        let old_len = vals.len();
+        vals.extend_from_slice(&val_format.encode(val));
+        self.remove_entry_ref(entry_ref_ptr);
+        self.compact();
+        if self
+            .insert_initial(val_format, idx, key, &vals, val_count + 1)
+            .is_some()
+        {
+            return Some(());
+        }
+        self.compact();
+        self.insert_initial(val_format, idx, key, &vals[..old_len], val_count)
+            .expect("Entry must fit back in the page it was taken from");
+        None
+    }
+
+    /// Inserts a value, splitting the node in two if it is full. Returns the new right
+    /// sibling if the node was split. The entry for `key` must not grow past MAX_ENTRY_LEN.
+    pub(crate) fn insert_or_split<C: KeyComparator>(
+        &mut self,
+        val_format: ValueFormat,
+        key: &[u8],
+        val: &[u8],
+        cmp: &C,
+    ) -> Option<Split<'a>> {
+        if self.try_insert(val_format, key, val, cmp).is_some() {
+            return None;
+        }
+
+        // There is no room for the value. Lay out the entries as they would be with it, and
+        // divide them by bytes, so that both nodes have room to grow even when entries
+        // differ a lot in size, as they do with variable-size values.
+        let val = val_format.encode(val);
+        let mut entries = self
+            .scan()
+            .map(|(_, entry)| {
+                let entry = unsafe { &*entry };
+                let vals = Cow::Borrowed(entry.values_buffer(val_format));
+                (entry.key(), vals, entry.val_count)
+            })
+            .collect::<Vec<_>>();
+        match self.search(key, cmp) {
+            Ok(idx) => {
+                let (_, vals, val_count) = &mut entries[idx];
+                vals.to_mut().extend_from_slice(&val);
+                *val_count += 1;
+            }
+            Err(idx) => entries.insert(idx, (key, Cow::Borrowed(&val[..]), 1)),
+        }
+        let sizes = entries
+            .iter()
+            .map(|(key, vals, _)| entry_size(val_format, key.len(), vals.len()))
+            .collect::<Vec<_>>();
+        let split = best_split(&sizes, |_| true)
+            .expect("Entries of at most MAX_ENTRY_LEN bytes must split into two nodes");
+
+        let mut left = LeafNode::new(self.pool);
+        let mut right = LeafNode::new(self.pool);
+        for (i, (key, vals, val_count)) in entries.iter().enumerate() {
+            let target = if i < split { &mut left } else { &mut right };
+            target
+                .insert_initial(val_format, target.entry_count(), key, vals, *val_count)
+                .expect("Entries must fit in the node they are moved to");
+        }
+        let pivot = entries.get(split).map(|(key, _, _)| key.to_vec());
+        drop(entries);
+
+        // The old page goes back to the pool when it is replaced.
+        left.set_next(self.next());
+        *self = left;
+        // Without the garbage that try_insert could not compact away, which the layout
+        // above leaves out, everything may fit in one node after all.
+        pivot.map(|pivot| Split {
+            pivot,
+            right: Node::LeafNode(right),
+        })
+    }
+
+    /// Removes the entry for `key` and all of its values. Returns whether it was found.
+    pub(crate) fn remove<C: KeyComparator>(&mut self, key: &[u8], cmp: &C) -> bool {
+        match self.find_entry(key, cmp) {
+            Some((entry_ref_ptr, _)) => {
+                self.remove_entry_ref(entry_ref_ptr);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the first value of `key` that equals `val`, and the entry itself if that was
+    /// its only value. Returns whether the value was found.
+    pub(crate) fn remove_value<C: KeyComparator>(
+        &mut self,
+        val_format: ValueFormat,
+        key: &[u8],
+        val: &[u8],
+        cmp: &C,
+    ) -> bool {
+        let (entry_ref_ptr, entry_ptr) = match self.find_entry_mut(key, cmp) {
+            Some(found) => found,
+            None => return false,
+        };
+        let (entry_ref, entry) = unsafe { (&mut *entry_ref_ptr, &mut *entry_ptr) };
+        let range = match entry.find_value(val_format, val) {
+            Some(range) => range,
+            None => return false,
+        };
+        if entry.val_count == 1 {
+            self.remove_entry_ref(entry_ref_ptr);
+            return true;
+        }
+
+        // Close the gap left by the value. The bytes after the new end of the entry are
+        // dead, and are reclaimed by the next compaction.
+        entry.data.copy_within(range.end.., range.start);
+        entry.val_count -= 1;
+        entry_ref.length -= range.len() as u16;
+        true
+    }
+
+    /// Removes an entry's EntryRef, which leaves the entry itself as garbage.
+    fn remove_entry_ref(&mut self, entry_ref_ptr: *mut EntryRef) {
+        let base = unsafe { self.offset_ptr_unchecked(0, 0) } as *const u8 as usize;
+        let offset = entry_ref_ptr as usize - base;
+        unsafe { self.unshift_start(offset, size_of::<EntryRef>()) };
+    }
+
+    /// Bytes of the page used by live entries and their EntryRefs.
+    fn used_len(&self) -> usize {
+        self.scan_entry_refs().map(entry_len).sum()
+    }
+
+    /// Moves the entries of `right`, the right sibling of this node, into this node if they
+    /// fit. Otherwise redistributes the entries of both so that they are about equally full,
+    /// choosing only from splits whose pivot is at most `max_pivot_len` bytes long.
+    pub(crate) fn merge_or_rebalance(
+        &mut self,
+        right: &mut LeafNode<'a>,
+        val_format: ValueFormat,
+        max_pivot_len: usize,
+    ) -> Rebalance {
+        let entries = self
+            .scan()
+            .chain(right.scan())
+            .map(|(entry_ref, entry)| unsafe { (entry_len(&*entry_ref), &*entry) })
+            .collect::<Vec<_>>();
+        let sizes = entries.iter().map(|(len, _)| *len).collect::<Vec<_>>();
+        let split = match best_split(&sizes, |i| entries[i].1.key().len() <= max_pivot_len) {
+            Some(split) => split,
+            None => return Rebalance::Unchanged,
+        };
+
+        let mut new_left = LeafNode::new(self.pool);
+        let mut new_right = LeafNode::new(self.pool);
+        for (i, (_, entry)) in entries.iter().enumerate() {
+            let target = if i < split {
+                &mut new_left
+            } else {
+                &mut new_right
+            };
+            target
+                .insert_initial(
+                    val_format,
+                    target.entry_count(),
+                    entry.key(),
+                    entry.values_buffer(val_format),
+                    entry.val_count,
+                )
+                .expect("Entries must fit in the node they are moved to");
         }
+        let result = match entries.get(split) {
+            Some((_, entry)) => Rebalance::Rebalanced(entry.key().to_vec()),
+            None => Rebalance::Merged,
+        };
+
+        // The old pages go back to the pool when they are replaced.
+        match result {
+            Rebalance::Merged => new_left.set_next(right.next()),
+            _ => new_left.set_next(self.next()),
+        }
+        new_right.set_next(right.next());
+        *self = new_left;
+        *right = new_right;
+        result
     }
 
+    /// Inserts a new entry, with its EntryRef at index `idx`, and `val_count` values that
+    /// are stored as they are in `vals`.
     fn insert_initial(
         &mut self,
-        val_layout: Layout,
+        val_format: ValueFormat,
+        idx: usize,
         key: &[u8],
         vals: &[u8],
         val_count: u16,
     ) -> Option<()> {
-        debug_assert_eq!(vals.len() / val_count as usize, val_layout.size());
+        if let ValueFormat::Fixed(layout) = val_format {
+            debug_assert_eq!(vals.len() / val_count as usize, layout.size());
+        }
         // Initial entry holds the sized struct fields of PageEntry plus a data buffer large
-        // enough to fit a single value.
+        // enough to fit `val_count` values.
         // TODO: Does the key need to be aligned?
         let key_len = key.len();
 
         // The data that each entry starts with is the key followed by enough bytes of padding
-        // to align the values appropriately, followed by a single value.
-        let initial_data_size = key_len
-            + pad_for(PAGE_ENTRY_HEADER_SIZE + key_len, val_layout.align())
-            + val_layout.size();
+        // to align the values appropriately, followed by the values.
+        let initial_data_size =
+            key_len + pad_for(PAGE_ENTRY_HEADER_SIZE + key_len, val_format.align()) + vals.len();
 
         let size = PAGE_ENTRY_HEADER_SIZE + initial_data_size;
         let Allocation {
@@ -170,8 +1106,7 @@ impl<'a> LeafNode<'a> {
         let entry = unsafe {
             // Reinterpret entry_ptr as a fat pointer to `initial_data_size` bytes, since this
             // is the dynamic portion of the PageEntry DST.
-            let dst_ptr =
-                slice::from_raw_parts_mut(entry_ptr as *mut u8, initial_data_size) as *mut [u8];
+            let dst_ptr = ptr::slice_from_raw_parts_mut(entry_ptr as *mut u8, initial_data_size);
             &mut *(dst_ptr as *mut PageEntry)
         };
 
@@ -180,10 +1115,10 @@ impl<'a> LeafNode<'a> {
         entry.key_len = key_len as u16;
         entry.val_count = val_count;
         entry.data[0..key_len].copy_from_slice(key);
-        let val_start = key_len + pad_for(PAGE_ENTRY_HEADER_SIZE + key_len, val_layout.align());
+        let val_start = key_len + pad_for(PAGE_ENTRY_HEADER_SIZE + key_len, val_format.align());
         entry.data[val_start..].copy_from_slice(vals);
 
-        let entry_ref = self.new_entry_ref(key)?;
+        let entry_ref = self.new_entry_ref(idx)?;
         entry_ref.offset = entry_start;
         entry_ref.length = initial_data_size as u16;
 
@@ -192,9 +1127,9 @@ impl<'a> LeafNode<'a> {
 
     // TODO: This does not order the records within the leaf node. In order to make scans simpler and lookups
     // more performant, we should order the records on insert.
+    /// Appends a value, as it is stored, to an existing entry.
     fn insert_extend(
         &mut self,
-        val_layout: Layout,
         entry_ref_ptr: *mut EntryRef,
         old_entry_ptr: *mut PageEntry,
         val: &[u8],
@@ -203,7 +1138,7 @@ impl<'a> LeafNode<'a> {
         // Copy old entry into new empty slot in data. The old
         // memory becomes dead and will eventually get garbage collected.
         let old_size = PAGE_ENTRY_HEADER_SIZE + old_entry.data.len();
-        let new_size = old_size + val_layout.size();
+        let new_size = old_size + val.len();
 
         // 1. Allocate a new slot.
         let Allocation {
@@ -230,75 +1165,30 @@ impl<'a> LeafNode<'a> {
         // TODO: Consider mitigating this by representing the `PageEntry` as a fat raw pointer
         // with a prefix that can be interpreted as a `PageEntryHeader`.
         let entry = unsafe {
-            let sized_slice = slice::from_raw_parts_mut(
+            let sized_slice = ptr::slice_from_raw_parts_mut(
                 entry_ptr as *mut u8,
-                old_entry.data.len() + val_layout.size(),
+                old_entry.data.len() + val.len(),
             );
-            &mut *(sized_slice as *mut [u8] as *mut PageEntry)
+            &mut *(sized_slice as *mut PageEntry)
         };
 
         // 4. Update new slot: increment val_count and append to data.
         // NOTE [DATA ALIGNMENT]:
         // We pack values contiguously, potentially inserting padding between the key and
-        // the start of the value array. This assumes that, like structs, the value size
-        // is a multiple of the value alignment. As long as value_layout was constructed
-        // safely, this is a sound assumption.
+        // the start of the value array. This assumes that, like structs, the size of Fixed
+        // values is a multiple of their alignment. As long as their layout was constructed
+        // safely, this is a sound assumption. Variable values are not aligned at all.
         let new_val_offset = old_entry.data.len();
         entry.val_count += 1;
         entry.data[new_val_offset..].copy_from_slice(val);
 
         // Update entry pointer.
-        entry_ref.offset = entry_start as u16;
-        entry_ref.length += val_layout.size() as u16;
+        entry_ref.offset = entry_start;
+        entry_ref.length += val.len() as u16;
 
         Some(())
     }
 
-    pub(crate) fn split(mut self, val_layout: Layout) -> (LeafNode<'a>, LeafNode<'a>) {
-        // Compact the node first so that contiguous entry refs point to contiguous entries.
-        // This allows us to simply copy two blocks of memory to the new node and update each
-        // entry ref's offset by some constant that is the delta between this node's free_end
-        // and the new node's free_end.
-        self.compact();
-
-        let mut right = LeafNode::new(self.pool);
-
-        // Move half of the keys to `right`.
-        // TODO: Try to divide in half by entry size rather than key count.
-        let left_count = self.entry_count() / 2;
-
-        // Iterate the entries to be copied, and insert them into the new page.
-        // Note that we cannot simply copy the memory for the EntryRefs and PageEntries
-        // to the new page then update the offsets of each EntryRef because each PageEntry
-        // needs to be placed such that the values array is properly aligned. Each PageEntry
-        // in the current table has some amount of padding between the key and the values
-        // array to ensure proper alignment, and we cannot maintain that alignment with a
-        // simple memory copy.
-        for (_, entry_ptr) in self.scan().skip(left_count) {
-            let entry = unsafe { &*entry_ptr };
-            right
-                .insert_initial(
-                    val_layout,
-                    entry.key(),
-                    entry.values_buffer(val_layout),
-                    entry.val_count,
-                )
-                .expect("New right node must have capacity to fit half of left node's entries");
-        }
-
-        // Reset the pointers for the left page to point to only the first half of the
-        // prior contents.
-        let &EntryRef {
-            offset: last_entry_offset,
-            length: last_entry_length,
-        } = self.scan_entry_refs().last().unwrap();
-        let header = self.header_mut();
-        header.free_start = (size_of::<Header>() + left_count * size_of::<EntryRef>()) as u16;
-        header.free_end = last_entry_offset + last_entry_length;
-
-        (self, right)
-    }
-
     pub(crate) fn entry_count(&self) -> usize {
         (self.free_start() as usize - size_of::<Header>()) / size_of::<EntryRef>()
     }
@@ -307,10 +1197,12 @@ impl<'a> LeafNode<'a> {
     /// 1. Old entries that do not have an EntryRef pointing to them are removed.
     /// 2. Entries are re-organized such that they grow from the end of the page
     ///    as their corresponding EntryRefs grow from the beginning of the page.
+    ///
     /// In order to simplify the process, we allocate a new page, copy the data
     /// over, and replace the current page with the new one.
     fn compact(&mut self) {
         let mut new_page = self.pool.get();
+        new_page.header_mut().next = self.header().next;
         let entry_ref_layout = EntryRef::layout();
         unsafe {
             for (entry_ref, entry) in self.scan() {
@@ -340,43 +1232,25 @@ impl<'a> LeafNode<'a> {
         self.pool.check_in(old_page);
     }
 
-    fn new_entry_ref(&mut self, key: &[u8]) -> Option<&mut EntryRef> {
-        // Find the first key < `key`, and insert to the left of it, shifting all other EntryRefs to the right.
-        // If no higher key is found, allocate a new EntryRef at the end of the free space.
+    fn new_entry_ref(&mut self, idx: usize) -> Option<&mut EntryRef> {
+        // Insert the new EntryRef at `idx`, shifting the rest to the right.
+        // If it goes after all others, allocate a new EntryRef at the end of the free space.
         // If no space left on the page, return None, and let the call site deal with allocation failure.
-
-        for offset in
-            (size_of::<Header>()..(self.free_start() as usize)).step_by(size_of::<EntryRef>())
-        {
-            let entry_ref_ptr: *mut EntryRef;
-            let entry_ref = unsafe {
-                entry_ref_ptr =
-                    self.offset_ptr_unchecked(offset, size_of::<EntryRef>()) as *mut EntryRef;
-                &mut *entry_ref_ptr
-            };
-            let entry = unsafe {
-                let start = entry_ref.offset as usize;
-                let length = entry_ref.length as usize;
-                let ptr = self.offset_ptr_unchecked(start, length) as *mut PageEntry;
-                &*ptr
-            };
-            // NOTE [KEY COMPARISONS]:
-            // We are naively comparing these keys, which will not always provide a valid comparison function
-            // for the key type. We should eventually allow a comparator to be passed in to all tree operations
-            // and use that for determining ordering.
-            if entry.key() > key {
-                unsafe {
-                    self.shift_start(offset, size_of::<EntryRef>())?;
-                }
-                // entry_ref now references the dead entry that is where the first shifted element was.
-                entry_ref.reset();
-                return Some(entry_ref);
-            }
+        if idx == self.entry_count() {
+            return self
+                .alloc_start(EntryRef::layout())
+                .map(|Allocation { ptr, .. }| unsafe { &mut *(ptr as *mut u8 as *mut EntryRef) });
         }
 
-        // All existing entries have keys <= supplied key.
-        self.alloc_start(EntryRef::layout())
-            .map(|Allocation { ptr, .. }| unsafe { &mut *(ptr as *mut u8 as *mut EntryRef) })
+        let offset = size_of::<Header>() + idx * size_of::<EntryRef>();
+        unsafe {
+            self.shift_start(offset, size_of::<EntryRef>())?;
+            // The slot now holds a stale copy of the first shifted EntryRef.
+            let entry_ref = &mut *(self.offset_ptr_unchecked_mut(offset, size_of::<EntryRef>())
+                as *mut EntryRef);
+            entry_ref.reset();
+            Some(entry_ref)
+        }
     }
 }
 
@@ -405,6 +1279,48 @@ impl<'a> Drop for LeafNode<'a> {
     }
 }
 
+/// Bytes of the page used by an entry and its EntryRef.
+fn entry_len(entry_ref: &EntryRef) -> usize {
+    size_of::<EntryRef>()
+        + round_to(
+            PAGE_ENTRY_HEADER_SIZE + entry_ref.length as usize,
+            PAGE_ENTRY_HEADER_ALIGN,
+        )
+}
+
+/// Bytes of the page that an entry would use, with a key of `key_len` bytes and values that
+/// take up `vals_len` bytes as they are stored.
+pub(crate) fn entry_size(val_format: ValueFormat, key_len: usize, vals_len: usize) -> usize {
+    let data_len =
+        key_len + pad_for(PAGE_ENTRY_HEADER_SIZE + key_len, val_format.align()) + vals_len;
+    size_of::<EntryRef>() + round_to(PAGE_ENTRY_HEADER_SIZE + data_len, PAGE_ENTRY_HEADER_ALIGN)
+}
+
+/// Given the sizes of the items of two sibling nodes in order, returns the number of items
+/// to keep in the left node. This is all of them if they fit. Otherwise, it is the split
+/// that divides them most evenly, such that both halves fit and `valid_pivot(index of the
+/// first item of the right node)` holds.
+fn best_split(sizes: &[usize], valid_pivot: impl Fn(usize) -> bool) -> Option<usize> {
+    let total: usize = sizes.iter().sum();
+    if total <= NODE_CAPACITY {
+        return Some(sizes.len());
+    }
+    let mut best: Option<(usize, usize)> = None;
+    let mut left = 0;
+    for i in 1..sizes.len() {
+        left += sizes[i - 1];
+        let right = total - left;
+        if left > NODE_CAPACITY || right > NODE_CAPACITY || !valid_pivot(i) {
+            continue;
+        }
+        let imbalance = left.abs_diff(right);
+        if best.is_none_or(|(_, b)| imbalance < b) {
+            best = Some((i, imbalance));
+        }
+    }
+    best.map(|(i, _)| i)
+}
+
 pub(crate) struct PageEntryIter<'a> {
     ref_iter: EntryRefIter<'a>,
 }
@@ -458,128 +1374,313 @@ impl<'a> Iterator for EntryRefIter<'a> {
 /// However, when an entry is stored, the pointers and key will be stored
 /// separately.
 pub(crate) struct InnerEntry {
-    pub(crate) left: *const u8,
-    pub(crate) right: *const u8,
+    pub(crate) left: NodeId,
+    pub(crate) right: NodeId,
     pub(crate) key: Vec<u8>,
 }
 
+// An inner node stores its child ids interleaved with EntryRefs to its keys from
+// the start of the page, and the keys themselves from the end of the page:
+//
+// | header | child 0 | key ref 0 | child 1 | ... | key ref n-1 | child n | free | keys |
+//
+// Each child id is followed by the ref for the key that separates it from the next
+// child, so every child except the last starts a fixed-size slot. All keys in the subtree
+// of child i are >= key i-1 and < key i.
+//
+// Children are owned by the tree's NodeTable, not by the inner node.
+const CHILD_ID_SIZE: usize = size_of::<NodeId>();
+const INNER_SLOT_SIZE: usize = CHILD_ID_SIZE + CHILD_ID_SIZE; // Child id plus padded EntryRef.
+const INNER_START: usize = size_of::<Header>().div_ceil(CHILD_ID_SIZE) * CHILD_ID_SIZE;
+
 pub(crate) struct InnerNode<'a> {
     page: Option<Page>, // Always Some<Page> until dropped.
     pool: &'a Pool,
 }
 
-impl InnerNode<'_> {
-    pub(crate) fn new<'a>(pool: &'a Pool) -> InnerNode<'a> {
+impl<'a> InnerNode<'a> {
+    pub(crate) fn new(pool: &'a Pool) -> InnerNode<'a> {
+        InnerNode::from_page(pool.get(), pool)
+    }
+
+    /// Wraps a page that already holds an inner node, such as one read back from disk.
+    pub(crate) fn from_page(page: Page, pool: &'a Pool) -> InnerNode<'a> {
         InnerNode {
-            page: Some(pool.get()),
+            page: Some(page),
             pool,
         }
     }
 
+    /// Initializes an empty node with a single key and the children on either side of it.
     pub(crate) fn insert_entry(&mut self, entry: InnerEntry) -> Option<()> {
-        let page = self.page.as_mut().unwrap();
-        let key_len = entry.key.len();
+        debug_assert_eq!(0, self.child_count());
+        if (self.page().free_len() as usize) < INNER_SLOT_SIZE + CHILD_ID_SIZE + entry.key.len() {
+            return None;
+        }
+        self.push_child(entry.left);
+        self.insert_child(0, &entry.key, entry.right)
+    }
+
+    /// Adds `child` as the new right sibling of child `idx`, with `pivot` as the key that
+    /// separates them. If there is no room for it, this node is split, and its new right
+    /// sibling is returned.
+    pub(crate) fn insert_or_split(
+        &mut self,
+        idx: usize,
+        pivot: Vec<u8>,
+        child: NodeId,
+    ) -> Option<Split<'a>> {
+        if self.insert_child(idx, &pivot, child).is_some() {
+            return None;
+        }
+
+        // There is no room for the new child. Rebuild this node from the children before
+        // the key that divides their bytes most evenly, and move the rest to a new right
+        // sibling. That key moves up to the parent.
+        let (mut keys, mut children) = self.take_children();
+        keys.insert(idx, pivot);
+        children.insert(idx + 1, child);
+        let mid = best_inner_split(&keys, usize::MAX)
+            .expect("Keys from entries of at most MAX_ENTRY_LEN bytes must split into two nodes");
+        let right_keys = keys.split_off(mid + 1);
+        let right_children = children.split_off(mid + 1);
+        let pivot = keys.pop().unwrap();
+
+        let mut right = InnerNode::new(self.pool);
+        self.build(&keys, &children);
+        right.build(&right_keys, &right_children);
+        Some(Split {
+            pivot,
+            right: Node::InnerNode(right),
+        })
+    }
+
+    /// Removes child `idx` along with the key that separates it from the child before it.
+    pub(crate) fn remove_child(&mut self, idx: usize) {
+        debug_assert!(idx > 0);
+        let (mut keys, mut children) = self.take_children();
+        keys.remove(idx - 1);
+        children.remove(idx);
+        self.build(&keys, &children);
+    }
 
-        let free_space_needed = size_of::<usize>() * 2  // left and right pointers
-            + size_of::<EntryRef>() // reference to key
-            + key_len; // key
-        if (page.free_len() as usize) < free_space_needed {
+    /// Replaces key `idx`. Returns None without modifying the node if the new key does not
+    /// fit.
+    pub(crate) fn set_key(&mut self, idx: usize, key: Vec<u8>) -> Option<()> {
+        if key.len() > self.max_key_len(idx) {
             return None;
         }
+        let (mut keys, children) = self.take_children();
+        keys[idx] = key;
+        self.build(&keys, &children);
+        Some(())
+    }
+
+    /// Whether a child can be added with a key of up to `max_key_len` bytes without
+    /// splitting the node.
+    pub(crate) fn can_fit_child(&self, max_key_len: usize) -> bool {
+        self.page().free_len() as usize >= INNER_SLOT_SIZE + max_key_len
+    }
+
+    /// Whether a child and a key of up to `max_key_len` bytes can be removed without
+    /// leaving the node underfull.
+    pub(crate) fn can_lose_child(&self, max_key_len: usize) -> bool {
+        self.used_len()
+            .saturating_sub(INNER_SLOT_SIZE + max_key_len)
+            >= NODE_CAPACITY / 4
+    }
+
+    /// The longest key that key `idx` could be replaced with.
+    pub(crate) fn max_key_len(&self, idx: usize) -> usize {
+        self.page().free_len() as usize + self.key(idx).len()
+    }
+
+    /// Bytes of the page used by children and keys.
+    fn used_len(&self) -> usize {
+        let page = self.page();
+        page.free_start() as usize - INNER_START + PAGE_SIZE - page.free_end() as usize
+    }
+
+    /// Moves the children of `right`, the right sibling of this node, into this node if
+    /// they fit, with `pivot` from their parent as the key between the two halves.
+    /// Otherwise redistributes the children of both so that they are about equally full,
+    /// choosing only from splits whose new pivot is at most `max_pivot_len` bytes long.
+    pub(crate) fn merge_or_rebalance(
+        &mut self,
+        pivot: &[u8],
+        right: &mut InnerNode<'a>,
+        max_pivot_len: usize,
+    ) -> Rebalance {
+        let (mut keys, mut children) = self.keys_and_children();
+        let (right_keys, right_children) = right.keys_and_children();
+        keys.push(pivot.to_vec());
+        keys.extend(right_keys);
+        children.extend(right_children);
+
+        if inner_len(&keys) <= NODE_CAPACITY {
+            self.build(&keys, &children);
+            right.page_mut().reset();
+            return Rebalance::Merged;
+        }
+
+        // The current split is always a candidate as long as `max_pivot_len` allows for the
+        // current pivot, since both nodes fit as they are.
+        let mid = match best_inner_split(&keys, max_pivot_len) {
+            Some(mid) => mid,
+            None => return Rebalance::Unchanged,
+        };
+        self.build(&keys[..mid], &children[..=mid]);
+        right.build(&keys[mid + 1..], &children[mid + 1..]);
+        Rebalance::Rebalanced(keys[mid].clone())
+    }
+
+    pub(crate) fn child_count(&self) -> usize {
+        match self.page().free_start() as usize {
+            end if end <= INNER_START => 0,
+            end => (end - INNER_START - CHILD_ID_SIZE) / INNER_SLOT_SIZE + 1,
+        }
+    }
+
+    /// Index of the child whose subtree would contain `key`: the first child whose
+    /// separating key is greater than `key`. Keys are in order, so this is a binary search.
+    pub(crate) fn child_index<C: KeyComparator>(&self, key: &[u8], cmp: &C) -> usize {
+        let key_count = self.child_count().saturating_sub(1);
+        let (mut lo, mut hi) = (0, key_count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if cmp.compare(key, self.key(mid)) == Ordering::Less {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        lo
+    }
 
-        let key_layout = Layout::from_size_align(key_len, 1).unwrap();
-        let ptr_layout = Layout::from_size_align(size_of::<usize>(), align_of::<usize>()).unwrap();
-        let entry_ref_layout =
-            Layout::from_size_align(size_of::<EntryRef>(), align_of::<EntryRef>()).unwrap();
+    pub(crate) fn child(&self, idx: usize) -> NodeId {
+        debug_assert!(idx < self.child_count());
+        unsafe {
+            let ptr = self
+                .page()
+                .offset_ptr_unchecked(INNER_START + idx * INNER_SLOT_SIZE, CHILD_ID_SIZE);
+            *(ptr as *const NodeId)
+        }
+    }
 
+    pub(crate) fn key(&self, idx: usize) -> &[u8] {
+        let page = self.page();
         unsafe {
-            // Append key.
-            // TODO: Handle when key length is larger than u16::MAX.
-            // TODO: Handle key alignment so that clients can perform an aligned read of some typed key without a copy.
+            let entry_ref_ptr = page.offset_ptr_unchecked(
+                INNER_START + idx * INNER_SLOT_SIZE + CHILD_ID_SIZE,
+                size_of::<EntryRef>(),
+            );
+            let entry_ref = &*(entry_ref_ptr as *const EntryRef);
+            &*page.offset_ptr_unchecked(entry_ref.offset as usize, entry_ref.length as usize)
+        }
+    }
 
-            // SAFETY:
-            // We have already ensured that the page has enough free space for all allocations made in this
-            // block, so none of the allocations will fail or return overlapping memory regions.
+    /// Inserts `key` after child `idx`, with `child` as the child that follows it.
+    /// Returns None without modifying the node if there is not enough space.
+    fn insert_child(&mut self, idx: usize, key: &[u8], child: NodeId) -> Option<()> {
+        if (self.page().free_len() as usize) < INNER_SLOT_SIZE + key.len() {
+            return None;
+        }
+        let page = self.page_mut();
+        let slot = INNER_START + idx * INNER_SLOT_SIZE + CHILD_ID_SIZE;
+        unsafe {
+            // SAFETY: The slot and the key both fit in the free space, and slots are a
+            // multiple of the id alignment, so shifted slots stay aligned.
+            page.shift_start(slot, INNER_SLOT_SIZE)?;
             let Allocation {
                 ptr: key_ptr,
                 offset: key_offset,
-            } = page.alloc_end_unchecked(key_layout);
-            (&mut *(key_ptr)).copy_from_slice(&entry.key);
-
-            // Allocate left pointer.
-            let Allocation { ptr: left_ptr, .. } = page.alloc_start_unchecked(ptr_layout);
-            *(left_ptr as *mut usize) = entry.left as usize;
+            } = page.alloc_end_unchecked(Layout::from_size_align_unchecked(key.len(), 1));
+            (*key_ptr).copy_from_slice(key);
 
-            // Allocate EntryRef.
-            let Allocation {
-                ptr: entry_ref_ptr,
-                ..
-            } = page.alloc_start_unchecked(entry_ref_layout);
-            let entry_ref = &mut *(entry_ref_ptr as *mut u8 as *mut EntryRef);
+            let entry_ref =
+                &mut *(page.offset_ptr_unchecked_mut(slot, size_of::<EntryRef>()) as *mut EntryRef);
             entry_ref.offset = key_offset;
-            entry_ref.length = entry.key.len() as u16;
-
-            // Allocate right pointer.
-            let Allocation { ptr: right_ptr, .. } = page.alloc_start_unchecked(ptr_layout);
-            *(right_ptr as *mut usize) = entry.right as usize;
+            entry_ref.length = key.len() as u16;
+            let child_ptr = page.offset_ptr_unchecked_mut(slot + CHILD_ID_SIZE, CHILD_ID_SIZE);
+            *(child_ptr as *mut NodeId) = child;
         }
-
         Some(())
     }
 
-    pub(crate) fn find(&self, key: &[u8]) -> Option<&PageEntry> {
-        self.greatest_child_lt(key)
-            .and_then(|child| child.find(key))
+    fn push_child(&mut self, child: NodeId) {
+        let page = self.page_mut();
+        let layout = Layout::from_size_align(CHILD_ID_SIZE, align_of::<NodeId>()).unwrap();
+        let Allocation { ptr, .. } = page
+            .alloc_start(layout)
+            .expect("Inner node must have capacity for its first child");
+        unsafe { *(ptr as *mut NodeId) = child };
     }
 
-    pub(crate) fn insert(&mut self, val_layout: Layout, key: &[u8], val: &[u8]) -> Option<()> {
-        self.greatest_child_lt_mut(key)
-            .and_then(|child| child.insert(val_layout, key, val))
+    /// Resets the node to hold exactly the given keys and children.
+    fn build(&mut self, keys: &[Vec<u8>], children: &[NodeId]) {
+        debug_assert_eq!(keys.len() + 1, children.len());
+        self.page_mut().reset();
+        self.push_child(children[0]);
+        for (i, (key, &child)) in keys.iter().zip(&children[1..]).enumerate() {
+            self.insert_child(i, key, child)
+                .expect("Keys must fit in the node they are moved to");
+        }
     }
 
-    fn greatest_child_lt(&self, key: &[u8]) -> Option<&Node> {
-        self.greatest_child_lt_ptr(key).map(|ptr| unsafe { &*ptr })
+    /// Removes all keys and children from the node, and returns them.
+    fn take_children(&mut self) -> (Vec<Vec<u8>>, Vec<NodeId>) {
+        let keys_and_children = self.keys_and_children();
+        self.page_mut().reset();
+        keys_and_children
     }
 
-    fn greatest_child_lt_mut(&self, key: &[u8]) -> Option<&mut Node> {
-        self.greatest_child_lt_ptr(key)
-            .map(|ptr| unsafe { &mut *(ptr as *mut Node) })
+    fn keys_and_children(&self) -> (Vec<Vec<u8>>, Vec<NodeId>) {
+        let count = self.child_count();
+        let keys = (0..count.saturating_sub(1))
+            .map(|i| self.key(i).to_vec())
+            .collect();
+        let children = (0..count).map(|i| self.child(i)).collect();
+        (keys, children)
     }
 
-    fn greatest_child_lt_ptr(&self, key: &[u8]) -> Option<*const Node> {
-        let page = self.page.as_ref().unwrap();
-
-        // Calculate offsets of components of each entry
-        let left_ptr_offset = 0usize;
-        let entry_ref_offset = round_to(left_ptr_offset+size_of::<usize>(), align_of::<EntryRef>());
-        let right_ptr_offset =  round_to(entry_ref_offset+size_of::<EntryRef>(), align_of::<usize>());
-        let total_item_size = right_ptr_offset + size_of::<usize>();
+    fn page(&self) -> &Page {
+        self.page.as_ref().unwrap()
+    }
 
-        // Range from the start of allocatable memory to
-        let start = round_to(size_of::<Header>(), align_of::<usize>());
-        let end = page.free_start() as usize - total_item_size;
+    fn page_mut(&mut self) -> &mut Page {
+        self.page.as_mut().unwrap()
+    }
+}
 
-        let mut last_right_child = None;
-        unsafe {
-            // The memory that we look at in each iteration overlaps the previous iteration
-            // such that the previous right pointer becomes the current right pointer.
-            for base_offset in (start..=end).step_by(right_ptr_offset) {
-                let left_ptr = page.offset_ptr_unchecked(base_offset+left_ptr_offset, size_of::<usize>()) as *const usize;
-                let entry_ref_ptr = page.offset_ptr_unchecked(base_offset+entry_ref_offset, size_of::<EntryRef>()) as *const EntryRef;
-                let right_ptr = page.offset_ptr_unchecked(base_offset+right_ptr_offset, size_of::<usize>()) as *const usize;
-
-                let entry_ref = &*entry_ref_ptr;
-                let entry_key = page.offset_ptr_unchecked(entry_ref.offset as usize, entry_ref.length as usize) as *const [u8];
-                if key < &*entry_key {
-                    return Some(*left_ptr as *const Node);
-                }
+/// Bytes of the page used by an inner node with `keys`, and a child on either side of each.
+fn inner_len(keys: &[Vec<u8>]) -> usize {
+    CHILD_ID_SIZE
+        + keys
+            .iter()
+            .map(|k| INNER_SLOT_SIZE + k.len())
+            .sum::<usize>()
+}
 
-                last_right_child = Some(*right_ptr as *const Node);
+/// Given the keys of two sibling inner nodes and the pivot between them in order, returns
+/// the index of the key to move up to their parent, while the keys on either side of it
+/// stay in the two nodes. It is the one that divides them most evenly, such that both
+/// halves fit and it is at most `max_pivot_len` bytes long.
+fn best_inner_split(keys: &[Vec<u8>], max_pivot_len: usize) -> Option<usize> {
+    let total = inner_len(keys);
+    let mut best: Option<(usize, usize)> = None;
+    let mut left = CHILD_ID_SIZE;
+    for (mid, key) in keys.iter().enumerate() {
+        let slot = INNER_SLOT_SIZE + key.len();
+        let right = total - left - slot + CHILD_ID_SIZE;
+        if key.len() <= max_pivot_len && left <= NODE_CAPACITY && right <= NODE_CAPACITY {
+            let imbalance = left.abs_diff(right);
+            if best.is_none_or(|(_, b)| imbalance < b) {
+                best = Some((mid, imbalance));
             }
         }
-
-        last_right_child
+        left += slot;
     }
+    best.map(|(mid, _)| mid)
 }
 
 impl<'a> Drop for InnerNode<'a> {
@@ -588,6 +1689,42 @@ impl<'a> Drop for InnerNode<'a> {
     }
 }
 
+/// Leaf operations for the benchmarks in `benches/`, which can only reach public items. Not
+/// part of the public API.
+#[doc(hidden)]
+pub mod bench {
+    use std::alloc::Layout;
+
+    use super::LeafNode;
+    use crate::comparator::Bytewise;
+    use crate::entry::ValueFormat;
+    use crate::page::Pool;
+
+    pub struct Leaf<'a>(LeafNode<'a>);
+
+    impl<'a> Leaf<'a> {
+        /// Returns a leaf holding as many of `keys` as fit in it, and how many that is.
+        pub fn fill(pool: &'a Pool, val_layout: Layout, keys: &[Vec<u8>]) -> (Leaf<'a>, usize) {
+            let mut leaf = LeafNode::new(pool);
+            let val = vec![0; val_layout.size()];
+            let val_format = ValueFormat::Fixed(val_layout);
+            let count = keys
+                .iter()
+                .take_while(|key| leaf.insert(val_format, key, &val, &Bytewise).is_some())
+                .count();
+            (Leaf(leaf), count)
+        }
+
+        pub fn find(&self, key: &[u8]) -> bool {
+            self.0.find_entry(key, &Bytewise).is_some()
+        }
+
+        pub fn find_linear(&self, key: &[u8]) -> bool {
+            self.0.find_entry_linear(key).is_some()
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
@@ -595,32 +1732,45 @@ mod tests {
         mem::{align_of, size_of},
     };
 
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
     use super::*;
+    use crate::comparator::Bytewise;
 
     #[test]
     fn test_insert_into_leaf() {
         let pool = Pool::new();
         let val_size = size_of::<u64>();
         let val_align = align_of::<u64>();
-        let val_layout = Layout::from_size_align(val_size, val_align).unwrap();
+        let val_format = ValueFormat::Fixed(Layout::from_size_align(val_size, val_align).unwrap());
         let mut leaf_node = LeafNode::new(&pool);
 
-        leaf_node.insert(val_layout, &[0, 1, 45, 23], &2345u64.to_le_bytes());
+        leaf_node.insert(
+            val_format,
+            &[0, 1, 45, 23],
+            &2345u64.to_le_bytes(),
+            &Bytewise,
+        );
         {
             // TODO: The return type should expose a value iterator that is aware of the value size.
-            let res = leaf_node.find(&[0, 1, 45, 23]).unwrap();
+            let res = leaf_node.find(&[0, 1, 45, 23], &Bytewise).unwrap();
             assert_eq!(res.key(), &[0, 1, 45, 23]);
             assert_eq!(
-                res.values_iter(val_layout).collect::<Vec<_>>(),
+                res.values_iter(val_format).collect::<Vec<_>>(),
                 vec![&2345u64.to_le_bytes()]
             );
         }
 
-        leaf_node.insert(val_layout, &[0, 1, 45, 23], &4985355u64.to_le_bytes());
+        leaf_node.insert(
+            val_format,
+            &[0, 1, 45, 23],
+            &4985355u64.to_le_bytes(),
+            &Bytewise,
+        );
         {
-            let res = leaf_node.find(&[0, 1, 45, 23]).unwrap();
+            let res = leaf_node.find(&[0, 1, 45, 23], &Bytewise).unwrap();
             assert_eq!(
-                res.values_iter(val_layout).collect::<Vec<_>>(),
+                res.values_iter(val_format).collect::<Vec<_>>(),
                 vec![&2345u64.to_le_bytes(), &4985355u64.to_le_bytes()]
             );
         }
@@ -629,106 +1779,489 @@ mod tests {
     #[test]
     fn test_entry_count() {
         let pool = Pool::new();
-        let val_layout = Layout::from_size_align(1, 1).unwrap();
+        let val_format = ValueFormat::Fixed(Layout::from_size_align(1, 1).unwrap());
         let mut leaf_node = LeafNode::new(&pool);
 
-        leaf_node.insert(val_layout, &[1], &[1]);
+        leaf_node.insert(val_format, &[1], &[1], &Bytewise);
         assert_eq!(1, leaf_node.entry_count());
 
         // Adding a new key should create a new entry.
-        leaf_node.insert(val_layout, &[2], &[2]);
+        leaf_node.insert(val_format, &[2], &[2], &Bytewise);
         assert_eq!(2, leaf_node.entry_count());
 
         // Inserting a new value for an existing key should not create a new entry (only garbage to clean up later).
-        leaf_node.insert(val_layout, &[1], &[3]);
+        leaf_node.insert(val_format, &[1], &[3], &Bytewise);
         assert_eq!(2, leaf_node.entry_count());
     }
 
     #[test]
     fn test_compact() {
         let pool = Pool::new();
-        let val_layout = Layout::from_size_align(8, 4).unwrap();
+        let val_format = ValueFormat::Fixed(Layout::from_size_align(8, 4).unwrap());
         let mut leaf_node = LeafNode::new(&pool);
 
-        leaf_node.insert(val_layout, "key 1".as_bytes(), &123u64.to_le_bytes());
-        leaf_node.insert(val_layout, "key 1".as_bytes(), &456u64.to_le_bytes());
-        leaf_node.insert(val_layout, "key 1".as_bytes(), &789u64.to_le_bytes());
+        leaf_node.insert(
+            val_format,
+            "key 1".as_bytes(),
+            &123u64.to_le_bytes(),
+            &Bytewise,
+        );
+        leaf_node.insert(
+            val_format,
+            "key 1".as_bytes(),
+            &456u64.to_le_bytes(),
+            &Bytewise,
+        );
+        leaf_node.insert(
+            val_format,
+            "key 1".as_bytes(),
+            &789u64.to_le_bytes(),
+            &Bytewise,
+        );
 
-        leaf_node.insert(val_layout, "other key".as_bytes(), &81235u64.to_le_bytes());
+        leaf_node.insert(
+            val_format,
+            "other key".as_bytes(),
+            &81235u64.to_le_bytes(),
+            &Bytewise,
+        );
 
         let initial_free = leaf_node.free_len();
-        let vals_for_key_1 = get_u64_values_for_key(&leaf_node, val_layout, "key 1".as_bytes());
+        let vals_for_key_1 = get_u64_values_for_key(&leaf_node, val_format, "key 1".as_bytes());
 
         leaf_node.compact();
 
         assert!(leaf_node.free_len() > initial_free);
         assert_eq!(
             vals_for_key_1,
-            get_u64_values_for_key(&leaf_node, val_layout, "key 1".as_bytes()),
+            get_u64_values_for_key(&leaf_node, val_format, "key 1".as_bytes()),
         )
     }
 
     #[test]
     fn test_node_split() {
         let pool = Pool::new();
-        let val_layout = Layout::from_size_align(8, 4).unwrap();
+        let val_format = ValueFormat::Fixed(Layout::from_size_align(8, 4).unwrap());
+        let mut leaf_node = LeafNode::new(&pool);
+
+        let key = |i: u32| format!("key {:05}", i).into_bytes();
+        let mut count = 0;
+        let split = loop {
+            let split = leaf_node.insert_or_split(
+                val_format,
+                &key(count),
+                &(count as u64).to_le_bytes(),
+                &Bytewise,
+            );
+            count += 1;
+            if let Some(split) = split {
+                break split;
+            }
+        };
+        let snd = split.right.as_leaf();
+
+        // Every key is on the side of the pivot that it belongs to, and the two nodes are
+        // equally full, give or take an entry.
+        assert_eq!(count as usize, leaf_node.entry_count() + snd.entry_count());
+        for i in 0..count {
+            let (node, other) = if key(i) < split.pivot {
+                (&leaf_node, snd)
+            } else {
+                (snd, &leaf_node)
+            };
+            assert_eq!(
+                vec![i as u64],
+                get_u64_values_for_key(node, val_format, &key(i))
+            );
+            assert!(other.find(&key(i), &Bytewise).is_none());
+        }
+        let entry_len = entry_size(val_format, key(0).len(), 8);
+        assert!(leaf_node.used_len().abs_diff(snd.used_len()) <= entry_len);
+    }
+
+    #[test]
+    fn test_split_around_large_entry() {
+        let pool = Pool::new();
+        let val_format = ValueFormat::Variable;
+        let mut leaf_node = LeafNode::new(&pool);
+        let small = [1u8; 100];
+        let key = |i: u32| format!("key {:05}", i).into_bytes();
+        let mut count = 0;
+        while leaf_node
+            .try_insert(val_format, &key(count), &small, &Bytewise)
+            .is_some()
+        {
+            count += 1;
+        }
+
+        // An entry of the largest size allowed fits in one of the halves, wherever it goes
+        // in a full node.
+        let new_key = |i: u32| [key(i), b"!".to_vec()].concat();
+        let val_len = (0..)
+            .take_while(|&len| {
+                entry_size(val_format, new_key(0).len(), val_format.encoded_len(len))
+                    <= MAX_ENTRY_LEN
+            })
+            .last()
+            .unwrap();
+        let big = vec![2u8; val_len];
+        for i in [0, count / 2, count] {
+            let mut node = LeafNode::new(&pool);
+            for j in 0..count {
+                node.insert(val_format, &key(j), &small, &Bytewise).unwrap();
+            }
+            let split = node
+                .insert_or_split(val_format, &new_key(i), &big, &Bytewise)
+                .unwrap();
+            let holder = if new_key(i) < split.pivot {
+                &node
+            } else {
+                split.right.as_leaf()
+            };
+            assert_eq!(
+                vec![&big[..]],
+                get_values_for_key(holder, val_format, &new_key(i))
+            );
+            assert_eq!(
+                count as usize + 1,
+                node.entry_count() + split.right.as_leaf().entry_count()
+            );
+        }
+    }
+
+    #[test]
+    fn test_search_matches_linear_scan() {
+        let pool = Pool::new();
+        let val_format = ValueFormat::Fixed(Layout::from_size_align(8, 8).unwrap());
         let mut leaf_node = LeafNode::new(&pool);
+        let mut rng = StdRng::seed_from_u64(45);
+        let mut keys = Vec::new();
+        loop {
+            let key = (0..rng.gen_range(0, 12))
+                .map(|_| rng.gen_range(0, 4))
+                .collect::<Vec<u8>>();
+            if leaf_node
+                .insert(val_format, &key, &[0; 8], &Bytewise)
+                .is_none()
+            {
+                break;
+            }
+            keys.push(key);
+        }
 
-        leaf_node.insert(val_layout, "key 1".as_bytes(), &123u64.to_le_bytes());
-        leaf_node.insert(val_layout, "key 2".as_bytes(), &456u64.to_le_bytes());
+        // Keys are yielded in order, and found by both searches at the same place.
+        let scanned = leaf_node
+            .scan()
+            .map(|(_, entry)| unsafe { &*entry }.key().to_vec())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys, scanned);
+        for key in (0..200).map(|i| vec![(i % 4) as u8; i / 20]).chain(keys) {
+            assert_eq!(
+                leaf_node.find_entry(&key, &Bytewise),
+                leaf_node.find_entry_linear(&key)
+            );
+        }
+    }
 
-        let vals_for_key_1 = get_u64_values_for_key(&leaf_node, val_layout, "key 1".as_bytes());
-        let vals_for_key_2 = get_u64_values_for_key(&leaf_node, val_layout, "key 2".as_bytes());
+    #[test]
+    fn test_remove_from_leaf() {
+        let pool = Pool::new();
+        let val_format = ValueFormat::Fixed(Layout::from_size_align(8, 8).unwrap());
+        let mut leaf_node = LeafNode::new(&pool);
+        let initial_free = leaf_node.free_len();
 
-        let (fst, snd) = leaf_node.split(val_layout);
+        for key in ["key 1", "key 2", "key 3"] {
+            leaf_node.insert(val_format, key.as_bytes(), &1u64.to_le_bytes(), &Bytewise);
+        }
+        leaf_node.insert(
+            val_format,
+            "key 3".as_bytes(),
+            &2u64.to_le_bytes(),
+            &Bytewise,
+        );
+        leaf_node.insert(
+            val_format,
+            "key 3".as_bytes(),
+            &3u64.to_le_bytes(),
+            &Bytewise,
+        );
 
-        // First node has only first entry.
+        assert!(leaf_node.remove("key 2".as_bytes(), &Bytewise));
+        assert!(!leaf_node.remove("key 2".as_bytes(), &Bytewise));
+        assert_eq!(2, leaf_node.entry_count());
+        assert!(leaf_node.find("key 2".as_bytes(), &Bytewise).is_none());
         assert_eq!(
-            vals_for_key_1,
-            get_u64_values_for_key(&fst, val_layout, "key 1".as_bytes()),
+            vec![1],
+            get_u64_values_for_key(&leaf_node, val_format, "key 1".as_bytes())
         );
-        assert!(fst.find("key 2".as_bytes()).is_none());
 
-        // Second node has only second entry.
+        // Removing a value from the middle keeps the others in order.
+        assert!(leaf_node.remove_value(
+            val_format,
+            "key 3".as_bytes(),
+            &2u64.to_le_bytes(),
+            &Bytewise
+        ));
+        assert!(!leaf_node.remove_value(
+            val_format,
+            "key 3".as_bytes(),
+            &2u64.to_le_bytes(),
+            &Bytewise
+        ));
         assert_eq!(
-            vals_for_key_2,
-            get_u64_values_for_key(&snd, val_layout, "key 2".as_bytes()),
+            vec![1, 3],
+            get_u64_values_for_key(&leaf_node, val_format, "key 3".as_bytes())
         );
-        assert!(snd.find("key 1".as_bytes()).is_none());
+
+        // Removing the last value removes the entry, and compaction reclaims all the space.
+        assert!(leaf_node.remove_value(
+            val_format,
+            "key 1".as_bytes(),
+            &1u64.to_le_bytes(),
+            &Bytewise
+        ));
+        assert!(leaf_node.find("key 1".as_bytes(), &Bytewise).is_none());
+        leaf_node.remove("key 3".as_bytes(), &Bytewise);
+        assert_eq!(0, leaf_node.entry_count());
+        leaf_node.compact();
+        assert_eq!(initial_free, leaf_node.free_len());
+    }
+
+    #[test]
+    fn test_variable_values() {
+        let pool = Pool::new();
+        let val_format = ValueFormat::Variable;
+        let mut leaf_node = LeafNode::new(&pool);
+        let initial_free = leaf_node.free_len();
+        let vals: [&[u8]; 4] = [b"row one", b"", b"a much longer second row", b"x"];
+
+        for val in &vals {
+            leaf_node.insert(val_format, b"key 1", val, &Bytewise);
+        }
+        leaf_node.insert(val_format, b"key 2", b"other", &Bytewise);
+        assert_eq!(
+            vals.to_vec(),
+            get_values_for_key(&leaf_node, val_format, b"key 1")
+        );
+
+        // Removing a value from the middle keeps the others, whatever their lengths.
+        assert!(leaf_node.remove_value(val_format, b"key 1", b"", &Bytewise));
+        assert!(!leaf_node.remove_value(val_format, b"key 1", b"", &Bytewise));
+        assert!(!leaf_node.remove_value(val_format, b"key 1", b"row", &Bytewise));
+        let expected = vec![vals[0], vals[2], vals[3]];
+        assert_eq!(
+            expected.clone(),
+            get_values_for_key(&leaf_node, val_format, b"key 1")
+        );
+
+        leaf_node.compact();
+        assert_eq!(
+            expected.clone(),
+            get_values_for_key(&leaf_node, val_format, b"key 1")
+        );
+        // Splitting keeps the values, whatever their lengths. Large values of later keys
+        // fill the node until it splits, and the small entries stay on the left.
+        let big = [7u8; 1000];
+        let key = |i: usize| format!("key 3 {:02}", i).into_bytes();
+        let mut i = 0;
+        let split = loop {
+            if let Some(split) = leaf_node.insert_or_split(val_format, &key(i), &big, &Bytewise) {
+                break split;
+            }
+            i += 1;
+        };
+        let snd = split.right.as_leaf();
+        assert_eq!(
+            expected.clone(),
+            get_values_for_key(&leaf_node, val_format, b"key 1")
+        );
+        assert_eq!(
+            vec![&b"other"[..]],
+            get_values_for_key(&leaf_node, val_format, b"key 2")
+        );
+        assert_eq!(vec![&big[..]], get_values_for_key(snd, val_format, &key(i)));
+        assert!(leaf_node.find(&split.pivot, &Bytewise).is_none());
+        assert!(snd.find(&split.pivot, &Bytewise).is_some());
+        drop(split);
+
+        for j in 0..i {
+            leaf_node.remove(&key(j), &Bytewise);
+        }
+        assert!(leaf_node.remove(b"key 2", &Bytewise));
+        for val in &[vals[0], vals[2], vals[3]] {
+            assert!(leaf_node.remove_value(val_format, b"key 1", val, &Bytewise));
+        }
+        assert_eq!(0, leaf_node.entry_count());
+        leaf_node.compact();
+        assert_eq!(initial_free, leaf_node.free_len());
+
+        // An entry can grow to more than half a page, until the page is full.
+        let big = [7u8; 1000];
+        let mut count = 0;
+        while leaf_node
+            .try_insert(val_format, b"key 3", &big, &Bytewise)
+            .is_some()
+        {
+            count += 1;
+        }
+        assert!(count * big.len() > PAGE_SIZE * 3 / 4);
+        assert_eq!(
+            vec![&big[..]; count],
+            get_values_for_key(&leaf_node, val_format, b"key 3")
+        );
+    }
+
+    #[test]
+    fn test_leaf_merge_or_rebalance() {
+        let pool = Pool::new();
+        let val_format = ValueFormat::Fixed(Layout::from_size_align(8, 8).unwrap());
+        let mut left = LeafNode::new(&pool);
+        let mut right = LeafNode::new(&pool);
+        left.insert(val_format, &[1], &1u64.to_le_bytes(), &Bytewise);
+        right.insert(val_format, &[2], &2u64.to_le_bytes(), &Bytewise);
+        right.set_next(Some(NodeId(7)));
+
+        assert!(matches!(
+            left.merge_or_rebalance(&mut right, val_format, 100),
+            Rebalance::Merged
+        ));
+        assert_eq!(2, left.entry_count());
+        assert_eq!(0, right.entry_count());
+        assert_eq!(Some(NodeId(7)), left.next());
+
+        // Fill the left node, so that both no longer fit in one page.
+        let mut i = 3u16;
+        while left
+            .insert(val_format, &i.to_be_bytes(), &[0; 8], &Bytewise)
+            .is_some()
+        {
+            i += 1;
+        }
+        right.insert(val_format, &[0xff, 0xff], &[0; 8], &Bytewise);
+        let pivot = match left.merge_or_rebalance(&mut right, val_format, 100) {
+            Rebalance::Rebalanced(pivot) => pivot,
+            _ => panic!("Expected a full node to be rebalanced"),
+        };
+        let difference = left.entry_count().abs_diff(right.entry_count());
+        assert!(difference <= 1);
+        assert!(left.scan().all(|(_, e)| unsafe { &*e }.key() < &pivot[..]));
+        assert_eq!(pivot, unsafe { &*right.scan().next().unwrap().1 }.key());
+
+        // Neither is possible if the parent has no room for a pivot.
+        assert!(matches!(
+            left.merge_or_rebalance(&mut right, val_format, 0),
+            Rebalance::Unchanged
+        ));
     }
 
     #[test]
     fn test_inner_node_basic() {
         let pool = Pool::new();
-        let leaf_node_left = Node::new_leaf(&pool);
-        let leaf_node_right = Node::new_leaf(&pool);
+        let mut nodes = NodeTable::new();
+        let leaf_node_left = nodes.insert(Node::new_leaf(&pool));
+        let leaf_node_right = nodes.insert(Node::new_leaf(&pool));
         let mut inner_node = InnerNode::new(&pool);
 
         inner_node.insert_entry(InnerEntry {
             key: "banana".as_bytes().to_vec(),
-            left: &leaf_node_left as *const Node as *const u8,
-            right: &leaf_node_right as *const Node as *const u8,
+            left: leaf_node_left,
+            right: leaf_node_right,
         });
 
         assert_eq!(
-            &leaf_node_left as *const Node as usize,
-            inner_node.greatest_child_lt("apple".as_bytes()).unwrap() as *const Node as usize
+            leaf_node_left,
+            inner_node.child(inner_node.child_index("apple".as_bytes(), &Bytewise))
         );
         assert_eq!(
-            &leaf_node_right as *const Node as usize,
-            inner_node.greatest_child_lt("banana".as_bytes()).unwrap() as *const Node as usize
+            leaf_node_right,
+            inner_node.child(inner_node.child_index("banana".as_bytes(), &Bytewise))
         );
         assert_eq!(
-            &leaf_node_right as *const Node as usize,
-            inner_node.greatest_child_lt("cherry".as_bytes()).unwrap() as *const Node as usize
+            leaf_node_right,
+            inner_node.child(inner_node.child_index("cherry".as_bytes(), &Bytewise))
         );
     }
 
-    fn get_u64_values_for_key(n: &LeafNode, val_layout: Layout, key: &[u8]) -> Vec<u64> {
-        n.find(key)
+    #[test]
+    fn test_inner_node_split() {
+        let pool = Pool::new();
+        let mut inner_node = InnerNode::new(&pool);
+        inner_node.insert_entry(InnerEntry {
+            key: vec![0; 100],
+            left: NodeId(1),
+            right: NodeId(2),
+        });
+
+        // Add children after the second one until the node has to split.
+        let mut split = None;
+        for i in 0u32.. {
+            let mut key = vec![1; 100];
+            key[96..].copy_from_slice(&i.to_be_bytes());
+            split = inner_node.insert_or_split(i as usize + 1, key, NodeId(u64::from(i) + 3));
+            if split.is_some() {
+                break;
+            }
+        }
+        let Split { pivot, right } = split.unwrap();
+        let right = match right {
+            Node::InnerNode(n) => n,
+            _ => panic!("Expected the split of an inner node to be an inner node"),
+        };
+
+        // Every key is in exactly one half, on the correct side of the pivot, and the
+        // children stay in order across both halves.
+        assert!(inner_node.child_count() > 1 && right.child_count() > 1);
+        assert!((0..inner_node.child_count() - 1).all(|i| inner_node.key(i) < &pivot[..]));
+        assert!((0..right.child_count() - 1).all(|i| right.key(i) > &pivot[..]));
+        let children = (0..inner_node.child_count())
+            .map(|i| inner_node.child(i))
+            .chain((0..right.child_count()).map(|i| right.child(i)))
+            .collect::<Vec<_>>();
+        let expected = (1..=children.len() as u64).map(NodeId).collect::<Vec<_>>();
+        assert_eq!(expected, children);
+        assert_eq!(
+            NodeId(1),
+            inner_node.child(inner_node.child_index(&[0; 99], &Bytewise))
+        );
+        assert_eq!(
+            NodeId(2),
+            inner_node.child(inner_node.child_index(&[0; 100], &Bytewise))
+        );
+
+        // The binary search agrees with a scan of the keys, between and on each key.
+        for node in [&inner_node, &right] {
+            let key_count = node.child_count() - 1;
+            for i in 0..key_count {
+                let mut between = node.key(i).to_vec();
+                between.push(0);
+                for key in [node.key(i), &between[..]] {
+                    let expected = (0..key_count)
+                        .find(|&j| key < node.key(j))
+                        .unwrap_or(key_count);
+                    assert_eq!(expected, node.child_index(key, &Bytewise));
+                }
+            }
+        }
+    }
+
+    fn get_u64_values_for_key(n: &LeafNode, val_format: ValueFormat, key: &[u8]) -> Vec<u64> {
+        n.find(key, &Bytewise)
             .unwrap()
-            .values_iter(val_layout)
+            .values_iter(val_format)
             .map(|val| u64::from_le_bytes(val.try_into().unwrap()))
             .collect()
     }
+
+    fn get_values_for_key<'a>(
+        n: &'a LeafNode,
+        val_format: ValueFormat,
+        key: &[u8],
+    ) -> Vec<&'a [u8]> {
+        n.find(key, &Bytewise)
+            .unwrap()
+            .values_iter(val_format)
+            .collect()
+    }
 }
@@ -1,56 +1,78 @@
-use std::alloc::{alloc_zeroed, Layout};
+use std::alloc::{alloc_zeroed, dealloc, Layout};
 use std::cell::UnsafeCell;
 use std::collections::LinkedList;
 use std::convert::TryFrom;
 use std::mem::{align_of, size_of};
 use std::num::NonZeroU64;
-use std::slice;
+use std::ptr;
 use std::sync::{Arc, Mutex};
 
 use crate::util::{round_down, round_to};
 
-// Since we are not mapping to disk pages, we can use fairly large page sizes.
-// We should to some perf tests to determine a good page size in the general case.
+// Pages are also the unit of I/O for disk-backed trees, where page N of a node is stored at
+// offset N * PAGE_SIZE of the file. We should to some perf tests to determine a good page
+// size in the general case.
 pub(crate) const PAGE_SIZE: usize = 1024 * 16;
 pub(crate) const ALIGNMENT: usize = align_of::<Header>();
 
+fn page_layout() -> Layout {
+    Layout::from_size_align(PAGE_SIZE, ALIGNMENT).unwrap()
+}
+
 fn uninitialized_page() -> Page {
-    let layout = Layout::from_size_align(PAGE_SIZE, ALIGNMENT).unwrap();
     unsafe {
-        let ptr = alloc_zeroed(layout);
+        let ptr = alloc_zeroed(page_layout());
         let cell_ptr = fatten(ptr, PAGE_SIZE);
         Page { ptr: cell_ptr }
     }
 }
 
 /// <https://users.rust-lang.org/t/construct-fat-pointer-to-struct/29198/9>
-/// Borrowed from [sled](https://github.com/spacejam/sled/blob/main/src/node.rs#L1148).
+/// Adapted from [sled](https://github.com/spacejam/sled/blob/main/src/node.rs#L1148). The
+/// pointer is built without going through a reference, which would be zero-sized and so
+/// leave it without permission to access the allocation.
 #[allow(trivial_casts)]
 fn fatten(data: *mut u8, len: usize) -> *mut UnsafeCell<[u8]> {
-    // Requirements of slice::from_raw_parts.
     assert!(!data.is_null());
     assert!(isize::try_from(len).is_ok());
 
-    let slice = unsafe { slice::from_raw_parts(data as *const (), len) };
-    slice as *const [()] as *mut _
+    ptr::slice_from_raw_parts_mut(data, len) as *mut UnsafeCell<[u8]>
 }
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct Header {
-    // TODO: Should the links be kept in the pages or in the nodes?
+    // The page number of the next page in a chain, which is also its offset in the file
+    // divided by PAGE_SIZE for a disk-backed tree.
     pub next: Option<NonZeroU64>,
+    // The log sequence number of the last logged change to the page, for a disk-backed tree.
+    // The page may only be written to disk once the log is on disk up to this point.
+    pub(crate) lsn: u64,
     pub(super) free_start: u16,
     // Marks the spot after the last free index.
     pub(super) free_end: u16,
+    // What the page holds, so that it can be interpreted when it is read back from disk.
+    // Only kept up to date when the page is written out.
+    pub(crate) kind: u8,
 }
 
 impl Header {
     pub(crate) fn reset(&mut self) {
         let start = size_of::<Header>();
         self.next = None;
+        self.lsn = 0;
         self.free_start = start as u16;
         self.free_end = PAGE_SIZE as u16;
+        self.kind = 0;
+    }
+
+    /// Whether the free space markers are within the page, and in order. Pages read from a
+    /// file are checked before their contents are trusted.
+    pub(crate) fn is_valid(&self) -> bool {
+        let start = size_of::<Header>();
+        start <= self.free_start as usize
+            && self.free_start <= self.free_end
+            && self.free_end as usize <= PAGE_SIZE
     }
 
     #[inline]
@@ -74,11 +96,19 @@ pub struct Page {
     ptr: *mut UnsafeCell<[u8]>,
 }
 
+// SAFETY: A page is the only owner of its allocation, so it can be moved to another thread
+// like a Box<[u8]>.
+unsafe impl Send for Page {}
+
+// SAFETY: The contents of a page are only modified through a mutable reference, so it can
+// be shared between threads like a Box<[u8]>.
+unsafe impl Sync for Page {}
+
 impl Page {
     pub fn new(next: Option<::std::num::NonZeroU64>) -> Page {
         let mut inner = uninitialized_page();
         // Get "zeroed" header from fresh page, then set fields manually.
-        let mut header = inner.header_mut();
+        let header = inner.header_mut();
         header.reset();
         header.next = next;
 
@@ -119,11 +149,10 @@ impl Page {
         } else {
             None
         }
-
     }
 
     pub(crate) unsafe fn alloc_start_unchecked(&mut self, layout: Layout) -> Allocation {
-        let mut header = self.header_mut();
+        let header = self.header_mut();
         let start = round_to(header.free_start as usize, layout.align()) as u16;
         header.free_start = start + layout.size() as u16;
 
@@ -140,13 +169,13 @@ impl Page {
     /// invalid.
     pub(crate) unsafe fn shift_start(&mut self, offset: usize, len: usize) -> Option<()> {
         let self_ptr = self.ptr as *const u8;
-        let mut header = self.header_mut();
+        let header = self.header_mut();
         if (header.free_len() as usize) < len {
             return None;
         }
 
-        let src = self_ptr.offset(offset as isize);
-        let dst = src.offset(len as isize) as *mut u8;
+        let src = self_ptr.add(offset);
+        let dst = src.add(len) as *mut u8;
         let count = (header.free_start as usize) - offset;
         std::ptr::copy(src, dst, count);
 
@@ -156,6 +185,22 @@ impl Page {
         Some(())
     }
 
+    /// Removes `len` bytes at `offset`, shifting the bytes after them to the left. This is
+    /// the inverse of `shift_start`, and has the same safety requirements.
+    pub(crate) unsafe fn unshift_start(&mut self, offset: usize, len: usize) {
+        let self_ptr = self.ptr as *mut u8;
+        let header = self.header_mut();
+        debug_assert!(offset + len <= header.free_start as usize);
+
+        let dst = self_ptr.add(offset);
+        let src = dst.add(len) as *const u8;
+        let count = (header.free_start as usize) - offset - len;
+        std::ptr::copy(src, dst, count);
+
+        // Shift free start marker to the left.
+        header.free_start -= len as u16;
+    }
+
     pub(crate) fn alloc_end(&mut self, layout: Layout) -> Option<Allocation> {
         if self.can_fit(layout) {
             unsafe { Some(self.alloc_end_unchecked(layout)) }
@@ -165,7 +210,7 @@ impl Page {
     }
 
     pub(crate) unsafe fn alloc_end_unchecked(&mut self, layout: Layout) -> Allocation {
-        let mut header = self.header_mut();
+        let header = self.header_mut();
         let start = round_down(header.free_end as usize - layout.size(), layout.align()) as u16;
         header.free_end = start;
 
@@ -185,34 +230,40 @@ impl Page {
     }
 
     #[inline]
-    pub(crate) fn buf(&self) -> &[u8] {
-        unsafe { &*(*self.ptr).get() }
+    pub(crate) unsafe fn offset_ptr_unchecked(&self, start: usize, len: usize) -> *const [u8] {
+        ptr::slice_from_raw_parts((self.ptr as *const u8).add(start), len)
     }
 
     #[inline]
-    pub(crate) fn buf_mut(&mut self) -> &mut [u8] {
-        unsafe { &mut *(*self.ptr).get() }
+    pub(crate) unsafe fn offset_ptr_unchecked_mut(
+        &mut self,
+        start: usize,
+        len: usize,
+    ) -> *mut [u8] {
+        ptr::slice_from_raw_parts_mut((self.ptr as *mut u8).add(start), len)
     }
 
-    #[inline]
-    pub(crate) fn buf_ptr(&mut self) -> *mut [u8] {
-        unsafe { (*self.ptr).get() }
+    pub(crate) fn header_mut(&mut self) -> &mut Header {
+        unsafe { &mut *(self.ptr as *mut Header) }
     }
 
-    #[inline]
-    pub(crate) unsafe fn offset_ptr_unchecked(&self, start: usize, len: usize) -> *const [u8] {
-        let ptr = self.buf().as_ptr().offset(start as isize);
-        slice::from_raw_parts(ptr as *const u8, len) as *const [u8]
+    /// The whole page, header included, as it is stored on disk.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        unsafe { &*(self.ptr as *const [u8]) }
     }
 
-    #[inline]
-    pub(crate) unsafe fn offset_ptr_unchecked_mut(&mut self, start: usize, len: usize) -> *mut [u8] {
-        let ptr = self.buf_mut().as_mut_ptr().offset(start as isize);
-        slice::from_raw_parts_mut(ptr as *mut u8, len) as *mut [u8]
+    /// The whole page as a buffer to read it from disk into. Any bytes may be written, so
+    /// the header must be checked with `Header::is_valid` before the page is used.
+    pub(crate) fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { &mut *(self.ptr as *mut [u8]) }
     }
+}
 
-    pub(crate) fn header_mut(&mut self) -> &mut Header {
-        unsafe { &mut *(self.ptr as *mut Header) }
+impl Drop for Page {
+    fn drop(&mut self) {
+        // SAFETY: The page was allocated with the same layout, and no pointers into it
+        // outlive the page.
+        unsafe { dealloc(self.ptr as *mut u8, page_layout()) };
     }
 }
 
@@ -221,6 +272,12 @@ pub struct Pool {
     pages: Arc<Mutex<LinkedList<Page>>>,
 }
 
+impl Default for Pool {
+    fn default() -> Pool {
+        Pool::new()
+    }
+}
+
 impl Pool {
     pub fn new() -> Pool {
         Pool {
@@ -233,6 +290,11 @@ impl Pool {
         pages.pop_front().unwrap_or_else(|| Page::new(None))
     }
 
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.pages.lock().unwrap().len()
+    }
+
     pub(crate) fn check_in(&self, mut page: Page) {
         page.reset();
         let mut pages = self.pages.lock().unwrap();
@@ -0,0 +1,250 @@
+use std::alloc::Layout;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::num::NonZeroU64;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::entry::ValueFormat;
+use crate::page::{Page, PAGE_SIZE};
+
+const MAGIC: &[u8; 8] = b"btree\0\0\x02";
+// Written in native byte order, so that a file written on a machine with a different byte
+// order, whose node pages can not be read as is, is rejected.
+const BYTE_ORDER_MARK: u32 = 0x0102_0304;
+
+/// The first page of a tree's file, which describes the rest of it. Node pages follow it,
+/// so page N starts at offset N * PAGE_SIZE.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct Meta {
+    pub(crate) root: NonZeroU64,
+    // The number of node pages in the file, whether they are in use or free.
+    pub(crate) page_count: u64,
+    // The first page of the free list, which is linked through the page headers.
+    pub(crate) free_head: Option<NonZeroU64>,
+    pub(crate) val_format: ValueFormat,
+    // The LSN of the last change to the tree at the time that the file was flushed.
+    pub(crate) lsn: u64,
+}
+
+// Fields of the meta page, in order, after the magic number and byte order mark.
+const META_FIELDS: usize = 7;
+const META_LEN: usize = MAGIC.len() + 4 + META_FIELDS * 8;
+
+impl Meta {
+    fn encode(&self) -> [u8; META_LEN] {
+        // Variable values are recorded with an alignment of 0, which no Layout has.
+        let (val_size, val_align) = match self.val_format {
+            ValueFormat::Fixed(layout) => (layout.size() as u64, layout.align() as u64),
+            ValueFormat::Variable => (0, 0),
+        };
+        let mut buf = [0; META_LEN];
+        buf[..8].copy_from_slice(MAGIC);
+        buf[8..12].copy_from_slice(&BYTE_ORDER_MARK.to_ne_bytes());
+        let fields = [
+            PAGE_SIZE as u64,
+            self.root.get(),
+            self.page_count,
+            self.free_head.map_or(0, NonZeroU64::get),
+            val_size,
+            val_align,
+            self.lsn,
+        ];
+        for (chunk, field) in buf[12..].chunks_exact_mut(8).zip(&fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8; META_LEN]) -> io::Result<Meta> {
+        if &buf[..8] != MAGIC {
+            return Err(invalid_data("Not a btree file"));
+        }
+        if buf[8..12] != BYTE_ORDER_MARK.to_ne_bytes() {
+            return Err(invalid_data("File was written with a different byte order"));
+        }
+        let mut fields = buf[12..]
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()));
+        let mut field = || fields.next().unwrap();
+
+        if field() != PAGE_SIZE as u64 {
+            return Err(invalid_data("File was written with a different page size"));
+        }
+        let root = NonZeroU64::new(field()).ok_or_else(|| invalid_data("Root page is 0"))?;
+        let page_count = field();
+        let free_head = NonZeroU64::new(field());
+        let val_format = match (field(), field()) {
+            (0, 0) => ValueFormat::Variable,
+            (size, align) => Layout::from_size_align(size as usize, align as usize)
+                .map(ValueFormat::Fixed)
+                .map_err(|_| invalid_data("Invalid value layout"))?,
+        };
+        let lsn = field();
+
+        let in_file = |id: NonZeroU64| id.get() <= page_count;
+        if !in_file(root) || !free_head.is_none_or(in_file) {
+            return Err(invalid_data("Page number is past the end of the file"));
+        }
+        Ok(Meta {
+            root,
+            page_count,
+            free_head,
+            val_format,
+            lsn,
+        })
+    }
+}
+
+/// Reads and writes whole pages of a tree's file by page number. Page 0 holds the Meta,
+/// and every other page holds a node or is on the free list. Caching pages in memory is
+/// up to the caller.
+pub(crate) struct Pager {
+    file: File,
+}
+
+impl Pager {
+    /// Opens the file at `path`, creating it if it does not exist.
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> io::Result<Pager> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Pager { file })
+    }
+
+    /// Returns None for a new, empty file.
+    pub(crate) fn read_meta(&self) -> io::Result<Option<Meta>> {
+        if self.file.metadata()?.len() == 0 {
+            return Ok(None);
+        }
+        let mut buf = [0; META_LEN];
+        self.file.read_exact_at(&mut buf, 0)?;
+        Meta::decode(&buf).map(Some)
+    }
+
+    pub(crate) fn write_meta(&self, meta: &Meta) -> io::Result<()> {
+        write_at(&self.file, &meta.encode(), 0)
+    }
+
+    /// Reads page `id` into `page`, and checks that its header is usable.
+    pub(crate) fn read_page(&self, id: NonZeroU64, page: &mut Page) -> io::Result<()> {
+        self.file.read_exact_at(page.as_bytes_mut(), offset(id))?;
+        if !page.header().is_valid() {
+            return Err(invalid_data(format!("Page {} has an invalid header", id)));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_page(&self, id: NonZeroU64, page: &Page) -> io::Result<()> {
+        write_at(&self.file, page.as_bytes(), offset(id))
+    }
+
+    /// Waits for every write so far to reach the disk.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// Writes all of `buf` at `offset` in `file`. Tests can simulate a crash at any write.
+pub(crate) fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    #[cfg(test)]
+    crate::test_util::crash::write(file, offset, offset + buf.len() as u64)?;
+    file.write_all_at(buf, offset)
+}
+
+/// Truncates or extends `file` to `len` bytes.
+pub(crate) fn set_len(file: &File, len: u64) -> io::Result<()> {
+    #[cfg(test)]
+    crate::test_util::crash::write(file, len, u64::MAX)?;
+    file.set_len(len)
+}
+
+fn offset(id: NonZeroU64) -> u64 {
+    id.get() * PAGE_SIZE as u64
+}
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TmpDir;
+
+    #[test]
+    fn test_meta_round_trip() {
+        let dir = TmpDir::new();
+        let pager = Pager::open(dir.as_ref().join("tree")).unwrap();
+        assert_eq!(None, pager.read_meta().unwrap());
+
+        let meta = Meta {
+            root: NonZeroU64::new(3).unwrap(),
+            page_count: 7,
+            free_head: NonZeroU64::new(5),
+            val_format: ValueFormat::Fixed(Layout::from_size_align(12, 4).unwrap()),
+            lsn: 42,
+        };
+        pager.write_meta(&meta).unwrap();
+        assert_eq!(Some(meta), pager.read_meta().unwrap());
+
+        let meta = Meta {
+            free_head: None,
+            val_format: ValueFormat::Variable,
+            ..meta
+        };
+        pager.write_meta(&meta).unwrap();
+        assert_eq!(Some(meta), pager.read_meta().unwrap());
+    }
+
+    #[test]
+    fn test_meta_rejects_garbage() {
+        let dir = TmpDir::new();
+        let path = dir.as_ref().join("tree");
+        std::fs::write(&path, vec![7; PAGE_SIZE]).unwrap();
+        let err = Pager::open(&path).unwrap().read_meta().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        let meta = Meta {
+            root: NonZeroU64::new(2).unwrap(),
+            page_count: 2,
+            free_head: None,
+            val_format: ValueFormat::Fixed(Layout::new::<u64>()),
+            lsn: 0,
+        };
+        let mut buf = meta.encode();
+        assert!(Meta::decode(&buf).is_ok());
+        buf[12] ^= 1;
+        assert!(Meta::decode(&buf).is_err());
+
+        let root = NonZeroU64::new(3).unwrap();
+        assert!(Meta::decode(&Meta { root, ..meta }.encode()).is_err());
+    }
+
+    #[test]
+    fn test_page_round_trip() {
+        let dir = TmpDir::new();
+        let pager = Pager::open(dir.as_ref().join("tree")).unwrap();
+        let id = NonZeroU64::new(2).unwrap();
+
+        let mut page = Page::new(NonZeroU64::new(1));
+        page.as_bytes_mut()[PAGE_SIZE - 1] = 42;
+        pager.write_page(id, &page).unwrap();
+
+        let mut read = Page::new(None);
+        pager.read_page(id, &mut read).unwrap();
+        assert_eq!(page.as_bytes(), read.as_bytes());
+        assert_eq!(NonZeroU64::new(1), read.header().next);
+
+        // Page 1 was never written, so it reads back as zeroes, which is not a valid header.
+        let err = pager.read_page(NonZeroU64::new(1).unwrap(), &mut read);
+        assert_eq!(io::ErrorKind::InvalidData, err.unwrap_err().kind());
+    }
+}
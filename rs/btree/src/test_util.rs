@@ -0,0 +1,119 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub(crate) struct TmpDir {
+    path: PathBuf,
+}
+
+impl TmpDir {
+    pub(crate) fn new() -> TmpDir {
+        let mut path = PathBuf::new();
+        path.push("target");
+        path.push("testdata");
+        path.push(format!("test-{:020}", rand::random::<u64>()));
+        fs::create_dir_all(&path).expect("could not create test data directory");
+
+        TmpDir { path }
+    }
+}
+
+impl Drop for TmpDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).expect("could not remove test data directory");
+    }
+}
+
+impl AsRef<Path> for TmpDir {
+    fn as_ref(&self) -> &Path {
+        self.path.as_ref()
+    }
+}
+
+/// Simulates crashes. The writes that a tree makes after the crash still happen, so that it
+/// carries on as usual, but `restart` rolls them back, which leaves its files the way they
+/// were when it crashed. Each test thread has its own crash.
+pub(crate) mod crash {
+    use std::cell::{Cell, RefCell};
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::fs::FileExt;
+
+    // How to roll back a write: the length of the file before it, and the bytes that it
+    // overwrote, along with their offset.
+    struct Undo {
+        file: File,
+        len: u64,
+        offset: u64,
+        bytes: Vec<u8>,
+    }
+
+    thread_local! {
+        static WRITES: Cell<usize> = const { Cell::new(0) };
+        static CRASH_AT: Cell<Option<(usize, bool)>> = const { Cell::new(None) };
+        static UNDO: RefCell<Vec<Undo>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Makes write number `write`, counting from 0, the first one that does not reach the
+    /// disk. Half of it does if `torn` is set, and none of the writes after it do.
+    pub(crate) fn crash_at(write: usize, torn: bool) {
+        WRITES.with(|w| w.set(0));
+        CRASH_AT.with(|c| c.set(Some((write, torn))));
+    }
+
+    /// Rolls back the writes made since the crash, as if the tree was restarted after it,
+    /// and lets writes reach the disk again.
+    pub(crate) fn restart() {
+        let undo = UNDO.with(|undo| undo.take());
+        for Undo {
+            file,
+            len,
+            offset,
+            bytes,
+        } in undo.into_iter().rev()
+        {
+            file.write_all_at(&bytes, offset).unwrap();
+            file.set_len(len).unwrap();
+        }
+        WRITES.with(|w| w.set(0));
+        CRASH_AT.with(|c| c.set(None));
+    }
+
+    /// Whether the write that crashes has been made.
+    pub(crate) fn has_crashed() -> bool {
+        let writes = WRITES.with(Cell::get);
+        CRASH_AT
+            .with(Cell::get)
+            .is_some_and(|(write, _)| writes > write)
+    }
+
+    /// The number of writes since the last `crash_at` or `restart`.
+    pub(crate) fn writes() -> usize {
+        WRITES.with(Cell::get)
+    }
+
+    /// Counts a write to bytes `offset..end` of `file`, and remembers how to roll it back
+    /// if it is made after the crash. Truncating the file counts as a write to everything
+    /// after the new end.
+    pub(crate) fn write(file: &File, offset: u64, end: u64) -> io::Result<()> {
+        let write = WRITES.with(|w| w.replace(w.get() + 1));
+        let start = match CRASH_AT.with(Cell::get) {
+            Some((at, true)) if at == write => offset + (end - offset) / 2,
+            Some((at, _)) if at <= write => offset,
+            _ => return Ok(()),
+        };
+
+        let len = file.metadata()?.len();
+        let mut bytes = vec![0; end.min(len).saturating_sub(start) as usize];
+        file.read_exact_at(&mut bytes, start)?;
+        let file = file.try_clone()?;
+        UNDO.with(|undo| {
+            undo.borrow_mut().push(Undo {
+                file,
+                len,
+                offset: start,
+                bytes,
+            })
+        });
+        Ok(())
+    }
+}
@@ -1,70 +1,1381 @@
-use std::{alloc::Layout, convert::TryInto, mem};
+use std::cmp::Ordering;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
 
 use crate::{
-    entry::ValuesIterator,
-    node::{InnerEntry, InnerNode, LeafNode, Node},
+    comparator::{Bytewise, KeyComparator},
+    entry::{ValueFormat, ValuesIterator},
+    node::{
+        check_entry_len, read_free_list, InnerEntry, LeafNode, Node, NodeId, NodeTable,
+        PageEntryIter, Rebalance, Split,
+    },
     page::Pool,
+    pager::Pager,
+    wal::{self, Record, Wal},
 };
 
-pub struct BTree<'a> {
-    root: Node<'a>,
-    val_layout: Layout,
+/// A B+ tree that maps keys to one or more values. Values either all have the same fixed
+/// layout, or are variable-size byte strings, as set by the tree's ValueFormat. Keys are
+/// ordered by a KeyComparator, which is bytewise by default.
+///
+/// A tree either lives in memory, or is backed by a file that it pages nodes in from as
+/// they are needed. Every update to a disk-backed tree is written to a write-ahead log next
+/// to the file, and is recovered from there when the tree is opened after a crash. Updates
+/// are on disk once the tree is flushed, which also happens when it is dropped. A
+/// disk-backed tree panics if it can not read a node while serving a lookup or an update,
+/// or can not log an update.
+///
+/// Updates take a mutable reference. A tree in memory that many threads read and update at
+/// once is a [`ConcurrentBTree`](crate::concurrent::ConcurrentBTree).
+pub struct BTree<'a, C = Bytewise> {
+    root: NodeId,
+    nodes: NodeTable<'a>,
+    val_format: ValueFormat,
     pool: &'a Pool,
+    cmp: C,
 }
 
 impl<'a> BTree<'a> {
-    pub fn new(val_layout: Layout, pool: &'a Pool) -> BTree<'a> {
+    pub fn new(val_format: impl Into<ValueFormat>, pool: &'a Pool) -> BTree<'a> {
+        BTree::with_comparator(val_format, pool, Bytewise)
+    }
+
+    /// Opens the tree stored in the file at `path`, or creates an empty one there if the
+    /// file does not exist. An existing tree must have been created with `val_format`. Its
+    /// log is kept at `path` with `-wal` appended, and any updates in it that did not make
+    /// it to the file before a crash are recovered.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        val_format: impl Into<ValueFormat>,
+        pool: &'a Pool,
+    ) -> io::Result<BTree<'a>> {
+        BTree::open_with_comparator(path, val_format, pool, Bytewise)
+    }
+
+    /// Returns an iterator over the keys that start with `prefix`, in order, along with
+    /// their values. Only bytewise order keeps such keys next to each other.
+    pub fn prefix(&self, prefix: &[u8]) -> Range<'_, 'a> {
+        let end = match prefix_successor(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.range((Bound::Included(prefix.to_vec()), end))
+    }
+}
+
+impl<'a, C: KeyComparator> BTree<'a, C> {
+    pub fn with_comparator(
+        val_format: impl Into<ValueFormat>,
+        pool: &'a Pool,
+        cmp: C,
+    ) -> BTree<'a, C> {
+        let val_format = val_format.into();
+        let mut nodes = NodeTable::new();
+        let root = nodes.insert(Node::new_leaf(pool));
         BTree {
-            root: Node::new_leaf(pool),
-            val_layout,
+            root,
+            nodes,
+            val_format,
             pool,
+            cmp,
         }
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<ValuesIterator> {
-        match &self.root {
-            Node::LeafNode(n) => n.find(key).map(|entry| entry.values_iter(self.val_layout)),
-            Node::InnerNode(n) => {
-                unimplemented!("TODO: Implement get over Node::InnerNode");
+    /// Like [`BTree::open`], but with keys ordered by `cmp`. It must order them the same way
+    /// as the comparator that the tree was created with.
+    pub fn open_with_comparator<P: AsRef<Path>>(
+        path: P,
+        val_format: impl Into<ValueFormat>,
+        pool: &'a Pool,
+        cmp: C,
+    ) -> io::Result<BTree<'a, C>> {
+        let val_format = val_format.into();
+        let pager = Pager::open(&path)?;
+        let meta = pager.read_meta()?;
+        if let Some(meta) = meta.filter(|meta| meta.val_format != val_format) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Tree was created with values of {:?}", meta.val_format),
+            ));
+        }
+        let lsn = meta.map_or(0, |meta| meta.lsn);
+        let (wal, recovery) = Wal::open(wal::path(path.as_ref()), lsn)?;
+
+        let meta = match meta {
+            Some(meta) => meta,
+            None => {
+                let mut nodes = NodeTable::open(pager, wal, pool, 0, Vec::new())?;
+                let root = nodes.insert(Node::new_leaf(pool));
+                let mut tree = BTree {
+                    root,
+                    nodes,
+                    val_format,
+                    pool,
+                    cmp,
+                };
+                // Write out the empty tree, so that the file can be opened again.
+                tree.commit();
+                tree.flush()?;
+                return Ok(tree);
+            }
+        };
+
+        // Updates that were logged after the file was last flushed are recovered on top of
+        // the tree as of the checkpoint at the start of the log. The free list in the file
+        // may have been overwritten by then, but the checkpoint has a copy of it.
+        let unflushed = recovery
+            .groups
+            .last()
+            .and_then(|group| group.last())
+            .is_some_and(|&(lsn, _)| lsn > meta.lsn);
+        let (root, page_count, free) = match recovery.checkpoint {
+            Some((
+                _,
+                Record::Checkpoint {
+                    root,
+                    page_count,
+                    free,
+                },
+            )) if unflushed => (root, page_count, free),
+            _ => {
+                let free = read_free_list(&pager, pool, meta.page_count, meta.free_head)?;
+                (meta.root.into(), meta.page_count, free)
             }
+        };
+        let mut tree = BTree {
+            root,
+            nodes: NodeTable::open(pager, wal, pool, page_count, free)?,
+            val_format,
+            pool,
+            cmp,
+        };
+        let logged = !recovery.groups.is_empty();
+        if unflushed {
+            tree.replay(recovery.groups)?;
         }
+        if logged {
+            // Start the log over, so that nothing in it needs to be recovered again.
+            tree.flush()?;
+        }
+        Ok(tree)
+    }
+
+    /// Writes every change to a disk-backed tree to its file, and waits for them to reach
+    /// the disk. Does nothing for a tree in memory.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.nodes.flush(self.root, self.val_format)?;
+        self.nodes.evict();
+        Ok(())
     }
 
-    pub fn insert(&mut self, key: &[u8], val: &[u8]) {
-        match &mut self.root {
+    /// Sets the number of nodes that a disk-backed tree keeps in memory. Lookups may read in
+    /// more than that, and the excess is written back and evicted by the next update or
+    /// flush. Does nothing for a tree in memory.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.nodes.set_capacity(capacity);
+        self.nodes.evict();
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<ValuesIterator<'_>> {
+        self.leaf(self.find_leaf(Some(key)))
+            .find(key, &self.cmp)
+            .map(|entry| entry.values_iter(self.val_format))
+    }
+
+    /// Returns an iterator over every key in the tree, in order, along with its values.
+    pub fn scan(&self) -> Range<'_, 'a, C> {
+        self.range::<&[u8], _>(..)
+    }
+
+    /// Returns an iterator over the keys within `range`, in order, along with their values.
+    pub fn range<K, R>(&self, range: R) -> Range<'_, 'a, C>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let start = range.start_bound().map(|k| k.as_ref().to_vec());
+        let end = range.end_bound().map(|k| k.as_ref().to_vec());
+        let leaf = match &start {
+            Bound::Included(k) | Bound::Excluded(k) => self.find_leaf(Some(k)),
+            Bound::Unbounded => self.find_leaf(None),
+        };
+        Range {
+            tree: self,
+            entries: Some(self.leaf(leaf).scan()),
+            next_leaf: self.leaf(leaf).next(),
+            start,
+            end,
+        }
+    }
+
+    /// Adds `val` to the values of `key`. Fails with InvalidInput, and leaves the tree as it
+    /// was, if the key and all of its values would no longer fit in half a page, or if `val`
+    /// is not the size of a Fixed value.
+    pub fn insert(&mut self, key: &[u8], val: &[u8]) -> io::Result<()> {
+        self.val_format.check(val)?;
+        let leaf = self.leaf(self.find_leaf(Some(key)));
+        check_entry_len(leaf.entry_len_with(self.val_format, key, val, &self.cmp))?;
+        self.nodes.evict();
+        self.apply_insert(key, val);
+        self.commit();
+        Ok(())
+    }
+
+    /// Removes `key` and all of its values. Returns whether the key was found.
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        self.nodes.evict();
+        let removed = self.apply_remove(key);
+        self.commit();
+        removed
+    }
+
+    /// Removes one value of `key` that equals `val`, and the key itself if that was its
+    /// last value. Returns whether the value was found.
+    pub fn remove_value(&mut self, key: &[u8], val: &[u8]) -> bool {
+        self.nodes.evict();
+        let removed = self.apply_remove_value(key, val);
+        self.commit();
+        removed
+    }
+
+    fn apply_insert(&mut self, key: &[u8], val: &[u8]) {
+        let (mut path, id) = self.path_to_leaf(key);
+        let leaf = self.nodes[id].as_leaf_mut();
+        let Split { mut pivot, right } =
+            match leaf.insert_or_split(self.val_format, key, val, &self.cmp) {
+                Some(split) => split,
+                None => {
+                    self.nodes.describe(id, || Record::Insert {
+                        id,
+                        key: key.to_vec(),
+                        val: val.to_vec(),
+                    });
+                    return;
+                }
+            };
+
+        // Link the new leaf in between the split leaf and its old successor.
+        let mut right = self.nodes.insert(right);
+        let next = self.nodes[id].as_leaf().next();
+        self.nodes[right].as_leaf_mut().set_next(next);
+        self.nodes[id].as_leaf_mut().set_next(Some(right));
+        self.nodes.describe(id, || Record::Split {
+            id,
+            key: key.to_vec(),
+            val: val.to_vec(),
+            right,
+        });
+
+        while let Some((parent, idx)) = path.pop() {
+            match self.nodes[parent]
+                .as_inner_mut()
+                .insert_or_split(idx, pivot, right)
+            {
+                Some(split) => {
+                    pivot = split.pivot;
+                    right = self.nodes.insert(split.right);
+                }
+                None => return,
+            }
+        }
+
+        // When the root splits, the tree grows by one level: the new root's only entry
+        // separates the old root from its new sibling.
+        let mut root = Node::new_inner(self.pool);
+        root.as_inner_mut()
+            .insert_entry(InnerEntry {
+                left: self.root,
+                right,
+                key: pivot,
+            })
+            .expect("Inner node must have capacity for entries after split");
+        self.root = self.nodes.insert(root);
+    }
+
+    fn apply_remove(&mut self, key: &[u8]) -> bool {
+        let (path, id) = self.path_to_leaf(key);
+        let removed = self.nodes[id].as_leaf_mut().remove(key, &self.cmp);
+        self.nodes.describe(id, || Record::Remove {
+            id,
+            key: key.to_vec(),
+        });
+        if removed {
+            self.rebalance(path, id);
+        }
+        removed
+    }
+
+    fn apply_remove_value(&mut self, key: &[u8], val: &[u8]) -> bool {
+        let (path, id) = self.path_to_leaf(key);
+        let leaf = self.nodes[id].as_leaf_mut();
+        let removed = leaf.remove_value(self.val_format, key, val, &self.cmp);
+        self.nodes.describe(id, || Record::RemoveValue {
+            id,
+            key: key.to_vec(),
+            val: val.to_vec(),
+        });
+        if removed {
+            self.rebalance(path, id);
+        }
+        removed
+    }
+
+    /// Logs the changes made by the current update.
+    fn commit(&mut self) {
+        if let Err(err) = self.nodes.commit(self.root) {
+            panic!("Failed to log update: {}", err);
+        }
+    }
+
+    /// Applies the updates in `groups`, which were recovered from the log, to the tree as
+    /// of the checkpoint at the start of the log. Leaf changes are made again in the same
+    /// way that they were made the first time, which gives the same result.
+    fn replay(&mut self, groups: Vec<Vec<(u64, Record)>>) -> io::Result<()> {
+        let (val_format, cmp) = (self.val_format, &self.cmp);
+        for (lsn, record) in groups.into_iter().flatten() {
+            let matches = match record {
+                Record::Image { id, page } => self.nodes.restore(id, &page).map(|_| true)?,
+                Record::Insert { id, key, val } => self
+                    .nodes
+                    .redo(id, lsn, |node| {
+                        let leaf = node.as_leaf_mut();
+                        leaf.insert_or_split(val_format, &key, &val, cmp).is_none()
+                    })
+                    .unwrap_or(true),
+                Record::Split {
+                    id,
+                    key,
+                    val,
+                    right,
+                } => self
+                    .nodes
+                    .redo(id, lsn, |node| {
+                        let leaf = node.as_leaf_mut();
+                        let split = leaf.insert_or_split(val_format, &key, &val, cmp);
+                        leaf.set_next(Some(right));
+                        split.is_some()
+                    })
+                    .unwrap_or(true),
+                Record::Remove { id, key } => {
+                    self.nodes
+                        .redo(id, lsn, |node| node.as_leaf_mut().remove(&key, cmp));
+                    true
+                }
+                Record::RemoveValue { id, key, val } => {
+                    self.nodes.redo(id, lsn, |node| {
+                        node.as_leaf_mut().remove_value(val_format, &key, &val, cmp)
+                    });
+                    true
+                }
+                Record::Free { id } => {
+                    self.nodes.remove(id);
+                    true
+                }
+                Record::Commit { root } => {
+                    self.root = root;
+                    true
+                }
+                Record::Checkpoint { .. } => false,
+            };
+            if !matches {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Record {} of the log does not match the tree", lsn),
+                ));
+            }
+        }
+        self.nodes.forget_changes();
+        Ok(())
+    }
+
+    /// Returns the leaf whose key range contains `key`, along with the path of inner nodes
+    /// leading to it and the index of the child taken at each of them.
+    fn path_to_leaf(&self, key: &[u8]) -> (Vec<(NodeId, usize)>, NodeId) {
+        let mut path = Vec::new();
+        let mut id = self.root;
+        while let Node::InnerNode(n) = &self.nodes[id] {
+            let idx = n.child_index(key, &self.cmp);
+            path.push((id, idx));
+            id = n.child(idx);
+        }
+        (path, id)
+    }
+
+    /// Restores the fill of node `id` and its ancestors on `path` after a removal. An
+    /// underfull node is merged with a sibling if they fit in one page, and otherwise
+    /// takes over some of its sibling's entries. Merges remove a child from the parent,
+    /// which may leave it underfull in turn.
+    fn rebalance(&mut self, mut path: Vec<(NodeId, usize)>, mut id: NodeId) {
+        while let Some((parent, idx)) = path.pop() {
+            if !self.nodes[id].is_underfull() {
+                return;
+            }
+
+            // Pair the node with its right sibling, or its left one if it is the last child.
+            let parent_node = self.nodes[parent].as_inner();
+            let left_idx = if idx + 1 < parent_node.child_count() {
+                idx
+            } else {
+                idx - 1
+            };
+            let left = parent_node.child(left_idx);
+            let right = parent_node.child(left_idx + 1);
+            let pivot = parent_node.key(left_idx).to_vec();
+            let max_pivot_len = parent_node.max_key_len(left_idx);
+
+            let result = match self.nodes.pair_mut(left, right) {
+                (Node::LeafNode(l), Node::LeafNode(r)) => {
+                    l.merge_or_rebalance(r, self.val_format, max_pivot_len)
+                }
+                (Node::InnerNode(l), Node::InnerNode(r)) => {
+                    l.merge_or_rebalance(&pivot, r, max_pivot_len)
+                }
+                _ => panic!("Siblings must be on the same level"),
+            };
+            let parent_node = self.nodes[parent].as_inner_mut();
+            match result {
+                Rebalance::Merged => {
+                    parent_node.remove_child(left_idx + 1);
+                    self.nodes.remove(right);
+                }
+                Rebalance::Rebalanced(pivot) => {
+                    parent_node
+                        .set_key(left_idx, pivot)
+                        .expect("Rebalancing must pick a pivot that fits in the parent");
+                    return;
+                }
+                Rebalance::Unchanged => return,
+            }
+            id = parent;
+        }
+
+        // When the root is left with a single child, the tree shrinks by one level.
+        while let Node::InnerNode(n) = &self.nodes[self.root] {
+            if n.child_count() > 1 {
+                break;
+            }
+            let child = n.child(0);
+            self.nodes.remove(self.root);
+            self.root = child;
+        }
+    }
+
+    /// Returns the leaf whose key range contains `key`, or the leftmost leaf for None.
+    fn find_leaf(&self, key: Option<&[u8]>) -> NodeId {
+        let mut id = self.root;
+        while let Node::InnerNode(n) = &self.nodes[id] {
+            id = match key {
+                Some(key) => n.child(n.child_index(key, &self.cmp)),
+                None => n.child(0),
+            };
+        }
+        id
+    }
+
+    fn leaf(&self, id: NodeId) -> &LeafNode<'a> {
+        self.nodes[id].as_leaf()
+    }
+}
+
+impl<'a, C> Drop for BTree<'a, C> {
+    fn drop(&mut self) {
+        // Errors can not be reported from here. Callers that need to know whether their
+        // changes were saved should flush first.
+        let _ = self.nodes.flush(self.root, self.val_format);
+    }
+}
+
+/// Returns the smallest key that is greater than every key starting with `prefix`, or None
+/// if there is no such key because the prefix consists only of 0xff bytes.
+pub(crate) fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// An ordered iterator over the keys of a BTree and their values, created by
+/// [`BTree::scan`], [`BTree::range`] and [`BTree::prefix`]. It walks the leaves from left
+/// to right through the `next` links in their page headers.
+pub struct Range<'t, 'a, C = Bytewise> {
+    tree: &'t BTree<'a, C>,
+    // None once the end of the range has been reached.
+    entries: Option<PageEntryIter<'t>>,
+    next_leaf: Option<NodeId>,
+    // Only needs to be checked until the first key in range is found.
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl<'t, 'a, C: KeyComparator> Iterator for Range<'t, 'a, C> {
+    type Item = (&'t [u8], ValuesIterator<'t>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.entries.as_mut()?.next() {
+                Some((_, entry)) => unsafe { &*entry },
+                None => {
+                    // Move on to the next leaf, if there is one.
+                    self.entries = self.next_leaf.map(|id| self.tree.leaf(id).scan());
+                    self.next_leaf = self.next_leaf.and_then(|id| self.tree.leaf(id).next());
+                    continue;
+                }
+            };
+
+            let key = entry.key();
+            let cmp = &self.tree.cmp;
+            let after_start = match &self.start {
+                Bound::Included(start) => cmp.compare(key, start) != Ordering::Less,
+                Bound::Excluded(start) => cmp.compare(key, start) == Ordering::Greater,
+                Bound::Unbounded => true,
+            };
+            if !after_start {
+                continue;
+            }
+            self.start = Bound::Unbounded;
+
+            let before_end = match &self.end {
+                Bound::Included(end) => cmp.compare(key, end) != Ordering::Greater,
+                Bound::Excluded(end) => cmp.compare(key, end) == Ordering::Less,
+                Bound::Unbounded => true,
+            };
+            if !before_end {
+                self.entries = None;
+                return None;
+            }
+            return Some((key, entry.values_iter(self.tree.val_format)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Layout;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::convert::TryInto;
+    use std::fs;
+
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    use super::*;
+    use crate::node::{entry_size, MAX_ENTRY_LEN};
+    use crate::test_util::{crash, TmpDir};
+
+    fn height<C: KeyComparator>(tree: &BTree<C>) -> usize {
+        let mut height = 1;
+        let mut id = tree.root;
+        while let Node::InnerNode(n) = &tree.nodes[id] {
+            height += 1;
+            id = n.child(0);
+        }
+        height
+    }
+
+    /// Checks that every key lies between the pivots on its path from the root, that all
+    /// leaves are at the same depth, and that the leaf links visit every leaf in order.
+    fn check_invariants<C: KeyComparator>(tree: &BTree<C>) {
+        let mut leaves = Vec::new();
+        check_node(tree, tree.root, None, None, 1, &mut leaves);
+        assert!(leaves.iter().all(|&(_, depth)| depth == height(tree)));
+
+        let mut linked = Vec::new();
+        let mut next = Some(leaves[0].0);
+        while let Some(id) = next {
+            linked.push(id);
+            next = tree.leaf(id).next();
+        }
+        assert_eq!(leaves.iter().map(|&(id, _)| id).collect::<Vec<_>>(), linked);
+    }
+
+    fn check_node<C: KeyComparator>(
+        tree: &BTree<C>,
+        id: NodeId,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+        depth: usize,
+        leaves: &mut Vec<(NodeId, usize)>,
+    ) {
+        let less = |a: &[u8], b: &[u8]| tree.cmp.compare(a, b) == Ordering::Less;
+        let in_bounds = |key: &[u8]| {
+            lower.is_none_or(|lower| !less(key, lower))
+                && upper.is_none_or(|upper| less(key, upper))
+        };
+        match &tree.nodes[id] {
             Node::LeafNode(n) => {
-                match n.insert(self.val_layout, key, val) {
-                    Some(_) => {}
-                    None => {
-                        let old_root: LeafNode =
-                            mem::replace(&mut self.root, Node::new_inner(self.pool))
-                                .try_into()
-                                .unwrap();
-                        let (left, right) = old_root.split(self.val_layout);
-                        // TODO: Is this the correct behaviour if the left node only has the capacity for a single entry?
-                        let pivot = right
-                            .scan()
-                            .next()
-                            .map(|(_, ptr)| {
-                                let entry = unsafe { &*ptr };
-                                entry.key().to_vec()
-                            })
-                            .unwrap_or_else(|| key.to_vec());
-
-                        self.root
-                            .as_inner_mut()
-                            .insert_entry(InnerEntry {
-                                left: &left as *const LeafNode as *const u8,
-                                right: &right as *const LeafNode as *const u8,
-                                key: pivot,
-                            })
-                            .expect("Inner node must have capacity for entries after split");
+                assert!(n
+                    .scan()
+                    .all(|(_, entry)| in_bounds(unsafe { &*entry }.key())));
+                leaves.push((id, depth));
+            }
+            Node::InnerNode(n) => {
+                let count = n.child_count();
+                for i in 0..count {
+                    let lower = if i == 0 { lower } else { Some(n.key(i - 1)) };
+                    let upper = if i + 1 == count {
+                        upper
+                    } else {
+                        Some(n.key(i))
+                    };
+                    assert!(lower.is_none_or(in_bounds));
+                    assert!(lower.zip(upper).is_none_or(|(l, u)| less(l, u)));
+                    check_node(tree, n.child(i), lower, upper, depth + 1, leaves);
+                }
+            }
+        }
+    }
+
+    fn get_u64s<C: KeyComparator>(tree: &BTree<C>, key: &[u8]) -> Vec<u64> {
+        tree.get(key)
+            .unwrap()
+            .map(|v| u64::from_le_bytes(v.try_into().unwrap()))
+            .collect()
+    }
+
+    // The tests with tens of thousands of keys are too slow to run under Miri. The large key
+    // tests exercise the same leaf, inner node and root splits with only a few hundred keys.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_insert_and_get() {
+        let pool = Pool::new();
+        let val_layout = Layout::from_size_align(8, 8).unwrap();
+        let mut tree = BTree::new(val_layout, &pool);
+        let mut expected = BTreeMap::<Vec<u8>, Vec<u64>>::new();
+        let mut keys = Vec::<Vec<u8>>::new();
+        let mut rng = StdRng::seed_from_u64(41);
+
+        // Long keys make nodes split often enough to grow the tree past two levels. Every
+        // tenth insert adds another value to an existing key.
+        for i in 0..40_000u64 {
+            let key = if i % 10 == 9 {
+                keys[rng.gen_range(0, keys.len())].clone()
+            } else {
+                let key = (0..rng.gen_range(16, 120))
+                    .map(|_| rng.gen::<u8>())
+                    .collect::<Vec<_>>();
+                keys.push(key.clone());
+                key
+            };
+            tree.insert(&key, &i.to_le_bytes()).unwrap();
+            expected.entry(key).or_default().push(i);
+        }
+        assert!(height(&tree) > 2);
+
+        for (key, vals) in &expected {
+            assert_eq!(vals, &get_u64s(&tree, key));
+        }
+        for _ in 0..1000 {
+            let key = (0..rng.gen_range(0, 120))
+                .map(|_| rng.gen::<u8>())
+                .collect::<Vec<_>>();
+            assert_eq!(expected.contains_key(&key), tree.get(&key).is_some());
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_sequential_inserts() {
+        let pool = Pool::new();
+        let val_layout = Layout::from_size_align(8, 8).unwrap();
+        let mut tree = BTree::new(val_layout, &pool);
+        for i in (0..20_000u64).chain((20_000..40_000).rev()) {
+            tree.insert(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        for i in 0..40_000u64 {
+            let vals = tree.get(&i.to_be_bytes()).unwrap().collect::<Vec<_>>();
+            assert_eq!(vec![&i.to_le_bytes()[..]], vals);
+        }
+        assert!(tree.get(&40_000u64.to_be_bytes()).is_none());
+    }
+
+    fn large_key(i: u32) -> Vec<u8> {
+        let mut key = vec![i as u8; 5000];
+        key[..4].copy_from_slice(&i.to_be_bytes());
+        key
+    }
+
+    fn build_tree<'a>(pool: &'a Pool, keys: &[Vec<u8>]) -> BTree<'a> {
+        let mut tree = BTree::new(Layout::from_size_align(8, 8).unwrap(), pool);
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(key, &(i as u64).to_le_bytes()).unwrap();
+        }
+        tree
+    }
+
+    fn collect_keys<C: KeyComparator>(range: Range<C>) -> Vec<Vec<u8>> {
+        range.map(|(key, _)| key.to_vec()).collect()
+    }
+
+    #[test]
+    fn test_scan() {
+        let pool = Pool::new();
+        let mut rng = StdRng::seed_from_u64(43);
+        let count = if cfg!(miri) { 200 } else { 20_000 };
+        let keys = (0..count)
+            .map(|_| {
+                (0..rng.gen_range(16, 120))
+                    .map(|_| rng.gen::<u8>())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let mut tree = build_tree(&pool, &keys);
+        let mut expected = BTreeMap::<Vec<u8>, Vec<u64>>::new();
+        for (i, key) in keys.iter().enumerate() {
+            expected.entry(key.clone()).or_default().push(i as u64);
+        }
+        // Add a second value to some keys, which the scan should yield together.
+        for (i, key) in keys.iter().enumerate().step_by(7) {
+            let val = (count + i) as u64;
+            tree.insert(key, &val.to_le_bytes()).unwrap();
+            expected.get_mut(key).unwrap().push(val);
+        }
+        if !cfg!(miri) {
+            assert!(height(&tree) > 1);
+        }
+
+        let actual = tree
+            .scan()
+            .map(|(key, vals)| {
+                let vals = vals.map(|v| u64::from_le_bytes(v.try_into().unwrap()));
+                (key.to_vec(), vals.collect::<Vec<_>>())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(expected.into_iter().collect::<Vec<_>>(), actual);
+    }
+
+    #[test]
+    fn test_range() {
+        let pool = Pool::new();
+        let count = if cfg!(miri) { 300 } else { 10_000u32 };
+        let keys = (0..count)
+            .map(|i| (i * 3).to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        let tree = build_tree(&pool, &keys);
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        let multiples =
+            |r: std::ops::Range<u32>| r.filter(|i| i % 3 == 0).map(key).collect::<Vec<_>>();
+
+        // Bounds that fall between keys and on keys, at the edges and in the middle.
+        assert_eq!(
+            multiples(100..200),
+            collect_keys(tree.range(key(100)..key(200)))
+        );
+        assert_eq!(
+            multiples(99..202),
+            collect_keys(tree.range(key(99)..=key(201)))
+        );
+        assert_eq!(
+            multiples(100..201),
+            collect_keys(tree.range((Bound::Excluded(key(99)), Bound::Excluded(key(201)))))
+        );
+        assert_eq!(multiples(0..150), collect_keys(tree.range(..key(150))));
+        assert_eq!(
+            multiples(count * 3 - 150..count * 3),
+            collect_keys(tree.range(key(count * 3 - 150)..))
+        );
+        assert_eq!(keys, collect_keys(tree.range::<Vec<u8>, _>(..)));
+        assert!(collect_keys(tree.range(key(100)..key(100))).is_empty());
+        assert!(collect_keys(tree.range(key(count * 3)..)).is_empty());
+
+        let (key, vals) = tree.range(key(4)..).next().unwrap();
+        assert_eq!(&6u32.to_be_bytes(), key);
+        assert_eq!(vec![&2u64.to_le_bytes()], vals.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_prefix() {
+        let pool = Pool::new();
+        let keys: [&[u8]; 9] = [
+            b"ap",
+            b"app",
+            b"apple",
+            b"apply",
+            b"apq",
+            b"ap\xff",
+            b"ap\xff\xff",
+            b"\xff",
+            b"\xff\xff",
+        ];
+        let keys = keys.iter().map(|k| k.to_vec()).collect::<Vec<_>>();
+        let tree = build_tree(&pool, &keys);
+        let prefix = |p: &[u8]| collect_keys(tree.prefix(p));
+
+        assert_eq!(keys[1..4], prefix(b"app")[..]);
+        assert_eq!(keys[2..3], prefix(b"apple")[..]);
+        assert!(prefix(b"apples").is_empty());
+        assert_eq!(keys[5..7], prefix(b"ap\xff")[..]);
+        // The end of a prefix of 0xff bytes is unbounded.
+        assert_eq!(keys[7..], prefix(b"\xff")[..]);
+        assert_eq!(keys, prefix(b""));
+    }
+
+    #[test]
+    fn test_prefix_successor() {
+        assert_eq!(Some(b"ab".to_vec()), prefix_successor(b"aa"));
+        assert_eq!(Some(b"b".to_vec()), prefix_successor(b"a\xff\xff"));
+        assert_eq!(None, prefix_successor(b"\xff\xff"));
+        assert_eq!(None, prefix_successor(b""));
+    }
+
+    #[test]
+    fn test_root_split_keeps_children() {
+        let pool = Pool::new();
+        let val_layout = Layout::from_size_align(8, 8).unwrap();
+        let mut tree = BTree::new(val_layout, &pool);
+
+        // A leaf fits three of these keys, so the fourth insert splits the root leaf.
+        for i in 0..4u32 {
+            tree.insert(&large_key(i), &u64::from(i).to_le_bytes())
+                .unwrap();
+        }
+        assert_eq!(2, height(&tree));
+
+        // Both children of the new root must still be usable after the split, for reads
+        // as well as for further inserts.
+        for i in 0..4u32 {
+            tree.insert(&large_key(i), &u64::from(i + 100).to_le_bytes())
+                .unwrap();
+        }
+        for i in 0..4u32 {
+            assert_eq!(
+                vec![u64::from(i), u64::from(i + 100)],
+                get_u64s(&tree, &large_key(i))
+            );
+        }
+    }
+
+    #[test]
+    fn test_large_keys() {
+        let pool = Pool::new();
+        let val_layout = Layout::from_size_align(8, 8).unwrap();
+        let mut tree = BTree::new(val_layout, &pool);
+
+        // Inner nodes only fit a few of these keys, so every level splits often, including
+        // inner roots. Insert in an order that splits nodes at both ends and in the middle.
+        let order = (0..300u32).map(|i| (i * 7919) % 300).collect::<Vec<_>>();
+        for &i in &order {
+            tree.insert(&large_key(i), &u64::from(i).to_le_bytes())
+                .unwrap();
+        }
+        assert!(height(&tree) > 3);
+
+        for i in 0..300u32 {
+            assert_eq!(vec![u64::from(i)], get_u64s(&tree, &large_key(i)));
+        }
+        assert!(tree.get(&large_key(300)).is_none());
+
+        let expected = (0..300u32).map(large_key).collect::<Vec<_>>();
+        assert_eq!(expected, collect_keys(tree.scan()));
+        assert_eq!(
+            expected[10..20],
+            collect_keys(tree.range(large_key(10)..large_key(20)))[..]
+        );
+    }
+
+    #[test]
+    fn test_entry_size_limit() {
+        let pool = Pool::new();
+        let mut tree = BTree::new(ValueFormat::Variable, &pool);
+        for i in 0..200u32 {
+            tree.insert(&i.to_be_bytes(), &[0; 100]).unwrap();
+        }
+
+        // The largest value that the key has room for, next to the one it already has. Each
+        // value is stored after its two-byte length.
+        let key = 100u32.to_be_bytes();
+        let max_len = (0..)
+            .take_while(|&len| {
+                entry_size(ValueFormat::Variable, key.len(), 2 + 100 + 2 + len) <= MAX_ENTRY_LEN
+            })
+            .last()
+            .unwrap();
+        let err = tree.insert(&key, &vec![1; max_len + 1]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert_eq!(1, tree.get(&key).unwrap().count());
+
+        tree.insert(&key, &vec![1; max_len]).unwrap();
+        assert!(tree.insert(&key, &[]).is_err());
+        let vals = tree.get(&key).unwrap().collect::<Vec<_>>();
+        assert_eq!(vec![&[0; 100][..], &vec![1; max_len][..]], vals);
+        check_invariants(&tree);
+
+        // A new key is held to the same limit.
+        let err = tree.insert(&[7; MAX_ENTRY_LEN], &[]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert_eq!(200, tree.scan().count());
+
+        // Fixed values must have the size of their layout.
+        let mut tree = BTree::new(Layout::from_size_align(8, 8).unwrap(), &pool);
+        for val in [&[1; 7][..], &[1; 9]] {
+            let err = tree.insert(&key, val).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+        assert!(tree.get(&key).is_none());
+        tree.insert(&key, &[1; 8]).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_remove() {
+        let pool = Pool::new();
+        let mut rng = StdRng::seed_from_u64(44);
+        let mut keys = (0..20_000)
+            .map(|_| {
+                (0..rng.gen_range(16, 120))
+                    .map(|_| rng.gen::<u8>())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        let mut tree = build_tree(&pool, &keys);
+        assert!(height(&tree) > 1);
+        let peak_nodes = tree.nodes.len();
+
+        let mut expected = keys.iter().cloned().collect::<BTreeSet<_>>();
+        let mut order = keys.clone();
+        order.shuffle(&mut rng);
+        for (i, key) in order.iter().enumerate() {
+            assert!(tree.remove(key));
+            assert!(!tree.remove(key));
+            assert!(tree.get(key).is_none());
+            expected.remove(key);
+            if i % 1000 == 0 {
+                check_invariants(&tree);
+                let expected = expected.iter().cloned().collect::<Vec<_>>();
+                assert_eq!(expected, collect_keys(tree.scan()));
+            }
+        }
+
+        // Every node but the root leaf was freed, and their pages went back to the pool.
+        assert!(tree.scan().next().is_none());
+        assert_eq!(1, height(&tree));
+        assert_eq!(1, tree.nodes.len());
+        assert!(pool.len() >= peak_nodes - 1);
+    }
+
+    #[test]
+    fn test_remove_value() {
+        let pool = Pool::new();
+        let mut tree = build_tree(&pool, &[b"a".to_vec(), b"b".to_vec()]);
+        for val in [2u64, 1] {
+            tree.insert(b"b", &val.to_le_bytes()).unwrap();
+        }
+
+        assert!(tree.remove_value(b"b", &1u64.to_le_bytes()));
+        assert_eq!(vec![2, 1], get_u64s(&tree, b"b"));
+        assert!(!tree.remove_value(b"b", &3u64.to_le_bytes()));
+        assert!(!tree.remove_value(b"c", &1u64.to_le_bytes()));
+
+        // Removing the last value of a key removes the key.
+        assert!(tree.remove_value(b"b", &2u64.to_le_bytes()));
+        assert!(tree.remove_value(b"b", &1u64.to_le_bytes()));
+        assert!(tree.get(b"b").is_none());
+        assert_eq!(vec![b"a".to_vec()], collect_keys(tree.scan()));
+    }
+
+    #[test]
+    fn test_remove_large_keys() {
+        let pool = Pool::new();
+        let val_layout = Layout::from_size_align(8, 8).unwrap();
+        let mut tree = BTree::new(val_layout, &pool);
+        let mut rng = StdRng::seed_from_u64(45);
+        let mut order = (0..300u32).collect::<Vec<_>>();
+        order.shuffle(&mut rng);
+        for &i in &order {
+            tree.insert(&large_key(i), &u64::from(i).to_le_bytes())
+                .unwrap();
+        }
+        let peak_height = height(&tree);
+        assert!(peak_height > 3);
+
+        // With only a few keys per node, removals merge and rebalance nodes on every level.
+        let mut expected = (0..300u32).collect::<Vec<_>>();
+        order.shuffle(&mut rng);
+        for (n, &i) in order[..250].iter().enumerate() {
+            assert!(tree.remove_value(&large_key(i), &u64::from(i).to_le_bytes()));
+            expected.retain(|&k| k != i);
+            if n % 10 == 0 {
+                check_invariants(&tree);
+                let keys = expected.iter().map(|&k| large_key(k)).collect::<Vec<_>>();
+                assert_eq!(keys, collect_keys(tree.scan()));
+            }
+        }
+        check_invariants(&tree);
+        assert!(height(&tree) < peak_height);
+
+        // The tree keeps working for inserts after shrinking.
+        for &i in &order[..250] {
+            tree.insert(&large_key(i), &u64::from(i).to_le_bytes())
+                .unwrap();
+        }
+        check_invariants(&tree);
+        for i in 0..300u32 {
+            assert_eq!(vec![u64::from(i)], get_u64s(&tree, &large_key(i)));
+        }
+        for &i in &order {
+            assert!(tree.remove(&large_key(i)));
+        }
+        assert_eq!(1, height(&tree));
+        assert_eq!(1, tree.nodes.len());
+    }
+
+    #[test]
+    fn test_comparator() {
+        let pool = Pool::new();
+        let val_layout = Layout::from_size_align(8, 8).unwrap();
+        // Composite keys of a little-endian u32 followed by a string, ordered by the number
+        // first. Bytewise order would sort 256 before 1.
+        let decode = |k: &[u8]| {
+            (
+                u32::from_le_bytes(k[..4].try_into().unwrap()),
+                k[4..].to_vec(),
+            )
+        };
+        let cmp = move |a: &[u8], b: &[u8]| decode(a).cmp(&decode(b));
+        let key = |n: u32, s: &str| [&n.to_le_bytes()[..], s.as_bytes()].concat();
+        let mut tree = BTree::with_comparator(val_layout, &pool, cmp);
+
+        let count = if cfg!(miri) { 300 } else { 5_000u32 };
+        let mut numbers = (0..count).collect::<Vec<_>>();
+        numbers.shuffle(&mut StdRng::seed_from_u64(46));
+        for &n in &numbers {
+            tree.insert(&key(n, "b"), &u64::from(n).to_le_bytes())
+                .unwrap();
+            tree.insert(&key(n, "a"), &u64::from(n).to_le_bytes())
+                .unwrap();
+        }
+        check_invariants(&tree);
+
+        let expected = (0..count)
+            .flat_map(|n| vec![key(n, "a"), key(n, "b")])
+            .collect::<Vec<_>>();
+        assert_eq!(expected, collect_keys(tree.scan()));
+        assert_eq!(
+            expected[2..513],
+            collect_keys(tree.range(key(1, "a")..=key(256, "a")))[..]
+        );
+        assert_eq!(vec![256], get_u64s(&tree, &key(256, "b")));
+
+        for &n in &numbers {
+            assert!(tree.remove(&key(n, "a")));
+        }
+        check_invariants(&tree);
+        let expected = (0..count).map(|n| key(n, "b")).collect::<Vec<_>>();
+        assert_eq!(expected, collect_keys(tree.scan()));
+    }
+
+    fn file_len(path: &Path) -> u64 {
+        std::fs::metadata(path).unwrap().len()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_reopen() {
+        let dir = TmpDir::new();
+        let path = dir.as_ref().join("tree");
+        let pool = Pool::new();
+        let val_layout = Layout::from_size_align(8, 8).unwrap();
+
+        let mut keys = (0..5_000u32)
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        keys.shuffle(&mut StdRng::seed_from_u64(47));
+        let mut tree = BTree::open(&path, val_layout, &pool).unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(key, &(i as u64).to_le_bytes()).unwrap();
+        }
+        tree.flush().unwrap();
+        let peak_len = file_len(&path);
+        drop(tree);
+
+        let mut tree = BTree::open(&path, val_layout, &pool).unwrap();
+        check_invariants(&tree);
+        keys.iter().enumerate().for_each(|(i, key)| {
+            assert_eq!(vec![i as u64], get_u64s(&tree, key));
+        });
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(sorted, collect_keys(tree.scan()));
+
+        // Dropping the tree saves the removals. Every node but the root is freed, and
+        // reused by later inserts instead of growing the file.
+        for key in &keys {
+            assert!(tree.remove(key));
+        }
+        drop(tree);
+        let mut tree = BTree::open(&path, val_layout, &pool).unwrap();
+        assert_eq!(0, tree.scan().count());
+        for key in &keys[..2_500] {
+            tree.insert(key, &[0; 8]).unwrap();
+        }
+        tree.flush().unwrap();
+        check_invariants(&tree);
+        assert_eq!(2_500, tree.scan().count());
+        assert_eq!(peak_len, file_len(&path));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_cache_eviction() {
+        let dir = TmpDir::new();
+        let path = dir.as_ref().join("tree");
+        let pool = Pool::new();
+        let val_layout = Layout::from_size_align(8, 8).unwrap();
+
+        let mut tree = BTree::open(&path, val_layout, &pool).unwrap();
+        tree.set_cache_capacity(4);
+        let keys = (0..300u32).map(large_key).collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(key, &(i as u64).to_le_bytes()).unwrap();
+            // Evicting happens before each update, which then touches at most a path from
+            // the root, and the nodes it splits off.
+            assert!(tree.nodes.resident() <= 4 + 2 * height(&tree));
+        }
+        assert!(tree.nodes.len() > 4);
+
+        // Lookups read evicted nodes back in.
+        check_invariants(&tree);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(vec![i as u64], get_u64s(&tree, key));
+        }
+        assert_eq!(keys, collect_keys(tree.scan()));
+        tree.flush().unwrap();
+        assert!(tree.nodes.resident() <= 4);
+
+        for key in keys.iter().step_by(2) {
+            assert!(tree.remove(key));
+        }
+        drop(tree);
+
+        let tree = BTree::open(&path, val_layout, &pool).unwrap();
+        check_invariants(&tree);
+        let expected = keys.iter().skip(1).step_by(2).cloned().collect::<Vec<_>>();
+        assert_eq!(expected, collect_keys(tree.scan()));
+    }
+
+    #[test]
+    fn test_open_checks_val_layout() {
+        let dir = TmpDir::new();
+        let path = dir.as_ref().join("tree");
+        let pool = Pool::new();
+        drop(BTree::open(&path, Layout::new::<u64>(), &pool).unwrap());
+
+        let err = BTree::open(&path, Layout::new::<u32>(), &pool)
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert!(BTree::open(&path, Layout::new::<u64>(), &pool).is_ok());
+        assert!(BTree::open(&path, ValueFormat::Variable, &pool).is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_variable_values() {
+        let dir = TmpDir::new();
+        let path = dir.as_ref().join("tree");
+        let pool = Pool::new();
+        let mut rng = StdRng::seed_from_u64(50);
+        let mut model = BTreeMap::<Vec<u8>, Vec<Vec<u8>>>::new();
+        let contents = |tree: &BTree| {
+            tree.scan()
+                .map(|(key, vals)| (key.to_vec(), vals.map(<[u8]>::to_vec).collect()))
+                .collect::<BTreeMap<_, Vec<_>>>()
+        };
+
+        let mut rejected = 0;
+        // Row payloads of up to a kilobyte, several to a key, so that leaves only hold
+        // a handful of entries and split and merge often.
+        let mut tree = BTree::open(&path, ValueFormat::Variable, &pool).unwrap();
+        for _ in 0..3_000 {
+            let key = rng.gen_range(0, 400u32).to_be_bytes().to_vec();
+            if rng.gen_range(0, 4) == 0 {
+                let vals = match model.get_mut(&key) {
+                    Some(vals) => vals,
+                    None => continue,
+                };
+                let val = vals.remove(rng.gen_range(0, vals.len()));
+                assert!(tree.remove_value(&key, &val));
+                if vals.is_empty() {
+                    model.remove(&key);
+                    assert!(tree.get(&key).is_none());
+                }
+            } else {
+                let val = (0..rng.gen_range(0, 1_000))
+                    .map(|_| rng.gen::<u8>())
+                    .collect::<Vec<_>>();
+                // A key can not take up more than half a page with all of its values.
+                let vals = model.entry(key.clone()).or_default();
+                let vals_len = vals.iter().chain([&val]).map(|v| 2 + v.len()).sum();
+                match tree.insert(&key, &val) {
+                    Ok(()) => vals.push(val),
+                    Err(err) => {
+                        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+                        assert!(
+                            entry_size(ValueFormat::Variable, key.len(), vals_len) > MAX_ENTRY_LEN
+                        );
+                        rejected += 1;
+                    }
+                }
+                if vals.is_empty() {
+                    model.remove(&key);
+                }
+            }
+        }
+        assert!(rejected > 0);
+        assert!(height(&tree) > 1);
+        check_invariants(&tree);
+        assert_eq!(model, contents(&tree));
+        drop(tree);
+
+        let tree = BTree::open(&path, ValueFormat::Variable, &pool).unwrap();
+        check_invariants(&tree);
+        assert_eq!(model, contents(&tree));
+    }
+
+    type Model = BTreeMap<Vec<u8>, Vec<u64>>;
+
+    fn contents<C: KeyComparator>(tree: &BTree<C>) -> Model {
+        tree.scan()
+            .map(|(key, vals)| {
+                let mut vals = vals
+                    .map(|val| u64::from_le_bytes(val.try_into().unwrap()))
+                    .collect::<Vec<_>>();
+                vals.sort_unstable();
+                (key.to_vec(), vals)
+            })
+            .collect()
+    }
+
+    /// Makes a random update to `tree` and to `model`.
+    fn update<C: KeyComparator>(tree: &mut BTree<C>, model: &mut Model, rng: &mut StdRng) {
+        let k = rng.gen_range(0, 150u32);
+        let mut key = vec![k as u8; 100 + (k as usize * 37) % 400];
+        key[..4].copy_from_slice(&k.to_be_bytes());
+        match rng.gen_range(0, 10) {
+            0..=5 => {
+                let val = rng.gen_range(0, 4u64);
+                tree.insert(&key, &val.to_le_bytes()).unwrap();
+                let vals = model.entry(key).or_default();
+                vals.push(val);
+                vals.sort_unstable();
+            }
+            6 | 7 => {
+                tree.remove(&key);
+                model.remove(&key);
+            }
+            _ => {
+                let val = rng.gen_range(0, 4u64);
+                tree.remove_value(&key, &val.to_le_bytes());
+                if let Some(vals) = model.get_mut(&key) {
+                    if let Some(idx) = vals.iter().position(|&v| v == val) {
+                        vals.remove(idx);
+                    }
+                    if vals.is_empty() {
+                        model.remove(&key);
                     }
                 }
             }
-            Node::InnerNode(n) => {
-                unimplemented!("TODO: Implement insert over Node::InnerNode");
+        }
+    }
+
+    /// Makes random updates to a disk-backed tree that crashes at write number `crash`,
+    /// then opens it again. Returns the number of writes made, the contents after the
+    /// updates that completed before the crash, and the recovered contents.
+    fn run_until_crash(path: &Path, seed: u64, crash: usize, torn: bool) -> (usize, Model, Model) {
+        let pool = Pool::new();
+        let val_layout = Layout::from_size_align(8, 8).unwrap();
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let mut tree = BTree::open(path, val_layout, &pool).unwrap();
+        tree.set_cache_capacity(4);
+        crash::crash_at(crash, torn);
+        let mut model = Model::new();
+        let mut durable = Model::new();
+        for i in 0..600 {
+            update(&mut tree, &mut model, &mut rng);
+            if !crash::has_crashed() {
+                durable = model.clone();
+            }
+            if i % 200 == 199 {
+                tree.flush().unwrap();
+            }
+        }
+        drop(tree);
+        let writes = crash::writes();
+        crash::restart();
+
+        let tree = BTree::open(path, val_layout, &pool).unwrap();
+        check_invariants(&tree);
+        (writes, durable, contents(&tree))
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_crash_recovery() {
+        let writes = {
+            let dir = TmpDir::new();
+            let (writes, model, recovered) =
+                run_until_crash(&dir.as_ref().join("tree"), 48, usize::MAX, false);
+            assert_eq!(model, recovered);
+            writes
+        };
+
+        // Crash at writes spread over the whole run, including ones made while flushing
+        // and while evicting nodes.
+        for (i, crash) in (0..writes).step_by(writes / 40 + 1).enumerate() {
+            let dir = TmpDir::new();
+            let torn = i % 2 == 0;
+            let (_, durable, recovered) =
+                run_until_crash(&dir.as_ref().join("tree"), 48, crash, torn);
+            assert_eq!(durable, recovered, "crash at write {}", crash);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_crash_during_recovery() {
+        let dir = TmpDir::new();
+        let path = dir.as_ref().join("tree");
+        let wal_path = wal::path(&path);
+        let pool = Pool::new();
+        let val_layout = Layout::from_size_align(8, 8).unwrap();
+
+        // Crash before the tree is ever flushed, so that every update is left in the log.
+        let mut rng = StdRng::seed_from_u64(49);
+        let mut model = Model::new();
+        let mut tree = BTree::open(&path, val_layout, &pool).unwrap();
+        tree.set_cache_capacity(4);
+        for _ in 0..300 {
+            update(&mut tree, &mut model, &mut rng);
+        }
+        crash::crash_at(0, false);
+        drop(tree);
+        crash::restart();
+        let backup = (fs::read(&path).unwrap(), fs::read(&wal_path).unwrap());
+
+        // Then crash at each write made while recovering them.
+        let mut crash = 0;
+        loop {
+            fs::write(&path, &backup.0).unwrap();
+            fs::write(&wal_path, &backup.1).unwrap();
+            crash::crash_at(crash, crash % 2 == 0);
+            drop(BTree::open(&path, val_layout, &pool).unwrap());
+            let recovering = crash::has_crashed();
+            crash::restart();
+
+            let tree = BTree::open(&path, val_layout, &pool).unwrap();
+            check_invariants(&tree);
+            assert_eq!(model, contents(&tree), "crash at write {}", crash);
+            if !recovering {
+                break;
             }
+            crash += 1;
         }
+        assert!(crash > 0);
     }
 }
@@ -0,0 +1,510 @@
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crc32fast::Hasher;
+
+use crate::node::NodeId;
+use crate::pager::{set_len, write_at};
+
+// The write-ahead log of a disk-backed tree records every change to its pages since the
+// last checkpoint, which is a flush of the tree. It starts with a Checkpoint record that
+// describes the tree as of that flush, and continues with one group of records per update.
+// Each group ends with a Commit record, and is written with a single write, so that an
+// update is either recovered as a whole or not at all.
+//
+// Record format:
+// +-------+--------+-----+------+--------+
+// | crc32 | length | lsn | kind | fields |
+// +-------+--------+-----+------+--------+
+//  4 bytes 4 bytes  8 bytes 1 byte
+//
+// `length` counts the bytes after it, and the checksum covers all of them. Integers are
+// little endian, and byte strings are prefixed with their length as 4 bytes.
+const FRAME_HEADER_LENGTH: usize = 8;
+
+const CHECKPOINT: u8 = 1;
+const IMAGE: u8 = 2;
+const INSERT: u8 = 3;
+const SPLIT: u8 = 4;
+const REMOVE: u8 = 5;
+const REMOVE_VALUE: u8 = 6;
+const FREE: u8 = 7;
+const COMMIT: u8 = 8;
+
+/// A change to the tree. Most records are physiological: they name the page that they
+/// change, and the operation that was applied to it, which is applied again during
+/// recovery if the page on disk does not have it yet.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) enum Record {
+    /// The state of the tree as of the last flush.
+    Checkpoint {
+        root: NodeId,
+        page_count: u64,
+        free: Vec<NodeId>,
+    },
+    /// The whole contents of a page. Logged for new pages, for pages that change in ways
+    /// that no other record describes, and for the first change to a page after a
+    /// checkpoint, so that a page that was torn by a crash while it was written back can
+    /// be rebuilt from the log.
+    Image {
+        id: NodeId,
+        page: Vec<u8>,
+    },
+    /// A leaf insert that fit in the leaf, possibly after compacting it.
+    Insert {
+        id: NodeId,
+        key: Vec<u8>,
+        val: Vec<u8>,
+    },
+    /// A leaf insert that split the leaf. The leaf keeps the lower half of its entries, and
+    /// links to `right`, the new leaf with the upper half.
+    Split {
+        id: NodeId,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        right: NodeId,
+    },
+    Remove {
+        id: NodeId,
+        key: Vec<u8>,
+    },
+    RemoveValue {
+        id: NodeId,
+        key: Vec<u8>,
+        val: Vec<u8>,
+    },
+    /// A page that was added to the free list.
+    Free {
+        id: NodeId,
+    },
+    /// The end of an update, after which the tree has this root.
+    Commit {
+        root: NodeId,
+    },
+}
+
+impl Record {
+    fn encode(&self, lsn: u64, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend_from_slice(&[0; FRAME_HEADER_LENGTH]);
+        buf.extend_from_slice(&lsn.to_le_bytes());
+        let id = |buf: &mut Vec<u8>, id: NodeId| buf.extend_from_slice(&id.get().to_le_bytes());
+        let bytes = |buf: &mut Vec<u8>, bytes: &[u8]| {
+            buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            buf.extend_from_slice(bytes);
+        };
+        match self {
+            Record::Checkpoint {
+                root,
+                page_count,
+                free,
+            } => {
+                buf.push(CHECKPOINT);
+                id(buf, *root);
+                buf.extend_from_slice(&page_count.to_le_bytes());
+                buf.extend_from_slice(&(free.len() as u64).to_le_bytes());
+                free.iter().for_each(|&free| id(buf, free));
+            }
+            Record::Image { id: page_id, page } => {
+                buf.push(IMAGE);
+                id(buf, *page_id);
+                bytes(buf, page);
+            }
+            Record::Insert {
+                id: page_id,
+                key,
+                val,
+            } => {
+                buf.push(INSERT);
+                id(buf, *page_id);
+                bytes(buf, key);
+                bytes(buf, val);
+            }
+            Record::Split {
+                id: page_id,
+                key,
+                val,
+                right,
+            } => {
+                buf.push(SPLIT);
+                id(buf, *page_id);
+                bytes(buf, key);
+                bytes(buf, val);
+                id(buf, *right);
+            }
+            Record::Remove { id: page_id, key } => {
+                buf.push(REMOVE);
+                id(buf, *page_id);
+                bytes(buf, key);
+            }
+            Record::RemoveValue {
+                id: page_id,
+                key,
+                val,
+            } => {
+                buf.push(REMOVE_VALUE);
+                id(buf, *page_id);
+                bytes(buf, key);
+                bytes(buf, val);
+            }
+            Record::Free { id: page_id } => {
+                buf.push(FREE);
+                id(buf, *page_id);
+            }
+            Record::Commit { root } => {
+                buf.push(COMMIT);
+                id(buf, *root);
+            }
+        }
+
+        let body = &buf[start + FRAME_HEADER_LENGTH..];
+        let length = (body.len() as u32).to_le_bytes();
+        let mut hasher = Hasher::new();
+        hasher.update(&length);
+        hasher.update(body);
+        let crc = hasher.finalize().to_le_bytes();
+        buf[start..start + 4].copy_from_slice(&crc);
+        buf[start + 4..start + 8].copy_from_slice(&length);
+    }
+
+    /// Decodes the record at the start of `buf`, and returns it along with its LSN and the
+    /// number of bytes that it took up. Returns None if the record is incomplete or its
+    /// checksum does not match, as it is for a record that was being written in a crash.
+    fn decode(buf: &[u8]) -> Option<(u64, Record, usize)> {
+        let length = u32::from_le_bytes(buf.get(4..8)?.try_into().unwrap()) as usize;
+        let body = buf.get(FRAME_HEADER_LENGTH..FRAME_HEADER_LENGTH + length)?;
+        let mut hasher = Hasher::new();
+        hasher.update(&buf[4..8]);
+        hasher.update(body);
+        if hasher.finalize().to_le_bytes() != buf[..4] {
+            return None;
+        }
+
+        let mut reader = Reader { buf: body };
+        let lsn = reader.u64()?;
+        let record = match reader.u8()? {
+            CHECKPOINT => Record::Checkpoint {
+                root: reader.id()?,
+                page_count: reader.u64()?,
+                free: {
+                    let count = reader.u64()?;
+                    (0..count).map(|_| reader.id()).collect::<Option<_>>()?
+                },
+            },
+            IMAGE => Record::Image {
+                id: reader.id()?,
+                page: reader.bytes()?,
+            },
+            INSERT => Record::Insert {
+                id: reader.id()?,
+                key: reader.bytes()?,
+                val: reader.bytes()?,
+            },
+            SPLIT => Record::Split {
+                id: reader.id()?,
+                key: reader.bytes()?,
+                val: reader.bytes()?,
+                right: reader.id()?,
+            },
+            REMOVE => Record::Remove {
+                id: reader.id()?,
+                key: reader.bytes()?,
+            },
+            REMOVE_VALUE => Record::RemoveValue {
+                id: reader.id()?,
+                key: reader.bytes()?,
+                val: reader.bytes()?,
+            },
+            FREE => Record::Free { id: reader.id()? },
+            COMMIT => Record::Commit { root: reader.id()? },
+            _ => return None,
+        };
+        Some((lsn, record, FRAME_HEADER_LENGTH + length))
+    }
+}
+
+struct Reader<'b> {
+    buf: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, len: usize) -> Option<&'b [u8]> {
+        if self.buf.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn id(&mut self) -> Option<NodeId> {
+        self.u64().and_then(NodeId::new)
+    }
+
+    fn bytes(&mut self) -> Option<Vec<u8>> {
+        let len = u32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        self.take(len as usize).map(<[u8]>::to_vec)
+    }
+}
+
+/// What was found in the log when it was opened.
+pub(crate) struct Recovery {
+    /// The Checkpoint record that the log starts with, and its LSN. None if the log is
+    /// empty, or a crash cut its first record short.
+    pub(crate) checkpoint: Option<(u64, Record)>,
+    /// The complete groups of records after the checkpoint, in order, along with their
+    /// LSNs. Each one ends with a Commit.
+    pub(crate) groups: Vec<Vec<(u64, Record)>>,
+}
+
+/// The path of the log of the tree stored at `path`.
+pub(crate) fn path(path: &Path) -> PathBuf {
+    let mut wal = path.as_os_str().to_owned();
+    wal.push("-wal");
+    wal.into()
+}
+
+pub(crate) struct Wal {
+    file: File,
+    // Where the next group is written.
+    len: u64,
+    // The last LSN that was written, and the last one that is known to be on disk.
+    written_lsn: u64,
+    synced_lsn: u64,
+}
+
+impl Wal {
+    /// Opens the log at `path`, creating it if it does not exist, and reads the records in
+    /// it. Anything after the last complete group is ignored, and overwritten by the next
+    /// group that is written. New records are numbered after both the records in the log
+    /// and `lsn`, the LSN of the checkpoint that the tree's file was last flushed at.
+    pub(crate) fn open<P: AsRef<Path>>(path: P, lsn: u64) -> io::Result<(Wal, Recovery)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let mut recovery = Recovery {
+            checkpoint: None,
+            groups: Vec::new(),
+        };
+        let mut offset = 0;
+        let mut log_lsn = 0;
+        if let Some((checkpoint_lsn, record @ Record::Checkpoint { .. }, len)) =
+            Record::decode(&buf)
+        {
+            recovery.checkpoint = Some((checkpoint_lsn, record));
+            offset = len;
+            log_lsn = checkpoint_lsn;
+
+            let mut group = Vec::new();
+            let mut group_offset = offset;
+            while let Some((record_lsn, record, len)) = Record::decode(&buf[group_offset..]) {
+                group_offset += len;
+                let commit = matches!(record, Record::Commit { .. });
+                group.push((record_lsn, record));
+                if commit {
+                    log_lsn = record_lsn;
+                    offset = group_offset;
+                    recovery.groups.push(std::mem::take(&mut group));
+                }
+            }
+        }
+
+        let lsn = log_lsn.max(lsn);
+        let wal = Wal {
+            file,
+            len: offset as u64,
+            written_lsn: lsn,
+            synced_lsn: lsn,
+        };
+        Ok((wal, recovery))
+    }
+
+    /// The LSN of the last record written.
+    pub(crate) fn lsn(&self) -> u64 {
+        self.written_lsn
+    }
+
+    /// Appends a group of records, which must end with a Commit. They are numbered from
+    /// the LSN after the last one written, and their LSNs are returned in order.
+    pub(crate) fn append(&mut self, records: &[Record]) -> io::Result<Vec<u64>> {
+        debug_assert!(matches!(records.last(), Some(Record::Commit { .. })));
+        let mut buf = Vec::new();
+        let lsns = (self.written_lsn + 1..)
+            .take(records.len())
+            .collect::<Vec<_>>();
+        for (record, &lsn) in records.iter().zip(&lsns) {
+            record.encode(lsn, &mut buf);
+        }
+        write_at(&self.file, &buf, self.len)?;
+        self.len += buf.len() as u64;
+        self.written_lsn += records.len() as u64;
+        Ok(lsns)
+    }
+
+    /// Waits for the log to be on disk up to at least `lsn`.
+    pub(crate) fn sync(&mut self, lsn: u64) -> io::Result<()> {
+        if self.synced_lsn < lsn {
+            self.file.sync_data()?;
+            self.synced_lsn = self.written_lsn;
+        }
+        Ok(())
+    }
+
+    /// Discards every record, and starts the log over with `checkpoint`, which takes the
+    /// next LSN.
+    pub(crate) fn reset(&mut self, checkpoint: &Record) -> io::Result<()> {
+        let mut buf = Vec::new();
+        let lsn = self.written_lsn + 1;
+        checkpoint.encode(lsn, &mut buf);
+        set_len(&self.file, 0)?;
+        write_at(&self.file, &buf, 0)?;
+        self.file.sync_data()?;
+        self.len = buf.len() as u64;
+        self.written_lsn = lsn;
+        self.synced_lsn = lsn;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{crash, TmpDir};
+
+    fn id(id: u64) -> NodeId {
+        NodeId::new(id).unwrap()
+    }
+
+    fn checkpoint() -> Record {
+        Record::Checkpoint {
+            root: id(1),
+            page_count: 4,
+            free: vec![id(3), id(2)],
+        }
+    }
+
+    fn group(n: u8) -> Vec<Record> {
+        vec![
+            Record::Image {
+                id: id(2),
+                page: vec![n; 100],
+            },
+            Record::Insert {
+                id: id(1),
+                key: vec![n],
+                val: vec![1, 2],
+            },
+            Record::Split {
+                id: id(1),
+                key: vec![n, n],
+                val: vec![3, 4],
+                right: id(2),
+            },
+            Record::Remove {
+                id: id(1),
+                key: vec![n],
+            },
+            Record::RemoveValue {
+                id: id(1),
+                key: vec![n],
+                val: vec![],
+            },
+            Record::Free { id: id(3) },
+            Record::Commit { root: id(1) },
+        ]
+    }
+
+    fn records(groups: &[Vec<(u64, Record)>]) -> Vec<Vec<Record>> {
+        groups
+            .iter()
+            .map(|group| group.iter().map(|(_, r)| r.clone()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_append_and_recover() {
+        let dir = TmpDir::new();
+        let path = dir.as_ref().join("wal");
+        let (mut wal, recovery) = Wal::open(&path, 0).unwrap();
+        assert!(recovery.checkpoint.is_none());
+        assert!(recovery.groups.is_empty());
+
+        wal.reset(&checkpoint()).unwrap();
+        assert_eq!((2..9).collect::<Vec<_>>(), wal.append(&group(1)).unwrap());
+        wal.append(&group(2)).unwrap();
+        drop(wal);
+
+        let (mut wal, recovery) = Wal::open(&path, 0).unwrap();
+        assert_eq!(Some((1, checkpoint())), recovery.checkpoint);
+        assert_eq!(vec![group(1), group(2)], records(&recovery.groups));
+        assert_eq!(
+            (9..16).collect::<Vec<_>>(),
+            recovery.groups[1]
+                .iter()
+                .map(|(lsn, _)| *lsn)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(15, wal.lsn());
+
+        // LSNs keep increasing after a reset.
+        wal.reset(&checkpoint()).unwrap();
+        drop(wal);
+        let (wal, recovery) = Wal::open(&path, 0).unwrap();
+        assert_eq!(Some((16, checkpoint())), recovery.checkpoint);
+        assert!(recovery.groups.is_empty());
+        assert_eq!(16, wal.lsn());
+
+        // As long as the LSN that the tree was last flushed at is not later.
+        let (wal, _) = Wal::open(&path, 20).unwrap();
+        assert_eq!(20, wal.lsn());
+    }
+
+    #[test]
+    fn test_torn_group_is_ignored() {
+        let dir = TmpDir::new();
+        let path = dir.as_ref().join("wal");
+        let (mut wal, _) = Wal::open(&path, 0).unwrap();
+        wal.reset(&checkpoint()).unwrap();
+        wal.append(&group(1)).unwrap();
+        crash::crash_at(0, true);
+        wal.append(&group(2)).unwrap();
+        drop(wal);
+        crash::restart();
+
+        // The next group overwrites the torn one.
+        let (mut wal, recovery) = Wal::open(&path, 0).unwrap();
+        assert_eq!(vec![group(1)], records(&recovery.groups));
+        wal.append(&group(3)).unwrap();
+        drop(wal);
+        let (_, recovery) = Wal::open(&path, 0).unwrap();
+        assert_eq!(vec![group(1), group(3)], records(&recovery.groups));
+
+        // A group without its Commit is not recovered either.
+        let (wal, _) = Wal::open(&path, 0).unwrap();
+        let mut buf = Vec::new();
+        for (lsn, record) in (100..).zip(&group(4)[..6]) {
+            record.encode(lsn, &mut buf);
+        }
+        write_at(&wal.file, &buf, wal.len).unwrap();
+        drop(wal);
+        let (_, recovery) = Wal::open(&path, 0).unwrap();
+        assert_eq!(vec![group(1), group(3)], records(&recovery.groups));
+    }
+}
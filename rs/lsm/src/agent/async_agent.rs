@@ -0,0 +1,529 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::thread;
+
+use futures_core::Stream;
+use tokio::sync::{mpsc as async_mpsc, oneshot};
+
+use super::config::Config;
+use super::write_controller::WriteController;
+use super::Agent;
+use crate::batch::WriteBatch;
+use crate::log::RecordType;
+
+// Maximum number of concurrently submitted writes that are committed together
+// as one log record and one fsync.
+const MAX_GROUP_SIZE: usize = 128;
+
+// Number of entries a scan reads from the agent each time it takes the read
+// lock.
+const SCAN_CHUNK_SIZE: usize = 64;
+
+type ReadJob = Box<dyn FnOnce(&RwLock<Agent>) + Send>;
+type ScanEntry = io::Result<(Vec<u8>, Vec<u8>)>;
+
+struct WriteRequest {
+    batch: WriteBatch,
+    done: oneshot::Sender<io::Result<()>>,
+}
+
+// A handle to an `Agent` for use from async code. All storage work runs on
+// dedicated threads owned by the handle, so no call blocks the runtime:
+//
+// - Writes are sent to a single writer thread, which drains every write that
+//   is waiting when it wakes up and commits them as one `WriteBatch` followed
+//   by a single `sync`. A write's future resolves once that sync succeeds, so
+//   writes submitted concurrently from many tasks share one fsync.
+// - Compactions run on a compactor thread, which the writer wakes after each
+//   group. A compaction only holds the agent to pick its inputs and to
+//   install its outputs, so reads and writes carry on while it runs. Writers
+//   are delayed or stopped by the `WriteController` while level 0 is over
+//   the configured triggers, until compaction catches up.
+// - Reads run on a pool of reader threads. No job on a reader thread waits
+//   for the task that submitted it, so a slow or abandoned consumer cannot
+//   hold a thread.
+//
+// The handle is cheap to clone, and open scans hold one too. Once the last
+// handle is dropped, the threads finish any work that was already submitted
+// and exit. Dropping does not wait for them; use `close` for that.
+#[derive(Clone)]
+pub struct AsyncAgent {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    write_tx: mpsc::Sender<WriteRequest>,
+    read_tx: mpsc::Sender<ReadJob>,
+    // Every thread holds a sender, so this yields `None` once all of them
+    // have exited.
+    exited: async_mpsc::Receiver<()>,
+}
+
+// Stream of the entries returned by `AsyncAgent::scan`. Entries are read one
+// chunk ahead of the consumer.
+pub struct Scan {
+    agent: AsyncAgent,
+    // Where the next chunk starts, and whether that key was already returned.
+    next: Vec<u8>,
+    resuming: bool,
+    end: Vec<u8>,
+    buffered: VecDeque<ScanEntry>,
+    // The chunk that a reader thread is working on. `None` once the last
+    // chunk has been read.
+    pending: Option<oneshot::Receiver<Vec<ScanEntry>>>,
+}
+
+impl AsyncAgent {
+    // Opens the agent described by `cfg` and starts one writer thread, one
+    // compactor thread and `read_threads` reader threads. Like `Agent::open`, this recovers the
+    // log, so it should be called from a blocking context.
+    pub fn open(cfg: Config, read_threads: usize) -> io::Result<AsyncAgent> {
+        assert!(read_threads > 0, "AsyncAgent requires at least one reader thread");
+        let mut agent = Agent::open(cfg)?;
+        agent.background_compaction = true;
+        let write_controller = Arc::clone(&agent.write_controller);
+        let agent = Arc::new(RwLock::new(agent));
+        let (exit_tx, exited) = async_mpsc::channel(1);
+
+        // The writer holds the only sender, so the compactor exits after it.
+        let (compact_tx, compact_rx) = mpsc::sync_channel(1);
+        let compactor = Arc::clone(&agent);
+        let controller = Arc::clone(&write_controller);
+        let exit = exit_tx.clone();
+        spawn("lsm-compactor", move || {
+            run_compactor(&compactor, &controller, compact_rx);
+            drop(exit);
+        })?;
+        // Recovery may have left more work than the strategy allows.
+        let _ = compact_tx.try_send(());
+
+        let (write_tx, write_rx) = mpsc::channel();
+        let writer = Arc::clone(&agent);
+        let exit = exit_tx.clone();
+        spawn("lsm-writer", move || {
+            run_writer(&writer, &write_controller, write_rx, compact_tx);
+            drop(exit);
+        })?;
+
+        let (read_tx, read_rx) = mpsc::channel::<ReadJob>();
+        let read_rx = Arc::new(Mutex::new(read_rx));
+        for i in 0..read_threads {
+            let agent = Arc::clone(&agent);
+            let read_rx = Arc::clone(&read_rx);
+            let exit = exit_tx.clone();
+            spawn(&format!("lsm-reader-{}", i), move || {
+                loop {
+                    // Hold the receiver lock only while waiting for a job, so
+                    // other readers can pick up the next one.
+                    let job = read_rx.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(&agent),
+                        Err(_) => break,
+                    }
+                }
+                drop(exit);
+            })?;
+        }
+
+        Ok(AsyncAgent {
+            inner: Arc::new(Inner {
+                write_tx,
+                read_tx,
+                exited,
+            }),
+        })
+    }
+
+    pub async fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let key = key.to_vec();
+        let (tx, rx) = oneshot::channel();
+        self.submit_read(Box::new(move |agent| {
+            let _ = tx.send(agent.read().unwrap().get(&key));
+        }))?;
+        rx.await.unwrap_or_else(|_| Err(shut_down()))
+    }
+
+    pub async fn put(&self, key: &[u8], val: &[u8]) -> io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, val);
+        self.write(batch).await
+    }
+
+    pub async fn delete(&self, key: &[u8]) -> io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(batch).await
+    }
+
+    // Applies all writes in `batch` atomically. Resolves once the writes are
+    // durable.
+    pub async fn write(&self, batch: WriteBatch) -> io::Result<()> {
+        let (done, rx) = oneshot::channel();
+        self.inner
+            .write_tx
+            .send(WriteRequest { batch, done })
+            .map_err(|_| shut_down())?;
+        rx.await.unwrap_or_else(|_| Err(shut_down()))
+    }
+
+    // Streams the keys in [start, end) in order, with their values. Resolves
+    // once the first chunk has been read. The stream ends after the first
+    // error.
+    //
+    // The scan reads the agent in chunks and releases the read lock between
+    // them, so it does not hold up writes for its whole duration. As a
+    // consequence it is not a consistent snapshot: a write that lands while
+    // the scan is running may or may not be seen, depending on whether its
+    // key has been reached yet.
+    // TODO: Scan from a snapshot once the memtable supports them.
+    pub async fn scan(&self, start: &[u8], end: &[u8]) -> io::Result<Scan> {
+        let mut scan = Scan {
+            agent: self.clone(),
+            next: start.to_vec(),
+            resuming: false,
+            end: end.to_vec(),
+            buffered: VecDeque::new(),
+            pending: None,
+        };
+        scan.read_next_chunk()?;
+        let chunk = scan.pending.take().unwrap().await.map_err(|_| shut_down())?;
+        scan.add_chunk(chunk)?;
+        Ok(scan)
+    }
+
+    // Drops this handle and, if it was the last one, waits for the threads to
+    // finish the work already submitted and exit. After that the agent is
+    // closed and the store can be opened again.
+    pub async fn close(self) {
+        if let Ok(inner) = Arc::try_unwrap(self.inner) {
+            let Inner {
+                write_tx,
+                read_tx,
+                mut exited,
+            } = inner;
+            // Closing the channels stops the threads once they have drained
+            // them.
+            drop((write_tx, read_tx));
+            while exited.recv().await.is_some() {}
+        }
+    }
+
+    fn submit_read(&self, job: ReadJob) -> io::Result<()> {
+        self.inner.read_tx.send(job).map_err(|_| shut_down())
+    }
+}
+
+impl Scan {
+    // Has a reader thread read the chunk after the entries returned so far.
+    fn read_next_chunk(&mut self) -> io::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        let (next, resuming, end) = (self.next.clone(), self.resuming, self.end.clone());
+        self.agent.submit_read(Box::new(move |agent| {
+            let chunk = agent
+                .read()
+                .unwrap()
+                .scan(&next, &end)
+                .skip_while(|entry| resuming && matches!(entry, Ok((k, _)) if *k == next))
+                .take(SCAN_CHUNK_SIZE)
+                .collect();
+            let _ = tx.send(chunk);
+        }))?;
+        self.pending = Some(rx);
+        Ok(())
+    }
+
+    // Buffers a chunk and starts reading the one after it, unless it was the
+    // last.
+    fn add_chunk(&mut self, chunk: Vec<ScanEntry>) -> io::Result<()> {
+        // The agent's scan stops after an error, so an error can only be the
+        // last entry of a chunk.
+        let done = chunk.len() < SCAN_CHUNK_SIZE || chunk.last().is_some_and(|e| e.is_err());
+        if let Some(Ok((last, _))) = chunk.last() {
+            // Each chunk after the first starts at the last key of the
+            // previous one, since there is no general way to find the key
+            // that follows it under the comparator.
+            self.next = last.clone();
+            self.resuming = true;
+        }
+        self.buffered.extend(chunk);
+        if !done {
+            self.read_next_chunk()?;
+        }
+        Ok(())
+    }
+}
+
+impl Stream for Scan {
+    type Item = ScanEntry;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(entry) = this.buffered.pop_front() {
+                return Poll::Ready(Some(entry));
+            }
+            let pending = match this.pending.as_mut() {
+                Some(pending) => pending,
+                None => return Poll::Ready(None),
+            };
+            let chunk = match Pin::new(pending).poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(chunk) => chunk.map_err(|_| shut_down()),
+            };
+            this.pending = None;
+            if let Err(err) = chunk.and_then(|chunk| this.add_chunk(chunk)) {
+                this.pending = None;
+                this.buffered.push_back(Err(err));
+            }
+        }
+    }
+}
+
+fn run_writer(
+    agent: &RwLock<Agent>,
+    write_controller: &WriteController,
+    rx: mpsc::Receiver<WriteRequest>,
+    compact_tx: mpsc::SyncSender<()>,
+) {
+    while let Ok(first) = rx.recv() {
+        let mut requests = vec![first];
+        while requests.len() < MAX_GROUP_SIZE {
+            match rx.try_recv() {
+                Ok(request) => requests.push(request),
+                Err(_) => break,
+            }
+        }
+
+        // Wait without holding the agent, so that the compactor can install
+        // the compactions that release the write.
+        write_controller.wait_for_write(requests.iter().map(|r| r.batch.approximate_size()).sum());
+        let mut agent = agent.write().unwrap();
+        let mut group = WriteBatch::new();
+        let mut waiting = Vec::with_capacity(requests.len());
+        for mut request in requests {
+            // Reject a batch that the agent would refuse here, rather than
+            // failing every other write in the group with it.
+            let has_merge = request.batch.ops().iter().any(|op| op.record_type == RecordType::Merge);
+            if has_merge {
+                if let Err(err) = agent.check_merge_operator() {
+                    let _ = request.done.send(Err(err));
+                    continue;
+                }
+            }
+            group.append(&mut request.batch);
+            waiting.push(request.done);
+        }
+
+        let res = agent.write(&group).and_then(|_| agent.sync());
+        drop(agent);
+        // The write may have flushed the memtable. If the compactor has not
+        // picked up the previous signal yet, it will see this flush too.
+        let _ = compact_tx.try_send(());
+        for done in waiting {
+            let res = match &res {
+                Ok(()) => Ok(()),
+                Err(err) => Err(io::Error::new(err.kind(), err.to_string())),
+            };
+            let _ = done.send(res);
+        }
+    }
+}
+
+// Runs compactions each time the writer signals, until the writer exits. If a
+// compaction fails, writes fail from then on rather than wait for a
+// compaction that will not happen.
+fn run_compactor(agent: &RwLock<Agent>, write_controller: &WriteController, rx: mpsc::Receiver<()>) {
+    while rx.recv().is_ok() {
+        if let Err(err) = compact(agent) {
+            agent.write().unwrap().set_background_error(err);
+            write_controller.release();
+            return;
+        }
+    }
+}
+
+// Runs compactions until the strategy is satisfied. Outputs are written
+// without holding the agent.
+fn compact(agent: &RwLock<Agent>) -> io::Result<()> {
+    loop {
+        let job = match agent.read().unwrap().pick_compaction()? {
+            Some(job) => job,
+            None => return Ok(()),
+        };
+        let outputs = job.run()?;
+        agent.write().unwrap().install_compaction(&job, outputs)?;
+    }
+}
+
+fn spawn<F>(name: &str, f: F) -> io::Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    thread::Builder::new().name(name.to_string()).spawn(f).map(|_| ())
+}
+
+fn shut_down() -> io::Error {
+    io::Error::other("AsyncAgent has shut down")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+
+    use super::*;
+    use crate::compaction::LeveledCompaction;
+    use crate::test_util::TmpDir;
+
+    fn open(dir: &TmpDir) -> AsyncAgent {
+        let cfg = Config {
+            log_dir: dir.as_ref().to_str().unwrap().into(),
+            sstable_dir: dir.as_ref().join("sstable").to_str().unwrap().into(),
+            ..Config::default()
+        };
+        AsyncAgent::open(cfg, 2).unwrap()
+    }
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(f)
+    }
+
+    async fn collect(mut scan: Scan) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries = Vec::new();
+        while let Some(entry) = std::future::poll_fn(|cx| Pin::new(&mut scan).poll_next(cx)).await {
+            entries.push(entry.unwrap());
+        }
+        entries
+    }
+
+    #[test]
+    fn test_get_put_delete() {
+        let dir = TmpDir::new();
+        let agent = open(&dir);
+        block_on(async {
+            agent.put(b"a", b"1").await.unwrap();
+            agent.put(b"b", b"2").await.unwrap();
+            agent.delete(b"a").await.unwrap();
+            assert_eq!(None, agent.get(b"a").await.unwrap());
+            assert_eq!(Some(b"2".to_vec()), agent.get(b"b").await.unwrap());
+        });
+
+        // Writes are durable once they have resolved.
+        block_on(agent.close());
+        let agent = open(&dir);
+        assert_eq!(Some(b"2".to_vec()), block_on(agent.get(b"b")).unwrap());
+    }
+
+    #[test]
+    fn test_scan() {
+        let dir = TmpDir::new();
+        let agent = open(&dir);
+        let keys = (0..200u32).map(|i| format!("key{:03}", i).into_bytes()).collect::<Vec<_>>();
+        block_on(async {
+            for key in &keys {
+                agent.put(key, key).await.unwrap();
+            }
+            // Spans several chunks.
+            let entries = collect(agent.scan(b"key010", b"key150").await.unwrap()).await;
+            let expected = keys[10..150].iter().map(|k| (k.clone(), k.clone())).collect::<Vec<_>>();
+            assert_eq!(expected, entries);
+
+            // Scans that are dropped or not consumed do not hold up the
+            // reader threads.
+            drop(agent.scan(b"", b"\xff").await.unwrap());
+            let idle = agent.scan(b"", b"\xff").await.unwrap();
+            let other = agent.scan(b"", b"\xff").await.unwrap();
+            assert_eq!(Some(b"key000".to_vec()), agent.get(b"key000").await.unwrap());
+            assert_eq!(200, collect(other).await.len());
+
+            // Closing the agent does not wait for an open scan, which keeps
+            // working until it is dropped.
+            agent.close().await;
+            assert_eq!(200, collect(idle).await.len());
+        });
+    }
+
+    #[test]
+    fn test_drop_in_async_context() {
+        let dir = TmpDir::new();
+        let agent = open(&dir);
+        block_on(async {
+            agent.put(b"a", b"1").await.unwrap();
+            // The last handle is dropped with a scan that was never read.
+            let scan = agent.scan(b"", b"\xff").await.unwrap();
+            drop(agent);
+            drop(scan);
+        });
+        let agent = open(&dir);
+        assert_eq!(Some(b"1".to_vec()), block_on(agent.get(b"a")).unwrap());
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let dir = TmpDir::new();
+        let agent = open(&dir);
+        let rt = tokio::runtime::Builder::new_multi_thread().worker_threads(4).build().unwrap();
+        rt.block_on(async {
+            let tasks = (0..100u32)
+                .map(|i| {
+                    let agent = agent.clone();
+                    tokio::spawn(async move {
+                        let key = i.to_be_bytes();
+                        agent.put(&key, &key).await.unwrap();
+                    })
+                })
+                .collect::<Vec<_>>();
+            for task in tasks {
+                task.await.unwrap();
+            }
+        });
+
+        for i in 0..100u32 {
+            let key = i.to_be_bytes();
+            assert_eq!(Some(key.to_vec()), block_on(agent.get(&key)).unwrap());
+        }
+        // A merge without a merge operator fails on its own, without failing
+        // the writes committed alongside it.
+        let mut batch = WriteBatch::new();
+        batch.merge(b"m", b"1");
+        assert!(block_on(agent.write(batch)).is_err());
+        block_on(agent.put(b"after", b"1")).unwrap();
+    }
+
+    #[test]
+    fn test_background_compaction() {
+        let dir = TmpDir::new();
+        let cfg = || Config {
+            log_dir: dir.as_ref().to_str().unwrap().into(),
+            sstable_dir: dir.as_ref().join("sstable").to_str().unwrap().into(),
+            memtable_bytes: 4096,
+            compaction_strategy: Arc::new(LeveledCompaction {
+                level0_file_num_trigger: 2,
+                ..LeveledCompaction::default()
+            }),
+            level0_slowdown_writes_trigger: 2,
+            level0_stop_writes_trigger: 3,
+            ..Config::default()
+        };
+        let agent = AsyncAgent::open(cfg(), 2).unwrap();
+        block_on(async {
+            for i in 0..50u32 {
+                let mut batch = WriteBatch::new();
+                for j in 0..10u32 {
+                    batch.put(&(i * 10 + j).to_be_bytes(), &[0; 100]);
+                }
+                agent.write(batch).await.unwrap();
+            }
+            agent.close().await;
+        });
+
+        // The compactor caught up before the agent closed.
+        let agent = Agent::open(cfg()).unwrap();
+        assert!(agent.level0_tables() < 2, "{} tables in level 0", agent.level0_tables());
+        assert_eq!(500, agent.scan(b"", b"\xff").count());
+    }
+}
@@ -0,0 +1,704 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::io::{self, Cursor};
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::merging_iter::{self, MergingIter, Source};
+use super::table_dir::TableDir;
+use super::Agent;
+use crate::comparator::Comparator;
+use crate::compaction::{Compaction, TableInfo};
+use crate::log::RecordType;
+use crate::merge::{self, MergeOperator};
+use crate::range_tombstone::{Fragment, RangeTombstones};
+use crate::rate_limiter::RateLimiter;
+use crate::table::{Table, TableBuilder, TableValue};
+
+// Compaction I/O is charged to the rate limiter in units of this many bytes.
+const RATE_LIMIT_CHUNK: u64 = 64 * 1024;
+
+// A compaction picked by the strategy, with everything needed to write its
+// outputs without holding the agent.
+pub(crate) struct CompactionJob {
+    compaction: Compaction,
+    // Newest first.
+    inputs: Vec<Arc<Table>>,
+    // The outputs inherit the largest sequence number of the inputs.
+    largest_seq: u64,
+    // Whether point and range deletions can be dropped, because no other
+    // table overlaps the inputs' key range and so there is no older data left
+    // for them to mask.
+    drop_deletions: bool,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    dir: TableDir,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl Agent {
+    // Runs the compactions that the compaction strategy picks, until it is
+    // satisfied with the shape of the tree.
+    pub fn compact(&mut self) -> io::Result<()> {
+        while let Some(job) = self.pick_compaction()? {
+            let outputs = job.run()?;
+            self.install_compaction(&job, outputs)?;
+        }
+        Ok(())
+    }
+
+    // Compactions run after every flush and ingestion, unless a background
+    // thread runs them.
+    pub(super) fn compact_unless_in_background(&mut self) -> io::Result<()> {
+        if self.background_compaction {
+            return Ok(());
+        }
+        self.compact()
+    }
+
+    // Returns the next compaction that the strategy picks, or `None` if there
+    // is nothing to do.
+    pub(crate) fn pick_compaction(&self) -> io::Result<Option<CompactionJob>> {
+        let infos = self.tables.infos().cloned().collect::<Vec<_>>();
+        let compaction = match self.compaction_strategy.pick(&infos, self.comparator.as_ref()) {
+            Some(compaction) => compaction,
+            None => return Ok(None),
+        };
+        let inputs = self
+            .tables
+            .tables()
+            .filter(|(info, _)| compaction.inputs.contains(&info.file_number))
+            .collect::<Vec<_>>();
+        if inputs.len() != compaction.inputs.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Compaction inputs {:?} are not all in the store", compaction.inputs),
+            ));
+        }
+
+        let cmp = self.comparator.as_ref();
+        let largest_seq = inputs.iter().map(|(info, _)| info.largest_seq).max().unwrap_or(0);
+        let smallest = inputs.iter().map(|(info, _)| &info.smallest).min_by(|a, b| cmp.compare(a, b));
+        let largest = inputs.iter().map(|(info, _)| &info.largest).max_by(|a, b| cmp.compare(a, b));
+        let drop_deletions = match (smallest, largest) {
+            (Some(smallest), Some(largest)) => !self.tables.infos().any(|t| {
+                !compaction.inputs.contains(&t.file_number)
+                    && cmp.compare(&t.smallest, largest) != Ordering::Greater
+                    && cmp.compare(smallest, &t.largest) != Ordering::Greater
+            }),
+            _ => true,
+        };
+        Ok(Some(CompactionJob {
+            inputs: inputs.iter().map(|(_, table)| Arc::clone(table)).collect(),
+            compaction,
+            largest_seq,
+            drop_deletions,
+            merge_operator: self.merge_operator.clone(),
+            dir: self.dir.clone(),
+            rate_limiter: self.compaction_rate_limiter.clone(),
+        }))
+    }
+
+    // Replaces the inputs of a job that has run with its outputs, by a single
+    // log record. The input files are deleted once the record is durable. If
+    // the record cannot be written, the outputs are left in place, and are
+    // deleted when the store is next opened unless the record made it to the
+    // log.
+    pub(crate) fn install_compaction(&mut self, job: &CompactionJob, outputs: Vec<(TableInfo, Table)>) -> io::Result<()> {
+        let inputs = &job.compaction.inputs;
+        let installed = inputs.iter().all(|&n| self.tables.infos().any(|t| t.file_number == n));
+        if !installed {
+            let outputs = outputs.iter().map(|(info, _)| info.file_number).collect::<Vec<_>>();
+            self.dir.remove_unlinked(&outputs);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Compaction inputs {:?} are no longer in the store", inputs),
+            ));
+        }
+
+        let record = encode_record(inputs, outputs.iter().map(|(info, _)| info));
+        self.log.append(RecordType::Compaction, &[], &record)?;
+        self.log.sync()?;
+        self.last_seq += 1;
+        for &file_number in inputs {
+            if let Some((info, _)) = self.tables.remove(file_number) {
+                self.compaction_stats.bytes_read += info.size;
+            }
+        }
+        for (info, table) in outputs {
+            self.compaction_stats.bytes_written += info.size;
+            self.tables.add(info, table);
+        }
+        self.compaction_stats.compactions += 1;
+        self.tables_changed();
+        // This also evicts the inputs' blocks from the cache.
+        self.dir.remove_unlinked(inputs);
+        Ok(())
+    }
+
+    // Records the first error from a background compaction. Writes fail from
+    // then on, see `make_room_for_write`.
+    #[cfg(feature = "async")]
+    pub(crate) fn set_background_error(&mut self, err: io::Error) {
+        self.background_error.get_or_insert(err);
+    }
+}
+
+impl CompactionJob {
+    // Writes the merged entries of the inputs to new tables in the output
+    // level, syncs the directory and opens them. The outputs are deleted if
+    // this fails.
+    pub(crate) fn run(&self) -> io::Result<Vec<(TableInfo, Table)>> {
+        let mut file_numbers = Vec::new();
+        let result = self.write_outputs(&mut file_numbers).and_then(|()| {
+            self.dir.sync()?;
+            file_numbers
+                .iter()
+                .map(|&n| self.dir.open_table(n, self.compaction.output_level, self.largest_seq))
+                .collect()
+        });
+        if result.is_err() {
+            self.dir.remove_unlinked(&file_numbers);
+        }
+        result
+    }
+
+    // The file number of each output is added to `file_numbers` before it is
+    // written. Entries covered by a range deletion in a newer input are
+    // dropped, and merge operands are folded into the values below them.
+    // Range deletions are kept unless deletions are dropped, and are split
+    // between the outputs at the first key of each output.
+    fn write_outputs(&self, file_numbers: &mut Vec<u64>) -> io::Result<()> {
+        let cmp = self.dir.comparator();
+        let sources = self
+            .inputs
+            .iter()
+            .map(|table| Box::new(table.iter()) as Source<'_, TableValue>)
+            .collect();
+        let mut range_tombstones = VecDeque::new();
+        if !self.drop_deletions {
+            let mut merged = RangeTombstones::new(Arc::clone(cmp));
+            for table in &self.inputs {
+                for fragment in table.range_tombstones().iter() {
+                    merged.add(&fragment.start, &fragment.end, 0);
+                }
+            }
+            range_tombstones.extend(merged.iter().cloned());
+        }
+
+        let mut builder: Option<TableBuilder> = None;
+        // Set once the current output has reached the target size. It is
+        // finished when the next entry arrives, so that the range deletions
+        // that span the two can be split at that entry's key.
+        let mut full = false;
+        let mut unpaid = 0;
+        let merge_op = self.merge_operator.as_deref();
+        let covered = |count: usize, key: &[u8]| {
+            self.inputs[..count]
+                .iter()
+                .any(|table| table.range_tombstones().covers(key))
+        };
+        for entry in MergingIter::new(cmp.as_ref(), sources) {
+            let (key, entries) = entry?;
+            unpaid += entries.iter().map(|(value, _)| (key.len() + value_len(value)) as u64).sum::<u64>();
+            let value = if covered(entries[0].1, &key) {
+                None
+            } else {
+                let versions = merging_iter::versions(&key, entries, covered);
+                match merge::fold_versions(merge_op, &key, versions)? {
+                    // Without older data, there is nothing for a deletion to
+                    // mask or for merge operands to apply to.
+                    Some(TableValue::Deleted) if self.drop_deletions => None,
+                    Some(TableValue::Merge(operand)) if self.drop_deletions => {
+                        let value = merge::read_value(merge_op, &key, Some(TableValue::Merge(operand)))?;
+                        value.map(|v| TableValue::Value(v.into()))
+                    }
+                    value => value,
+                }
+            };
+            if let Some(value) = value {
+                if full {
+                    let mut output = builder.take().unwrap();
+                    add_range_tombstones(&mut output, &mut range_tombstones, Some(&key), cmp.as_ref());
+                    output.finish()?;
+                }
+                let output = match &mut builder {
+                    Some(builder) => builder,
+                    None => builder.insert(self.new_output(file_numbers)?),
+                };
+                output.add(&key, &value)?;
+                unpaid += (key.len() + value_len(&value)) as u64;
+                full = self.compaction.target_file_size.is_some_and(|size| output.estimated_size() >= size);
+            }
+            if unpaid >= RATE_LIMIT_CHUNK {
+                self.pay(unpaid);
+                unpaid = 0;
+            }
+        }
+        if builder.is_none() && !range_tombstones.is_empty() {
+            builder = Some(self.new_output(file_numbers)?);
+        }
+        if let Some(mut builder) = builder {
+            add_range_tombstones(&mut builder, &mut range_tombstones, None, cmp.as_ref());
+            builder.finish()?;
+        }
+        self.pay(unpaid);
+        Ok(())
+    }
+
+    fn new_output(&self, file_numbers: &mut Vec<u64>) -> io::Result<TableBuilder> {
+        let file_number = self.dir.new_file_number();
+        file_numbers.push(file_number);
+        self.dir.create(file_number)
+    }
+
+    // Waits for the rate limiter to admit `bytes` of reads and writes.
+    fn pay(&self, bytes: u64) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.request(bytes);
+        }
+    }
+}
+
+// Adds the pending range deletions that start before `until` to `builder`,
+// and leaves the rest of them, from `until` on, pending. Adds all of them if
+// `until` is `None`.
+fn add_range_tombstones(
+    builder: &mut TableBuilder,
+    pending: &mut VecDeque<Fragment>,
+    until: Option<&[u8]>,
+    cmp: &dyn Comparator,
+) {
+    while let Some(fragment) = pending.front_mut() {
+        match until {
+            Some(until) if cmp.compare(&fragment.start, until) != Ordering::Less => return,
+            Some(until) if cmp.compare(&fragment.end, until) == Ordering::Greater => {
+                builder.delete_range(&fragment.start, until);
+                fragment.start = until.to_vec();
+                return;
+            }
+            _ => {
+                builder.delete_range(&fragment.start, &fragment.end);
+                pending.pop_front();
+            }
+        }
+    }
+}
+
+fn value_len(value: &TableValue) -> usize {
+    match value {
+        TableValue::Value(v) | TableValue::Merge(v) => v.len(),
+        TableValue::Deleted => 0,
+    }
+}
+
+// Compaction record format (the value of a `RecordType::Compaction` log
+// record):
+// +---------------+---------+-...-+---------+-------------+----------+-...-+----------+
+// | removed count | input_0 | ... | input_n | added count | output_0 | ... | output_n |
+// +---------------+---------+-...-+---------+-------------+----------+-...-+----------+
+//     4 bytes      8 bytes each                 4 bytes
+//
+// Inputs are file numbers. Outputs keep the largest sequence number of the
+// inputs, rather than taking the record's:
+// +-------------+-------+-------------+
+// | file_number | level | largest_seq |
+// +-------------+-------+-------------+
+//     8 bytes    4 bytes    8 bytes
+fn encode_record<'a>(inputs: &[u64], outputs: impl ExactSizeIterator<Item = &'a TableInfo>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.write_u32::<LittleEndian>(inputs.len() as u32).unwrap();
+    for &file_number in inputs {
+        buf.write_u64::<LittleEndian>(file_number).unwrap();
+    }
+    buf.write_u32::<LittleEndian>(outputs.len() as u32).unwrap();
+    for info in outputs {
+        buf.write_u64::<LittleEndian>(info.file_number).unwrap();
+        buf.write_u32::<LittleEndian>(info.level as u32).unwrap();
+        buf.write_u64::<LittleEndian>(info.largest_seq).unwrap();
+    }
+    buf
+}
+
+// File number, level and largest sequence number of a compaction output.
+pub(super) type Output = (u64, usize, u64);
+
+// Returns the file numbers of the inputs in a compaction record, and its
+// outputs.
+pub(super) fn decode_record(buf: &[u8]) -> io::Result<(Vec<u64>, Vec<Output>)> {
+    let mut cursor = Cursor::new(buf);
+    let mut inputs = Vec::new();
+    for _ in 0..cursor.read_u32::<LittleEndian>()? {
+        inputs.push(cursor.read_u64::<LittleEndian>()?);
+    }
+    let mut outputs = Vec::new();
+    for _ in 0..cursor.read_u32::<LittleEndian>()? {
+        let file_number = cursor.read_u64::<LittleEndian>()?;
+        let level = cursor.read_u32::<LittleEndian>()? as usize;
+        let largest_seq = cursor.read_u64::<LittleEndian>()?;
+        outputs.push((file_number, level, largest_seq));
+    }
+    if cursor.position() != buf.len() as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Compaction record has trailing bytes"));
+    }
+    Ok((inputs, outputs))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::super::config::Config;
+    use super::*;
+    use crate::compaction::{CompactionStrategy, LeveledCompaction, UniversalCompaction};
+    use crate::fs::{FileSystem, MemFileSystem};
+
+    fn config(fs: &MemFileSystem) -> Config {
+        Config {
+            log_dir: "/data/log".into(),
+            sstable_dir: "/data/sstable".into(),
+            fs: Arc::new(fs.clone()),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_compact() {
+        let fs = MemFileSystem::new();
+        let cfg = || Config {
+            compaction_strategy: Arc::new(LeveledCompaction {
+                level0_file_num_trigger: 3,
+                ..LeveledCompaction::default()
+            }),
+            ..config(&fs)
+        };
+        let mut agent = Agent::open(cfg()).unwrap();
+        agent.put(b"a", b"1").unwrap();
+        agent.put(b"b", b"1").unwrap();
+        agent.flush().unwrap();
+        agent.put(b"a", b"2").unwrap();
+        agent.delete(b"b").unwrap();
+        agent.flush().unwrap();
+        assert_eq!(2, agent.tables.infos().count());
+        assert_eq!(Some(b"2".to_vec()), agent.get(b"a").unwrap());
+        let cached = agent.stats().block_cache.usage_bytes;
+
+        // The third table reaches the trigger, and level 0 is merged into
+        // level 1. The deletion of "b" is dropped, since nothing older is
+        // left for it to mask.
+        agent.put(b"c", b"3").unwrap();
+        agent.flush().unwrap();
+        // The blocks of the deleted inputs are no longer cached. Only the
+        // output's first block is, which was read when it was opened.
+        let block = agent.tables.tables().next().unwrap().1.properties().size;
+        let usage = agent.stats().block_cache.usage_bytes;
+        assert!(usage < cached && usage < block, "{} bytes cached, {} before", usage, cached);
+        let stats = agent.stats().compaction;
+        assert_eq!((3, 1), (stats.flushes, stats.compactions));
+        let tables = agent.tables.tables().collect::<Vec<_>>();
+        assert_eq!(vec![1], tables.iter().map(|(info, _)| info.level).collect::<Vec<_>>());
+        assert_eq!(2, tables[0].1.properties().num_entries);
+        assert_eq!(Some(b"2".to_vec()), agent.get(b"a").unwrap());
+        assert_eq!(None, agent.get(b"b").unwrap());
+
+        // The inputs were deleted.
+        let files = fs.read_dir(Path::new("/data/sstable")).unwrap();
+        assert_eq!(vec![agent.dir.table_path(tables[0].0.file_number)], files);
+
+        drop(agent);
+        let agent = Agent::open(cfg()).unwrap();
+        assert_eq!(1, agent.tables.infos().count());
+        let entries = agent.scan(b"", b"\xff").map(|e| e.unwrap()).collect::<Vec<_>>();
+        assert_eq!(vec![(b"a".to_vec(), b"2".to_vec()), (b"c".to_vec(), b"3".to_vec())], entries);
+        drop(agent);
+        assert!(crate::agent::fsck(&cfg(), false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_memtable_is_flushed_when_full() {
+        let fs = MemFileSystem::new();
+        let mut agent = Agent::open(Config {
+            memtable_bytes: 1000,
+            ..config(&fs)
+        })
+        .unwrap();
+        for i in 0..30u32 {
+            agent.put(&i.to_be_bytes(), &[0; 100]).unwrap();
+        }
+        assert_eq!(2, agent.stats().compaction.flushes);
+        assert_eq!(30, agent.scan(b"", b"\xff").count());
+    }
+
+    #[test]
+    fn test_stopped_writes() {
+        let fs = MemFileSystem::new();
+        // Universal compaction does not touch level 0 below its trigger.
+        let mut agent = Agent::open(Config {
+            compaction_strategy: Arc::new(UniversalCompaction {
+                level0_file_num_trigger: 100,
+                ..UniversalCompaction::default()
+            }),
+            level0_slowdown_writes_trigger: 2,
+            level0_stop_writes_trigger: 2,
+            ..config(&fs)
+        })
+        .unwrap();
+        for key in [b"a", b"b"] {
+            agent.put(key, b"1").unwrap();
+            agent.flush().unwrap();
+        }
+        let err = agent.put(b"c", b"1").unwrap_err();
+        assert!(err.to_string().starts_with("Writes are stopped: 2 tables in level 0"), "{}", err);
+        assert_eq!(None, agent.get(b"c").unwrap());
+        assert_eq!(Some(b"1".to_vec()), agent.get(b"b").unwrap());
+    }
+
+    #[test]
+    fn test_delayed_writes() {
+        let fs = MemFileSystem::new();
+        let mut agent = Agent::open(Config {
+            compaction_strategy: Arc::new(UniversalCompaction {
+                level0_file_num_trigger: 100,
+                ..UniversalCompaction::default()
+            }),
+            level0_slowdown_writes_trigger: 1,
+            delayed_write_bytes_per_sec: 40_000,
+            ..config(&fs)
+        })
+        .unwrap();
+        agent.put(b"a", b"1").unwrap();
+        agent.flush().unwrap();
+
+        // The first 40 KB are a burst, and the last 20 KB wait half a second.
+        let start = Instant::now();
+        for key in [b"b", b"c", b"d"] {
+            agent.put(key, &[0; 20_000]).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(400), "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn test_compaction_is_rate_limited() {
+        let fs = MemFileSystem::new();
+        let mut agent = Agent::open(Config {
+            compaction_strategy: Arc::new(LeveledCompaction {
+                level0_file_num_trigger: 3,
+                ..LeveledCompaction::default()
+            }),
+            compaction_bytes_per_sec: Some(500_000),
+            ..config(&fs)
+        })
+        .unwrap();
+        let start = Instant::now();
+        for table in 0..3u8 {
+            for i in 0..10u8 {
+                agent.put(&[table, i], &[0; 10_000]).unwrap();
+            }
+            agent.flush().unwrap();
+        }
+
+        // The compaction reads and writes 300 KB each, of which 500 KB are a
+        // burst, and the rest takes at least a fifth of a second.
+        assert_eq!(1, agent.stats().compaction.compactions);
+        assert!(start.elapsed() >= Duration::from_millis(150), "{:?}", start.elapsed());
+    }
+
+    // Runs the compaction that the test sets, once.
+    #[derive(Default)]
+    struct Manual(Mutex<Option<Compaction>>);
+
+    impl CompactionStrategy for Manual {
+        fn name(&self) -> &str {
+            "manual"
+        }
+
+        fn num_levels(&self) -> usize {
+            3
+        }
+
+        fn pick(&self, _tables: &[TableInfo], _cmp: &dyn Comparator) -> Option<Compaction> {
+            self.0.lock().unwrap().take()
+        }
+    }
+
+    #[test]
+    fn test_range_deletions() {
+        let fs = MemFileSystem::new();
+        let strategy = Arc::new(Manual::default());
+        let cfg = || Config {
+            compaction_strategy: strategy.clone(),
+            ..config(&fs)
+        };
+        let key = |i: u32| format!("k{:02}", i).into_bytes();
+        let mut agent = Agent::open(cfg()).unwrap();
+        for i in 0..100 {
+            agent.put(&key(i), &[0; 100]).unwrap();
+        }
+        agent.flush().unwrap();
+        agent.delete_range(&key(10), &key(60)).unwrap();
+        agent.put(&key(20), b"new").unwrap();
+        agent.flush().unwrap();
+        agent.delete_range(&key(70), &key(80)).unwrap();
+        agent.put(&key(75), b"new").unwrap();
+        agent.flush().unwrap();
+        let file_numbers = agent.tables.infos().map(|t| t.file_number).collect::<Vec<_>>();
+        let expected = (0..10).chain([20]).chain(60..70).chain([75]).chain(80..100).map(key).collect::<Vec<_>>();
+        let keys = |agent: &Agent| agent.scan(b"", b"\xff").map(|e| e.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(expected, keys(&agent));
+
+        // The oldest table is left out, so the deletions are kept to mask it.
+        // One output per entry splits the deletions between the outputs.
+        *strategy.0.lock().unwrap() = Some(Compaction {
+            inputs: file_numbers[..2].to_vec(),
+            output_level: 0,
+            target_file_size: Some(1),
+        });
+        agent.compact().unwrap();
+        let mut outputs = agent.tables.tables().take(2).collect::<Vec<_>>();
+        outputs.sort_by(|a, b| a.0.smallest.cmp(&b.0.smallest));
+        let fragments = |table: &Table| {
+            let fragments = table.range_tombstones().iter();
+            fragments.map(|f| (f.start.clone(), f.end.clone())).collect::<Vec<_>>()
+        };
+        assert_eq!(vec![(key(10), key(60)), (key(70), key(75))], fragments(&outputs[0].1));
+        assert_eq!(vec![(key(75), key(80))], fragments(&outputs[1].1));
+        assert_eq!(expected, keys(&agent));
+        assert_eq!(Some(b"new".to_vec()), agent.get(&key(75)).unwrap());
+        assert_eq!(None, agent.get(&key(79)).unwrap());
+        drop(agent);
+        let mut agent = Agent::open(cfg()).unwrap();
+        assert_eq!(expected, keys(&agent));
+
+        // With every table in the compaction, the covered data and the
+        // deletions themselves are dropped.
+        let file_numbers = agent.tables.infos().map(|t| t.file_number).collect::<Vec<_>>();
+        *strategy.0.lock().unwrap() = Some(Compaction {
+            inputs: file_numbers,
+            output_level: 1,
+            target_file_size: None,
+        });
+        agent.compact().unwrap();
+        let tables = agent.tables.tables().collect::<Vec<_>>();
+        assert_eq!(1, tables.len());
+        assert_eq!(expected.len() as u64, tables[0].1.properties().num_entries);
+        assert!(tables[0].1.range_tombstones().is_empty());
+        assert_eq!(expected, keys(&agent));
+    }
+
+    #[test]
+    fn test_levels_mask_older_levels() {
+        let fs = MemFileSystem::new();
+        let strategy = Arc::new(Manual::default());
+        let cfg = || Config {
+            compaction_strategy: strategy.clone(),
+            ..config(&fs)
+        };
+        let mut agent = Agent::open(cfg()).unwrap();
+        let compact = |agent: &mut Agent, inputs: Vec<u64>, output_level: usize| {
+            *strategy.0.lock().unwrap() = Some(Compaction {
+                inputs,
+                output_level,
+                target_file_size: Some(1),
+            });
+            agent.compact().unwrap();
+        };
+        let in_level = |agent: &Agent, level: usize| {
+            agent.tables.infos().filter(|t| t.level == level).map(|t| t.file_number).collect::<Vec<_>>()
+        };
+
+        agent.put(b"a", b"old").unwrap();
+        agent.put(b"e", b"old").unwrap();
+        agent.put(b"z", b"old").unwrap();
+        agent.flush().unwrap();
+        let inputs = in_level(&agent, 0);
+        compact(&mut agent, inputs, 2);
+        agent.put(b"b", b"new").unwrap();
+        agent.put(b"e", b"new").unwrap();
+        agent.flush().unwrap();
+        let inputs = in_level(&agent, 0);
+        compact(&mut agent, inputs, 1);
+        assert_eq!(2, in_level(&agent, 1).len());
+
+        // The table with "b" is merged into level 2, and the output inherits
+        // its sequence number, which is that of the table with "e" left in
+        // level 1. The newer "e" in level 1 still masks the one below.
+        let b = agent.tables.infos().find(|t| t.level == 1 && t.smallest == b"b").unwrap().file_number;
+        let inputs = std::iter::once(b).chain(in_level(&agent, 2)).collect();
+        compact(&mut agent, inputs, 2);
+        assert_eq!(1, in_level(&agent, 1).len());
+        let expected = vec![(&b"a"[..], &b"old"[..]), (b"b", b"new"), (b"e", b"new"), (b"z", b"old")];
+        let check = |agent: &Agent| {
+            assert_eq!(Some(b"new".to_vec()), agent.get(b"e").unwrap());
+            let entries = agent.scan(b"", b"\xff").map(|e| e.unwrap()).collect::<Vec<_>>();
+            let entries = entries.iter().map(|(k, v)| (&k[..], &v[..])).collect::<Vec<_>>();
+            assert_eq!(expected, entries);
+        };
+        check(&agent);
+        drop(agent);
+        check(&Agent::open(cfg()).unwrap());
+    }
+
+    struct Amplification {
+        write: f64,
+        space: f64,
+    }
+
+    // Writes uniformly random keys to a store using `strategy`, flushing the
+    // memtable every 500 writes. Write amplification is the bytes written to
+    // tables per byte written to the store, and space amplification is the
+    // size of the tables relative to the live data, averaged over samples.
+    fn measure(strategy: Arc<dyn CompactionStrategy>) -> Amplification {
+        const ENTRY_BYTES: usize = 8 + 100;
+        let fs = MemFileSystem::new();
+        let mut agent = Agent::open(Config {
+            compaction_strategy: strategy,
+            ..config(&fs)
+        })
+        .unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut live = HashSet::new();
+        let (mut written, mut space_amp_sum, mut samples) = (0, 0.0, 0);
+        for i in 0..100 {
+            for _ in 0..500 {
+                let key = rng.gen_range(0, 20_000u64).to_be_bytes();
+                agent.put(&key, &[0u8; ENTRY_BYTES - 8]).unwrap();
+                written += ENTRY_BYTES;
+                live.insert(key);
+            }
+            agent.flush().unwrap();
+            if i % 10 == 9 {
+                let stored = agent.tables.infos().map(|t| t.size).sum::<u64>();
+                space_amp_sum += stored as f64 / (live.len() * ENTRY_BYTES) as f64;
+                samples += 1;
+            }
+        }
+        assert_eq!(live.len(), agent.scan(b"", b"\xff").count());
+
+        let stats = agent.stats().compaction;
+        Amplification {
+            write: (stats.flush_bytes_written + stats.bytes_written) as f64 / written as f64,
+            space: space_amp_sum / samples as f64,
+        }
+    }
+
+    #[test]
+    fn test_amplification() {
+        let leveled = measure(Arc::new(LeveledCompaction {
+            base_level_bytes: 200 * 1024,
+            target_file_size: 50 * 1024,
+            ..LeveledCompaction::default()
+        }));
+        let universal = measure(Arc::new(UniversalCompaction::default()));
+
+        // Universal trades space for lower write amplification.
+        assert!(universal.write < leveled.write, "{} >= {}", universal.write, leveled.write);
+        assert!(universal.space > leveled.space, "{} <= {}", universal.space, leveled.space);
+        assert!(universal.space < 3.0, "{}", universal.space);
+    }
+}
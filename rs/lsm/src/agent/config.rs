@@ -1,5 +1,72 @@
+use std::sync::Arc;
+
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::compaction::{CompactionStrategy, LeveledCompaction};
+use crate::encryption::KeyProvider;
+use crate::fs::{FileSystem, OsFileSystem};
+use crate::merge::MergeOperator;
 
 pub struct Config {
     pub log_dir: String,
     pub sstable_dir: String,
+
+    // The memtable is written to a table once it holds this many bytes.
+    pub memtable_bytes: usize,
+
+    // Writers are delayed once this many tables are waiting in level 0, and
+    // stopped entirely at the stop trigger until compaction catches up.
+    pub level0_slowdown_writes_trigger: usize,
+    pub level0_stop_writes_trigger: usize,
+    // Upper bound on the rate at which delayed writers may proceed.
+    pub delayed_write_bytes_per_sec: u64,
+
+    // Limits the combined read and write rate of background compaction I/O.
+    // `None` disables the limit.
+    pub compaction_bytes_per_sec: Option<u64>,
+
+    // Chooses which tables to compact. Defaults to `LeveledCompaction`;
+    // `UniversalCompaction` trades space for lower write amplification.
+    pub compaction_strategy: Arc<dyn CompactionStrategy>,
+
+    // Orders keys. A store must always be opened with a comparator of the
+    // same name as the one it was created with.
+    pub comparator: Arc<dyn Comparator>,
+
+    // Capacity of the block cache shared by all table readers.
+    pub block_cache_bytes: usize,
+
+    // Maps table files into memory and reads blocks from the mapping rather
+    // than through the block cache. Suits read-heavy nodes whose tables fit
+    // comfortably in the address space. `fsck` also maps the segments it
+    // checks. Files that cannot be mapped are read with `read_at`.
+    pub mmap_reads: bool,
+
+    // Required for `Agent::merge`.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+
+    // Encrypts data files at rest when set.
+    pub key_provider: Option<Arc<dyn KeyProvider>>,
+
+    pub fs: Arc<dyn FileSystem>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            log_dir: "./data/log".into(),
+            sstable_dir: "./data/sstable".into(),
+            memtable_bytes: 64 * 1024 * 1024,
+            level0_slowdown_writes_trigger: 8,
+            level0_stop_writes_trigger: 12,
+            delayed_write_bytes_per_sec: 16 * 1024 * 1024,
+            compaction_bytes_per_sec: None,
+            compaction_strategy: Arc::new(LeveledCompaction::default()),
+            comparator: Arc::new(BytewiseComparator),
+            block_cache_bytes: 64 * 1024 * 1024,
+            mmap_reads: false,
+            merge_operator: None,
+            key_provider: None,
+            fs: Arc::new(OsFileSystem),
+        }
+    }
 }
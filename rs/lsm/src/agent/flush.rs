@@ -0,0 +1,293 @@
+use std::io::{self, Cursor};
+use std::mem;
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{compact, Agent};
+use crate::compaction::TableInfo;
+use crate::log::segment::{self, Segment};
+use crate::log::RecordType;
+use crate::memtable::Memtable;
+
+impl Agent {
+    // Writes the memtable to a new table in level 0 and starts an empty
+    // memtable, along with a new segment. The segment starts with a log
+    // record that links in the table and every other table in the store, and
+    // replaying the log starts from that record, so the writes before it are
+    // read from the table from then on and the segments before it are
+    // deleted. Range deletions are written to the table too, where they mask
+    // the tables below it. The flush is durable once this returns. Unless
+    // compactions run in the background, they are run afterwards, see
+    // `compact`.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let seq = self.last_seq + 1;
+        let file_number = self.write_memtable()?;
+        let (info, table) = self.dir.open_table(file_number, 0, seq)?;
+
+        let tables = self.tables.infos().chain(std::iter::once(&info)).collect::<Vec<_>>();
+        let record = encode_record(&tables);
+        let log = match self.start_segment(&record) {
+            Ok(log) => log,
+            Err(err) => {
+                self.dir.remove_unlinked(&[file_number]);
+                return Err(err);
+            }
+        };
+        let old = mem::replace(&mut self.log, log);
+        self.retired_log_stats.add(old.stats());
+        self.log_dir_unsynced = true;
+        self.last_seq = seq;
+        self.memtable = Memtable::new(Arc::clone(&self.comparator));
+        self.tables.add(info, table);
+        self.tables_changed();
+        // The old segments are only deleted once the new one's name is
+        // durable. Failures to delete them are ignored, since they are also
+        // deleted when the store is next opened.
+        self.sync_log_dir()?;
+        let _ = self.remove_obsolete_segments();
+        self.compact_unless_in_background()
+    }
+
+    // Writes a segment that starts after the last record and holds only the
+    // Flush record `record`. It is written under a temporary name and renamed
+    // into place once it is synced, so that a crash leaves either the old
+    // segments or a new one whose first record is intact. The temporary file
+    // is removed if this fails.
+    fn start_segment(&self, record: &[u8]) -> io::Result<Segment> {
+        let fs = self.fs.as_ref();
+        let base_offset = self.last_seq;
+        let temp = segment::temp_segment_file_path(&self.log_dir, base_offset);
+        let merge_operator = self.merge_operator.as_ref().map(|m| m.name());
+        let key_provider = self.dir.key_provider();
+        let result = Segment::create(fs, &temp, base_offset, key_provider, self.comparator.name(), merge_operator)
+            .and_then(|mut log| {
+                log.append(RecordType::Flush, &[], record)?;
+                log.sync()?;
+                fs.rename(&temp, &segment::segment_file_path(&self.log_dir, base_offset))?;
+                Ok(log)
+            });
+        if result.is_err() {
+            let _ = fs.remove(&temp);
+        }
+        result
+    }
+
+    // Writes the memtable's entries and range deletions to a new table and
+    // returns its file number. The file is removed if it cannot be written.
+    fn write_memtable(&mut self) -> io::Result<u64> {
+        let file_number = self.dir.new_file_number();
+        let result = self.dir.create(file_number).and_then(|mut builder| {
+            for entry in self.memtable.iter(self.merge_operator.as_deref()) {
+                let (key, value) = entry?;
+                builder.add(&key, &value)?;
+            }
+            for fragment in self.memtable.range_tombstones().iter() {
+                builder.delete_range(&fragment.start, &fragment.end);
+            }
+            let props = builder.finish()?;
+            self.dir.sync()?;
+            Ok(props)
+        });
+        match result {
+            Ok(props) => {
+                self.compaction_stats.flushes += 1;
+                self.compaction_stats.flush_bytes_written += props.size;
+                Ok(file_number)
+            }
+            Err(err) => {
+                self.dir.remove_unlinked(&[file_number]);
+                Err(err)
+            }
+        }
+    }
+}
+
+// Flush record format (the value of `RecordType::Flush` log records):
+// +-------+---------+-...-+---------+
+// | count | table_0 | ... | table_n |
+// +-------+---------+-...-+---------+
+//  4 bytes
+//
+// Table:
+// +-------------+-------+-------------+
+// | file_number | level | largest_seq |
+// +-------------+-------+-------------+
+//     8 bytes    4 bytes    8 bytes
+fn encode_record(tables: &[&TableInfo]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.write_u32::<LittleEndian>(tables.len() as u32).unwrap();
+    for info in tables {
+        buf.write_u64::<LittleEndian>(info.file_number).unwrap();
+        buf.write_u32::<LittleEndian>(info.level as u32).unwrap();
+        buf.write_u64::<LittleEndian>(info.largest_seq).unwrap();
+    }
+    buf
+}
+
+// Returns the file number, level and largest sequence number of each table
+// in a Flush record.
+pub(super) fn decode_record(buf: &[u8]) -> io::Result<Vec<compact::Output>> {
+    let mut cursor = Cursor::new(buf);
+    let mut tables = Vec::new();
+    for _ in 0..cursor.read_u32::<LittleEndian>()? {
+        let file_number = cursor.read_u64::<LittleEndian>()?;
+        let level = cursor.read_u32::<LittleEndian>()? as usize;
+        let largest_seq = cursor.read_u64::<LittleEndian>()?;
+        tables.push((file_number, level, largest_seq));
+    }
+    if cursor.position() != buf.len() as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Flush record has trailing bytes"));
+    }
+    Ok(tables)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::super::config::Config;
+    use super::*;
+    use crate::fs::{FileSystem, FsOp, MemFileSystem};
+
+    fn config(fs: &MemFileSystem) -> Config {
+        Config {
+            log_dir: "/data/log".into(),
+            sstable_dir: "/data/sstable".into(),
+            fs: Arc::new(fs.clone()),
+            ..Config::default()
+        }
+    }
+
+    fn keys(agent: &Agent) -> Vec<String> {
+        agent
+            .scan(b"", b"\xff")
+            .map(|e| String::from_utf8(e.unwrap().0).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_flush() {
+        let fs = MemFileSystem::new();
+        let mut agent = Agent::open(config(&fs)).unwrap();
+        for key in &["a", "b", "c", "d"] {
+            agent.put(key.as_bytes(), key.as_bytes()).unwrap();
+        }
+        agent.delete(b"a").unwrap();
+        agent.flush().unwrap();
+        assert_eq!(0, agent.stats().memtable_bytes);
+        assert_eq!(vec![0], agent.tables.infos().map(|t| t.level).collect::<Vec<_>>());
+        assert_eq!(vec!["b", "c", "d"], keys(&agent));
+
+        // A range deletion masks the keys in the table below it once it is
+        // flushed too, but not the keys written after it.
+        agent.delete_range(b"b", b"d").unwrap();
+        agent.put(b"c", b"new").unwrap();
+        agent.flush().unwrap();
+        assert_eq!(2, agent.tables.infos().count());
+        assert_eq!(None, agent.get(b"b").unwrap());
+        assert_eq!(Some(b"new".to_vec()), agent.get(b"c").unwrap());
+        assert_eq!(vec!["c", "d"], keys(&agent));
+
+        // Flushing an empty memtable does nothing.
+        let records = agent.stats().log.records_appended;
+        agent.flush().unwrap();
+        assert_eq!(records, agent.stats().log.records_appended);
+
+        agent.put(b"e", b"memtable").unwrap();
+        agent.sync().unwrap();
+        fs.crash();
+        let agent = Agent::open(config(&fs)).unwrap();
+        assert_eq!(vec!["c", "d", "e"], keys(&agent));
+        assert_eq!(2, agent.tables.infos().count());
+        assert!(crate::agent::fsck(&config(&fs), false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_failed_flush() {
+        let fs = MemFileSystem::new();
+        let mut agent = Agent::open(config(&fs)).unwrap();
+        agent.put(b"a", b"1").unwrap();
+        agent.sync().unwrap();
+
+        // A table that cannot be written is removed, and the memtable is kept.
+        fs.fail_nth(FsOp::Sync, 0);
+        assert!(agent.flush().is_err());
+        assert!(fs.read_dir(Path::new("/data/sstable")).unwrap().is_empty());
+        assert_eq!(Some(b"1".to_vec()), agent.get(b"a").unwrap());
+
+        // So is one whose directory entry cannot be made durable.
+        fs.fail_nth(FsOp::SyncDir, 0);
+        assert!(agent.flush().is_err());
+        assert!(fs.read_dir(Path::new("/data/sstable")).unwrap().is_empty());
+        assert_eq!(Some(b"1".to_vec()), agent.get(b"a").unwrap());
+
+        // So is one whose segment cannot be written, here because its Flush
+        // record fails to sync. Writes go on to the old segment.
+        fs.fail_nth(FsOp::Sync, 2);
+        assert!(agent.flush().is_err());
+        assert!(fs.read_dir(Path::new("/data/sstable")).unwrap().is_empty());
+        let log = vec![segment::segment_file_path("/data/log", 0)];
+        assert_eq!(log, fs.read_dir(Path::new("/data/log")).unwrap());
+        agent.put(b"b", b"2").unwrap();
+        agent.sync().unwrap();
+        fs.crash();
+        let mut agent = Agent::open(config(&fs)).unwrap();
+        assert_eq!(vec!["a", "b"], keys(&agent));
+
+        // Once the new segment has been renamed into place, the flush has
+        // taken effect even if the log directory cannot be synced, and
+        // syncing the log tries again.
+        fs.fail_nth(FsOp::SyncDir, 1);
+        assert!(agent.flush().is_err());
+        assert_eq!(1, agent.tables.infos().count());
+        agent.put(b"c", b"3").unwrap();
+        agent.sync().unwrap();
+        fs.crash();
+        let agent = Agent::open(config(&fs)).unwrap();
+        assert_eq!(vec!["a", "b", "c"], keys(&agent));
+        assert_eq!(1, agent.tables.infos().count());
+    }
+
+    #[test]
+    fn test_flush_starts_new_segment() {
+        let fs = MemFileSystem::new();
+        let segments = || fs.read_dir(Path::new("/data/log")).unwrap();
+        let mut agent = Agent::open(config(&fs)).unwrap();
+        agent.put(b"a", b"1").unwrap();
+        agent.put(b"b", b"2").unwrap();
+        agent.flush().unwrap();
+        // The new segment is named after the last record before it, and
+        // holds just the Flush record. The log stats cover both segments.
+        assert_eq!(vec![segment::segment_file_path("/data/log", 2)], segments());
+        assert_eq!(1, agent.log.iter().count());
+        assert_eq!(3, agent.stats().log.records_appended);
+
+        // A segment that a flush could not delete, and one that was never
+        // renamed into place, are deleted when the store is opened.
+        fs.fail_nth(FsOp::Remove, 0);
+        agent.put(b"c", b"3").unwrap();
+        agent.flush().unwrap();
+        assert_eq!(2, segments().len());
+        agent.put(b"d", b"4").unwrap();
+        agent.sync().unwrap();
+        drop(agent);
+        let report = crate::agent::fsck(&config(&fs), false).unwrap();
+        let kinds = report.problems.iter().map(|p| (p.kind, p.path.clone())).collect::<Vec<_>>();
+        let obsolete = segment::segment_file_path("/data/log", 2);
+        assert_eq!(vec![(crate::agent::ProblemKind::ObsoleteSegment, obsolete)], kinds);
+        fs.open_append(&segment::temp_segment_file_path("/data/log", 9)).unwrap();
+        let mut agent = Agent::open(config(&fs)).unwrap();
+        assert_eq!(vec![segment::segment_file_path("/data/log", 4)], segments());
+        assert_eq!(vec!["a", "b", "c", "d"], keys(&agent));
+
+        // Sequence numbers carry on from the segments before.
+        assert_eq!(6, agent.last_seq);
+        agent.put(b"e", b"5").unwrap();
+        agent.flush().unwrap();
+        assert_eq!(vec![segment::segment_file_path("/data/log", 7)], segments());
+    }
+}
@@ -0,0 +1,474 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::config::Config;
+use super::{compact, flush, ingest};
+use crate::batch::WriteBatch;
+use crate::encryption::DecryptionError;
+use crate::log::segment::{self, Segment};
+use crate::log::RecordType;
+use crate::table::{self, ComparatorMismatch, Table};
+
+// Name of the directory, inside the log and table directories, that `fsck`
+// moves bad files into.
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProblemKind {
+    // The file could not be opened, or its header, footer or index is
+    // invalid.
+    Unreadable,
+    // A record or block failed its checksum or could not be decoded.
+    Corrupt,
+    // The last segment ends with an incomplete record, as left by a crash
+    // during a write. Opening the agent truncates it.
+    TornTail,
+    // A segment before the last one, left behind by a flush that could not
+    // delete it. Its contents are all in tables, so it is not read. Opening
+    // the agent deletes it.
+    ObsoleteSegment,
+    // A table's keys are not in strictly increasing order.
+    Unsorted,
+    // A table referenced by the log does not exist.
+    MissingTable,
+    // A table that nothing in the log references, such as one left behind by
+    // an ingestion, flush or compaction that did not complete. Opening the
+    // agent deletes it.
+    UnreferencedTable,
+    // A segment or table records a different comparator from the configured
+    // one. The agent refuses to open the store.
+    ComparatorMismatch,
+    // The file is encrypted and cannot be decrypted: no key provider is
+    // configured, it does not have the file's key, or the data fails
+    // authentication. Damage to an encrypted file cannot be told apart from
+    // a wrong key, so such files are never quarantined.
+    Undecryptable,
+}
+
+impl ProblemKind {
+    // Whether the problem means that the configuration does not match the
+    // store, rather than that a file is damaged.
+    fn is_config_mismatch(self) -> bool {
+        matches!(self, ProblemKind::ComparatorMismatch | ProblemKind::Undecryptable)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    pub path: PathBuf,
+    pub detail: String,
+    // Whether the file was moved into the quarantine directory.
+    pub quarantined: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    pub segments_checked: usize,
+    pub tables_checked: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn push(&mut self, kind: ProblemKind, path: PathBuf, detail: String) {
+        self.problems.push(Problem {
+            kind,
+            path,
+            detail,
+            quarantined: false,
+        });
+    }
+}
+
+// Checks the data directories described by `cfg` without modifying them.
+// The last segment, which the agent replays, is read in full and every table
+// is verified, and the tables that the log references are compared with the
+// ones on disk. Problems with
+// individual files are collected into the report; an error means that the
+// directories themselves could not be read.
+//
+// With `quarantine`, unreadable, corrupt and unsorted files are then moved
+// into a `QUARANTINE_DIR` directory next to them, where the agent no longer
+// sees them. Quarantining must not be done while an agent has the
+// directories open.
+//
+// A segment that records another comparator or cannot be decrypted means
+// that `cfg` does not describe the store. The tables are then not checked,
+// since every one of them would be reported, and nothing is quarantined.
+pub fn fsck(cfg: &Config, quarantine: bool) -> io::Result<FsckReport> {
+    let fs = cfg.fs.as_ref();
+    let sstable_dir = Path::new(&cfg.sstable_dir);
+    let mut report = FsckReport::default();
+
+    // Table file numbers referenced by the log, with the segment that
+    // references each.
+    let mut referenced = BTreeMap::new();
+    let mut segments = segment::list_segment_files(fs, &cfg.log_dir)?;
+    if let Some(last) = segments.pop() {
+        for path in segments {
+            report.push(ProblemKind::ObsoleteSegment, path, "superseded by a later flush".into());
+        }
+        report.segments_checked += 1;
+        if let Err((kind, detail)) = check_segment(cfg, &last, &mut referenced) {
+            report.push(kind, last, detail);
+        }
+    }
+    if report.problems.iter().any(|p| p.kind.is_config_mismatch()) {
+        return Ok(report);
+    }
+
+    let mut on_disk = BTreeSet::new();
+    for path in fs.read_dir(sstable_dir)? {
+        let file_number = match path.file_name().and_then(|n| n.to_str()).and_then(table::parse_table_file_name) {
+            Some(n) => n,
+            None => continue,
+        };
+        on_disk.insert(file_number);
+        report.tables_checked += 1;
+        if let Err((kind, detail)) = check_table(cfg, &path, file_number) {
+            report.push(kind, path, detail);
+        } else if !referenced.contains_key(&file_number) {
+            report.push(ProblemKind::UnreferencedTable, path, "not referenced by the log".into());
+        }
+    }
+    for (file_number, segment) in referenced {
+        if !on_disk.contains(&file_number) {
+            let path = sstable_dir.join(table::table_file_name(file_number));
+            report.push(ProblemKind::MissingTable, path, format!("referenced by {}", segment.display()));
+        }
+    }
+
+    if quarantine {
+        for problem in &mut report.problems {
+            match problem.kind {
+                ProblemKind::Unreadable | ProblemKind::Corrupt | ProblemKind::Unsorted => {}
+                _ => continue,
+            }
+            let dir = problem.path.parent().unwrap_or_else(|| Path::new("")).join(QUARANTINE_DIR);
+            fs.create_dir_all(&dir)?;
+            fs.rename(&problem.path, &dir.join(problem.path.file_name().unwrap()))?;
+            fs.sync_dir(&dir)?;
+            fs.sync_dir(dir.parent().unwrap())?;
+            problem.quarantined = true;
+        }
+    }
+    Ok(report)
+}
+
+// Reads every record in a segment, and records the tables that it references.
+fn check_segment(
+    cfg: &Config,
+    path: &Path,
+    referenced: &mut BTreeMap<u64, PathBuf>,
+) -> Result<(), (ProblemKind, String)> {
+    let segment = Segment::open_read_only(cfg.fs.as_ref(), path, cfg.key_provider.as_deref(), cfg.mmap_reads)
+        .map_err(|err| (unreadable_kind(&err), err.to_string()))?;
+    let mut iter = segment.iter();
+    while let Some(record) = iter.next() {
+        let (offset, entry) = match record {
+            Ok(record) => record,
+            Err(err) => {
+                // A record that runs past the end of the file is what a crash
+                // part way through an append leaves behind. Anywhere else, or
+                // with a bad checksum, the data is damaged.
+                let kind = match err.kind() {
+                    io::ErrorKind::UnexpectedEof => ProblemKind::TornTail,
+                    _ if DecryptionError::is(&err) => ProblemKind::Undecryptable,
+                    _ => ProblemKind::Corrupt,
+                };
+                return Err((kind, format!("record at offset {}: {}", iter.offset(), err)));
+            }
+        };
+        let decoded = match entry.record_type {
+            RecordType::IngestTables => ingest::decode_record(&entry.value).map(|tables| {
+                for (file_number, _) in tables {
+                    referenced.entry(file_number).or_insert_with(|| path.to_path_buf());
+                }
+            }),
+            RecordType::Flush => flush::decode_record(&entry.value).map(|tables| {
+                for (file_number, _, _) in tables {
+                    referenced.entry(file_number).or_insert_with(|| path.to_path_buf());
+                }
+            }),
+            RecordType::Compaction => compact::decode_record(&entry.value).map(|(removed, added)| {
+                for file_number in removed {
+                    referenced.remove(&file_number);
+                }
+                for (file_number, _, _) in added {
+                    referenced.entry(file_number).or_insert_with(|| path.to_path_buf());
+                }
+            }),
+            RecordType::Batch => WriteBatch::decode(&entry.value).map(|_| ()),
+            _ => Ok(()),
+        };
+        if let Err(err) = decoded {
+            return Err((ProblemKind::Corrupt, format!("record at offset {}: {}", offset, err)));
+        }
+    }
+    // Reported last, so that the tables the segment references are still
+    // collected.
+    if segment.comparator() != cfg.comparator.name() {
+        let detail = format!("written with comparator {}, not {}", segment.comparator(), cfg.comparator.name());
+        return Err((ProblemKind::ComparatorMismatch, detail));
+    }
+    Ok(())
+}
+
+fn check_table(cfg: &Config, path: &Path, file_number: u64) -> Result<(), (ProblemKind, String)> {
+    let cmp = cfg.comparator.as_ref();
+    let table = cfg
+        .fs
+        .open(path)
+        .and_then(|file| {
+            let key_provider = cfg.key_provider.as_deref();
+            Table::open(file, file_number, None, Arc::clone(&cfg.comparator), key_provider, cfg.mmap_reads)
+        })
+        .map_err(|err| (unreadable_kind(&err), err.to_string()))?;
+    let mut prev: Option<Vec<u8>> = None;
+    for entry in table.iter() {
+        let (key, _) = entry.map_err(|err| (corrupt_kind(&err), err.to_string()))?;
+        if let Some(prev) = prev.filter(|p| cmp.compare(p, &key) != Ordering::Less) {
+            return Err((ProblemKind::Unsorted, format!("key {:?} follows {:?}", key, prev)));
+        }
+        prev = Some(key);
+    }
+    // Checks the parts of the table that iterating does not, such as the
+    // index keys and the entry count.
+    table.verify().map_err(|err| (corrupt_kind(&err), err.to_string()))
+}
+
+// Kind of problem for a file that could not be opened.
+fn unreadable_kind(err: &io::Error) -> ProblemKind {
+    if ComparatorMismatch::from_io_error(err).is_some() {
+        ProblemKind::ComparatorMismatch
+    } else if DecryptionError::is(err) {
+        ProblemKind::Undecryptable
+    } else {
+        ProblemKind::Unreadable
+    }
+}
+
+// Kind of problem for data that could not be read from an open file.
+fn corrupt_kind(err: &io::Error) -> ProblemKind {
+    if DecryptionError::is(err) {
+        ProblemKind::Undecryptable
+    } else {
+        ProblemKind::Corrupt
+    }
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ProblemKind::Unreadable => "unreadable",
+            ProblemKind::Corrupt => "corrupt",
+            ProblemKind::TornTail => "torn tail",
+            ProblemKind::ObsoleteSegment => "obsolete segment",
+            ProblemKind::Unsorted => "unsorted",
+            ProblemKind::MissingTable => "missing table",
+            ProblemKind::UnreferencedTable => "unreferenced table",
+            ProblemKind::ComparatorMismatch => "comparator mismatch",
+            ProblemKind::Undecryptable => "undecryptable",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.path.display(), self.kind, self.detail)?;
+        if self.quarantined {
+            write!(f, " (quarantined)")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "checked {} segments and {} tables, found {} problems",
+            self.segments_checked,
+            self.tables_checked,
+            self.problems.len()
+        )?;
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::agent::Agent;
+    use crate::comparator::{BytewiseComparator, Comparator, ReverseBytewiseComparator};
+    use crate::encryption::{StaticKeyProvider, KEY_LENGTH};
+    use crate::fs::{FileSystem, MemFileSystem};
+    use crate::table::TableBuilder;
+
+    fn config(fs: &MemFileSystem) -> Config {
+        Config {
+            log_dir: "/data/log".into(),
+            sstable_dir: "/data/sstable".into(),
+            fs: Arc::new(fs.clone()),
+            ..Config::default()
+        }
+    }
+
+    // Writes a few records and ingests two tables, 000001.sst and 000002.sst.
+    fn populate(fs: &MemFileSystem) {
+        populate_with(fs, config(fs));
+    }
+
+    fn populate_with(fs: &MemFileSystem, cfg: Config) {
+        let mut agent = Agent::open(cfg).unwrap();
+        agent.put(b"z", b"1").unwrap();
+        fs.create_dir_all(Path::new("/external")).unwrap();
+        let mut paths = Vec::new();
+        for (name, prefix) in &[("a.sst", "a"), ("b.sst", "b")] {
+            let path = Path::new("/external").join(name);
+            let mut builder = TableBuilder::create(fs, &path, Arc::new(BytewiseComparator)).unwrap();
+            for i in 0..500 {
+                builder.put(format!("{}{:04}", prefix, i).as_bytes(), b"value").unwrap();
+            }
+            builder.finish().unwrap();
+            paths.push(path);
+        }
+        agent.ingest_files(&paths).unwrap();
+        agent.delete(b"z").unwrap();
+    }
+
+    fn kinds(report: &FsckReport) -> Vec<(ProblemKind, PathBuf)> {
+        report.problems.iter().map(|p| (p.kind, p.path.clone())).collect()
+    }
+
+    fn replace(fs: &MemFileSystem, path: &Path, contents: &[u8]) {
+        let mut file = fs.open_append(path).unwrap();
+        file.set_len(0).unwrap();
+        file.append(contents).unwrap();
+    }
+
+    #[test]
+    fn test_clean() {
+        let fs = MemFileSystem::new();
+        populate(&fs);
+        let report = fsck(&config(&fs), false).unwrap();
+        assert_eq!(1, report.segments_checked);
+        assert_eq!(2, report.tables_checked);
+        assert!(report.is_clean(), "{}", report);
+
+        let cfg = Config {
+            comparator: Arc::new(ReverseBytewiseComparator),
+            ..config(&fs)
+        };
+        // The tables are not checked against a configuration that does not
+        // match the store, and nothing is quarantined.
+        let report = fsck(&cfg, true).unwrap();
+        let segment = PathBuf::from("/data/log/00000000000000000000.log");
+        assert_eq!(vec![(ProblemKind::ComparatorMismatch, segment)], kinds(&report));
+        assert_eq!(0, report.tables_checked);
+        assert!(!report.problems[0].quarantined);
+        assert!(fsck(&config(&fs), false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let fs = MemFileSystem::new();
+        let cfg = |key: u8| Config {
+            key_provider: Some(Arc::new(StaticKeyProvider::new(5, [key; KEY_LENGTH]))),
+            ..config(&fs)
+        };
+        populate_with(&fs, cfg(1));
+        let report = fsck(&cfg(1), false).unwrap();
+        assert_eq!(2, report.tables_checked);
+        assert!(report.is_clean(), "{}", report);
+
+        // Without the key, or with the wrong one, the segment cannot be
+        // read. That is not mistaken for corruption.
+        let segment = PathBuf::from("/data/log/00000000000000000000.log");
+        for cfg in [config(&fs), cfg(2)] {
+            let report = fsck(&cfg, true).unwrap();
+            assert_eq!(vec![(ProblemKind::Undecryptable, segment.clone())], kinds(&report));
+            assert_eq!(0, report.tables_checked);
+            assert!(!report.problems[0].quarantined);
+        }
+        assert!(fsck(&cfg(1), false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_problems() {
+        let fs = MemFileSystem::new();
+        populate(&fs);
+        let segment = PathBuf::from("/data/log/00000000000000000000.log");
+        let first = PathBuf::from("/data/sstable/000001.sst");
+        let second = PathBuf::from("/data/sstable/000002.sst");
+        let orphan = PathBuf::from("/data/sstable/000003.sst");
+
+        // Flip a byte in the second data block, which unlike the first is
+        // not read when the table is opened. Blocks are cut at 4KiB.
+        let mut contents = fs.contents(&first).unwrap();
+        contents[4200] ^= 0xff;
+        replace(&fs, &first, &contents);
+        let contents = fs.contents(&second).unwrap();
+        replace(&fs, &orphan, &contents);
+        fs.rename(&second, Path::new("/elsewhere.sst")).unwrap();
+        // Cut the last record short, as a crash during the append would.
+        let contents = fs.contents(&segment).unwrap();
+        replace(&fs, &segment, &contents[..contents.len() - 3]);
+
+        let report = fsck(&config(&fs), false).unwrap();
+        let expected = vec![
+            (ProblemKind::TornTail, segment.clone()),
+            (ProblemKind::Corrupt, first.clone()),
+            (ProblemKind::UnreferencedTable, orphan),
+            (ProblemKind::MissingTable, second),
+        ];
+        assert_eq!(expected, kinds(&report));
+        assert!(report.problems.iter().all(|p| !p.quarantined));
+        assert!(fs.contents(&first).is_some());
+
+        // A bad checksum in the middle of the log is corruption rather than a
+        // torn write.
+        let mut contents = fs.contents(&segment).unwrap();
+        // The key of the first record, after the file header (10 bytes, the
+        // comparator name and no merge operator name) and the record header.
+        contents[10 + BytewiseComparator.name().len() + 13] ^= 0xff;
+        replace(&fs, &segment, &contents);
+        let report = fsck(&config(&fs), false).unwrap();
+        assert_eq!(ProblemKind::Corrupt, report.problems[0].kind);
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let fs = MemFileSystem::new();
+        populate(&fs);
+        let first = PathBuf::from("/data/sstable/000001.sst");
+        let second = PathBuf::from("/data/sstable/000002.sst");
+        let contents = fs.contents(&first).unwrap();
+        replace(&fs, &first, &contents[..contents.len() - 1]);
+
+        let report = fsck(&config(&fs), true).unwrap();
+        assert_eq!(vec![(ProblemKind::Unreadable, first.clone())], kinds(&report));
+        assert!(report.problems[0].quarantined);
+        assert!(fs.contents(&first).is_none());
+        assert!(fs.contents(Path::new("/data/sstable/quarantine/000001.sst")).is_some());
+        assert!(fs.contents(&second).is_some());
+
+        // The quarantined table is now reported as missing.
+        let report = fsck(&config(&fs), true).unwrap();
+        assert_eq!(vec![(ProblemKind::MissingTable, first)], kinds(&report));
+    }
+}
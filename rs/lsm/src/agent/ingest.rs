@@ -0,0 +1,463 @@
+use std::cmp::Ordering;
+use std::io::{self, Cursor};
+use std::path::Path;
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::Agent;
+use crate::compaction::TableInfo;
+use crate::fs::FileSystem;
+use crate::log::RecordType;
+use crate::table::{Table, TableProperties};
+
+const COPY_BUFFER_SIZE: usize = 1 << 20;
+
+impl Agent {
+    // Loads tables built with `TableBuilder` into the store, without going
+    // through the log and memtable.
+    //
+    // All files are validated before anything is changed: each must be a
+    // well-formed table built with the store's comparator, and their key
+    // ranges must not overlap each other. If any of them overlaps a write
+    // that is still in the memtable, the memtable is flushed first so that
+    // the ingested data is newer than it. The files are then copied into the
+    // store and linked in atomically by a single log record, which gives all
+    // of them the same sequence number. Each table is placed in the bottom
+    // level if it does not overlap an existing table, and in level 0
+    // otherwise. The ingestion is durable once this returns, and is followed
+    // by any compactions that it calls for.
+    //
+    // Encrypted files can be ingested if the store's key provider has their
+    // keys. When a key provider is configured, the copies are encrypted with
+    // its current key.
+    pub fn ingest_files<P: AsRef<Path>>(&mut self, paths: &[P]) -> io::Result<()> {
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let path = path.as_ref();
+            // A table built with another comparator is rejected on open.
+            let file = self.fs.open(path)?;
+            let table = Table::open(file, 0, None, Arc::clone(&self.comparator), self.dir.key_provider(), false)?;
+            table.verify()?;
+            files.push((path, table));
+        }
+        let cmp = self.comparator.as_ref();
+        files.sort_by(|(_, a), (_, b)| cmp.compare(&a.properties().smallest, &b.properties().smallest));
+        for pair in files.windows(2) {
+            if cmp.compare(&pair[0].1.properties().largest, &pair[1].1.properties().smallest) != Ordering::Less {
+                return Err(invalid_input(format!(
+                    "Ingested files {} and {} overlap",
+                    pair[0].0.display(),
+                    pair[1].0.display()
+                )));
+            }
+        }
+        let overlaps_memtable = files.iter().any(|(_, table)| {
+            let props = table.properties();
+            self.memtable.overlaps(&props.smallest, &props.largest)
+        });
+        if overlaps_memtable {
+            self.flush()?;
+        }
+
+        let seq = self.last_seq + 1;
+        let mut copies = Vec::with_capacity(files.len());
+        let linked = match self.copy_tables(&files, seq, &mut copies) {
+            Ok(linked) => linked,
+            Err(err) => {
+                self.dir.remove_unlinked(&copies);
+                return Err(err);
+            }
+        };
+
+        // From here on the copies are left in place on failure: the record
+        // may have reached the log. If it did not, they are deleted when the
+        // store is next opened.
+        let record = encode_record(linked.iter().map(|(info, _)| info));
+        self.log.append(RecordType::IngestTables, &[], &record)?;
+        self.log.sync()?;
+        self.last_seq = seq;
+        for (info, table) in linked {
+            self.tables.add(info, table);
+        }
+        self.tables_changed();
+        self.compact_unless_in_background()
+    }
+
+    // Copies `files` into the store as tables with sequence number `seq`. The
+    // file number of each copy is added to `copies` before it is written.
+    fn copy_tables(&self, files: &[(&Path, Table)], seq: u64, copies: &mut Vec<u64>) -> io::Result<Vec<(TableInfo, Table)>> {
+        let mut linked = Vec::with_capacity(files.len());
+        for (path, table) in files {
+            let file_number = self.dir.new_file_number();
+            copies.push(file_number);
+            match self.dir.key_provider() {
+                Some(_) => self.rewrite_table(table, file_number)?,
+                None => copy_file(self.fs.as_ref(), path, &self.dir.table_path(file_number))?,
+            }
+            let level = self.ingest_level(table.properties());
+            linked.push(self.dir.open_table(file_number, level, seq)?);
+        }
+        self.dir.sync()?;
+        Ok(linked)
+    }
+
+    fn ingest_level(&self, props: &TableProperties) -> usize {
+        if self.tables.overlaps(&props.smallest, &props.largest) {
+            0
+        } else {
+            self.compaction_strategy.num_levels() - 1
+        }
+    }
+
+    // Writes the entries and range deletions of `table` to a new table in the
+    // store, encrypted with the key provider's current key.
+    fn rewrite_table(&self, table: &Table, file_number: u64) -> io::Result<()> {
+        let mut builder = self.dir.create(file_number)?;
+        for entry in table.iter() {
+            let (key, value) = entry?;
+            builder.add(&key, &value)?;
+        }
+        for fragment in table.range_tombstones().iter() {
+            builder.delete_range(&fragment.start, &fragment.end);
+        }
+        builder.finish().map(|_| ())
+    }
+}
+
+// Table record format (the value of `RecordType::IngestTables` log
+// records):
+// +-------+---------+-...-+---------+
+// | count | table_0 | ... | table_n |
+// +-------+---------+-...-+---------+
+//  4 bytes
+//
+// Table:
+// +-------------+-------+
+// | file_number | level |
+// +-------------+-------+
+//     8 bytes    4 bytes
+pub(super) fn encode_record<'a>(tables: impl ExactSizeIterator<Item = &'a TableInfo>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.write_u32::<LittleEndian>(tables.len() as u32).unwrap();
+    for info in tables {
+        buf.write_u64::<LittleEndian>(info.file_number).unwrap();
+        buf.write_u32::<LittleEndian>(info.level as u32).unwrap();
+    }
+    buf
+}
+
+// Returns the file number and level of each table in a table record.
+pub(super) fn decode_record(buf: &[u8]) -> io::Result<Vec<(u64, usize)>> {
+    let mut cursor = Cursor::new(buf);
+    let count = cursor.read_u32::<LittleEndian>()?;
+    let mut tables = Vec::new();
+    for _ in 0..count {
+        let file_number = cursor.read_u64::<LittleEndian>()?;
+        let level = cursor.read_u32::<LittleEndian>()? as usize;
+        tables.push((file_number, level));
+    }
+    if cursor.position() != buf.len() as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Table record has trailing bytes"));
+    }
+    Ok(tables)
+}
+
+// Copies `from` to `to` and syncs the copy. Anything already at `to` is
+// replaced.
+fn copy_file(fs: &dyn FileSystem, from: &Path, to: &Path) -> io::Result<()> {
+    let src = fs.open(from)?;
+    let mut dst = fs.open_append(to)?;
+    dst.set_len(0)?;
+    let len = src.size()?;
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    let mut offset = 0;
+    while offset < len {
+        let n = COPY_BUFFER_SIZE.min((len - offset) as usize);
+        src.read_exact_at(&mut buf[..n], offset)?;
+        dst.append(&buf[..n])?;
+        offset += n as u64;
+    }
+    dst.sync()
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::super::config::Config;
+    use super::*;
+    use crate::comparator::{BytewiseComparator, ReverseBytewiseComparator};
+    use crate::encryption::{StaticKeyProvider, KEY_LENGTH};
+    use crate::fs::{FileSystem, FsOp, MemFileSystem};
+    use crate::merge::MergeOperator;
+    use crate::table::{ComparatorMismatch, TableBuilder};
+
+    fn config(fs: &MemFileSystem) -> Config {
+        Config {
+            log_dir: "/data/log".into(),
+            sstable_dir: "/data/sstable".into(),
+            fs: Arc::new(fs.clone()),
+            ..Config::default()
+        }
+    }
+
+    // Builds a table at /external/`name` holding `entries`, where a `None`
+    // value is a deletion.
+    fn build(fs: &MemFileSystem, name: &str, entries: &[(&str, Option<&str>)]) -> PathBuf {
+        fs.create_dir_all(Path::new("/external")).unwrap();
+        let path = Path::new("/external").join(name);
+        let mut builder = TableBuilder::create(fs, &path, Arc::new(BytewiseComparator)).unwrap();
+        for (key, value) in entries {
+            match value {
+                Some(v) => builder.put(key.as_bytes(), v.as_bytes()).unwrap(),
+                None => builder.delete(key.as_bytes()).unwrap(),
+            }
+        }
+        builder.finish().unwrap();
+        fs.sync_dir(Path::new("/external")).unwrap();
+        path
+    }
+
+    fn scan(agent: &Agent, start: &[u8], end: &[u8]) -> Vec<(String, String)> {
+        agent
+            .scan(start, end)
+            .map(|e| {
+                let (k, v) = e.unwrap();
+                (String::from_utf8(k).unwrap(), String::from_utf8(v).unwrap())
+            })
+            .collect()
+    }
+
+    fn pairs(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_ingest() {
+        let fs = MemFileSystem::new();
+        let mut agent = Agent::open(config(&fs)).unwrap();
+        agent.put(b"z", b"memtable").unwrap();
+
+        let first = build(&fs, "first.sst", &[("a", Some("1")), ("b", Some("2")), ("c", Some("3"))]);
+        let second = build(&fs, "second.sst", &[("m", Some("4")), ("n", Some("5"))]);
+        agent.ingest_files(&[second, first]).unwrap();
+        assert_eq!(Some(b"2".to_vec()), agent.get(b"b").unwrap());
+        let levels = agent.tables.infos().map(|t| t.level).collect::<Vec<_>>();
+        assert_eq!(vec![6, 6], levels);
+
+        // Newer writes mask the ingested data.
+        agent.put(b"a", b"new").unwrap();
+        agent.delete(b"b").unwrap();
+        agent.delete_range(b"m", b"n").unwrap();
+        let expected = pairs(&[("a", "new"), ("c", "3"), ("n", "5"), ("z", "memtable")]);
+        assert_eq!(expected, scan(&agent, b"", b"\xff"));
+        assert_eq!(expected[1..3].to_vec(), scan(&agent, b"c", b"z"));
+
+        // A later ingestion that overlaps existing tables lands in level 0
+        // and masks them.
+        let third = build(&fs, "third.sst", &[("c", None), ("d", Some("6"))]);
+        agent.ingest_files(&[third]).unwrap();
+        assert_eq!(None, agent.get(b"c").unwrap());
+        assert_eq!(Some(0), agent.tables.infos().map(|t| t.level).next());
+
+        drop(agent);
+        let agent = Agent::open(config(&fs)).unwrap();
+        let expected = pairs(&[("a", "new"), ("d", "6"), ("n", "5"), ("z", "memtable")]);
+        assert_eq!(expected, scan(&agent, b"", b"\xff"));
+
+        // Mapped tables read the same data without going through the cache.
+        drop(agent);
+        let agent = Agent::open(Config {
+            mmap_reads: true,
+            ..config(&fs)
+        })
+        .unwrap();
+        assert_eq!(expected, scan(&agent, b"", b"\xff"));
+        assert_eq!(0, agent.block_cache.stats().misses);
+    }
+
+    #[test]
+    fn test_ingest_validation() {
+        let fs = MemFileSystem::new();
+        let mut agent = Agent::open(config(&fs)).unwrap();
+        agent.put(b"k", b"memtable").unwrap();
+
+        let ab = build(&fs, "ab.sst", &[("a", Some("1")), ("b", Some("2"))]);
+        let bc = build(&fs, "bc.sst", &[("b", Some("3")), ("c", Some("4"))]);
+        let err = agent.ingest_files(&[ab.clone(), bc]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        let mut contents = fs.contents(&ab).unwrap();
+        contents[0] ^= 0xff;
+        let corrupt = Path::new("/external/corrupt.sst");
+        fs.open_append(corrupt).unwrap().append(&contents).unwrap();
+        let err = agent.ingest_files(&[corrupt]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // Nothing was linked in by the failed ingestions.
+        assert_eq!(None, agent.get(b"a").unwrap());
+        assert_eq!(0, agent.tables.infos().count());
+        agent.ingest_files(&[ab]).unwrap();
+        assert_eq!(Some(b"1".to_vec()), agent.get(b"a").unwrap());
+    }
+
+    #[test]
+    fn test_ingest_flushes_memtable() {
+        let fs = MemFileSystem::new();
+        let mut agent = Agent::open(config(&fs)).unwrap();
+        agent.put(b"j", b"memtable").unwrap();
+        agent.put(b"k", b"memtable").unwrap();
+        agent.put(b"z", b"memtable").unwrap();
+
+        // The ingested table overlaps the memtable, so the memtable is
+        // flushed and the ingested values are newer than it.
+        let jl = build(&fs, "jl.sst", &[("j", Some("5")), ("l", Some("6"))]);
+        agent.ingest_files(&[jl]).unwrap();
+        assert_eq!(0, agent.stats().memtable_bytes);
+        assert_eq!(vec![0, 0], agent.tables.infos().map(|t| t.level).collect::<Vec<_>>());
+        let expected = pairs(&[("j", "5"), ("k", "memtable"), ("l", "6"), ("z", "memtable")]);
+        assert_eq!(expected, scan(&agent, b"", b"\xff"));
+
+        drop(agent);
+        let agent = Agent::open(config(&fs)).unwrap();
+        assert_eq!(expected, scan(&agent, b"", b"\xff"));
+    }
+
+    #[test]
+    fn test_ingest_is_atomic() {
+        let fs = MemFileSystem::new();
+        let mut agent = Agent::open(config(&fs)).unwrap();
+        let first = build(&fs, "first.sst", &[("a", Some("1"))]);
+        let second = build(&fs, "second.sst", &[("b", Some("2"))]);
+        let sstables = || fs.read_dir(Path::new("/data/sstable")).unwrap();
+
+        // A failed copy removes the copies made so far.
+        fs.fail_nth(FsOp::Sync, 1);
+        assert!(agent.ingest_files(&[first.clone(), second.clone()]).is_err());
+        assert!(sstables().is_empty());
+
+        // Fail the sync of the log record that links the tables in. The
+        // table copies have already been synced by then.
+        fs.fail_nth(FsOp::Sync, 2);
+        assert!(agent.ingest_files(&[first.clone(), second.clone()]).is_err());
+        fs.crash();
+        assert_eq!(2, sstables().len());
+        let mut agent = Agent::open(config(&fs)).unwrap();
+        assert_eq!(None, agent.get(b"a").unwrap());
+        assert_eq!(None, agent.get(b"b").unwrap());
+
+        // The copies left behind were not linked in, so opening deleted them.
+        assert!(sstables().is_empty());
+        agent.ingest_files(&[first, second]).unwrap();
+        assert_eq!(2, sstables().len());
+        fs.crash();
+        let agent = Agent::open(config(&fs)).unwrap();
+        assert_eq!(Some(b"2".to_vec()), agent.get(b"b").unwrap());
+    }
+
+    #[test]
+    fn test_ingest_with_comparator() {
+        let fs = MemFileSystem::new();
+        let cfg = || Config {
+            comparator: Arc::new(ReverseBytewiseComparator),
+            ..config(&fs)
+        };
+        let mut agent = Agent::open(cfg()).unwrap();
+        agent.put(b"z", b"memtable").unwrap();
+
+        // A table built bytewise is rejected by the comparator in its footer.
+        let bytewise = build(&fs, "bytewise.sst", &[("a", Some("1")), ("b", Some("2"))]);
+        let err = agent.ingest_files(&[bytewise]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert!(ComparatorMismatch::from_io_error(&err).is_some());
+
+        let path = Path::new("/external/reverse.sst");
+        let mut builder = TableBuilder::create(&fs, path, Arc::new(ReverseBytewiseComparator)).unwrap();
+        for key in &["c", "b", "a"] {
+            builder.put(key.as_bytes(), key.as_bytes()).unwrap();
+        }
+        builder.finish().unwrap();
+        agent.ingest_files(&[path]).unwrap();
+        let expected = pairs(&[("z", "memtable"), ("c", "c"), ("b", "b"), ("a", "a")]);
+        assert_eq!(expected, scan(&agent, b"\xff", b""));
+        assert_eq!(expected[2..].to_vec(), scan(&agent, b"b", b""));
+
+        drop(agent);
+        let agent = Agent::open(cfg()).unwrap();
+        assert_eq!(Some(b"b".to_vec()), agent.get(b"b").unwrap());
+    }
+
+    #[test]
+    fn test_ingest_encrypts_copies() {
+        let fs = MemFileSystem::new();
+        let cfg = || Config {
+            key_provider: Some(Arc::new(StaticKeyProvider::new(7, [3u8; KEY_LENGTH]))),
+            ..config(&fs)
+        };
+        let mut agent = Agent::open(cfg()).unwrap();
+        let path = build(&fs, "plain.sst", &[("apple", Some("red")), ("banana", None), ("cherry", Some("dark"))]);
+        agent.ingest_files(&[path]).unwrap();
+
+        // The copy in the store is encrypted, unlike the table it came from.
+        let copies = fs.read_dir(Path::new("/data/sstable")).unwrap();
+        assert_eq!(1, copies.len());
+        let contents = fs.contents(&copies[0]).unwrap();
+        assert!(!contents.windows(5).any(|w| w == b"apple"));
+        assert!(!contents.windows(4).any(|w| w == b"dark"));
+        let file = fs.open(&copies[0]).unwrap();
+        let err = Table::open(file, 0, None, Arc::new(BytewiseComparator), None, false).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        drop(agent);
+        let agent = Agent::open(cfg()).unwrap();
+        assert_eq!(pairs(&[("apple", "red"), ("cherry", "dark")]), scan(&agent, b"", b"\xff"));
+        drop(agent);
+        assert!(crate::agent::fsck(&cfg(), false).unwrap().is_clean());
+    }
+
+    struct Append;
+
+    impl MergeOperator for Append {
+        fn name(&self) -> &str {
+            "append"
+        }
+
+        fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+            let mut value = existing.unwrap_or_default().to_vec();
+            value.extend_from_slice(operand);
+            value
+        }
+    }
+
+    #[test]
+    fn test_merge_and_transactions() {
+        let fs = MemFileSystem::new();
+        let cfg = || Config {
+            merge_operator: Some(Arc::new(Append)),
+            ..config(&fs)
+        };
+        let mut agent = Agent::open(cfg()).unwrap();
+        let path = build(&fs, "t.sst", &[("a", Some("x")), ("b", None)]);
+        // Ingestion conflicts with transactions that read the ingested keys.
+        let mut txn = agent.begin_transaction();
+        txn.get(&agent, b"a").unwrap();
+        txn.put(b"c", b"1");
+        agent.ingest_files(&[path]).unwrap();
+        assert!(txn.commit(&mut agent).unwrap_err().is_retryable());
+
+        agent.merge(b"a", b"y").unwrap();
+        agent.merge(b"b", b"y").unwrap();
+        assert_eq!(Some(b"xy".to_vec()), agent.get(b"a").unwrap());
+        assert_eq!(Some(b"y".to_vec()), agent.get(b"b").unwrap());
+
+        agent.sync().unwrap();
+        drop(agent);
+        let agent = Agent::open(cfg()).unwrap();
+        assert_eq!(Some(b"xy".to_vec()), agent.get(b"a").unwrap());
+    }
+}
@@ -0,0 +1,137 @@
+use std::cmp::Ordering;
+use std::io;
+use std::iter::Peekable;
+
+use crate::comparator::Comparator;
+use crate::table::TableValue;
+
+pub(crate) type Source<'a, V> = Box<dyn Iterator<Item = io::Result<(Vec<u8>, V)>> + 'a>;
+
+// Merges sources sorted by `cmp` into one sorted iterator. Each key is
+// returned once, with the entries that the sources have for it, in the order
+// of `sources`, and the index of the source that each came from. Iteration
+// stops after the first error.
+pub(crate) struct MergingIter<'a, V> {
+    cmp: &'a dyn Comparator,
+    sources: Vec<Peekable<Source<'a, V>>>,
+    failed: bool,
+}
+
+impl<'a, V> MergingIter<'a, V> {
+    pub(crate) fn new(cmp: &'a dyn Comparator, sources: Vec<Source<'a, V>>) -> MergingIter<'a, V> {
+        MergingIter {
+            cmp,
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+            failed: false,
+        }
+    }
+}
+
+impl<'a, V> Iterator for MergingIter<'a, V> {
+    type Item = io::Result<(Vec<u8>, Vec<(V, usize)>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let cmp = self.cmp;
+        let mut min: Option<(usize, Vec<u8>)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Ok((key, _)))
+                    if min.as_ref().is_none_or(|(_, min_key)| cmp.compare(key, min_key) == Ordering::Less) =>
+                {
+                    min = Some((i, key.clone()));
+                }
+                Some(Err(_)) => {
+                    self.failed = true;
+                    return source.next().map(|entry| Err(entry.err().unwrap()));
+                }
+                _ => {}
+            }
+        }
+
+        let (i, key) = min?;
+        let (_, value) = self.sources[i].next().unwrap().unwrap();
+        let mut entries = vec![(value, i)];
+        for (j, source) in self.sources.iter_mut().enumerate().skip(i + 1) {
+            if let Some(Ok((_, value))) = source.next_if(|entry| matches!(entry, Ok((k, _)) if *k == key)) {
+                entries.push((value, j));
+            }
+        }
+        Some(Ok((key, entries)))
+    }
+}
+
+// The versions of a key that `MergingIter` returned, newest first, down to
+// the first one that is not a merge operand. Where a range deletion masks a
+// version, a deletion takes its place and ends the versions. `covered(n,
+// key)` is whether a range deletion in one of the first `n` sources covers
+// `key`. A source's range deletions mask only the older sources.
+pub(crate) fn versions<F>(
+    key: &[u8],
+    entries: Vec<(TableValue, usize)>,
+    covered: F,
+) -> impl Iterator<Item = io::Result<TableValue>>
+where
+    F: Fn(usize, &[u8]) -> bool,
+{
+    let mut versions = Vec::with_capacity(entries.len());
+    let mut operands_end = 0;
+    for (value, source) in entries {
+        if covered(source, key) {
+            versions.push(TableValue::Deleted);
+            return versions.into_iter().map(Ok);
+        }
+        let is_operand = matches!(value, TableValue::Merge(_));
+        versions.push(value);
+        if !is_operand {
+            return versions.into_iter().map(Ok);
+        }
+        operands_end = source + 1;
+    }
+    // There is no value below the operands, but a range deletion in the
+    // source of the oldest one may still mask the older sources.
+    if covered(operands_end, key) {
+        versions.push(TableValue::Deleted);
+    }
+    versions.into_iter().map(Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparator::BytewiseComparator;
+
+    fn source<'a>(entries: &'a [(&'a str, Option<&'a str>)]) -> Source<'a, Option<Vec<u8>>> {
+        Box::new(
+            entries
+                .iter()
+                .map(|(k, v)| Ok((k.as_bytes().to_vec(), v.map(|v| v.as_bytes().to_vec())))),
+        )
+    }
+
+    #[test]
+    fn test_merge() {
+        let newer = [("a", Some("1")), ("c", None), ("d", Some("new"))];
+        let older = [("b", Some("2")), ("c", Some("3")), ("d", Some("old")), ("e", Some("4"))];
+        let merged = MergingIter::new(&BytewiseComparator, vec![source(&newer), source(&older)])
+            .map(|e| {
+                let (k, entries) = e.unwrap();
+                let entries = entries
+                    .into_iter()
+                    .map(|(v, i)| (v.map(|v| String::from_utf8(v).unwrap()), i))
+                    .collect::<Vec<_>>();
+                (String::from_utf8(k).unwrap(), entries)
+            })
+            .collect::<Vec<_>>();
+        let expected = vec![
+            ("a".to_string(), vec![(Some("1".to_string()), 0)]),
+            ("b".to_string(), vec![(Some("2".to_string()), 1)]),
+            ("c".to_string(), vec![(None, 0), (Some("3".to_string()), 1)]),
+            ("d".to_string(), vec![(Some("new".to_string()), 0), (Some("old".to_string()), 1)]),
+            ("e".to_string(), vec![(Some("4".to_string()), 1)]),
+        ];
+        assert_eq!(expected, merged);
+    }
+}
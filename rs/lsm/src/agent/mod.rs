@@ -1,20 +1,499 @@
-use crate::log;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use crate::batch::WriteBatch;
+use crate::cache::BlockCache;
+use crate::comparator::Comparator;
+use crate::compaction::CompactionStrategy;
+use crate::fs::FileSystem;
+use crate::log::segment::{self, Segment};
+use crate::log::{LogEntry, RecordType};
+use crate::memtable::Memtable;
+use crate::merge::{self, MergeOperator};
+use crate::stats::{CompactionStats, Histogram, LevelStats, LogStats, Stats};
+use crate::rate_limiter::RateLimiter;
+use crate::table::TableValue;
+use merging_iter::{MergingIter, Source};
+use table_dir::TableDir;
+use table_set::TableSet;
+use write_controller::{WriteController, WriteState};
+
+#[cfg(feature = "async")]
+pub mod async_agent;
+mod compact;
 pub mod config;
+mod flush;
+pub mod fsck;
+mod ingest;
+mod merging_iter;
+mod table_dir;
+mod table_set;
+pub mod transaction;
+pub mod write_controller;
+
+#[cfg(feature = "async")]
+pub use async_agent::{AsyncAgent, Scan};
+pub use fsck::{fsck, FsckReport, Problem, ProblemKind};
+pub use transaction::{Transaction, TransactionError};
 
 pub struct Agent {
-    // TODO: Use "Log" structure, which manages multiple segments
-    log: log::segment::Segment,
+    // The segment that writes are appended to. Each flush starts a new one,
+    // named after the sequence number of the last record before it, and the
+    // log is replayed from the last segment only. See `flush`.
+    log: Segment,
+    log_dir: PathBuf,
+    // Set while the name of the current segment may not be durable, because
+    // syncing the log directory after renaming it into place failed.
+    log_dir_unsynced: bool,
+    // Counters of the segments that were written to before the current one.
+    retired_log_stats: LogStats,
+    memtable: Memtable,
+    tables: TableSet,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    block_cache: Arc<BlockCache>,
+    fs: Arc<dyn FileSystem>,
+    dir: TableDir,
+    compaction_strategy: Arc<dyn CompactionStrategy>,
+    comparator: Arc<dyn Comparator>,
+    // The memtable is flushed before a write once it holds this many bytes.
+    memtable_bytes: usize,
+    write_controller: Arc<WriteController>,
+    // Paces compaction I/O, if a limit is configured.
+    compaction_rate_limiter: Option<Arc<RateLimiter>>,
+    // Set when compactions are run by a background thread instead of after
+    // each flush and ingestion. See `AsyncAgent`.
+    background_compaction: bool,
+    // The first error from a background compaction. Writes fail once it is
+    // set, since level 0 may no longer be brought down.
+    background_error: Option<io::Error>,
+    compaction_stats: CompactionStats,
+    // Reads take `&self`, so they record their latencies behind a lock.
+    get_latency: Mutex<Histogram>,
+    scan_latency: Mutex<Histogram>,
+    // Sequence number of the most recent log record. Every record, including
+    // a whole write batch, is assigned the next sequence number.
+    last_seq: u64,
 }
 
 impl Agent {
-
     pub fn new(cfg: config::Config) -> Agent {
+        Agent::open(cfg).expect("Error opening log")
+    }
+
+    // Opens the store in `cfg.log_dir` and `cfg.sstable_dir`, creating it if
+    // necessary, and replays the log into the memtable.
+    pub fn open(cfg: config::Config) -> io::Result<Agent> {
         // let log = log::Log::open(format!("{}/{}", cfg.log_dir, "log")).expect("Error opening log");
-        let log = log::segment::Segment::new(cfg.log_dir, 0).expect("Error opening log");
+        let fs = cfg.fs.as_ref();
+        let key_provider = cfg.key_provider.as_deref();
+        fs.create_dir_all(Path::new(&cfg.log_dir))?;
+        let write_controller = Arc::new(WriteController::new(&cfg));
 
-        Agent{
+        let comparator = cfg.comparator;
+        let merge_operator = cfg.merge_operator.as_ref().map(|m| m.name());
+        let log = match segment::list_segment_files(fs, &cfg.log_dir)?.pop() {
+            Some(path) => Segment::open(fs, path, key_provider)?,
+            None => Segment::new(fs, &cfg.log_dir, 0, key_provider, comparator.name(), merge_operator)?,
+        };
+        if log.comparator() != comparator.name() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Store was created with comparator {}, but opened with {}",
+                    log.comparator(),
+                    comparator.name()
+                ),
+            ));
+        }
+        // Merge operands in the store can only be read back with the operator
+        // that they were written for.
+        if log.merge_operator() != merge_operator {
+            let name = |op: Option<&str>| op.unwrap_or("none").to_string();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Store was created with merge operator {}, but opened with {}",
+                    name(log.merge_operator()),
+                    name(merge_operator)
+                ),
+            ));
+        }
+        let block_cache = Arc::new(BlockCache::new(cfg.block_cache_bytes));
+        let dir = TableDir::open(
+            Arc::clone(&cfg.fs),
+            Path::new(&cfg.sstable_dir),
+            Arc::clone(&comparator),
+            cfg.key_provider.clone(),
+            Arc::clone(&block_cache),
+            cfg.mmap_reads,
+        )?;
+        let mut agent = Agent {
             log,
+            log_dir: PathBuf::from(&cfg.log_dir),
+            log_dir_unsynced: false,
+            retired_log_stats: LogStats::default(),
+            memtable: Memtable::new(Arc::clone(&comparator)),
+            tables: TableSet::new(Arc::clone(&comparator)),
+            merge_operator: cfg.merge_operator,
+            block_cache,
+            fs: cfg.fs,
+            dir,
+            compaction_strategy: cfg.compaction_strategy,
+            comparator,
+            memtable_bytes: cfg.memtable_bytes,
+            write_controller,
+            compaction_rate_limiter: cfg.compaction_bytes_per_sec.map(|rate| Arc::new(RateLimiter::new(rate))),
+            background_compaction: false,
+            background_error: None,
+            compaction_stats: CompactionStats::default(),
+            get_latency: Mutex::default(),
+            scan_latency: Mutex::default(),
+            last_seq: 0,
+        };
+        agent.recover()?;
+        // Tables left behind by an ingestion, flush or compaction that did
+        // not complete are not linked in by the log.
+        agent.remove_unreferenced_tables()?;
+        agent.remove_obsolete_segments()?;
+        agent.tables_changed();
+        Ok(agent)
+    }
+
+    pub fn put(&mut self, key: &[u8], val: &[u8]) -> io::Result<()> {
+        self.write_record(RecordType::Put, key, val)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> io::Result<()> {
+        self.write_record(RecordType::Delete, key, &[])
+    }
+
+    // Deletes every key in [start, end) with a single log record.
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) -> io::Result<()> {
+        self.write_record(RecordType::RangeDelete, start, end)
+    }
+
+    // Logs `operand` to be folded into the value of `key` by the configured
+    // merge operator the next time it is read.
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) -> io::Result<()> {
+        self.check_merge_operator()?;
+        self.write_record(RecordType::Merge, key, operand)
+    }
+
+    // Applies all writes in `batch` atomically, as a single log record.
+    pub fn write(&mut self, batch: &WriteBatch) -> io::Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        if batch.ops().iter().any(|op| op.record_type == RecordType::Merge) {
+            self.check_merge_operator()?;
+        }
+        self.make_room_for_write(batch.approximate_size())?;
+        self.log.append(RecordType::Batch, &[], &batch.encode())?;
+        self.last_seq += 1;
+        apply(&mut self.memtable, self.last_seq, batch.ops());
+        Ok(())
+    }
+
+    // Starts an optimistic transaction. See `Transaction`.
+    pub fn begin_transaction(&self) -> Transaction {
+        Transaction::new(self.last_seq)
+    }
+
+    // Makes all writes so far durable. Writes are only guaranteed to survive a
+    // crash once a subsequent call to `sync` has succeeded.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.sync_log_dir()?;
+        self.log.sync()
+    }
+
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let start = Instant::now();
+        let merge_op = self.merge_operator.as_deref();
+        let versions = self.memtable.get(key, merge_op).into_iter().chain(self.tables.versions(key));
+        let value = merge::fold_versions(merge_op, key, versions).and_then(|v| merge::read_value(merge_op, key, v));
+        self.get_latency.lock().unwrap().record(start.elapsed());
+        value
+    }
+
+    // Iterates over the keys in [start, end) in the comparator's order, with
+    // their values. Iteration stops after the first error.
+    pub fn scan<'a>(&'a self, start: &[u8], end: &[u8]) -> impl Iterator<Item = io::Result<(Vec<u8>, Vec<u8>)>> + 'a {
+        let merge_op = self.merge_operator.as_deref();
+        let mut sources: Vec<Source<'a, TableValue>> = vec![Box::new(self.memtable.scan(start, end, merge_op))];
+        for iter in self.tables.iters_from(start) {
+            let end = end.to_vec();
+            let cmp = self.comparator.as_ref();
+            let iter = iter.take_while(move |entry| {
+                entry.as_ref().map_or(true, |(k, _)| cmp.compare(k, &end) == Ordering::Less)
+            });
+            sources.push(Box::new(iter));
+        }
+        // Whether a range deletion in one of the `count` newest sources covers
+        // `key`.
+        let covered = move |count: usize, key: &[u8]| {
+            count > 0 && (self.memtable.covers(key) || self.tables.covered_by_newest(count - 1, key))
+        };
+        let iter = MergingIter::new(self.comparator.as_ref(), sources).filter_map(move |entry| {
+            let read = entry.and_then(|(key, entries)| {
+                let versions = merging_iter::versions(&key, entries, covered);
+                let version = merge::fold_versions(merge_op, &key, versions)?;
+                Ok(merge::read_value(merge_op, &key, version)?.map(|value| (key, value)))
+            });
+            read.transpose()
+        });
+        TimedScan {
+            iter,
+            elapsed: Duration::default(),
+            latency: &self.scan_latency,
+        }
+    }
+
+    // Returns an upper bound on the sequence number of the last write to
+    // `key`.
+    pub(crate) fn last_write_seq(&self, key: &[u8]) -> Option<u64> {
+        self.memtable.last_write_seq(key).max(self.tables.last_write_seq(key))
+    }
+
+    fn write_record(&mut self, record_type: RecordType, key: &[u8], val: &[u8]) -> io::Result<()> {
+        let ops = [LogEntry {
+            record_type,
+            key: key.to_vec(),
+            value: val.to_vec(),
+        }];
+        self.make_room_for_write((key.len() + val.len()) as u64)?;
+        self.log.append(record_type, key, val)?;
+        self.last_seq += 1;
+        apply(&mut self.memtable, self.last_seq, &ops);
+        Ok(())
+    }
+
+    // Flushes the memtable if it is full, and applies backpressure from the
+    // write controller. Without a background compactor, a write that is
+    // stopped runs the compactions itself, and fails if level 0 is still at
+    // the stop trigger after them.
+    fn make_room_for_write(&mut self, bytes: u64) -> io::Result<()> {
+        if let Some(err) = &self.background_error {
+            return Err(io::Error::new(err.kind(), format!("Background compaction failed: {}", err)));
+        }
+        if self.memtable.approximate_size() >= self.memtable_bytes {
+            self.flush()?;
+        }
+        // `AsyncAgent` waits on the controller before taking the agent, so
+        // that the compactor can make progress in the meantime.
+        if self.background_compaction {
+            return Ok(());
+        }
+        if self.write_controller.state() == WriteState::Stopped {
+            self.compact()?;
+        }
+        if self.write_controller.state() == WriteState::Stopped {
+            return Err(io::Error::other(format!(
+                "Writes are stopped: {} tables in level 0, and compaction does not reduce them",
+                self.level0_tables()
+            )));
+        }
+        self.write_controller.wait_for_write(bytes);
+        Ok(())
+    }
+
+    // Reports the number of tables in level 0 to the write controller. Must be
+    // called whenever tables are added or removed.
+    fn tables_changed(&self) {
+        self.write_controller.set_level0_tables(self.level0_tables());
+    }
+
+    fn level0_tables(&self) -> usize {
+        self.tables.infos().filter(|t| t.level == 0).count()
+    }
+
+    // Replays the log into the memtable and links in the tables recorded in
+    // it. Only the last segment is read: any before it were left behind by a
+    // flush, whose record at the start of the last segment links in every
+    // table that they did. A record that runs past the end of the segment is
+    // the tail of a write that was interrupted by a crash, and it is
+    // truncated away. Any other bad record means the log is damaged, and
+    // opening fails rather than silently dropping the writes after it. This
+    // is the same rule that `fsck` uses to tell a torn tail from corruption.
+    fn recover(&mut self) -> io::Result<()> {
+        let mut iter = self.log.iter();
+        let mut torn = None;
+        let mut seq = self.log.base_offset();
+        // The tables linked in, with their level and largest sequence number.
+        // They are only opened once the whole log has been read, since a
+        // later compaction may have removed them.
+        let mut tables = BTreeMap::new();
+        while let Some(record) = iter.next() {
+            let entry = match record {
+                Ok((_, entry)) => entry,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    torn = Some(iter.offset());
+                    break;
+                }
+                Err(err) => {
+                    return Err(io::Error::new(
+                        err.kind(),
+                        format!("Log record at offset {} is unreadable: {}", iter.offset(), err),
+                    ))
+                }
+            };
+            seq += 1;
+            match entry.record_type {
+                RecordType::IngestTables => {
+                    for (file_number, level) in ingest::decode_record(&entry.value)? {
+                        tables.insert(file_number, (level, seq));
+                    }
+                }
+                // The writes before a flush are in its table, and it links
+                // in every table.
+                RecordType::Flush => {
+                    self.memtable = Memtable::new(Arc::clone(&self.comparator));
+                    tables.clear();
+                    for (file_number, level, largest_seq) in flush::decode_record(&entry.value)? {
+                        tables.insert(file_number, (level, largest_seq));
+                    }
+                }
+                RecordType::Compaction => {
+                    let (removed, added) = compact::decode_record(&entry.value)?;
+                    for file_number in removed {
+                        tables.remove(&file_number);
+                    }
+                    for (file_number, level, largest_seq) in added {
+                        tables.insert(file_number, (level, largest_seq));
+                    }
+                }
+                RecordType::Batch => apply(&mut self.memtable, seq, WriteBatch::decode(&entry.value)?.ops()),
+                _ => apply(&mut self.memtable, seq, &[entry]),
+            }
+        }
+
+        for (file_number, (level, largest_seq)) in tables {
+            let (info, table) = self.dir.open_table(file_number, level, largest_seq)?;
+            self.tables.add(info, table);
+        }
+
+        if let Some(offset) = torn {
+            self.log.truncate(offset)?;
+        }
+        self.last_seq = seq;
+        Ok(())
+    }
+
+    // Deletes the segments before the current one, and any that were never
+    // renamed into place.
+    fn remove_obsolete_segments(&self) -> io::Result<()> {
+        let fs = self.fs.as_ref();
+        let current = segment::segment_file_path(&self.log_dir, self.log.base_offset());
+        let mut obsolete = segment::list_segment_files(fs, &self.log_dir)?;
+        obsolete.retain(|path| *path != current);
+        obsolete.extend(segment::list_temp_segment_files(fs, &self.log_dir)?);
+        if obsolete.is_empty() {
+            return Ok(());
+        }
+        for path in obsolete {
+            fs.remove(&path)?;
+        }
+        fs.sync_dir(&self.log_dir)
+    }
+
+    // Syncs the log directory if the current segment's name may not be
+    // durable yet. Writes to the segment are not durable until it is.
+    fn sync_log_dir(&mut self) -> io::Result<()> {
+        if self.log_dir_unsynced {
+            self.fs.sync_dir(&self.log_dir)?;
+            self.log_dir_unsynced = false;
+        }
+        Ok(())
+    }
+
+    // Deletes the table files that no log record links in.
+    fn remove_unreferenced_tables(&self) -> io::Result<()> {
+        let linked = self.tables.infos().map(|t| t.file_number).collect::<HashSet<_>>();
+        for file_number in self.dir.file_numbers()? {
+            if !linked.contains(&file_number) {
+                self.fs.remove(&self.dir.table_path(file_number))?;
+            }
+        }
+        self.dir.sync()
+    }
+
+    fn check_merge_operator(&self) -> io::Result<()> {
+        if self.merge_operator.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Agent::merge requires a merge operator to be configured",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut log = self.retired_log_stats.clone();
+        log.add(self.log.stats());
+        Stats {
+            log,
+            memtable_bytes: self.memtable.approximate_size() as u64,
+            block_cache: self.block_cache.stats(),
+            compaction: self.compaction_stats.clone(),
+            levels: self.level_stats(),
+            filter: self.dir.filter_stats(),
+            get_latency: self.get_latency.lock().unwrap().clone(),
+            scan_latency: self.scan_latency.lock().unwrap().clone(),
+        }
+    }
+
+    fn level_stats(&self) -> Vec<LevelStats> {
+        let mut levels = vec![LevelStats::default(); self.compaction_strategy.num_levels()];
+        for info in self.tables.infos() {
+            if info.level >= levels.len() {
+                levels.resize(info.level + 1, LevelStats::default());
+            }
+            let level = &mut levels[info.level];
+            level.tables += 1;
+            level.bytes += info.size;
+        }
+        levels
+    }
+}
+
+// Adds up the time spent reading a scan, and records it once the scan is
+// dropped.
+struct TimedScan<'a, I> {
+    iter: I,
+    elapsed: Duration,
+    latency: &'a Mutex<Histogram>,
+}
+
+impl<'a, I: Iterator> Iterator for TimedScan<'a, I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        let start = Instant::now();
+        let entry = self.iter.next();
+        self.elapsed += start.elapsed();
+        entry
+    }
+}
+
+impl<'a, I> Drop for TimedScan<'a, I> {
+    fn drop(&mut self) {
+        self.latency.lock().unwrap().record(self.elapsed);
+    }
+}
+
+fn apply(memtable: &mut Memtable, seq: u64, ops: &[LogEntry]) {
+    for LogEntry { record_type, key, value } in ops {
+        match record_type {
+            RecordType::Put => memtable.put(seq, key, value),
+            RecordType::Delete => memtable.delete(seq, key),
+            RecordType::Merge => memtable.merge(seq, key, value),
+            RecordType::RangeDelete => memtable.delete_range(seq, key, value),
+            RecordType::Batch | RecordType::IngestTables | RecordType::Flush | RecordType::Compaction => {
+                unreachable!("{:?} is not a write", record_type)
+            }
         }
     }
 }
@@ -22,12 +501,522 @@ impl Agent {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::comparator::NumericComparator;
+    use crate::compaction::LeveledCompaction;
+    use crate::fs::{FsOp, MemFileSystem};
+    use crate::test_util::TmpDir;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeMap;
+    use std::convert::TryInto;
+
+    fn test_config(dir: &TmpDir) -> config::Config {
+        config::Config {
+            log_dir: dir.as_ref().to_str().unwrap().into(),
+            sstable_dir: dir.as_ref().join("sstable").to_str().unwrap().into(),
+            ..config::Config::default()
+        }
+    }
 
     #[test]
     fn test_create_agent() {
-        let agent = Agent::new(config::Config{
-            log_dir: "./data/log".into(),
-            sstable_dir: "".into(),
+        let dir = TmpDir::new();
+        let agent = Agent::new(test_config(&dir));
+
+        // Writing the segment's magic bytes is synced on creation.
+        let stats = agent.stats();
+        assert_eq!(1, stats.log.fsync_count);
+        assert_eq!(0, stats.log.bytes_appended);
+    }
+
+    #[test]
+    fn test_put_delete() {
+        let dir = TmpDir::new();
+        let mut agent = Agent::new(test_config(&dir));
+
+        agent.put(b"name", b"Andrew").unwrap();
+        assert_eq!(Some(b"Andrew".to_vec()), agent.get(b"name").unwrap());
+        assert_eq!(None, agent.get(b"age").unwrap());
+
+        agent.delete(b"name").unwrap();
+        assert_eq!(None, agent.get(b"name").unwrap());
+        assert_eq!(2, agent.stats().log.records_appended);
+    }
+
+    #[test]
+    fn test_read_stats() {
+        let dir = TmpDir::new();
+        let mut agent = Agent::new(test_config(&dir));
+        // Even keys in the older table and odd keys in the newer one, so that
+        // both cover every key.
+        for parity in 0..2 {
+            for i in (parity..100).step_by(2) {
+                agent.put(format!("k{:02}", i).as_bytes(), b"v").unwrap();
+            }
+            agent.flush().unwrap();
+        }
+        let stats = agent.stats();
+        assert_eq!(LeveledCompaction::default().num_levels, stats.levels.len());
+        let bytes = agent.tables.infos().map(|t| t.size).sum();
+        assert_eq!(LevelStats { tables: 2, bytes }, stats.levels[0]);
+        assert!(stats.levels[1..].iter().all(|l| l.tables == 0));
+
+        // A key in the newer table is only looked up there. A key in the
+        // older one, or in neither, is first missed in the newer one. Keys
+        // outside a table's range are not looked up in it at all, so only
+        // keys within both are read.
+        for i in 1..98 {
+            assert!(agent.get(format!("k{:02}", i).as_bytes()).unwrap().is_some());
+            assert!(agent.get(format!("k{:02}x", i).as_bytes()).unwrap().is_none());
+        }
+        let filter = agent.stats().filter;
+        assert_eq!(49 + 2 * 48 + 2 * 97, filter.checks);
+        assert_eq!(48 + 2 * 97, filter.useful + filter.false_positives);
+        assert!(filter.hit_rate() > 0.9, "{:?}", filter);
+
+        assert_eq!(100, agent.scan(b"", b"\xff").count());
+        let stats = agent.stats();
+        assert_eq!(2 * 97, stats.get_latency.count());
+        assert_eq!(1, stats.scan_latency.count());
+    }
+
+    #[test]
+    fn test_comparator() {
+        let dir = TmpDir::new();
+        let numeric = || config::Config {
+            comparator: Arc::new(NumericComparator),
+            ..test_config(&dir)
+        };
+        let mut agent = Agent::open(numeric()).unwrap();
+        for key in &["1", "9", "10", "100", "25"] {
+            agent.put(key.as_bytes(), b"").unwrap();
+        }
+        // Bytewise, this range would also cover "9".
+        agent.delete_range(b"20", b"99").unwrap();
+        let keys = |agent: &Agent| {
+            agent
+                .scan(b"0", b"1000")
+                .map(|e| String::from_utf8(e.unwrap().0).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec!["1", "9", "10", "100"], keys(&agent));
+        drop(agent);
+
+        // The store cannot be opened with a different comparator.
+        let err = Agent::open(test_config(&dir)).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let agent = Agent::open(numeric()).unwrap();
+        assert_eq!(vec!["1", "9", "10", "100"], keys(&agent));
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let dir = TmpDir::new();
+        let mut agent = Agent::new(test_config(&dir));
+        for key in &["tenant1/a", "tenant1/b", "tenant2/a", "tenant3/a"] {
+            agent.put(key.as_bytes(), b"v").unwrap();
+        }
+
+        agent.delete_range(b"tenant1/", b"tenant2/").unwrap();
+        assert_eq!(5, agent.stats().log.records_appended);
+        assert_eq!(None, agent.get(b"tenant1/a").unwrap());
+        assert_eq!(Some(b"v".to_vec()), agent.get(b"tenant2/a").unwrap());
+
+        // Writes after the tombstone are visible.
+        agent.put(b"tenant1/c", b"new").unwrap();
+        let keys = |agent: &Agent| agent.scan(b"", b"\xff").map(|e| e.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(
+            vec![b"tenant1/c".to_vec(), b"tenant2/a".to_vec(), b"tenant3/a".to_vec()],
+            keys(&agent)
+        );
+
+        // The tombstone is replayed in order on recovery.
+        agent.sync().unwrap();
+        drop(agent);
+        let agent = Agent::new(test_config(&dir));
+        assert_eq!(
+            vec![b"tenant1/c".to_vec(), b"tenant2/a".to_vec(), b"tenant3/a".to_vec()],
+            keys(&agent)
+        );
+    }
+
+    #[test]
+    fn test_scan() {
+        let dir = TmpDir::new();
+        let mut agent = Agent::new(config::Config {
+            merge_operator: Some(Arc::new(AddU64)),
+            ..test_config(&dir)
         });
+        agent.put(b"a", &1u64.to_le_bytes()).unwrap();
+        agent.put(b"b", &2u64.to_le_bytes()).unwrap();
+        agent.merge(b"b", &3u64.to_le_bytes()).unwrap();
+        agent.put(b"c", &4u64.to_le_bytes()).unwrap();
+        agent.delete(b"c").unwrap();
+        agent.merge(b"d", &5u64.to_le_bytes()).unwrap();
+
+        let scanned = agent
+            .scan(b"a", b"z")
+            .map(|e| e.unwrap())
+            .map(|(k, v)| (k, u64::from_le_bytes(v.as_slice().try_into().unwrap())))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(b"a".to_vec(), 1), (b"b".to_vec(), 5), (b"d".to_vec(), 5)], scanned);
+
+        assert_eq!(1, agent.scan(b"b", b"c").count());
+        assert_eq!(0, agent.scan(b"z", b"a").count());
+    }
+
+    struct AddU64;
+
+    impl MergeOperator for AddU64 {
+        fn name(&self) -> &str {
+            "add_u64"
+        }
+
+        fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+            let existing = existing.map_or(0, |v| u64::from_le_bytes(v.try_into().unwrap()));
+            let operand = u64::from_le_bytes(operand.try_into().unwrap());
+            (existing + operand).to_le_bytes().to_vec()
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = TmpDir::new();
+        let mut agent = Agent::new(config::Config {
+            merge_operator: Some(Arc::new(AddU64)),
+            ..test_config(&dir)
+        });
+        let get_u64 = |agent: &Agent, key: &[u8]| {
+            agent
+                .get(key)
+                .unwrap()
+                .map(|v| u64::from_le_bytes(v.as_slice().try_into().unwrap()))
+        };
+
+        // Operands on a missing key fold from nothing.
+        agent.merge(b"hits", &1u64.to_le_bytes()).unwrap();
+        agent.merge(b"hits", &2u64.to_le_bytes()).unwrap();
+        assert_eq!(Some(3), get_u64(&agent, b"hits"));
+
+        // Operands on top of a put fold into the stored value.
+        agent.put(b"hits", &10u64.to_le_bytes()).unwrap();
+        agent.merge(b"hits", &5u64.to_le_bytes()).unwrap();
+        assert_eq!(Some(15), get_u64(&agent, b"hits"));
+
+        // A delete discards everything before it.
+        agent.delete(b"hits").unwrap();
+        assert_eq!(None, get_u64(&agent, b"hits"));
+        agent.merge(b"hits", &7u64.to_le_bytes()).unwrap();
+        assert_eq!(Some(7), get_u64(&agent, b"hits"));
+    }
+
+    #[test]
+    fn test_merge_operands_are_flushed() {
+        let dir = TmpDir::new();
+        let cfg = || config::Config {
+            merge_operator: Some(Arc::new(AddU64)),
+            ..test_config(&dir)
+        };
+        let get_u64 = |agent: &Agent, key: &[u8]| {
+            agent
+                .get(key)
+                .unwrap()
+                .map(|v| u64::from_le_bytes(v.as_slice().try_into().unwrap()))
+        };
+        let merges_in_tables = |agent: &Agent| {
+            agent
+                .tables
+                .tables()
+                .flat_map(|(_, table)| table.iter())
+                .filter(|e| matches!(e.as_ref().unwrap().1, TableValue::Merge(_)))
+                .count()
+        };
+
+        let mut agent = Agent::new(cfg());
+        agent.put(b"hits", &10u64.to_le_bytes()).unwrap();
+        agent.flush().unwrap();
+
+        // Merging does not read the base value from the table.
+        let cache = agent.stats().block_cache;
+        agent.merge(b"hits", &1u64.to_le_bytes()).unwrap();
+        agent.merge(b"hits", &2u64.to_le_bytes()).unwrap();
+        let after = agent.stats().block_cache;
+        assert_eq!((cache.hits, cache.misses), (after.hits, after.misses));
+        assert_eq!(Some(13), get_u64(&agent, b"hits"));
+
+        // The operands are flushed as one collapsed operand and folded into
+        // the base when read.
+        agent.flush().unwrap();
+        assert_eq!(1, merges_in_tables(&agent));
+        assert_eq!(Some(13), get_u64(&agent, b"hits"));
+
+        // Operands under a range deletion fold from nothing.
+        agent.put(b"other", &100u64.to_le_bytes()).unwrap();
+        agent.flush().unwrap();
+        agent.delete_range(b"o", b"p").unwrap();
+        agent.merge(b"other", &5u64.to_le_bytes()).unwrap();
+        agent.merge(b"hits", &4u64.to_le_bytes()).unwrap();
+        assert_eq!(Some(5), get_u64(&agent, b"other"));
+        assert_eq!(Some(17), get_u64(&agent, b"hits"));
+        agent.sync().unwrap();
+        drop(agent);
+
+        let mut agent = Agent::new(cfg());
+        assert_eq!(Some(5), get_u64(&agent, b"other"));
+        assert_eq!(Some(17), get_u64(&agent, b"hits"));
+
+        // Compacting into the last level folds the operands into values.
+        agent.flush().unwrap();
+        assert_eq!(vec![1], agent.tables.infos().map(|t| t.level).collect::<Vec<_>>());
+        assert_eq!(0, merges_in_tables(&agent));
+        assert_eq!(Some(5), get_u64(&agent, b"other"));
+        assert_eq!(Some(17), get_u64(&agent, b"hits"));
+    }
+
+    #[test]
+    fn test_merge_without_operator() {
+        let dir = TmpDir::new();
+        let mut agent = Agent::new(test_config(&dir));
+
+        let err = agent.merge(b"hits", &1u64.to_le_bytes()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert_eq!(0, agent.stats().log.records_appended);
+    }
+
+    struct Max;
+
+    impl MergeOperator for Max {
+        fn name(&self) -> &str {
+            "max"
+        }
+
+        fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+            existing.map_or(operand, |v| v.max(operand)).to_vec()
+        }
+    }
+
+    #[test]
+    fn test_merge_operator_is_checked_on_open() {
+        let dir = TmpDir::new();
+        let cfg = |merge_operator: Option<Arc<dyn MergeOperator>>| config::Config {
+            merge_operator,
+            ..test_config(&dir)
+        };
+        let mut agent = Agent::open(cfg(Some(Arc::new(AddU64)))).unwrap();
+        agent.merge(b"hits", &1u64.to_le_bytes()).unwrap();
+        drop(agent);
+
+        // Opening with another operator, or none, would misread the operands.
+        let err = Agent::open(cfg(Some(Arc::new(Max)))).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let err = Agent::open(cfg(None)).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        let agent = Agent::open(cfg(Some(Arc::new(AddU64)))).unwrap();
+        assert_eq!(Some(1u64.to_le_bytes().to_vec()), agent.get(b"hits").unwrap());
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = TmpDir::new();
+        {
+            let mut agent = Agent::new(test_config(&dir));
+            agent.put(b"name", b"Andrew").unwrap();
+            agent.put(b"age", b"33").unwrap();
+            agent.delete(b"age").unwrap();
+            agent.sync().unwrap();
+        }
+
+        let agent = Agent::new(test_config(&dir));
+        assert_eq!(Some(b"Andrew".to_vec()), agent.get(b"name").unwrap());
+        assert_eq!(None, agent.get(b"age").unwrap());
+    }
+
+    #[test]
+    fn test_failed_sync_is_not_acknowledged() {
+        let fs = MemFileSystem::new();
+        let cfg = || config::Config {
+            log_dir: "/data/log".into(),
+            sstable_dir: "/data/sstable".into(),
+            fs: Arc::new(fs.clone()),
+            ..config::Config::default()
+        };
+        let mut agent = Agent::open(cfg()).unwrap();
+        agent.put(b"a", b"1").unwrap();
+        agent.sync().unwrap();
+
+        fs.fail_nth(FsOp::Sync, 0);
+        agent.put(b"b", b"2").unwrap();
+        assert!(agent.sync().is_err());
+        // The log refuses further writes rather than risk acknowledging lost data.
+        assert!(agent.put(b"c", b"3").is_err());
+
+        fs.crash();
+        let agent = Agent::open(cfg()).unwrap();
+        assert_eq!(Some(b"1".to_vec()), agent.get(b"a").unwrap());
+        assert_eq!(None, agent.get(b"b").unwrap());
+    }
+
+    #[test]
+    fn test_only_torn_tail_is_truncated() {
+        let fs = MemFileSystem::new();
+        let cfg = || config::Config {
+            log_dir: "/data/log".into(),
+            sstable_dir: "/data/sstable".into(),
+            fs: Arc::new(fs.clone()),
+            ..config::Config::default()
+        };
+        let mut agent = Agent::open(cfg()).unwrap();
+        agent.put(b"a", b"1").unwrap();
+        agent.put(b"b", b"2").unwrap();
+        agent.sync().unwrap();
+        drop(agent);
+
+        let path = segment::segment_file_path("/data/log", 0);
+        let contents = fs.contents(&path).unwrap();
+        let rewrite = |contents: &[u8]| {
+            let mut file = fs.open_append(&path).unwrap();
+            file.set_len(0).unwrap();
+            file.append(contents).unwrap();
+            file.sync().unwrap();
+        };
+
+        // A bad checksum in the middle of the log fails the open and leaves
+        // the log as it was. Each record here is 19 bytes long, and ends with
+        // its checksum.
+        let mut corrupt = contents.clone();
+        corrupt[contents.len() - 20] ^= 0xff;
+        rewrite(&corrupt);
+        let err = Agent::open(cfg()).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!(Some(corrupt), fs.contents(&path));
+
+        // A record cut short at the end of the log is dropped.
+        rewrite(&contents[..contents.len() - 1]);
+        let agent = Agent::open(cfg()).unwrap();
+        assert_eq!(Some(b"1".to_vec()), agent.get(b"a").unwrap());
+        assert_eq!(None, agent.get(b"b").unwrap());
+        assert!(fs.contents(&path).unwrap().len() < contents.len() - 1);
+    }
+
+    const CRASH_TEST_KEYS: u8 = 8;
+
+    // Runs rounds of random writes against a MemFileSystem that randomly fails
+    // writes and syncs, then crashes it, keeping a random amount of unsynced
+    // data. After each crash, the recovered store must match the state after
+    // some prefix of the attempted writes that includes every write that was
+    // acknowledged by a successful sync.
+    #[test]
+    fn test_crash_recovery() {
+        for seed in 0..100 {
+            check_crash_recovery(seed);
+        }
+    }
+
+    fn check_crash_recovery(seed: u64) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let fs = MemFileSystem::new();
+        let cfg = || config::Config {
+            log_dir: "/data/log".into(),
+            sstable_dir: "/data/sstable".into(),
+            merge_operator: Some(Arc::new(AddU64)),
+            fs: Arc::new(fs.clone()),
+            ..config::Config::default()
+        };
+
+        // states[i] is the expected contents after the first i attempted writes.
+        let mut states = vec![BTreeMap::<u8, u64>::new()];
+        let mut acked = 0;
+
+        for round in 0..10 {
+            let mut agent = Agent::open(cfg()).unwrap();
+            assert_recovered(&agent, &mut states, acked, seed, round);
+            acked = states.len() - 1;
+
+            if rng.gen_bool(0.3) {
+                fs.fail_nth(FsOp::Sync, rng.gen_range(0, 5));
+            }
+            if rng.gen_bool(0.3) {
+                fs.tear_nth_write(rng.gen_range(0, 30), rng.gen_range(0, 20));
+            }
+            if rng.gen_bool(0.2) {
+                fs.fail_nth(FsOp::SyncDir, rng.gen_range(0, 3));
+            }
+            if rng.gen_bool(0.2) {
+                fs.fail_nth(FsOp::Rename, rng.gen_range(0, 2));
+            }
+
+            for _ in 0..rng.gen_range(0, 30) {
+                let key = rng.gen_range(0, CRASH_TEST_KEYS);
+                let mut state = states.last().unwrap().clone();
+                let res = match rng.gen_range(0, 4) {
+                    0 => {
+                        let val = rng.gen::<u32>() as u64;
+                        state.insert(key, val);
+                        agent.put(&[key], &val.to_le_bytes())
+                    }
+                    1 => {
+                        state.remove(&key);
+                        agent.delete(&[key])
+                    }
+                    2 => {
+                        let end = key + rng.gen_range(0, 4);
+                        state.retain(|k, _| *k < key || *k >= end);
+                        agent.delete_range(&[key], &[end])
+                    }
+                    _ => {
+                        let operand = rng.gen::<u32>() as u64;
+                        *state.entry(key).or_insert(0) += operand;
+                        agent.merge(&[key], &operand.to_le_bytes())
+                    }
+                };
+                // A failed write may still have partially reached the file.
+                states.push(state);
+                if res.is_err() {
+                    break;
+                }
+
+                if rng.gen_bool(0.2) {
+                    if agent.sync().is_err() {
+                        break;
+                    }
+                    acked = states.len() - 1;
+                }
+                // A flush makes every write before it durable.
+                if rng.gen_bool(0.1) {
+                    if agent.flush().is_err() {
+                        break;
+                    }
+                    acked = states.len() - 1;
+                }
+            }
+
+            fs.crash_with(|_, unsynced| rng.gen_range(0, unsynced + 1));
+        }
+    }
+
+    // Checks that the agent holds one of `states[acked..]` and discards the
+    // states after it, which were lost in the crash.
+    fn assert_recovered(
+        agent: &Agent,
+        states: &mut Vec<BTreeMap<u8, u64>>,
+        acked: usize,
+        seed: u64,
+        round: usize,
+    ) {
+        let mut recovered = BTreeMap::new();
+        for key in 0..CRASH_TEST_KEYS {
+            if let Some(v) = agent.get(&[key]).unwrap() {
+                recovered.insert(key, u64::from_le_bytes(v.as_slice().try_into().unwrap()));
+            }
+        }
+
+        match (acked..states.len()).rev().find(|&i| states[i] == recovered) {
+            Some(i) => states.truncate(i + 1),
+            None => panic!(
+                "seed {} round {}: recovered {:?}, which does not include all {} acknowledged writes",
+                seed, round, recovered, acked
+            ),
+        }
     }
 }
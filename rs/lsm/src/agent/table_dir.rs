@@ -0,0 +1,142 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::cache::BlockCache;
+use crate::comparator::Comparator;
+use crate::compaction::TableInfo;
+use crate::encryption::KeyProvider;
+use crate::fs::FileSystem;
+use crate::stats::FilterStats;
+use crate::table::{self, FilterCounters, Table, TableBuilder};
+
+// The directory that holds the store's tables. Clones refer to the same
+// directory and share its file numbers, so background compactions can write
+// tables without holding the agent. The tables that it opens count their
+// filter lookups together.
+#[derive(Clone)]
+pub(crate) struct TableDir {
+    fs: Arc<dyn FileSystem>,
+    path: PathBuf,
+    comparator: Arc<dyn Comparator>,
+    key_provider: Option<Arc<dyn KeyProvider>>,
+    block_cache: Arc<BlockCache>,
+    mmap_reads: bool,
+    filter_counters: Arc<FilterCounters>,
+    // Number to give the next table file.
+    next_file_number: Arc<AtomicU64>,
+}
+
+impl TableDir {
+    // Creates the directory if necessary. New tables are numbered after every
+    // table already in it, including any that are not linked in.
+    pub(crate) fn open(
+        fs: Arc<dyn FileSystem>,
+        path: &Path,
+        comparator: Arc<dyn Comparator>,
+        key_provider: Option<Arc<dyn KeyProvider>>,
+        block_cache: Arc<BlockCache>,
+        mmap_reads: bool,
+    ) -> io::Result<TableDir> {
+        fs.create_dir_all(path)?;
+        let dir = TableDir {
+            fs,
+            path: path.to_path_buf(),
+            comparator,
+            key_provider,
+            block_cache,
+            mmap_reads,
+            filter_counters: Arc::default(),
+            next_file_number: Arc::new(AtomicU64::new(0)),
+        };
+        let next = dir.file_numbers()?.into_iter().max().map_or(1, |n| n + 1);
+        dir.next_file_number.store(next, Ordering::Relaxed);
+        Ok(dir)
+    }
+
+    pub(crate) fn comparator(&self) -> &Arc<dyn Comparator> {
+        &self.comparator
+    }
+
+    pub(crate) fn key_provider(&self) -> Option<&dyn KeyProvider> {
+        self.key_provider.as_deref()
+    }
+
+    pub(crate) fn filter_stats(&self) -> FilterStats {
+        self.filter_counters.stats()
+    }
+
+    pub(crate) fn table_path(&self, file_number: u64) -> PathBuf {
+        self.path.join(table::table_file_name(file_number))
+    }
+
+    pub(crate) fn new_file_number(&self) -> u64 {
+        self.next_file_number.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // File numbers of the tables in the directory.
+    pub(crate) fn file_numbers(&self) -> io::Result<Vec<u64>> {
+        Ok(self
+            .fs
+            .read_dir(&self.path)?
+            .iter()
+            .filter_map(|p| table::parse_table_file_name(p.file_name()?.to_str()?))
+            .collect())
+    }
+
+    // Creates a table, encrypted with the key provider's current key if one
+    // is configured.
+    pub(crate) fn create(&self, file_number: u64) -> io::Result<TableBuilder> {
+        let path = self.table_path(file_number);
+        let builder = TableBuilder::create(self.fs.as_ref(), &path, Arc::clone(&self.comparator))?;
+        match &self.key_provider {
+            Some(key_provider) => builder.with_key_provider(key_provider.as_ref()),
+            None => Ok(builder),
+        }
+    }
+
+    // Makes the creation and removal of tables so far durable. New tables
+    // must be synced before a log record links them in, or a crash could
+    // lose a table that the log refers to.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        self.fs.sync_dir(&self.path)
+    }
+
+    // Opens a table for reading. `seq` is the largest sequence number of the
+    // writes in the table.
+    pub(crate) fn open_table(&self, file_number: u64, level: usize, seq: u64) -> io::Result<(TableInfo, Table)> {
+        let file = self.fs.open(&self.table_path(file_number))?;
+        let mut table = Table::open(
+            file,
+            file_number,
+            Some(Arc::clone(&self.block_cache)),
+            Arc::clone(&self.comparator),
+            self.key_provider.as_deref(),
+            self.mmap_reads,
+        )?;
+        table.count_filter_lookups(Arc::clone(&self.filter_counters));
+        let props = table.properties();
+        let info = TableInfo {
+            file_number,
+            level,
+            size: props.size,
+            largest_seq: seq,
+            smallest: props.smallest.clone(),
+            largest: props.largest.clone(),
+        };
+        Ok((info, table))
+    }
+
+    // Removes tables that are not linked in, and drops their blocks from the
+    // cache so that they do not push out the blocks of live tables. Failures
+    // to remove a file are ignored, since such tables are also deleted when
+    // the store is next opened.
+    pub(crate) fn remove_unlinked(&self, file_numbers: &[u64]) {
+        for &file_number in file_numbers {
+            self.block_cache.evict_file(file_number);
+            let _ = self.fs.remove(&self.table_path(file_number));
+        }
+        let _ = self.sync();
+    }
+}
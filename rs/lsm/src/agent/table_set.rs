@@ -0,0 +1,92 @@
+use std::cmp::{Ordering, Reverse};
+use std::io;
+use std::sync::Arc;
+
+use crate::comparator::Comparator;
+use crate::compaction::TableInfo;
+use crate::table::{Table, TableIter, TableValue};
+
+// The tables below the memtable.
+pub(crate) struct TableSet {
+    cmp: Arc<dyn Comparator>,
+    // Ordered from newest to oldest data, so that the first table containing
+    // a key holds its current value: level 0 newest first, then each level
+    // below it in turn. Data in a level is newer than the data it overlaps
+    // in the levels below, whatever the tables' sequence numbers, since a
+    // compaction output inherits the largest sequence number of its inputs.
+    // Tables are shared with compactions that read them in the background.
+    tables: Vec<(TableInfo, Arc<Table>)>,
+}
+
+impl TableSet {
+    pub(crate) fn new(cmp: Arc<dyn Comparator>) -> TableSet {
+        TableSet { cmp, tables: Vec::new() }
+    }
+
+    pub(crate) fn add(&mut self, info: TableInfo, table: Table) {
+        let order = |t: &TableInfo| (t.level, Reverse(t.largest_seq));
+        let idx = self.tables.partition_point(|(t, _)| order(t) <= order(&info));
+        self.tables.insert(idx, (info, Arc::new(table)));
+    }
+
+    // Removes the table with the given file number, if it is in the set.
+    pub(crate) fn remove(&mut self, file_number: u64) -> Option<(TableInfo, Arc<Table>)> {
+        let idx = self.tables.iter().position(|(info, _)| info.file_number == file_number)?;
+        Some(self.tables.remove(idx))
+    }
+
+    pub(crate) fn infos(&self) -> impl Iterator<Item = &TableInfo> {
+        self.tables.iter().map(|(info, _)| info)
+    }
+
+    // The tables with their metadata, newest first.
+    pub(crate) fn tables(&self) -> impl Iterator<Item = &(TableInfo, Arc<Table>)> {
+        self.tables.iter()
+    }
+
+    // The versions of `key` in the tables, newest first. A table whose range
+    // deletions cover the key adds a deletion after its own version, since
+    // they mask the older tables. Tables are read as the versions are taken.
+    pub(crate) fn versions<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = io::Result<TableValue>> + 'a {
+        self.containing(key).flat_map(move |(_, table)| {
+            let covered = table.range_tombstones().covers(key).then_some(Ok(TableValue::Deleted));
+            table.get(key).transpose().into_iter().chain(covered)
+        })
+    }
+
+    // Whether a range deletion in one of the `count` newest tables covers
+    // `key`.
+    pub(crate) fn covered_by_newest(&self, count: usize, key: &[u8]) -> bool {
+        self.tables[..count]
+            .iter()
+            .any(|(_, table)| table.range_tombstones().covers(key))
+    }
+
+    // Returns the largest sequence number of the tables whose key range
+    // includes `key`. This is an upper bound on the sequence number of the
+    // last write to `key` in the tables.
+    pub(crate) fn last_write_seq(&self, key: &[u8]) -> Option<u64> {
+        self.containing(key).map(|(info, _)| info.largest_seq).max()
+    }
+
+    // Whether any table's key range overlaps [smallest, largest].
+    pub(crate) fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        self.infos().any(|t| {
+            self.cmp.compare(&t.smallest, largest) != Ordering::Greater
+                && self.cmp.compare(smallest, &t.largest) != Ordering::Greater
+        })
+    }
+
+    // Iterators over every table starting at `start`, newest first.
+    pub(crate) fn iters_from<'a>(&'a self, start: &[u8]) -> impl Iterator<Item = TableIter<'a>> + 'a {
+        let start = start.to_vec();
+        self.tables.iter().map(move |(_, table)| table.iter_from(&start))
+    }
+
+    fn containing<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = &'a (TableInfo, Arc<Table>)> + 'a {
+        self.tables.iter().filter(move |(info, _)| {
+            self.cmp.compare(&info.smallest, key) != Ordering::Greater
+                && self.cmp.compare(key, &info.largest) != Ordering::Greater
+        })
+    }
+}
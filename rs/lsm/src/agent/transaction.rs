@@ -0,0 +1,242 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use super::Agent;
+use crate::batch::WriteBatch;
+use crate::log::RecordType;
+
+// An optimistic transaction. Writes are buffered in the transaction, and reads
+// see the transaction's own writes on top of the latest committed data. Every
+// key that is read or written is recorded, and on commit the transaction fails
+// with `TransactionError::Conflict` if any of those keys was written by anyone
+// else after the transaction began. Otherwise its writes are applied
+// atomically as a single log record.
+//
+// A transaction does not borrow the `Agent`, so other writes and transactions
+// can proceed while it is open; the agent is passed to each read and to
+// `commit`.
+pub struct Transaction {
+    // Sequence number of the last write visible when the transaction began.
+    snapshot_seq: u64,
+    read_set: BTreeSet<Vec<u8>>,
+    write_set: BTreeSet<Vec<u8>>,
+    batch: WriteBatch,
+}
+
+#[derive(Debug)]
+pub enum TransactionError {
+    // `key` was written after the transaction began. The transaction had no
+    // effect and can be retried.
+    Conflict { key: Vec<u8> },
+    Io(io::Error),
+}
+
+impl Transaction {
+    pub(super) fn new(snapshot_seq: u64) -> Transaction {
+        Transaction {
+            snapshot_seq,
+            read_set: BTreeSet::new(),
+            write_set: BTreeSet::new(),
+            batch: WriteBatch::new(),
+        }
+    }
+
+    pub fn get(&mut self, agent: &Agent, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.read_set.insert(key.to_vec());
+        let mut value = agent.get(key)?;
+        for op in self.batch.ops().iter().filter(|op| op.key == key) {
+            value = match op.record_type {
+                RecordType::Put => Some(op.value.clone()),
+                RecordType::Delete => None,
+                RecordType::Merge => {
+                    agent.check_merge_operator()?;
+                    let merge_op = agent.merge_operator.as_deref().unwrap();
+                    Some(merge_op.merge(key, value.as_deref(), &op.value))
+                }
+                RecordType::RangeDelete
+                | RecordType::Batch
+                | RecordType::IngestTables
+                | RecordType::Flush
+                | RecordType::Compaction => {
+                    unreachable!("transactions only buffer point writes")
+                }
+            };
+        }
+        Ok(value)
+    }
+
+    pub fn put(&mut self, key: &[u8], val: &[u8]) {
+        self.write_set.insert(key.to_vec());
+        self.batch.put(key, val);
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.write_set.insert(key.to_vec());
+        self.batch.delete(key);
+    }
+
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        self.write_set.insert(key.to_vec());
+        self.batch.merge(key, operand);
+    }
+
+    pub fn commit(self, agent: &mut Agent) -> Result<(), TransactionError> {
+        for key in self.read_set.iter().chain(self.write_set.iter()) {
+            let modified = agent.last_write_seq(key).is_some_and(|seq| seq > self.snapshot_seq);
+            if modified {
+                return Err(TransactionError::Conflict { key: key.clone() });
+            }
+        }
+        agent.write(&self.batch)?;
+        Ok(())
+    }
+}
+
+impl TransactionError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, TransactionError::Conflict { .. })
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::Conflict { key } => {
+                write!(f, "transaction conflict on key {:?}", key)
+            }
+            TransactionError::Io(err) => write!(f, "transaction failed: {}", err),
+        }
+    }
+}
+
+impl Error for TransactionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TransactionError::Conflict { .. } => None,
+            TransactionError::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for TransactionError {
+    fn from(err: io::Error) -> TransactionError {
+        TransactionError::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::config::Config;
+    use super::*;
+    use crate::test_util::TmpDir;
+
+    fn open(dir: &TmpDir) -> Agent {
+        Agent::new(Config {
+            log_dir: dir.as_ref().to_str().unwrap().into(),
+            sstable_dir: dir.as_ref().join("sstable").to_str().unwrap().into(),
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn test_commit() {
+        let dir = TmpDir::new();
+        let mut agent = open(&dir);
+        agent.put(b"from", b"10").unwrap();
+
+        let mut txn = agent.begin_transaction();
+        assert_eq!(Some(b"10".to_vec()), txn.get(&agent, b"from").unwrap());
+        txn.put(b"from", b"7");
+        txn.put(b"to", b"3");
+        // Reads see the transaction's own writes, and nothing is visible
+        // outside it before commit.
+        assert_eq!(Some(b"7".to_vec()), txn.get(&agent, b"from").unwrap());
+        assert_eq!(None, agent.get(b"to").unwrap());
+
+        txn.commit(&mut agent).unwrap();
+        assert_eq!(Some(b"7".to_vec()), agent.get(b"from").unwrap());
+        assert_eq!(Some(b"3".to_vec()), agent.get(b"to").unwrap());
+        // The whole transaction is a single log record.
+        assert_eq!(2, agent.stats().log.records_appended);
+    }
+
+    #[test]
+    fn test_read_conflict() {
+        let dir = TmpDir::new();
+        let mut agent = open(&dir);
+        agent.put(b"counter", b"1").unwrap();
+
+        let mut fst = agent.begin_transaction();
+        let mut snd = agent.begin_transaction();
+        fst.get(&agent, b"counter").unwrap();
+        snd.get(&agent, b"counter").unwrap();
+        fst.put(b"counter", b"2");
+        snd.put(b"counter", b"2");
+
+        fst.commit(&mut agent).unwrap();
+        let err = snd.commit(&mut agent).unwrap_err();
+        assert!(err.is_retryable());
+        assert_eq!(Some(b"2".to_vec()), agent.get(b"counter").unwrap());
+
+        // A retry that starts after the conflicting commit succeeds.
+        let mut retry = agent.begin_transaction();
+        retry.get(&agent, b"counter").unwrap();
+        retry.put(b"counter", b"3");
+        retry.commit(&mut agent).unwrap();
+    }
+
+    #[test]
+    fn test_write_and_range_delete_conflicts() {
+        let dir = TmpDir::new();
+        let mut agent = open(&dir);
+
+        // Blind writes to the same key conflict.
+        let mut txn = agent.begin_transaction();
+        txn.put(b"a", b"txn");
+        agent.put(b"a", b"other").unwrap();
+        assert!(txn.commit(&mut agent).unwrap_err().is_retryable());
+
+        // A range delete covering a key that was read conflicts.
+        agent.put(b"tenant/x", b"1").unwrap();
+        let mut txn = agent.begin_transaction();
+        txn.get(&agent, b"tenant/x").unwrap();
+        txn.put(b"b", b"1");
+        agent.delete_range(b"tenant/", b"tenant0").unwrap();
+        assert!(txn.commit(&mut agent).unwrap_err().is_retryable());
+        assert_eq!(None, agent.get(b"b").unwrap());
+
+        // Writes to unrelated keys do not.
+        let mut txn = agent.begin_transaction();
+        txn.get(&agent, b"c").unwrap();
+        txn.put(b"c", b"1");
+        agent.put(b"d", b"1").unwrap();
+        txn.commit(&mut agent).unwrap();
+    }
+
+    #[test]
+    fn test_committed_transaction_is_recovered() {
+        let dir = TmpDir::new();
+        {
+            let mut agent = open(&dir);
+            let mut txn = agent.begin_transaction();
+            txn.put(b"a", b"1");
+            txn.put(b"b", b"2");
+            txn.delete(b"a");
+            txn.commit(&mut agent).unwrap();
+            agent.sync().unwrap();
+        }
+
+        let mut agent = open(&dir);
+        assert_eq!(None, agent.get(b"a").unwrap());
+        assert_eq!(Some(b"2".to_vec()), agent.get(b"b").unwrap());
+
+        // Sequence numbers continue after recovery, so transactions that
+        // began before a later write still detect it.
+        let mut txn = agent.begin_transaction();
+        txn.get(&agent, b"b").unwrap();
+        agent.put(b"b", b"3").unwrap();
+        assert!(txn.commit(&mut agent).unwrap_err().is_retryable());
+    }
+}
@@ -0,0 +1,154 @@
+use std::sync::{Condvar, Mutex};
+
+use super::config::Config;
+use crate::rate_limiter::RateLimiter;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum WriteState {
+    Normal,
+    Delayed,
+    Stopped,
+}
+
+// Applies backpressure to foreground writes when flushes and compactions fall
+// behind. Background work reports the number of tables in level 0 through
+// `set_level0_tables`, and every write passes through `wait_for_write` before
+// it is appended to the log.
+pub struct WriteController {
+    slowdown_trigger: usize,
+    stop_trigger: usize,
+    delayed: RateLimiter,
+    level0: Mutex<Level0>,
+    cond: Condvar,
+}
+
+struct Level0 {
+    tables: usize,
+    // Set by `release`.
+    released: bool,
+}
+
+impl WriteController {
+    pub fn new(cfg: &Config) -> WriteController {
+        assert!(
+            cfg.level0_slowdown_writes_trigger <= cfg.level0_stop_writes_trigger,
+            "level 0 slowdown trigger must not exceed the stop trigger"
+        );
+        WriteController {
+            slowdown_trigger: cfg.level0_slowdown_writes_trigger,
+            stop_trigger: cfg.level0_stop_writes_trigger,
+            delayed: RateLimiter::new(cfg.delayed_write_bytes_per_sec),
+            level0: Mutex::new(Level0 {
+                tables: 0,
+                released: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    pub fn set_level0_tables(&self, count: usize) {
+        self.level0.lock().unwrap().tables = count;
+        self.cond.notify_all();
+    }
+
+    // Admits every write from now on without delay. Used once background
+    // work has failed and will not bring level 0 down, so that writers reach
+    // the agent and fail rather than wait forever.
+    pub fn release(&self) {
+        self.level0.lock().unwrap().released = true;
+        self.cond.notify_all();
+    }
+
+    pub fn state(&self) -> WriteState {
+        self.state_for(&self.level0.lock().unwrap())
+    }
+
+    fn state_for(&self, level0: &Level0) -> WriteState {
+        if level0.released {
+            WriteState::Normal
+        } else if level0.tables >= self.stop_trigger {
+            WriteState::Stopped
+        } else if level0.tables >= self.slowdown_trigger {
+            WriteState::Delayed
+        } else {
+            WriteState::Normal
+        }
+    }
+
+    // Blocks while writes are stopped, then paces the write to the delayed
+    // write rate if level 0 is still above the slowdown trigger. Returns the
+    // state that the write was admitted under.
+    pub fn wait_for_write(&self, bytes: u64) -> WriteState {
+        let mut level0 = self.level0.lock().unwrap();
+        while self.state_for(&level0) == WriteState::Stopped {
+            level0 = self.cond.wait(level0).unwrap();
+        }
+        let admitted = self.state_for(&level0);
+        drop(level0);
+
+        if admitted == WriteState::Delayed {
+            self.delayed.request(bytes);
+        }
+        admitted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn controller() -> WriteController {
+        WriteController::new(&Config {
+            level0_slowdown_writes_trigger: 2,
+            level0_stop_writes_trigger: 4,
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn test_write_state() {
+        let wc = controller();
+        assert_eq!(WriteState::Normal, wc.wait_for_write(100));
+
+        wc.set_level0_tables(3);
+        assert_eq!(WriteState::Delayed, wc.wait_for_write(100));
+
+        wc.set_level0_tables(4);
+        assert_eq!(WriteState::Stopped, wc.state());
+    }
+
+    #[test]
+    fn test_stopped_writes_resume() {
+        let wc = Arc::new(controller());
+        wc.set_level0_tables(5);
+
+        let writer = {
+            let wc = wc.clone();
+            thread::spawn(move || wc.wait_for_write(100))
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert!(!writer.is_finished());
+
+        wc.set_level0_tables(1);
+        assert_eq!(WriteState::Normal, writer.join().unwrap());
+    }
+
+    #[test]
+    fn test_release() {
+        let wc = Arc::new(controller());
+        wc.set_level0_tables(5);
+
+        let writer = {
+            let wc = wc.clone();
+            thread::spawn(move || wc.wait_for_write(100))
+        };
+        thread::sleep(Duration::from_millis(20));
+        wc.release();
+        assert_eq!(WriteState::Normal, writer.join().unwrap());
+        wc.set_level0_tables(3);
+        assert_eq!(WriteState::Normal, wc.wait_for_write(100));
+    }
+}
@@ -0,0 +1,161 @@
+use std::io::{self, Cursor, Read};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::log::{LogEntry, RecordType};
+
+// A group of writes that are logged as a single record and so are applied
+// atomically, all sharing one sequence number.
+//
+// Encoded batch format (the value of a `RecordType::Batch` log record):
+// +-------+------+-...-+------+
+// | count | op_0 | ... | op_n |
+// +-------+------+-...-+------+
+//  4 bytes
+//
+// Op:
+// +-------------+------------+--------------+-----+-------+
+// | record_type | key_length | value_length | key | value |
+// +-------------+------------+--------------+-----+-------+
+//     1 byte        4 bytes      8 bytes
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<LogEntry>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], val: &[u8]) {
+        self.push(RecordType::Put, key, val);
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.push(RecordType::Delete, key, &[]);
+    }
+
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) {
+        self.push(RecordType::RangeDelete, start, end);
+    }
+
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        self.push(RecordType::Merge, key, operand);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    // Total size of the keys and values in the batch.
+    pub fn approximate_size(&self) -> u64 {
+        self.ops.iter().map(|op| (op.key.len() + op.value.len()) as u64).sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    // Moves all ops from `other` to the end of this batch.
+    pub fn append(&mut self, other: &mut WriteBatch) {
+        self.ops.append(&mut other.ops);
+    }
+
+    pub(crate) fn ops(&self) -> &[LogEntry] {
+        &self.ops
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(self.ops.len() as u32).unwrap();
+        for op in &self.ops {
+            buf.write_u8(op.record_type as u8).unwrap();
+            buf.write_u32::<LittleEndian>(op.key.len() as u32).unwrap();
+            buf.write_u64::<LittleEndian>(op.value.len() as u64).unwrap();
+            buf.extend_from_slice(&op.key);
+            buf.extend_from_slice(&op.value);
+        }
+        buf
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> io::Result<WriteBatch> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let mut cursor = Cursor::new(buf);
+        let count = cursor.read_u32::<LittleEndian>()?;
+        let mut batch = WriteBatch::new();
+        for _ in 0..count {
+            let record_type = match RecordType::from_u8(cursor.read_u8()?) {
+                Some(RecordType::Batch) | Some(RecordType::IngestTables) | Some(RecordType::Flush) | Some(RecordType::Compaction) | None => {
+                    return Err(invalid("Write batch contains an invalid op type"))
+                }
+                Some(t) => t,
+            };
+            let key_len = cursor.read_u32::<LittleEndian>()? as u64;
+            let val_len = cursor.read_u64::<LittleEndian>()?;
+            let remaining = buf.len() as u64 - cursor.position();
+            if key_len.saturating_add(val_len) > remaining {
+                return Err(invalid("Write batch op extends past the end of the batch"));
+            }
+            let mut key = vec![0u8; key_len as usize];
+            cursor.read_exact(&mut key)?;
+            let mut value = vec![0u8; val_len as usize];
+            cursor.read_exact(&mut value)?;
+            batch.ops.push(LogEntry {
+                record_type,
+                key,
+                value,
+            });
+        }
+        if cursor.position() != buf.len() as u64 {
+            return Err(invalid("Write batch has trailing bytes"));
+        }
+
+        Ok(batch)
+    }
+
+    fn push(&mut self, record_type: RecordType, key: &[u8], value: &[u8]) {
+        self.ops.push(LogEntry {
+            record_type,
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"1");
+        batch.delete(b"b");
+        batch.merge(b"c", b"2");
+        batch.delete_range(b"d", b"e");
+
+        let encoded = batch.encode();
+        let decoded = WriteBatch::decode(&encoded).unwrap();
+        let ops = decoded
+            .ops()
+            .iter()
+            .map(|op| (op.record_type, op.key.clone(), op.value.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (RecordType::Put, b"a".to_vec(), b"1".to_vec()),
+                (RecordType::Delete, b"b".to_vec(), vec![]),
+                (RecordType::Merge, b"c".to_vec(), b"2".to_vec()),
+                (RecordType::RangeDelete, b"d".to_vec(), b"e".to_vec()),
+            ],
+            ops
+        );
+
+        assert!(WriteBatch::decode(&encoded[..encoded.len() - 1]).is_err());
+    }
+}
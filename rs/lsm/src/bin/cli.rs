@@ -1,3 +1,108 @@
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::Arc;
+
+use lsm::agent;
+use lsm::agent::config::Config;
+use lsm::comparator::{BytewiseComparator, Comparator, NumericComparator, ReverseBytewiseComparator};
+use lsm::encryption::{StaticKeyProvider, KEY_LENGTH, PLAINTEXT_KEY_ID};
+
+const USAGE: &str = "usage: cli fsck <log_dir> <sstable_dir> [--quarantine] [--comparator <name>] [--key-file <path>]
+
+  --comparator <name>  the store's comparator, one of lsm.BytewiseComparator (the
+                       default), lsm.ReverseBytewiseComparator or lsm.NumericComparator
+  --key-file <path>    file holding the store's encryption key id and the key as 64
+                       hex digits, separated by whitespace";
+
 fn main() {
-    println!("Hello from CLI!");
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    match args.first().map(String::as_str) {
+        Some("fsck") => fsck(&args[1..]),
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+// Checks a data directory and prints the report. Exits with status 1 if any
+// problems were found.
+fn fsck(args: &[String]) {
+    let mut cfg = Config::default();
+    let mut quarantine = false;
+    let mut dirs = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--quarantine" => quarantine = true,
+            "--comparator" => {
+                let name = args.next().unwrap_or_else(|| usage());
+                cfg.comparator = comparator(name).unwrap_or_else(|| {
+                    eprintln!("unknown comparator: {}", name);
+                    process::exit(2);
+                });
+            }
+            "--key-file" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                let provider = read_key_file(path).unwrap_or_else(|err| {
+                    eprintln!("invalid key file {}: {}", path, err);
+                    process::exit(2);
+                });
+                cfg.key_provider = Some(Arc::new(provider));
+            }
+            _ if arg.starts_with("--") => usage(),
+            _ => dirs.push(arg.clone()),
+        }
+    }
+    if dirs.len() != 2 {
+        usage();
+    }
+    cfg.sstable_dir = dirs.pop().unwrap();
+    cfg.log_dir = dirs.pop().unwrap();
+
+    match agent::fsck(&cfg, quarantine) {
+        Ok(report) => {
+            print!("{}", report);
+            if !report.is_clean() {
+                process::exit(1);
+            }
+        }
+        Err(err) => {
+            eprintln!("fsck failed: {}", err);
+            process::exit(2);
+        }
+    }
+}
+
+fn comparator(name: &str) -> Option<Arc<dyn Comparator>> {
+    let comparators: Vec<Arc<dyn Comparator>> = vec![
+        Arc::new(BytewiseComparator),
+        Arc::new(ReverseBytewiseComparator),
+        Arc::new(NumericComparator),
+    ];
+    comparators.into_iter().find(|c| c.name() == name)
+}
+
+fn read_key_file(path: &str) -> Result<StaticKeyProvider, String> {
+    let contents = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let mut fields = contents.split_whitespace();
+    let (key_id, hex) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(key_id), Some(hex), None) => (key_id, hex),
+        _ => return Err("expected a key id and a key".into()),
+    };
+    let key_id = match key_id.parse::<u32>() {
+        Ok(id) if id != PLAINTEXT_KEY_ID => id,
+        _ => return Err(format!("invalid key id: {}", key_id)),
+    };
+    if hex.len() != 2 * KEY_LENGTH || !hex.is_ascii() {
+        return Err(format!("key must be {} hex digits", 2 * KEY_LENGTH));
+    }
+    let mut key = [0u8; KEY_LENGTH];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| "key is not hex".to_string())?;
+    }
+    Ok(StaticKeyProvider::new(key_id, key))
 }
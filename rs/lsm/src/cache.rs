@@ -0,0 +1,352 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref, Range};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::fs::Mapping;
+
+const SHARD_COUNT: usize = 16;
+
+// Identifies a block by the file it belongs to and its offset in that file.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct BlockKey {
+    pub file_number: u64,
+    pub offset: u64,
+}
+
+// The contents of a table block. Holding a `Block` pins it: a pinned block is
+// never evicted, so iterators can keep borrowing from it while it stays in the
+// cache.
+//
+// A block read from a mapped table is a slice of the mapping instead, and is
+// never cached. It keeps the whole mapping alive, so iterators stay valid
+// even if the table is closed while they are in use.
+//
+// Values read from a table are slices of their block, so that they are not
+// copied out of the cache or the mapping. A value pins its block just like an
+// iterator does.
+#[derive(Clone)]
+pub struct Block {
+    buf: Mapping,
+    range: Range<usize>,
+}
+
+impl Block {
+    // Borrows `range` of a mapped file without copying it.
+    pub fn mapped(mapping: &Mapping, range: Range<usize>) -> Block {
+        assert!(range.start <= range.end && range.end <= (**mapping).as_ref().len());
+        Block {
+            buf: Arc::clone(mapping),
+            range,
+        }
+    }
+
+    // Borrows `range` of this block without copying it.
+    pub fn slice(&self, range: Range<usize>) -> Block {
+        assert!(range.start <= range.end && range.end <= self.len());
+        Block {
+            buf: Arc::clone(&self.buf),
+            range: self.range.start + range.start..self.range.start + range.end,
+        }
+    }
+
+    fn is_pinned(&self) -> bool {
+        Arc::strong_count(&self.buf) > 1
+    }
+}
+
+impl Deref for Block {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &(*self.buf).as_ref()[self.range.clone()]
+    }
+}
+
+impl fmt::Debug for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl PartialEq for Block {
+    fn eq(&self, other: &Block) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Block {}
+
+impl From<Vec<u8>> for Block {
+    fn from(data: Vec<u8>) -> Block {
+        Block {
+            range: 0..data.len(),
+            buf: Arc::new(data),
+        }
+    }
+}
+
+impl From<&[u8]> for Block {
+    fn from(data: &[u8]) -> Block {
+        data.to_vec().into()
+    }
+}
+
+// A size-bounded LRU cache of decoded table blocks, shared by all table
+// readers of an `Agent`. The cache is split into shards by key hash so that
+// concurrent readers rarely contend on the same lock.
+pub struct BlockCache {
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Shard {
+    capacity: usize,
+    usage: usize,
+    entries: HashMap<BlockKey, Entry>,
+    // Entries ordered from least to most recently used, keyed by the tick of
+    // their last access.
+    lru: BTreeMap<u64, BlockKey>,
+    tick: u64,
+}
+
+struct Entry {
+    block: Block,
+    last_used: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub usage_bytes: u64,
+    pub capacity_bytes: u64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> BlockCache {
+        let shard_capacity = capacity.div_ceil(SHARD_COUNT);
+        BlockCache {
+            shards: (0..SHARD_COUNT).map(|_| Mutex::new(Shard::new(shard_capacity))).collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: BlockKey) -> Option<Block> {
+        let block = self.shard(key).lock().unwrap().get(key);
+        let counter = if block.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        block
+    }
+
+    // Inserts a block and returns it pinned. If the block is already cached,
+    // the cached copy is returned instead.
+    pub fn insert(&self, key: BlockKey, data: Vec<u8>) -> Block {
+        self.shard(key).lock().unwrap().insert(key, data.into())
+    }
+
+    // Returns the cached block, or loads and caches it on a miss.
+    pub fn get_or_load<F, E>(&self, key: BlockKey, load: F) -> Result<Block, E>
+    where
+        F: FnOnce() -> Result<Vec<u8>, E>,
+    {
+        match self.get(key) {
+            Some(block) => Ok(block),
+            None => Ok(self.insert(key, load()?)),
+        }
+    }
+
+    // Drops every unpinned block of a file, for example after it is deleted by
+    // compaction.
+    pub fn evict_file(&self, file_number: u64) {
+        for shard in &self.shards {
+            shard.lock().unwrap().evict_file(file_number);
+        }
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        let (usage, capacity) = self.shards.iter().fold((0, 0), |(usage, capacity), shard| {
+            let shard = shard.lock().unwrap();
+            (usage + shard.usage, capacity + shard.capacity)
+        });
+        BlockCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            usage_bytes: usage as u64,
+            capacity_bytes: capacity as u64,
+        }
+    }
+
+    fn shard(&self, key: BlockKey) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARD_COUNT]
+    }
+}
+
+impl Shard {
+    fn new(capacity: usize) -> Shard {
+        Shard {
+            capacity,
+            usage: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, key: BlockKey) -> Option<Block> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(&key)?;
+        self.lru.remove(&entry.last_used);
+        self.lru.insert(tick, key);
+        entry.last_used = tick;
+        Some(entry.block.clone())
+    }
+
+    fn insert(&mut self, key: BlockKey, block: Block) -> Block {
+        if let Some(existing) = self.get(key) {
+            return existing;
+        }
+        self.evict(block.len());
+        let tick = self.next_tick();
+        self.usage += block.len();
+        self.lru.insert(tick, key);
+        self.entries.insert(
+            key,
+            Entry {
+                block: block.clone(),
+                last_used: tick,
+            },
+        );
+        block
+    }
+
+    // Evicts least recently used blocks until `incoming` more bytes fit within
+    // the shard's capacity. Pinned blocks are skipped, so usage can exceed
+    // capacity while many blocks are in use.
+    fn evict(&mut self, incoming: usize) {
+        let mut victims = Vec::new();
+        let mut usage = self.usage;
+        for (&tick, key) in &self.lru {
+            if usage + incoming <= self.capacity {
+                break;
+            }
+            let entry = &self.entries[key];
+            if !entry.block.is_pinned() {
+                usage -= entry.block.len();
+                victims.push(tick);
+            }
+        }
+        for tick in victims {
+            self.remove(tick);
+        }
+    }
+
+    fn evict_file(&mut self, file_number: u64) {
+        let victims = self
+            .lru
+            .iter()
+            .filter(|(_, key)| key.file_number == file_number)
+            .filter(|(_, key)| !self.entries[key].block.is_pinned())
+            .map(|(&tick, _)| tick)
+            .collect::<Vec<_>>();
+        for tick in victims {
+            self.remove(tick);
+        }
+    }
+
+    fn remove(&mut self, tick: u64) {
+        let key = self.lru.remove(&tick).unwrap();
+        let entry = self.entries.remove(&key).unwrap();
+        self.usage -= entry.block.len();
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(offset: u64) -> BlockKey {
+        BlockKey {
+            file_number: 1,
+            offset,
+        }
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut shard = Shard::new(300);
+        shard.insert(key(0), vec![0u8; 100].into());
+        shard.insert(key(1), vec![1u8; 100].into());
+        shard.insert(key(2), vec![2u8; 100].into());
+
+        // Touch block 0 so that block 1 is the least recently used.
+        assert!(shard.get(key(0)).is_some());
+        shard.insert(key(3), vec![3u8; 100].into());
+
+        assert!(shard.get(key(1)).is_none());
+        assert!(shard.get(key(0)).is_some());
+        assert_eq!(300, shard.usage);
+    }
+
+    #[test]
+    fn test_pinned_blocks_are_not_evicted() {
+        let mut shard = Shard::new(200);
+        let pinned = shard.insert(key(0), vec![0u8; 100].into());
+        shard.insert(key(1), vec![1u8; 100].into());
+        shard.insert(key(2), vec![2u8; 100].into());
+
+        // The unpinned block is evicted and the pinned one stays, even though
+        // it is older.
+        assert!(!shard.entries.contains_key(&key(1)));
+        assert!(shard.entries.contains_key(&key(0)));
+
+        // Once released, it can be evicted again.
+        drop(pinned);
+        shard.insert(key(3), vec![3u8; 100].into());
+        assert!(!shard.entries.contains_key(&key(0)));
+        assert_eq!(200, shard.usage);
+
+        // When everything is pinned, usage exceeds capacity rather than
+        // evicting blocks that are in use.
+        let pins = (4..7).map(|i| shard.insert(key(i), vec![0u8; 100].into())).collect::<Vec<_>>();
+        assert_eq!(300, shard.usage);
+        drop(pins);
+    }
+
+    #[test]
+    fn test_block_cache_stats() {
+        let cache = BlockCache::new(1 << 20);
+        let mut loads = 0;
+        for _ in 0..3 {
+            let block = cache
+                .get_or_load::<_, ()>(key(7), || {
+                    loads += 1;
+                    Ok(vec![7u8; 64])
+                })
+                .unwrap();
+            assert_eq!(64, block.len());
+        }
+        assert!(cache.get(key(8)).is_none());
+
+        let stats = cache.stats();
+        assert_eq!(1, loads);
+        assert_eq!(2, stats.hits);
+        assert_eq!(2, stats.misses);
+        assert_eq!(64, stats.usage_bytes);
+
+        cache.evict_file(1);
+        assert_eq!(0, cache.stats().usage_bytes);
+    }
+}
@@ -0,0 +1,273 @@
+// Compaction strategies decide which tables to merge next. They only look at
+// table metadata. `Agent::compact` runs the compactions that the configured
+// strategy picks after each flush and ingestion.
+use std::cmp::Ordering;
+
+use crate::comparator::Comparator;
+
+// Metadata for one table, as recorded in the manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableInfo {
+    pub file_number: u64,
+    pub level: usize,
+    pub size: u64,
+    // Sequence number of the newest write in the table. A compaction output
+    // inherits the largest sequence number of its inputs, so this orders the
+    // sorted runs of level 0 by the age of their data. It does not order
+    // tables in different levels.
+    pub largest_seq: u64,
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compaction {
+    // File numbers of the tables to merge.
+    pub inputs: Vec<u64>,
+    pub output_level: usize,
+    // Size at which the output is split into a new table, or `None` to write
+    // the output as a single table.
+    pub target_file_size: Option<u64>,
+}
+
+pub trait CompactionStrategy: Send + Sync {
+    fn name(&self) -> &str;
+
+    // Number of levels that tables may be placed in. The last level holds the
+    // oldest data.
+    fn num_levels(&self) -> usize;
+
+    // Returns the next compaction to run over `tables`, or `None` if the
+    // shape of the tree is acceptable as it is. `cmp` orders the tables' key
+    // ranges.
+    fn pick(&self, tables: &[TableInfo], cmp: &dyn Comparator) -> Option<Compaction>;
+}
+
+// Leveled compaction keeps each level below level 0 as a single sorted run
+// whose size is bounded by a target that grows by `level_size_multiplier` per
+// level. This keeps space and read amplification low, at the cost of
+// rewriting data about `level_size_multiplier` times per level.
+#[derive(Debug, Clone)]
+pub struct LeveledCompaction {
+    pub level0_file_num_trigger: usize,
+    // Target size of level 1.
+    pub base_level_bytes: u64,
+    pub level_size_multiplier: u64,
+    pub num_levels: usize,
+    pub target_file_size: u64,
+}
+
+impl Default for LeveledCompaction {
+    fn default() -> LeveledCompaction {
+        LeveledCompaction {
+            level0_file_num_trigger: 4,
+            base_level_bytes: 256 * 1024 * 1024,
+            level_size_multiplier: 10,
+            num_levels: 7,
+            target_file_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl CompactionStrategy for LeveledCompaction {
+    fn name(&self) -> &str {
+        "leveled"
+    }
+
+    fn num_levels(&self) -> usize {
+        self.num_levels
+    }
+
+    fn pick(&self, tables: &[TableInfo], cmp: &dyn Comparator) -> Option<Compaction> {
+        let level0 = tables.iter().filter(|t| t.level == 0).collect::<Vec<_>>();
+        if level0.len() >= self.level0_file_num_trigger {
+            let smallest = level0.iter().map(|t| &t.smallest).min_by(|a, b| cmp.compare(a, b)).unwrap();
+            let largest = level0.iter().map(|t| &t.largest).max_by(|a, b| cmp.compare(a, b)).unwrap();
+            let mut inputs = level0.iter().map(|t| t.file_number).collect::<Vec<_>>();
+            inputs.extend(overlapping(tables, cmp, 1, smallest, largest));
+            return Some(self.compaction(inputs, 1));
+        }
+
+        // Compact the level that is furthest over its target, starting from
+        // its oldest table.
+        let mut target = self.base_level_bytes;
+        let mut best: Option<(f64, usize)> = None;
+        for level in 1..self.num_levels - 1 {
+            let size = tables.iter().filter(|t| t.level == level).map(|t| t.size).sum::<u64>();
+            let score = size as f64 / target as f64;
+            if score > 1.0 && best.is_none_or(|(s, _)| score > s) {
+                best = Some((score, level));
+            }
+            target = target.saturating_mul(self.level_size_multiplier);
+        }
+        let (_, level) = best?;
+        let table = tables
+            .iter()
+            .filter(|t| t.level == level)
+            .min_by_key(|t| t.file_number)
+            .unwrap();
+        let mut inputs = vec![table.file_number];
+        inputs.extend(overlapping(tables, cmp, level + 1, &table.smallest, &table.largest));
+        Some(self.compaction(inputs, level + 1))
+    }
+}
+
+impl LeveledCompaction {
+    fn compaction(&self, inputs: Vec<u64>, output_level: usize) -> Compaction {
+        Compaction {
+            inputs,
+            output_level,
+            target_file_size: Some(self.target_file_size),
+        }
+    }
+}
+
+// Universal (size-tiered) compaction keeps every table in level 0 as its own
+// sorted run, and merges runs of similar size. Each byte is rewritten only
+// about once per size tier, so write amplification is much lower than with
+// leveling, but up to `max_size_amplification_percent` extra space is used by
+// data that has not yet been merged away.
+#[derive(Debug, Clone)]
+pub struct UniversalCompaction {
+    // Runs are only merged once there are at least this many.
+    pub level0_file_num_trigger: usize,
+    // A run is merged with the newer runs before it if it is no more than
+    // this percentage larger than their combined size.
+    pub size_ratio_percent: u64,
+    pub min_merge_width: usize,
+    // Everything is merged into a single run once the newer runs add up to
+    // this percentage of the oldest (and normally largest) run.
+    pub max_size_amplification_percent: u64,
+}
+
+impl Default for UniversalCompaction {
+    fn default() -> UniversalCompaction {
+        UniversalCompaction {
+            level0_file_num_trigger: 4,
+            size_ratio_percent: 1,
+            min_merge_width: 2,
+            max_size_amplification_percent: 200,
+        }
+    }
+}
+
+impl CompactionStrategy for UniversalCompaction {
+    fn name(&self) -> &str {
+        "universal"
+    }
+
+    fn num_levels(&self) -> usize {
+        1
+    }
+
+    fn pick(&self, tables: &[TableInfo], _cmp: &dyn Comparator) -> Option<Compaction> {
+        let mut runs = tables.iter().collect::<Vec<_>>();
+        if runs.len() < self.level0_file_num_trigger {
+            return None;
+        }
+        // Newest first.
+        runs.sort_by_key(|t| std::cmp::Reverse(t.largest_seq));
+        let compaction = |runs: &[&TableInfo]| Compaction {
+            inputs: runs.iter().map(|t| t.file_number).collect(),
+            output_level: 0,
+            target_file_size: None,
+        };
+
+        let (oldest, newer) = runs.split_last().unwrap();
+        let newer_size = newer.iter().map(|t| t.size).sum::<u64>();
+        if newer_size.saturating_mul(100) >= oldest.size.saturating_mul(self.max_size_amplification_percent) {
+            return Some(compaction(&runs));
+        }
+
+        // Merge the longest prefix of newer runs in which each run is not
+        // much larger than all of the runs before it.
+        for start in 0..runs.len() {
+            let mut acc = runs[start].size;
+            let mut end = start + 1;
+            while end < runs.len() && runs[end].size.saturating_mul(100) <= acc.saturating_mul(100 + self.size_ratio_percent) {
+                acc += runs[end].size;
+                end += 1;
+            }
+            if end - start >= self.min_merge_width {
+                return Some(compaction(&runs[start..end]));
+            }
+        }
+
+        // No runs are similar in size, so merge just enough of the newest
+        // runs to get back under the trigger.
+        let width = runs.len() - self.level0_file_num_trigger + 1;
+        Some(compaction(&runs[..width.max(self.min_merge_width)]))
+    }
+}
+
+// File numbers of the tables in `level` whose key range overlaps
+// [smallest, largest].
+fn overlapping<'a>(
+    tables: &'a [TableInfo],
+    cmp: &'a dyn Comparator,
+    level: usize,
+    smallest: &'a [u8],
+    largest: &'a [u8],
+) -> impl Iterator<Item = u64> + 'a {
+    tables
+        .iter()
+        .filter(move |t| {
+            t.level == level
+                && cmp.compare(&t.smallest, largest) != Ordering::Greater
+                && cmp.compare(smallest, &t.largest) != Ordering::Greater
+        })
+        .map(|t| t.file_number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparator::BytewiseComparator;
+
+    fn table(file_number: u64, level: usize, size: u64) -> TableInfo {
+        TableInfo {
+            file_number,
+            level,
+            size,
+            largest_seq: file_number,
+            smallest: vec![0],
+            largest: vec![0xff],
+        }
+    }
+
+    #[test]
+    fn test_leveled_pick() {
+        let strategy = LeveledCompaction {
+            base_level_bytes: 100,
+            ..LeveledCompaction::default()
+        };
+        let mut tables = vec![table(1, 1, 60), table(2, 2, 500), table(3, 0, 10), table(4, 0, 10), table(5, 0, 10)];
+        assert_eq!(None, strategy.pick(&tables, &BytewiseComparator));
+
+        // Level 0 is merged into level 1 once it reaches the trigger.
+        tables.push(table(6, 0, 10));
+        assert_eq!(vec![3, 4, 5, 6, 1], strategy.pick(&tables, &BytewiseComparator).unwrap().inputs);
+
+        // An oversized level is pushed down into the next.
+        tables.retain(|t| t.level != 0);
+        tables.push(table(7, 1, 60));
+        let compaction = strategy.pick(&tables, &BytewiseComparator).unwrap();
+        assert_eq!((vec![1, 2], 2), (compaction.inputs, compaction.output_level));
+    }
+
+    #[test]
+    fn test_universal_pick() {
+        let strategy = UniversalCompaction::default();
+        let tables = [table(1, 0, 1000), table(2, 0, 10), table(3, 0, 10)];
+        assert_eq!(None, strategy.pick(&tables, &BytewiseComparator));
+
+        // Similar-sized newer runs are merged, leaving the large run alone.
+        let tables = [table(1, 0, 1000), table(2, 0, 100), table(3, 0, 10), table(4, 0, 10)];
+        assert_eq!(vec![4, 3], strategy.pick(&tables, &BytewiseComparator).unwrap().inputs);
+
+        // Everything is merged once the newer runs are too large relative to
+        // the oldest.
+        let tables = [table(1, 0, 100), table(2, 0, 150), table(3, 0, 40), table(4, 0, 20)];
+        assert_eq!(vec![4, 3, 2, 1], strategy.pick(&tables, &BytewiseComparator).unwrap().inputs);
+    }
+}
@@ -0,0 +1,102 @@
+use std::cmp::Ordering;
+
+// Defines the order of keys in the memtable, in tables and in scans.
+//
+// The comparator's name is recorded in the log when a store is created, and
+// the store cannot be opened with a comparator of another name, since its
+// tables would not be in the order that readers expect. Give a comparator a
+// new name whenever its ordering changes.
+//
+// `compare` must be a total order, and may only return `Equal` for keys that
+// are byte for byte identical.
+pub trait Comparator: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+// Orders keys bytewise. This is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        "lsm.BytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+// Orders keys bytewise, largest first, so that scans return the newest of
+// keys such as timestamps first.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReverseBytewiseComparator;
+
+impl Comparator for ReverseBytewiseComparator {
+    fn name(&self) -> &str {
+        "lsm.ReverseBytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+// Orders keys that are unsigned decimal numbers in ASCII by their value, so
+// that "9" sorts before "10", and leading zeros are ignored. Numbers that are
+// equal apart from leading zeros are ordered bytewise.
+//
+// Keys that are not numbers are still totally ordered: every key is ordered
+// by its length without leading '0' bytes, then bytewise.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NumericComparator;
+
+impl Comparator for NumericComparator {
+    fn name(&self) -> &str {
+        "lsm.NumericComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let trim = |k: &[u8]| {
+            let zeros = k.iter().take_while(|&&b| b == b'0').count();
+            // Keep the last zero of a key that is all zeros, so that 0 sorts
+            // by the same rules as any other number.
+            k[zeros.min(k.len().saturating_sub(1))..].to_vec()
+        };
+        let (ta, tb) = (trim(a), trim(b));
+        ta.len()
+            .cmp(&tb.len())
+            .then_with(|| ta.cmp(&tb))
+            .then_with(|| a.cmp(b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(cmp: &dyn Comparator, keys: &[&str]) -> Vec<String> {
+        let mut keys = keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+        keys.sort_by(|a, b| cmp.compare(a.as_bytes(), b.as_bytes()));
+        keys
+    }
+
+    #[test]
+    fn test_comparators() {
+        let keys = ["10", "9", "", "0100", "100", "0", "00", "a", "b"];
+        assert_eq!(
+            vec!["", "0", "00", "0100", "10", "100", "9", "a", "b"],
+            sorted(&BytewiseComparator, &keys)
+        );
+        assert_eq!(
+            vec!["b", "a", "9", "100", "10", "0100", "00", "0", ""],
+            sorted(&ReverseBytewiseComparator, &keys)
+        );
+        assert_eq!(
+            vec!["", "0", "00", "9", "a", "b", "10", "0100", "100"],
+            sorted(&NumericComparator, &keys)
+        );
+    }
+}
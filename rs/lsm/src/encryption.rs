@@ -0,0 +1,198 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+pub const KEY_LENGTH: usize = 32;
+pub type Key = [u8; KEY_LENGTH];
+
+// Key id recorded in the header of files that are not encrypted. Key
+// providers must never hand out this id.
+pub const PLAINTEXT_KEY_ID: u32 = 0;
+
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+// Supplies the keys that data files are encrypted with. Every file records
+// the id of the key it was written with, so a provider must be able to look
+// up retired keys for as long as files written with them exist.
+pub trait KeyProvider: Send + Sync {
+    // The id of the key that newly created files should be encrypted with.
+    fn current_key_id(&self) -> u32;
+
+    fn key(&self, key_id: u32) -> io::Result<Key>;
+}
+
+// Provides a single fixed key.
+pub struct StaticKeyProvider {
+    key_id: u32,
+    key: Key,
+}
+
+impl StaticKeyProvider {
+    pub fn new(key_id: u32, key: Key) -> StaticKeyProvider {
+        assert_ne!(key_id, PLAINTEXT_KEY_ID, "key id {} is reserved", PLAINTEXT_KEY_ID);
+        StaticKeyProvider { key_id, key }
+    }
+}
+
+impl KeyProvider for StaticKeyProvider {
+    fn current_key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    fn key(&self, key_id: u32) -> io::Result<Key> {
+        if key_id != self.key_id {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Unknown encryption key id: {}", key_id),
+            ));
+        }
+        Ok(self.key)
+    }
+}
+
+// Wrapped by the `io::Error`s for encrypted data that cannot be decrypted with
+// the keys at hand: no key provider was supplied, the provider does not have
+// the data's key, or the data failed authentication because it was damaged,
+// moved, or sealed with another key.
+#[derive(Debug)]
+pub struct DecryptionError(String);
+
+impl DecryptionError {
+    // Whether `err` wraps a `DecryptionError`.
+    pub fn is(err: &io::Error) -> bool {
+        err.get_ref().is_some_and(|e| e.is::<DecryptionError>())
+    }
+}
+
+impl fmt::Display for DecryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for DecryptionError {}
+
+fn decryption_error(kind: io::ErrorKind, msg: String) -> io::Error {
+    io::Error::new(kind, DecryptionError(msg))
+}
+
+// Authenticated encryption (ChaCha20-Poly1305) of individual records or
+// blocks. Sealed data has the format:
+//
+// +-------+------------+-----+
+// | nonce | ciphertext | tag |
+// +-------+------------+-----+
+//  12 bytes             16 bytes
+//
+// Nonces are random, so the same key can safely be used across files. The
+// associated data is authenticated but not stored, and callers use it to
+// bind sealed data to its location so that it cannot be moved undetected.
+pub(crate) struct Cipher {
+    key_id: u32,
+    aead: ChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub(crate) const OVERHEAD: usize = NONCE_LENGTH + TAG_LENGTH;
+
+    pub(crate) fn new(key_id: u32, key: &Key) -> Cipher {
+        Cipher {
+            key_id,
+            aead: ChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    pub(crate) fn for_key_id(provider: &dyn KeyProvider, key_id: u32) -> io::Result<Cipher> {
+        Ok(Cipher::new(key_id, &provider.key(key_id)?))
+    }
+
+    // Returns the cipher for reading a file that records `key_id` in its
+    // header or footer, or `None` if the file is not encrypted. `what` names
+    // the kind of file in errors.
+    pub(crate) fn for_reading(
+        provider: Option<&dyn KeyProvider>,
+        key_id: u32,
+        what: &str,
+    ) -> io::Result<Option<Cipher>> {
+        match (key_id, provider) {
+            (PLAINTEXT_KEY_ID, _) => Ok(None),
+            (key_id, Some(provider)) => match provider.key(key_id) {
+                Ok(key) => Ok(Some(Cipher::new(key_id, &key))),
+                Err(err) => Err(decryption_error(
+                    err.kind(),
+                    format!("{} is encrypted with key {}: {}", what, key_id, err),
+                )),
+            },
+            (key_id, None) => Err(decryption_error(
+                io::ErrorKind::InvalidInput,
+                format!("{} is encrypted with key {}, but no key provider was supplied", what, key_id),
+            )),
+        }
+    }
+
+    pub(crate) fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    // A random id for a file, to bind its sealed contents to it.
+    pub(crate) fn random_id() -> u64 {
+        OsRng.next_u64()
+    }
+
+    pub(crate) fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .aead
+            .encrypt(&nonce, Payload { msg: plaintext, aad })
+            .expect("ChaCha20Poly1305 encryption is infallible for in-memory buffers");
+
+        let mut sealed = Vec::with_capacity(NONCE_LENGTH + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    pub(crate) fn open(&self, aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+        if sealed.len() < Cipher::OVERHEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Encrypted data is shorter than its nonce and tag",
+            ));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        self.aead
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| {
+                decryption_error(
+                    io::ErrorKind::InvalidData,
+                    "Encrypted data failed authentication".to_string(),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let cipher = Cipher::new(1, &[7u8; KEY_LENGTH]);
+        let sealed = cipher.seal(b"offset 2", b"secret value");
+        assert_eq!(b"secret value".len() + Cipher::OVERHEAD, sealed.len());
+        assert_eq!(b"secret value".to_vec(), cipher.open(b"offset 2", &sealed).unwrap());
+
+        // Wrong associated data, tampered ciphertext and the wrong key are all rejected.
+        assert!(cipher.open(b"offset 3", &sealed).is_err());
+        let mut tampered = sealed.clone();
+        tampered[NONCE_LENGTH] ^= 1;
+        assert!(cipher.open(b"offset 2", &tampered).is_err());
+        let other = Cipher::new(2, &[8u8; KEY_LENGTH]);
+        assert!(other.open(b"offset 2", &sealed).is_err());
+    }
+}
@@ -0,0 +1,481 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::{FileHandle, FileSystem, Mapping};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FsOp {
+    Open,
+    Read,
+    Write,
+    Sync,
+    SyncDir,
+    SetLen,
+    Rename,
+    Remove,
+    Map,
+}
+
+// An in-memory file system for testing crash and I/O error handling.
+//
+// Each file tracks how much of its contents has been synced. `crash` models
+// a power loss: unsynced bytes are discarded, or partially kept to model torn
+// writes, and every handle opened before the crash stops working. Individual
+// calls can also be made to fail with `fail_nth` and `tear_nth_write`.
+//
+// Creating, renaming or removing a file only survives a crash once its
+// directory has been synced with `sync_dir`: a crash puts back the entries
+// as of each directory's last sync, so a file that was removed since comes
+// back with its synced contents. Directories themselves are durable, and only
+// exist as a namespace for `read_dir`.
+#[derive(Clone, Default)]
+pub struct MemFileSystem {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    // The inode of the file at each path.
+    files: BTreeMap<PathBuf, u64>,
+    // The entries that survive a crash, as of the last sync of their
+    // directory.
+    synced_files: BTreeMap<PathBuf, u64>,
+    inodes: BTreeMap<u64, MemFile>,
+    next_inode: u64,
+    dirs: BTreeSet<PathBuf>,
+    // Incremented on every crash to invalidate open handles.
+    generation: u64,
+    faults: Vec<Fault>,
+}
+
+#[derive(Default)]
+struct MemFile {
+    data: Vec<u8>,
+    synced_len: usize,
+}
+
+struct Fault {
+    op: FsOp,
+    // Number of matching calls to let through before this fault fires.
+    skip: usize,
+    // For writes, the number of bytes that reach the file before failing.
+    keep: usize,
+}
+
+impl MemFileSystem {
+    pub fn new() -> MemFileSystem {
+        MemFileSystem::default()
+    }
+
+    // Makes the `n`th upcoming call of `op` (counting from 0) fail without
+    // any effect.
+    pub fn fail_nth(&self, op: FsOp, n: usize) {
+        self.lock().faults.push(Fault { op, skip: n, keep: 0 });
+    }
+
+    // Makes the `n`th upcoming write (counting from 0) append only the first
+    // `keep` bytes of its buffer and then fail.
+    pub fn tear_nth_write(&self, n: usize, keep: usize) {
+        self.lock().faults.push(Fault {
+            op: FsOp::Write,
+            skip: n,
+            keep,
+        });
+    }
+
+    // Simulates a crash that loses all unsynced data.
+    pub fn crash(&self) {
+        self.crash_with(|_, _| 0);
+    }
+
+    // Simulates a crash in which `keep(path, unsynced_len)` bytes of each
+    // file's unsynced data happened to reach the disk.
+    pub fn crash_with<F>(&self, mut keep: F)
+    where
+        F: FnMut(&Path, usize) -> usize,
+    {
+        let mut state = self.lock();
+        let state = &mut *state;
+        state.files = state.synced_files.clone();
+        state.collect_garbage();
+        for (path, inode) in &state.files {
+            let file = state.inodes.get_mut(inode).unwrap();
+            let unsynced = file.data.len() - file.synced_len;
+            let len = file.synced_len + keep(path, unsynced).min(unsynced);
+            file.data.truncate(len);
+            file.synced_len = len;
+        }
+        state.generation += 1;
+        state.faults.clear();
+    }
+
+    // Returns the current contents of the file at `path`, including unsynced
+    // data.
+    pub fn contents(&self, path: &Path) -> Option<Vec<u8>> {
+        let state = self.lock();
+        let inode = state.files.get(path)?;
+        Some(state.inodes[inode].data.clone())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl State {
+    // Drops the files that are neither in a directory nor would be put back
+    // in one by a crash.
+    fn collect_garbage(&mut self) {
+        let live = self.files.values().chain(self.synced_files.values()).copied().collect::<BTreeSet<_>>();
+        self.inodes.retain(|inode, _| live.contains(inode));
+    }
+
+    // Returns the fault that applies to this call of `op`, if any.
+    fn check(&mut self, op: FsOp) -> Option<Fault> {
+        let mut fired = None;
+        for (i, fault) in self.faults.iter_mut().enumerate() {
+            if fault.op != op {
+                continue;
+            }
+            if fault.skip > 0 {
+                fault.skip -= 1;
+            } else if fired.is_none() {
+                fired = Some(i);
+            }
+        }
+        fired.map(|i| self.faults.remove(i))
+    }
+}
+
+fn injected(op: FsOp) -> io::Error {
+    io::Error::other(format!("injected {:?} failure", op))
+}
+
+impl FileSystem for MemFileSystem {
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        let mut state = self.lock();
+        if state.check(FsOp::Open).is_some() {
+            return Err(injected(FsOp::Open));
+        }
+        let inode = match state.files.get(path) {
+            Some(&inode) => inode,
+            None => {
+                let inode = state.next_inode;
+                state.next_inode += 1;
+                state.inodes.insert(inode, MemFile::default());
+                state.files.insert(path.to_path_buf(), inode);
+                inode
+            }
+        };
+        Ok(Box::new(MemHandle {
+            fs: self.clone(),
+            inode,
+            generation: state.generation,
+        }))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        let mut state = self.lock();
+        if state.check(FsOp::Open).is_some() {
+            return Err(injected(FsOp::Open));
+        }
+        let inode = *state.files.get(path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No such file: {}", path.display()),
+            )
+        })?;
+        Ok(Box::new(MemHandle {
+            fs: self.clone(),
+            inode,
+            generation: state.generation,
+        }))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.lock();
+        if !state.dirs.contains(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No such directory: {}", path.display()),
+            ));
+        }
+        let files = state.files.keys();
+        let dirs = state.dirs.iter();
+        let mut entries = files
+            .chain(dirs)
+            .filter(|p| p.parent() == Some(path))
+            .cloned()
+            .collect::<Vec<_>>();
+        entries.sort();
+        Ok(entries)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        for dir in path.ancestors() {
+            state.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    // Handles opened on `from` keep working, as on Unix.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        if state.check(FsOp::Rename).is_some() {
+            return Err(injected(FsOp::Rename));
+        }
+        let file = state.files.remove(from).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No such file: {}", from.display()),
+            )
+        })?;
+        state.files.insert(to.to_path_buf(), file);
+        state.collect_garbage();
+        Ok(())
+    }
+
+    // Handles opened on the file stop working once it has been removed.
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        if state.check(FsOp::Remove).is_some() {
+            return Err(injected(FsOp::Remove));
+        }
+        match state.files.remove(path) {
+            Some(_) => {
+                state.collect_garbage();
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No such file: {}", path.display()),
+            )),
+        }
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        if state.check(FsOp::SyncDir).is_some() {
+            return Err(injected(FsOp::SyncDir));
+        }
+        if !state.dirs.contains(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No such directory: {}", path.display()),
+            ));
+        }
+        let state = &mut *state;
+        state.synced_files.retain(|p, _| p.parent() != Some(path));
+        let entries = state.files.iter().filter(|(p, _)| p.parent() == Some(path));
+        state.synced_files.extend(entries.map(|(p, &inode)| (p.clone(), inode)));
+        state.collect_garbage();
+        Ok(())
+    }
+}
+
+struct MemHandle {
+    fs: MemFileSystem,
+    inode: u64,
+    generation: u64,
+}
+
+impl MemHandle {
+    fn with_file<F, T>(&self, op: FsOp, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut MemFile, Option<Fault>) -> io::Result<T>,
+    {
+        let mut state = self.fs.lock();
+        if state.generation != self.generation {
+            return Err(io::Error::other("file handle was opened before a crash"));
+        }
+        let fault = state.check(op);
+        if !state.files.values().any(|&inode| inode == self.inode) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "File was removed"));
+        }
+        f(state.inodes.get_mut(&self.inode).unwrap(), fault)
+    }
+}
+
+impl FileHandle for MemHandle {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.with_file(FsOp::Read, |file, fault| {
+            if fault.is_some() {
+                return Err(injected(FsOp::Read));
+            }
+            let start = (offset as usize).min(file.data.len());
+            let n = buf.len().min(file.data.len() - start);
+            buf[..n].copy_from_slice(&file.data[start..start + n]);
+            Ok(n)
+        })
+    }
+
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.with_file(FsOp::Write, |file, fault| match fault {
+            Some(Fault { keep, .. }) => {
+                file.data.extend_from_slice(&buf[..keep.min(buf.len())]);
+                Err(injected(FsOp::Write))
+            }
+            None => {
+                file.data.extend_from_slice(buf);
+                Ok(())
+            }
+        })
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.with_file(FsOp::Sync, |file, fault| {
+            if fault.is_some() {
+                return Err(injected(FsOp::Sync));
+            }
+            file.synced_len = file.data.len();
+            Ok(())
+        })
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.with_file(FsOp::Read, |file, fault| {
+            if fault.is_some() {
+                return Err(injected(FsOp::Read));
+            }
+            Ok(file.data.len() as u64)
+        })
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.with_file(FsOp::SetLen, |file, fault| {
+            if fault.is_some() {
+                return Err(injected(FsOp::SetLen));
+            }
+            file.data.resize(len as usize, 0);
+            file.synced_len = file.synced_len.min(file.data.len());
+            Ok(())
+        })
+    }
+
+    // Mapped files are immutable, so a copy of the contents behaves the same
+    // as a real mapping.
+    fn map(&self) -> io::Result<Option<Mapping>> {
+        self.with_file(FsOp::Map, |file, fault| {
+            if fault.is_some() {
+                return Err(injected(FsOp::Map));
+            }
+            let mapping: Mapping = Arc::new(file.data.clone());
+            Ok(Some(mapping))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(fs: &MemFileSystem, name: &str) -> Box<dyn FileHandle> {
+        fs.create_dir_all(Path::new("/data")).unwrap();
+        let file = fs.open_append(&Path::new("/data").join(name)).unwrap();
+        fs.sync_dir(Path::new("/data")).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_crash_drops_unsynced() {
+        let fs = MemFileSystem::new();
+        let mut f = open(&fs, "a");
+        f.append(b"synced").unwrap();
+        f.sync().unwrap();
+        f.append(b" lost").unwrap();
+        assert_eq!(11, f.size().unwrap());
+
+        fs.crash();
+        assert!(f.append(b"stale").is_err());
+
+        let f = open(&fs, "a");
+        let mut buf = vec![0u8; 16];
+        let n = f.read_at(&mut buf, 0).unwrap();
+        assert_eq!(b"synced", &buf[..n]);
+    }
+
+    #[test]
+    fn test_crash_with_torn_write() {
+        let fs = MemFileSystem::new();
+        let mut f = open(&fs, "a");
+        f.append(b"abc").unwrap();
+        f.sync().unwrap();
+        f.append(b"defgh").unwrap();
+
+        fs.crash_with(|_, unsynced| {
+            assert_eq!(5, unsynced);
+            2
+        });
+        assert_eq!(Some(b"abcde".to_vec()), fs.contents(Path::new("/data/a")));
+    }
+
+    #[test]
+    fn test_crash_drops_unsynced_entries() {
+        let fs = MemFileSystem::new();
+        let dir = Path::new("/data");
+        let mut a = open(&fs, "a");
+        a.append(b"a").unwrap();
+        a.sync().unwrap();
+        let mut b = open(&fs, "b");
+        b.append(b"b").unwrap();
+        b.sync().unwrap();
+
+        // A created file, a rename and a removal, none of them synced.
+        let mut c = fs.open_append(&dir.join("c")).unwrap();
+        c.append(b"c").unwrap();
+        c.sync().unwrap();
+        fs.rename(&dir.join("a"), &dir.join("d")).unwrap();
+        fs.remove(&dir.join("b")).unwrap();
+        let names = |fs: &MemFileSystem| fs.read_dir(dir).unwrap();
+        assert_eq!(vec![dir.join("c"), dir.join("d")], names(&fs));
+
+        fs.crash();
+        assert_eq!(vec![dir.join("a"), dir.join("b")], names(&fs));
+        assert_eq!(Some(b"a".to_vec()), fs.contents(&dir.join("a")));
+        assert_eq!(Some(b"b".to_vec()), fs.contents(&dir.join("b")));
+
+        // Once the directory is synced, they survive a crash.
+        fs.open_append(&dir.join("c")).unwrap();
+        fs.rename(&dir.join("a"), &dir.join("d")).unwrap();
+        fs.remove(&dir.join("b")).unwrap();
+        fs.sync_dir(dir).unwrap();
+        fs.crash();
+        assert_eq!(vec![dir.join("c"), dir.join("d")], names(&fs));
+        assert_eq!(Some(b"a".to_vec()), fs.contents(&dir.join("d")));
+    }
+
+    #[test]
+    fn test_handles_follow_renames() {
+        let fs = MemFileSystem::new();
+        let mut f = open(&fs, "a");
+        fs.rename(Path::new("/data/a"), Path::new("/data/b")).unwrap();
+        f.append(b"moved").unwrap();
+        assert_eq!(Some(b"moved".to_vec()), fs.contents(Path::new("/data/b")));
+
+        fs.remove(Path::new("/data/b")).unwrap();
+        assert_eq!(io::ErrorKind::NotFound, f.append(b"gone").unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_injected_faults() {
+        let fs = MemFileSystem::new();
+        let mut f = open(&fs, "a");
+
+        fs.fail_nth(FsOp::Sync, 1);
+        f.sync().unwrap();
+        assert!(f.sync().is_err());
+        f.sync().unwrap();
+
+        fs.tear_nth_write(0, 3);
+        assert!(f.append(b"abcdef").is_err());
+        f.append(b"xyz").unwrap();
+        assert_eq!(Some(b"abcxyz".to_vec()), fs.contents(Path::new("/data/a")));
+
+        fs.fail_nth(FsOp::Open, 0);
+        assert!(fs.open_append(Path::new("/data/b")).is_err());
+        assert_eq!(vec![PathBuf::from("/data/a")], fs.read_dir(Path::new("/data")).unwrap());
+    }
+}
@@ -0,0 +1,82 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub mod mem;
+pub mod os;
+
+pub use mem::{FsOp, MemFileSystem};
+pub use os::OsFileSystem;
+
+// The contents of a file mapped into memory. The mapping stays valid for as
+// long as any clone of it is alive, even after the handle is dropped.
+pub type Mapping = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+// All file access made by the storage engine goes through a `FileSystem`, so
+// that tests can substitute an implementation that loses unsynced data or
+// fails individual calls.
+pub trait FileSystem: Send + Sync {
+    // Opens the file at `path` for reading and appending, creating it if it
+    // does not exist.
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn FileHandle>>;
+
+    // Opens an existing file for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>>;
+
+    // Lists the entries of a directory in sorted order.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    // Moves a file, replacing anything already at `to`.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    // Makes the files created, renamed or removed in a directory so far
+    // durable, as `FileHandle::sync` does for a file's contents.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+}
+
+pub trait FileHandle: Send + Sync {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    // Appends all of `buf` to the end of the file.
+    fn append(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    // Makes everything appended so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    fn size(&self) -> io::Result<u64>;
+
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    // Maps the whole file into memory for reading, or returns `None` if the
+    // file system does not support mapping, in which case callers read with
+    // `read_at` instead. Only immutable files may be mapped: the file must
+    // never be written to or truncated while any clone of the mapping lives.
+    fn map(&self) -> io::Result<Option<Mapping>> {
+        Ok(None)
+    }
+
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => break,
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            ));
+        }
+        Ok(())
+    }
+}
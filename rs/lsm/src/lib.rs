@@ -1,5 +1,17 @@
 pub mod agent;
+pub mod batch;
+pub mod cache;
+pub mod comparator;
+pub mod compaction;
+pub mod encryption;
+pub mod fs;
 pub mod log;
+mod memtable;
+pub mod merge;
+mod range_tombstone;
+pub mod rate_limiter;
+pub mod stats;
+pub mod table;
 #[cfg(test)]
 mod test_util;
 
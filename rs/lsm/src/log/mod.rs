@@ -1,6 +1,47 @@
 pub mod segment;
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u8)]
+pub enum RecordType {
+    Put = 1,
+    Delete = 2,
+    // An operand to be folded into the key's value by the configured
+    // `MergeOperator`.
+    Merge = 3,
+    // Deletes every key in [key, value).
+    RangeDelete = 4,
+    // An encoded `WriteBatch`, applied atomically.
+    Batch = 5,
+    // Links tables into the store. See `Agent::ingest_files`.
+    IngestTables = 6,
+    // The first record of the segment that a flush starts. Links in the
+    // table that the memtable was written to and every other table in the
+    // store, so that the segments before it are no longer needed. See
+    // `Agent::flush`.
+    Flush = 7,
+    // Replaces the input tables of a compaction with its outputs. See
+    // `Agent::compact`.
+    Compaction = 8,
+}
+
+impl RecordType {
+    pub fn from_u8(b: u8) -> Option<RecordType> {
+        match b {
+            1 => Some(RecordType::Put),
+            2 => Some(RecordType::Delete),
+            3 => Some(RecordType::Merge),
+            4 => Some(RecordType::RangeDelete),
+            5 => Some(RecordType::Batch),
+            6 => Some(RecordType::IngestTables),
+            7 => Some(RecordType::Flush),
+            8 => Some(RecordType::Compaction),
+            _ => None,
+        }
+    }
+}
+
 pub struct LogEntry {
+    pub record_type: RecordType,
     pub key: Vec<u8>,
     pub value: Vec<u8>,
 }
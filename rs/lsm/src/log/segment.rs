@@ -1,14 +1,19 @@
 
+use std::borrow::Cow;
+use std::convert::TryInto;
 use std::io::{self, Write/*, Read, Seek, SeekFrom */};
-use std::os::unix::fs::FileExt;
-use std::fs::{File, OpenOptions};
 use std::path::{Path, PathBuf};
-use byteorder::{WriteBytesExt, LittleEndian};
+use std::time::Instant;
+use byteorder::{ByteOrder, WriteBytesExt, LittleEndian};
 use crc32fast::{Hasher};
 
-use super::LogEntry;
+use super::{LogEntry, RecordType};
+use crate::encryption::{Cipher, KeyProvider, PLAINTEXT_KEY_ID};
+use crate::fs::{FileHandle, FileSystem, Mapping};
+use crate::stats::LogStats;
 
-const SEGMENT_FILE_EXT: &'static str = "log";
+const SEGMENT_FILE_EXT: &str = "log";
+const TEMP_FILE_EXT: &str = "tmp";
 
 // Record format:
 // +--------+-----+-------+----------+
@@ -16,10 +21,10 @@ const SEGMENT_FILE_EXT: &'static str = "log";
 // +--------+-----+-------+----------+
 //
 // Header:
-// +------------+--------------+
-// | key_length | value_length |
-// +------------+--------------+
-//     4 bytes      8 bytes
+// +-------------+------------+--------------+
+// | record_type | key_length | value_length |
+// +-------------+------------+--------------+
+//     1 byte        4 bytes      8 bytes
 //
 // Checksum:
 // +-------+
@@ -27,17 +32,52 @@ const SEGMENT_FILE_EXT: &'static str = "log";
 // +-------+
 //  4 bytes
 //
-const HEADER_LENGTH: usize = 12;
+// The checksum covers the record type, key and value.
+//
+// In an encrypted segment, each record above is sealed as a whole and
+// written as:
+// +---------------+---------------------------------+
+// | sealed_length | sealed record (see `Cipher`)    |
+// +---------------+---------------------------------+
+//      4 bytes      sealed_length bytes
+//
+// The associated data is the segment's base offset and file header followed
+// by the record's offset in the file, so sealed records cannot be reordered,
+// moved between positions or segments, or kept under a rewritten header.
+const HEADER_LENGTH: usize = 13;
 const CHECKSUM_LENGTH: usize = 4;
+const SEALED_LENGTH_LENGTH: usize = 4;
 
 // File format:
-// +-------+----------+-...-+----------+
-// | magic | record_0 | ... | record_n |
-// +-------+----------+-...-+----------+
+// +-------+--------+----------------+------------+--------------------+----------------+----------+-...-+
+// | magic | key_id | comparator_len | comparator | merge_operator_len | merge_operator | record_0 | ... |
+// +-------+--------+----------------+------------+--------------------+----------------+----------+-...-+
+//  2 bytes  4 bytes      2 bytes                        2 bytes
+//
+// `key_id` identifies the encryption key that the records were written with,
+// or is `PLAINTEXT_KEY_ID` if the segment is not encrypted. `comparator` is
+// the name of the comparator that orders the store's keys, and
+// `merge_operator` the name of the merge operator that folds its merge
+// operands, both in UTF-8. An empty `merge_operator` means the store has no
+// merge operator.
 const FILE_MAGIC: [u8; 2] = [0xff, 0xff];
+// Length of the header up to the comparator name.
+const FILE_HEADER_LENGTH: usize = FILE_MAGIC.len() + 4 + 2;
+
+struct FileHeader {
+    key_id: u32,
+    comparator: String,
+    merge_operator: Option<String>,
+    // The header as it is in the file.
+    bytes: Vec<u8>,
+}
 
 pub struct Segment {
-    file: File,
+    file: Box<dyn FileHandle>,
+
+    // Set for read-only segments opened with `mmap`. Records are then read
+    // from the mapping rather than with `read_at`.
+    mapping: Option<Mapping>,
 
     // Following the example of Kafka, each segment of the log is named for the
     // index of the first record that it contains.
@@ -45,40 +85,166 @@ pub struct Segment {
 
     // pos keeps track of the offset of the next byte to write
     pos: usize,
+
+    // Offset of the first record.
+    header_len: usize,
+
+    comparator: String,
+
+    merge_operator: Option<String>,
+
+    cipher: Option<Cipher>,
+
+    // The part of each record's associated data that is the same for the
+    // whole segment: its base offset and file header.
+    aad_prefix: Vec<u8>,
+
+    // Set when a write or sync fails. After that, the file may contain a
+    // partial record or have lost data that was already written, so the
+    // segment refuses further writes and must be recovered by reopening it.
+    failed: bool,
+
+    stats: LogStats,
 }
 
 impl Segment {
-    pub fn new<P>(dir: P, base_offset: u64) -> io::Result<Segment>
+    // Creates a segment whose records are encrypted with the key provider's
+    // current key, if a key provider is supplied. `comparator` and
+    // `merge_operator` are the names of the store's comparator and merge
+    // operator, which are recorded in the segment's header.
+    pub fn new<P>(
+        fs: &dyn FileSystem,
+        dir: P,
+        base_offset: u64,
+        key_provider: Option<&dyn KeyProvider>,
+        comparator: &str,
+        merge_operator: Option<&str>,
+    ) -> io::Result<Segment>
     where
         P: AsRef<Path>,
     {
-        let file_path = {
-            let mut path_buf = PathBuf::new();
-            path_buf.push(dir);
-            path_buf.push(format!("{:020}", base_offset));
-            path_buf.set_extension(SEGMENT_FILE_EXT);
-            path_buf
+        let dir = dir.as_ref();
+        let file_path = segment_file_path(dir, base_offset);
+        let segment = Segment::create(fs, &file_path, base_offset, key_provider, comparator, merge_operator)?;
+        fs.sync_dir(dir)?;
+        Ok(segment)
+    }
+
+    // Creates a segment at `file_path`, which must not already exist or be
+    // empty, as in `new`. The file name must be a base offset, but the
+    // extension may differ from `segment_file_path`'s, so that a segment can
+    // be written in full under another name before it is renamed into place.
+    // The directory is not synced.
+    pub fn create(
+        fs: &dyn FileSystem,
+        file_path: &Path,
+        base_offset: u64,
+        key_provider: Option<&dyn KeyProvider>,
+        comparator: &str,
+        merge_operator: Option<&str>,
+    ) -> io::Result<Segment> {
+        let cipher = match key_provider {
+            Some(provider) => Some(Cipher::for_key_id(provider, provider.current_key_id())?),
+            None => None,
         };
+        let key_id = cipher.as_ref().map_or(PLAINTEXT_KEY_ID, |c| c.key_id());
+        if comparator.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Comparator name is too long"));
+        }
+        match merge_operator {
+            Some("") => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Merge operator name is empty"))
+            }
+            Some(name) if name.len() > u16::MAX as usize => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Merge operator name is too long"))
+            }
+            _ => {}
+        }
+        let merge_operator_name = merge_operator.unwrap_or_default();
 
-        println!("Opening Log File: {}", file_path.to_str().unwrap());
-        let mut file = open_log_file(&file_path)?;
-        file.write_all(&FILE_MAGIC)?;
-        file.sync_all()?;
+        let mut file = fs.open_append(file_path)?;
+        if file.size()? != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Segment file is not empty: {}", file_path.display()),
+            ));
+        }
+        let mut header = Vec::with_capacity(FILE_HEADER_LENGTH + comparator.len() + 2 + merge_operator_name.len());
+        header.write_all(&FILE_MAGIC)?;
+        header.write_u32::<LittleEndian>(key_id)?;
+        header.write_u16::<LittleEndian>(comparator.len() as u16)?;
+        header.write_all(comparator.as_bytes())?;
+        header.write_u16::<LittleEndian>(merge_operator_name.len() as u16)?;
+        header.write_all(merge_operator_name.as_bytes())?;
+        file.append(&header)?;
 
-        Ok(Segment {
+        let mut segment = Segment {
             file,
+            mapping: None,
             base_offset,
-            pos: FILE_MAGIC.len(),
-        })
+            pos: header.len(),
+            header_len: header.len(),
+            comparator: comparator.to_string(),
+            merge_operator: merge_operator.map(str::to_string),
+            cipher,
+            aad_prefix: aad_prefix(base_offset, &header),
+            failed: false,
+            stats: LogStats::default(),
+        };
+        segment.sync()?;
+
+        Ok(segment)
+    }
+
+    // Opens an existing segment. A key provider is required if the segment is
+    // encrypted.
+    pub fn open<P>(
+        fs: &dyn FileSystem,
+        file_path: P,
+        key_provider: Option<&dyn KeyProvider>,
+    ) -> io::Result<Segment>
+    where
+        P: AsRef<Path>,
+    {
+        let file = fs.open_append(file_path.as_ref())?;
+        Segment::from_file(file, file_path.as_ref(), key_provider)
     }
 
-    pub fn open<P>(file_path: P) -> io::Result<Segment>
+    // Opens an existing segment for reading only, for inspecting a log that
+    // no agent has open or a segment that is no longer written to. Writes to
+    // the segment fail.
+    //
+    // If `mmap` is set, the segment is mapped into memory, so it must not be
+    // written to by anyone while it is open. A segment that cannot be mapped
+    // is read with `read_at` instead.
+    pub fn open_read_only<P>(
+        fs: &dyn FileSystem,
+        file_path: P,
+        key_provider: Option<&dyn KeyProvider>,
+        mmap: bool,
+    ) -> io::Result<Segment>
     where
         P: AsRef<Path>,
     {
-        let mut file = open_log_file(&file_path.as_ref())?;
-        let file_name = file_path.as_ref().file_name().unwrap().to_str().unwrap();
-        let base_offset = match u64::from_str_radix(file_name, 10) {
+        let file = fs.open(file_path.as_ref())?;
+        let mut segment = Segment::from_file(file, file_path.as_ref(), key_provider)?;
+        if mmap {
+            segment.mapping = segment
+                .file
+                .map()
+                .unwrap_or(None)
+                .filter(|m| (**m).as_ref().len() >= segment.pos);
+        }
+        Ok(segment)
+    }
+
+    fn from_file(
+        file: Box<dyn FileHandle>,
+        file_path: &Path,
+        key_provider: Option<&dyn KeyProvider>,
+    ) -> io::Result<Segment> {
+        let file_name = file_path.file_stem().unwrap().to_str().unwrap();
+        let base_offset = match file_name.parse::<u64>() {
             Ok(offset) => offset,
             Err(_) => {
                 return Err(io::Error::new(
@@ -87,73 +253,400 @@ impl Segment {
                 ))
             }
         };
-        let file_len = file.metadata()?.len();
+        let file_len = file.size()?;
 
-        validate_segment_file(&mut file)?;
+        let header = validate_segment_file(file.as_ref())?;
+        let cipher = Cipher::for_reading(key_provider, header.key_id, "Segment")?;
 
         Ok(Segment {
             file,
+            mapping: None,
             base_offset,
             pos: file_len as usize,
+            header_len: header.bytes.len(),
+            aad_prefix: aad_prefix(base_offset, &header.bytes),
+            comparator: header.comparator,
+            merge_operator: header.merge_operator,
+            cipher,
+            failed: false,
+            stats: LogStats::default(),
         })
     }
 
-    pub fn append(&mut self, key: &[u8], val: &[u8]) -> io::Result<u64> {
+    pub fn append(&mut self, record_type: RecordType, key: &[u8], val: &[u8]) -> io::Result<u64> {
+        self.check_failed()?;
+        self.check_unmapped()?;
+
         let key_len = key.len();
         let val_len = val.len();
-        let record_len = HEADER_LENGTH + key_len + val_len + CHECKSUM_LENGTH;
-        let mut f = io::BufWriter::with_capacity(record_len, &mut self.file);
-
-        let mut body = Vec::with_capacity(key_len+val_len);
-        body.write(key).unwrap();
-        body.write(val).unwrap();
+        let mut record = Vec::with_capacity(HEADER_LENGTH + key_len + val_len + CHECKSUM_LENGTH);
 
         let mut hasher = Hasher::new();
-        hasher.update(&body);
+        hasher.update(&[record_type as u8]);
+        hasher.update(key);
+        hasher.update(val);
         let checksum = hasher.finalize();
 
         // Write header
-        f.write_u32::<LittleEndian>(key_len as u32)?;
-        f.write_u64::<LittleEndian>(val_len as u64)?;
+        record.write_u8(record_type as u8)?;
+        record.write_u32::<LittleEndian>(key_len as u32)?;
+        record.write_u64::<LittleEndian>(val_len as u64)?;
 
         // Write body
-        f.write_all(&mut body)?;
-        f.write_u32::<LittleEndian>(checksum)?;
+        record.write_all(key)?;
+        record.write_all(val)?;
+        record.write_u32::<LittleEndian>(checksum)?;
+
+        let curr_offset = self.pos;
+        if let Some(cipher) = &self.cipher {
+            let sealed = cipher.seal(&self.record_aad(curr_offset as u64), &record);
+            record.clear();
+            record.write_u32::<LittleEndian>(sealed.len() as u32)?;
+            record.write_all(&sealed)?;
+        }
 
         // TODO: do batched flush periodically
-        f.flush()?;
+        if let Err(err) = self.file.append(&record) {
+            self.failed = true;
+            return Err(err);
+        }
 
-        let curr_offset = self.pos;
-        self.pos += record_len;
+        self.pos += record.len();
+        self.stats.records_appended += 1;
+        self.stats.bytes_appended += record.len() as u64;
 
         Ok(curr_offset as u64)
     }
 
+    // Flushes all appended records to stable storage.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.check_failed()?;
+
+        let start = Instant::now();
+        if let Err(err) = self.file.sync() {
+            self.failed = true;
+            return Err(err);
+        }
+        self.stats.fsync_count += 1;
+        self.stats.fsync_latency.record(start.elapsed());
+
+        Ok(())
+    }
+
+    pub fn base_offset(&self) -> u64 {
+        self.base_offset
+    }
+
+    pub fn stats(&self) -> &LogStats {
+        &self.stats
+    }
+
+    // Name of the comparator recorded in the segment's header.
+    pub fn comparator(&self) -> &str {
+        &self.comparator
+    }
+
+    // Name of the merge operator recorded in the segment's header, if any.
+    pub fn merge_operator(&self) -> Option<&str> {
+        self.merge_operator.as_deref()
+    }
+
+    // Whether records are read from a mapping of the file.
+    pub fn is_mapped(&self) -> bool {
+        self.mapping.is_some()
+    }
+
+    // Reads the record that starts at `offset`, as returned by `append`.
+    // Returns `None` if `offset` is at or past the end of the segment.
     pub fn get(&self, offset: u64) -> io::Result<Option<LogEntry>> {
-        // self.file.seek(SeekFrom::Current(0))
-        Err(io::Error::new(io::ErrorKind::Other, "foo"))
+        self.read_record(offset).map(|r| r.map(|(entry, _)| entry))
+    }
+
+    // Iterates over the records in the segment in the order they were written,
+    // along with their offsets. Iteration stops after the first error, which
+    // is either an I/O error or a record that is corrupt or was only partially
+    // written.
+    pub fn iter(&self) -> SegmentIter<'_> {
+        SegmentIter {
+            segment: self,
+            offset: self.header_len as u64,
+            done: false,
+        }
+    }
+
+    // Discards everything from `offset` onwards, so that a partially written
+    // record found during recovery is not followed by new records. This also
+    // clears a previous write failure.
+    pub fn truncate(&mut self, offset: u64) -> io::Result<()> {
+        self.check_unmapped()?;
+        if offset < self.header_len as u64 || offset > self.pos as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Cannot truncate segment of length {} to {}", self.pos, offset),
+            ));
+        }
+        self.file.set_len(offset)?;
+        self.file.sync()?;
+        self.pos = offset as usize;
+        self.failed = false;
+
+        Ok(())
+    }
+
+    fn check_failed(&self) -> io::Result<()> {
+        if self.failed {
+            return Err(io::Error::other(
+                "Segment is unusable after a failed write and must be reopened",
+            ));
+        }
+        Ok(())
+    }
+
+    // Truncating a mapped file would make reads through the mapping fault, so
+    // mapped segments are never modified.
+    fn check_unmapped(&self) -> io::Result<()> {
+        if self.mapping.is_some() {
+            return Err(io::Error::other("Segment is mapped and cannot be modified"));
+        }
+        Ok(())
+    }
+
+    // Reads `len` bytes at `offset`, borrowing them from the mapping if there
+    // is one.
+    fn read(&self, offset: u64, len: usize) -> io::Result<Cow<'_, [u8]>> {
+        match &self.mapping {
+            Some(mapping) => {
+                let data = (**mapping).as_ref();
+                let start = offset as usize;
+                match start.checked_add(len) {
+                    Some(end) if end <= data.len() => Ok(Cow::Borrowed(&data[start..end])),
+                    _ => Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Log record extends past the end of the segment",
+                    )),
+                }
+            }
+            None => {
+                let mut buf = vec![0u8; len];
+                self.file.read_exact_at(&mut buf, offset)?;
+                Ok(Cow::Owned(buf))
+            }
+        }
+    }
+
+    // Reads the record at `offset` and returns it with the offset of the
+    // record that follows it.
+    fn read_record(&self, offset: u64) -> io::Result<Option<(LogEntry, u64)>> {
+        if offset as usize >= self.pos {
+            return Ok(None);
+        }
+
+        let (record, next) = match &self.cipher {
+            Some(cipher) => {
+                let len_buf = self.read(offset, SEALED_LENGTH_LENGTH)?;
+                let sealed_len = LittleEndian::read_u32(&len_buf) as u64;
+                let start = offset + SEALED_LENGTH_LENGTH as u64;
+                let sealed = self.read(start, self.checked_len(start, sealed_len)?)?;
+                (Cow::Owned(cipher.open(&self.record_aad(offset), &sealed)?), start + sealed_len)
+            }
+            None => {
+                let header = self.read(offset, HEADER_LENGTH)?;
+                let key_len = LittleEndian::read_u32(&header[1..5]) as u64;
+                let val_len = LittleEndian::read_u64(&header[5..13]);
+                let record_len = (HEADER_LENGTH + CHECKSUM_LENGTH) as u64 + key_len + val_len;
+                let record = self.read(offset, self.checked_len(offset, record_len)?)?;
+                (record, offset + record_len)
+            }
+        };
+
+        decode_record(&record).map(|entry| Some((entry, next)))
+    }
+
+    // The associated data that the record at `offset` is sealed with.
+    fn record_aad(&self, offset: u64) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(self.aad_prefix.len() + 8);
+        aad.extend_from_slice(&self.aad_prefix);
+        aad.extend_from_slice(&offset.to_le_bytes());
+        aad
+    }
+
+    // Guards against allocating buffers for lengths read from a torn or
+    // corrupt record that would run past the end of the segment.
+    fn checked_len(&self, offset: u64, len: u64) -> io::Result<usize> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.pos as u64 => Ok(len as usize),
+            _ => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Log record extends past the end of the segment",
+            )),
+        }
+    }
+}
+
+pub struct SegmentIter<'a> {
+    segment: &'a Segment,
+    offset: u64,
+    done: bool,
+}
+
+impl<'a> SegmentIter<'a> {
+    // The offset of the next record to be read. After an error, this is the
+    // offset of the record that could not be read.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl<'a> Iterator for SegmentIter<'a> {
+    type Item = io::Result<(u64, LogEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.segment.read_record(self.offset) {
+            Ok(Some((entry, next))) => {
+                let offset = self.offset;
+                self.offset = next;
+                Some(Ok((offset, entry)))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
     }
 }
 
-fn open_log_file(path: &Path) -> io::Result<File> {
-    OpenOptions::new()
-        .create(true)
-        .read(true)
-        .append(true)
-        .open(path)
+pub fn segment_file_path<P>(dir: P, base_offset: u64) -> PathBuf
+where
+    P: AsRef<Path>,
+{
+    let mut path_buf = PathBuf::new();
+    path_buf.push(dir);
+    path_buf.push(format!("{:020}", base_offset));
+    path_buf.set_extension(SEGMENT_FILE_EXT);
+    path_buf
+}
+
+// Where a segment is written before it is renamed to `segment_file_path`.
+// Such files are not listed by `list_segment_files`.
+pub fn temp_segment_file_path<P>(dir: P, base_offset: u64) -> PathBuf
+where
+    P: AsRef<Path>,
+{
+    segment_file_path(dir, base_offset).with_extension(TEMP_FILE_EXT)
+}
+
+// Lists the segment files in `dir`, ordered by base offset.
+pub fn list_segment_files<P>(fs: &dyn FileSystem, dir: P) -> io::Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+{
+    list_files(fs, dir.as_ref(), SEGMENT_FILE_EXT)
+}
+
+// Lists the segments in `dir` that were never renamed into place, such as
+// those left behind by a crash while they were written.
+pub fn list_temp_segment_files<P>(fs: &dyn FileSystem, dir: P) -> io::Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+{
+    list_files(fs, dir.as_ref(), TEMP_FILE_EXT)
+}
+
+fn list_files(fs: &dyn FileSystem, dir: &Path, ext: &str) -> io::Result<Vec<PathBuf>> {
+    Ok(fs
+        .read_dir(dir)?
+        .into_iter()
+        .filter(|p| p.extension().and_then(|e| e.to_str()) == Some(ext))
+        .collect())
+}
+
+fn aad_prefix(base_offset: u64, header: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(8 + header.len());
+    prefix.extend_from_slice(&base_offset.to_le_bytes());
+    prefix.extend_from_slice(header);
+    prefix
+}
+
+fn decode_record(record: &[u8]) -> io::Result<LogEntry> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    if record.len() < HEADER_LENGTH + CHECKSUM_LENGTH {
+        return Err(invalid("Log record is shorter than its header and checksum"));
+    }
+
+    let record_type = RecordType::from_u8(record[0]).ok_or_else(|| invalid("Log record has unknown type"))?;
+    let key_len = LittleEndian::read_u32(&record[1..5]) as usize;
+    let val_len = LittleEndian::read_u64(&record[5..13]) as usize;
+    if record.len() != HEADER_LENGTH + key_len + val_len + CHECKSUM_LENGTH {
+        return Err(invalid("Log record length does not match its header"));
+    }
+
+    let body = &record[HEADER_LENGTH..HEADER_LENGTH + key_len + val_len];
+    let checksum = LittleEndian::read_u32(&record[record.len() - CHECKSUM_LENGTH..]);
+    let mut hasher = Hasher::new();
+    hasher.update(&record[0..1]);
+    hasher.update(body);
+    if hasher.finalize() != checksum {
+        return Err(invalid("Log record checksum mismatch"));
+    }
+
+    Ok(LogEntry {
+        record_type,
+        key: body[..key_len].to_vec(),
+        value: body[key_len..].to_vec(),
+    })
 }
 
-fn validate_segment_file(f: &mut File) -> io::Result<()> {
-    let mut magic_bytes = [0u8; 2];
-    let size = f.read_at(&mut magic_bytes, 0)?;
-    if size < 2 || magic_bytes != FILE_MAGIC {
+// Checks and reads the file header.
+fn validate_segment_file(f: &dyn FileHandle) -> io::Result<FileHeader> {
+    let mut header = [0u8; FILE_HEADER_LENGTH];
+    let size = f.read_at(&mut header, 0)?;
+    if size < FILE_MAGIC.len() || header[..FILE_MAGIC.len()] != FILE_MAGIC {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
             "Segment file does not contain valid magic bytes",
         ));
     }
+    if size < FILE_HEADER_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Segment file header is truncated",
+        ));
+    }
+
+    let key_id = u32::from_le_bytes(header[FILE_MAGIC.len()..FILE_MAGIC.len() + 4].try_into().unwrap());
+    let mut bytes = header.to_vec();
+    let comparator = read_header_name(f, &mut bytes, "comparator")?;
+    let mut merge_operator_len = [0u8; 2];
+    f.read_exact_at(&mut merge_operator_len, bytes.len() as u64)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Segment file header is truncated"))?;
+    bytes.extend_from_slice(&merge_operator_len);
+    let merge_operator = read_header_name(f, &mut bytes, "merge operator")?;
 
-    Ok(())
+    Ok(FileHeader {
+        key_id,
+        comparator,
+        merge_operator: Some(merge_operator).filter(|name| !name.is_empty()),
+        bytes,
+    })
+}
+
+// Reads the name whose length makes up the last two bytes of `bytes`, and
+// appends it to them.
+fn read_header_name(f: &dyn FileHandle, bytes: &mut Vec<u8>, what: &str) -> io::Result<String> {
+    let start = bytes.len();
+    let len = LittleEndian::read_u16(&bytes[start - 2..]) as usize;
+    bytes.resize(start + len, 0);
+    f.read_exact_at(&mut bytes[start..], start as u64)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Segment file header is truncated"))?;
+    String::from_utf8(bytes[start..].to_vec())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Segment {} name is not valid UTF-8", what)))
 }
 
 pub fn test_log() {
@@ -163,24 +656,199 @@ pub fn test_log() {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::encryption::{StaticKeyProvider, KEY_LENGTH};
+    use crate::fs::{FsOp, MemFileSystem, OsFileSystem};
     use crate::test_util::*;
 
+    const COMPARATOR: &str = "lsm.BytewiseComparator";
+
     #[test]
     fn test_write() {
-    //   let dir = TmpDir::new();
-        let dir = "/tmp";
-      let mut segment = Segment::new(&dir, 0).unwrap();
+      let dir = TmpDir::new();
+      let mut segment = Segment::new(&OsFileSystem, &dir, 0, None, COMPARATOR, None).unwrap();
 
-      let _ = segment.append("name".as_bytes(), "Andrew".as_bytes()).unwrap();
+      let _ = segment.append(RecordType::Put, "name".as_bytes(), "Andrew".as_bytes()).unwrap();
 
       let file_path = {
         let mut path_buf = PathBuf::new();
-        path_buf.push(dir);
+        path_buf.push(&dir);
         path_buf.push(format!("{:020}", 0));
         path_buf.set_extension(SEGMENT_FILE_EXT);
         path_buf
       };
       println!("File Path: {}", file_path.to_str().unwrap());
-      let f = open_log_file(&file_path).unwrap();
+      let _f = OsFileSystem.open_append(&file_path).unwrap();
+    }
+
+    #[test]
+    fn test_append_stats() {
+        let dir = TmpDir::new();
+        let mut segment = Segment::new(&OsFileSystem, &dir, 0, None, COMPARATOR, None).unwrap();
+        assert_eq!(1, segment.stats().fsync_count);
+
+        segment.append(RecordType::Put, "name".as_bytes(), "Andrew".as_bytes()).unwrap();
+        segment.append(RecordType::Put, "age".as_bytes(), "33".as_bytes()).unwrap();
+        segment.sync().unwrap();
+
+        let stats = segment.stats();
+        assert_eq!(2, stats.records_appended);
+        assert_eq!((2 * (HEADER_LENGTH + CHECKSUM_LENGTH) + 10 + 5) as u64, stats.bytes_appended);
+        assert_eq!(2, stats.fsync_count);
+        assert_eq!(2, stats.fsync_latency.count());
+    }
+
+    #[test]
+    fn test_get() {
+        let dir = TmpDir::new();
+        let mut segment = Segment::new(&OsFileSystem, &dir, 0, None, COMPARATOR, None).unwrap();
+
+        let fst = segment.append(RecordType::Put, b"name", b"Andrew").unwrap();
+        let snd = segment.append(RecordType::Delete, b"name", b"").unwrap();
+
+        let entry = segment.get(fst).unwrap().unwrap();
+        assert_eq!(RecordType::Put, entry.record_type);
+        assert_eq!(b"name".to_vec(), entry.key);
+        assert_eq!(b"Andrew".to_vec(), entry.value);
+        assert_eq!(RecordType::Delete, segment.get(snd).unwrap().unwrap().record_type);
+        assert!(segment.get(segment.pos as u64).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_merge_operator_name() {
+        let fs = MemFileSystem::new();
+        fs.create_dir_all(Path::new("/log")).unwrap();
+        Segment::new(&fs, "/log", 0, None, COMPARATOR, Some("append")).unwrap();
+        let segment = Segment::open(&fs, segment_file_path("/log", 0), None).unwrap();
+        assert_eq!(COMPARATOR, segment.comparator());
+        assert_eq!(Some("append"), segment.merge_operator());
+
+        Segment::new(&fs, "/log", 1, None, COMPARATOR, None).unwrap();
+        let segment = Segment::open(&fs, segment_file_path("/log", 1), None).unwrap();
+        assert_eq!(None, segment.merge_operator());
+
+        let err = Segment::new(&fs, "/log", 2, None, COMPARATOR, Some("")).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        // An existing segment is never written over.
+        let err = Segment::new(&fs, "/log", 1, None, COMPARATOR, None).err().unwrap();
+        assert_eq!(io::ErrorKind::AlreadyExists, err.kind());
+    }
+
+    #[test]
+    fn test_encrypted_segment() {
+        let dir = TmpDir::new();
+        let provider = StaticKeyProvider::new(42, [9u8; KEY_LENGTH]);
+        let mut segment = Segment::new(&OsFileSystem, &dir, 0, Some(&provider), COMPARATOR, None).unwrap();
+        let offset = segment.append(RecordType::Put, b"name", b"Andrew").unwrap();
+        let path = dir.as_ref().join(format!("{:020}.{}", 0, SEGMENT_FILE_EXT));
+
+        // Neither the key nor the value is readable from the file.
+        let raw = std::fs::read(&path).unwrap();
+        assert_eq!(&FILE_MAGIC[..], &raw[..FILE_MAGIC.len()]);
+        assert_eq!(42u32.to_le_bytes(), raw[FILE_MAGIC.len()..FILE_MAGIC.len() + 4]);
+        assert!(!raw.windows(4).any(|w| w == b"name"));
+        assert!(!raw.windows(6).any(|w| w == b"Andrew"));
+
+        let reopened = Segment::open(&OsFileSystem, &path, Some(&provider)).unwrap();
+        assert_eq!(b"Andrew".to_vec(), reopened.get(offset).unwrap().unwrap().value);
+
+        assert!(Segment::open(&OsFileSystem, &path, None).is_err());
+        let wrong_key = StaticKeyProvider::new(43, [9u8; KEY_LENGTH]);
+        assert!(Segment::open(&OsFileSystem, &path, Some(&wrong_key)).is_err());
+
+        // Records are bound to their segment: they cannot be read from a copy
+        // of the file under another base offset, or under a changed header.
+        let moved = segment_file_path(&dir, 1);
+        std::fs::write(&moved, &raw).unwrap();
+        let moved = Segment::open(&OsFileSystem, &moved, Some(&provider)).unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, moved.get(offset).err().unwrap().kind());
+        let mut rewritten = raw.clone();
+        let name_start = FILE_HEADER_LENGTH;
+        rewritten[name_start..name_start + COMPARATOR.len()].copy_from_slice(b"lsm.BytewiseComparatoR");
+        std::fs::write(&path, &rewritten).unwrap();
+        let rewritten = Segment::open(&OsFileSystem, &path, Some(&provider)).unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, rewritten.get(offset).err().unwrap().kind());
+    }
+
+    #[test]
+    fn test_mapped_segment() {
+        let records = |segment: &Segment| {
+            segment
+                .iter()
+                .map(|r| r.map(|(offset, entry)| (offset, entry.key, entry.value)).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let dir = TmpDir::new();
+        let provider = StaticKeyProvider::new(42, [9u8; KEY_LENGTH]);
+        for key_provider in [None, Some(&provider as &dyn KeyProvider)] {
+            let mut segment = Segment::new(&OsFileSystem, &dir, 0, key_provider, COMPARATOR, None).unwrap();
+            for i in 0..100u32 {
+                segment.append(RecordType::Put, &i.to_be_bytes(), &[i as u8; 100]).unwrap();
+            }
+            segment.sync().unwrap();
+            let path = segment_file_path(&dir, 0);
+            let expected = records(&segment);
+
+            let mut mapped = Segment::open_read_only(&OsFileSystem, &path, key_provider, true).unwrap();
+            assert!(mapped.is_mapped());
+            assert_eq!(expected, records(&mapped));
+            assert_eq!(expected[7].2, mapped.get(expected[7].0).unwrap().unwrap().value);
+
+            // The file must not change while it is mapped.
+            assert!(mapped.append(RecordType::Put, b"a", b"1").is_err());
+            assert!(mapped.truncate(expected[1].0).is_err());
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        // Segments are read with `read_at` when they cannot be mapped.
+        let fs = MemFileSystem::new();
+        fs.create_dir_all(Path::new("/log")).unwrap();
+        let mut segment = Segment::new(&fs, "/log", 0, None, COMPARATOR, None).unwrap();
+        segment.append(RecordType::Put, b"a", b"1").unwrap();
+        fs.fail_nth(FsOp::Map, 0);
+        let unmapped = Segment::open_read_only(&fs, segment_file_path("/log", 0), None, true).unwrap();
+        assert!(!unmapped.is_mapped());
+        assert_eq!(1, unmapped.iter().count());
+    }
+
+    #[test]
+    fn test_iter_stops_at_torn_record() {
+        let fs = MemFileSystem::new();
+        let dir = Path::new("/log");
+        fs.create_dir_all(dir).unwrap();
+        let mut segment = Segment::new(&fs, dir, 0, None, COMPARATOR, None).unwrap();
+        segment.append(RecordType::Put, b"a", b"1").unwrap();
+        let torn = segment.append(RecordType::Put, b"b", b"2").unwrap();
+        segment.sync().unwrap();
+
+        // Lose the last few bytes of the second record.
+        let path = segment_file_path(dir, 0);
+        let contents = fs.contents(&path).unwrap();
+        let mut segment = Segment::open(&fs, &path, None).unwrap();
+        assert_eq!(COMPARATOR, segment.comparator());
+        segment.truncate(contents.len() as u64 - 3).unwrap();
+
+        let records = segment.iter().collect::<Vec<_>>();
+        assert_eq!(2, records.len());
+        assert_eq!(b"a".to_vec(), records[0].as_ref().unwrap().1.key);
+        assert!(records[1].is_err());
+
+        segment.truncate(torn).unwrap();
+        segment.append(RecordType::Put, b"c", b"3").unwrap();
+        let keys = segment.iter().map(|r| r.unwrap().1.key).collect::<Vec<_>>();
+        assert_eq!(vec![b"a".to_vec(), b"c".to_vec()], keys);
+    }
+
+    #[test]
+    fn test_failed_write_poisons_segment() {
+        let fs = MemFileSystem::new();
+        let dir = Path::new("/log");
+        fs.create_dir_all(dir).unwrap();
+        let mut segment = Segment::new(&fs, dir, 0, None, COMPARATOR, None).unwrap();
+
+        fs.tear_nth_write(0, 5);
+        assert!(segment.append(RecordType::Put, b"a", b"1").is_err());
+        assert!(segment.append(RecordType::Put, b"b", b"2").is_err());
+        assert!(segment.sync().is_err());
     }
 }
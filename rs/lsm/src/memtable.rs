@@ -0,0 +1,255 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::comparator::Comparator;
+use crate::merge::{self, MergeOperator};
+use crate::range_tombstone::RangeTombstones;
+use crate::table::TableValue;
+
+pub(crate) enum Entry {
+    Value(Vec<u8>),
+    Deleted,
+    // Merge operands that have not been folded yet, oldest first. `base` is the
+    // value the operands apply to, or `None` if there was no value.
+    Merge {
+        base: Option<Vec<u8>>,
+        operands: Vec<Vec<u8>>,
+    },
+    // Merge operands to fold into the key's value in the tables below, oldest
+    // first.
+    Operands(Vec<Vec<u8>>),
+}
+
+struct Slot {
+    // Sequence number of the most recent write to the key.
+    seq: u64,
+    entry: Entry,
+}
+
+// A key ordered by the memtable's comparator.
+struct Key {
+    bytes: Vec<u8>,
+    cmp: Arc<dyn Comparator>,
+}
+
+// In-memory, ordered view of the most recent writes to each key.
+pub(crate) struct Memtable {
+    cmp: Arc<dyn Comparator>,
+    entries: BTreeMap<Key, Slot>,
+    // Range deletions applied to this memtable. Covered entries are dropped
+    // when the deletion is applied; the deletions themselves are kept to mask
+    // the tables below, and to answer `last_write_seq` for the keys they
+    // covered. They are written to the table that the memtable is flushed to.
+    range_tombstones: RangeTombstones,
+    // Total bytes of keys and values written. Superseded values are not
+    // subtracted, mirroring the memory an arena-backed memtable would hold.
+    approximate_size: usize,
+}
+
+impl Memtable {
+    pub(crate) fn new(cmp: Arc<dyn Comparator>) -> Memtable {
+        Memtable {
+            cmp: Arc::clone(&cmp),
+            entries: BTreeMap::new(),
+            range_tombstones: RangeTombstones::new(Arc::clone(&cmp)),
+            approximate_size: 0,
+        }
+    }
+
+    pub(crate) fn approximate_size(&self) -> usize {
+        self.approximate_size
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.range_tombstones.is_empty()
+    }
+
+    pub(crate) fn put(&mut self, seq: u64, key: &[u8], val: &[u8]) {
+        self.approximate_size += key.len() + val.len();
+        self.insert(seq, key, Entry::Value(val.to_vec()));
+    }
+
+    pub(crate) fn delete(&mut self, seq: u64, key: &[u8]) {
+        self.approximate_size += key.len();
+        self.insert(seq, key, Entry::Deleted);
+    }
+
+    // Deletes every key in [start, end).
+    pub(crate) fn delete_range(&mut self, seq: u64, start: &[u8], end: &[u8]) {
+        self.approximate_size += start.len() + end.len();
+        if self.cmp.compare(start, end) != Ordering::Less {
+            return;
+        }
+        let covered = self
+            .entries
+            .range((Bound::Included(self.key(start)), Bound::Excluded(self.key(end))))
+            .map(|(k, _)| self.key(&k.bytes))
+            .collect::<Vec<_>>();
+        for key in covered {
+            self.entries.remove(&key);
+        }
+        self.range_tombstones.add(start, end, seq);
+    }
+
+    // Adds a merge operand for `key`. The tables below are not read: if the
+    // memtable has no value for the key, the operands are folded into the
+    // tables' value when the key is read.
+    pub(crate) fn merge(&mut self, seq: u64, key: &[u8], operand: &[u8]) {
+        self.approximate_size += key.len() + operand.len();
+        let entry = self.entries.remove(&self.key(key)).map(|slot| slot.entry);
+        let entry = match entry {
+            Some(Entry::Merge { base, mut operands }) => {
+                operands.push(operand.to_vec());
+                Entry::Merge { base, operands }
+            }
+            Some(Entry::Operands(mut operands)) => {
+                operands.push(operand.to_vec());
+                Entry::Operands(operands)
+            }
+            Some(Entry::Value(v)) => Entry::Merge {
+                base: Some(v),
+                operands: vec![operand.to_vec()],
+            },
+            Some(Entry::Deleted) => Entry::Merge {
+                base: None,
+                operands: vec![operand.to_vec()],
+            },
+            None if self.covers(key) => Entry::Merge {
+                base: None,
+                operands: vec![operand.to_vec()],
+            },
+            None => Entry::Operands(vec![operand.to_vec()]),
+        };
+        self.insert(seq, key, entry);
+    }
+
+    // Returns the key's state in the memtable, in the form it is written to a
+    // table, or `None` if the memtable has no state for it. A key that a range
+    // deletion covers is `Deleted`. Fails if the key has merge operands but
+    // there is no merge operator to fold them.
+    pub(crate) fn get(&self, key: &[u8], merge_op: Option<&dyn MergeOperator>) -> Option<io::Result<TableValue>> {
+        match self.entries.get(&self.key(key)) {
+            Some(slot) => Some(slot.entry.resolve(key, merge_op)),
+            None if self.covers(key) => Some(Ok(TableValue::Deleted)),
+            None => None,
+        }
+    }
+
+    // Whether `key` is covered by a range deletion in this memtable.
+    pub(crate) fn covers(&self, key: &[u8]) -> bool {
+        self.range_tombstones.covers(key)
+    }
+
+    // Whether the memtable has state for any key in [smallest, largest].
+    pub(crate) fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        if self.cmp.compare(smallest, largest) == Ordering::Greater {
+            return false;
+        }
+        let entries = self
+            .entries
+            .range((Bound::Included(self.key(smallest)), Bound::Included(self.key(largest))))
+            .next()
+            .is_some();
+        entries || self.range_tombstones.overlaps(smallest, largest)
+    }
+
+    // Returns the sequence number of the most recent write that affected
+    // `key`, including range deletions that covered it.
+    pub(crate) fn last_write_seq(&self, key: &[u8]) -> Option<u64> {
+        let point = self.entries.get(&self.key(key)).map(|slot| slot.seq);
+        let range = self.range_tombstones.covering(key).map(|f| f.seq);
+        point.max(range)
+    }
+
+    // Iterates over the keys in [start, end) in order, with their state as in
+    // `get`. Keys covered by range deletions are not included; use `covers`
+    // to check for those.
+    pub(crate) fn scan<'a>(
+        &'a self,
+        start: &[u8],
+        end: &[u8],
+        merge_op: Option<&'a dyn MergeOperator>,
+    ) -> impl Iterator<Item = io::Result<(Vec<u8>, TableValue)>> + 'a {
+        let range = if self.cmp.compare(start, end) == Ordering::Less {
+            Some(self.entries.range((Bound::Included(self.key(start)), Bound::Excluded(self.key(end)))))
+        } else {
+            None
+        };
+        range
+            .into_iter()
+            .flatten()
+            .map(move |(k, slot)| Ok((k.bytes.clone(), slot.entry.resolve(&k.bytes, merge_op)?)))
+    }
+
+    // Iterates over every key in order, like `scan`.
+    pub(crate) fn iter<'a>(
+        &'a self,
+        merge_op: Option<&'a dyn MergeOperator>,
+    ) -> impl Iterator<Item = io::Result<(Vec<u8>, TableValue)>> + 'a {
+        self.entries
+            .iter()
+            .map(move |(k, slot)| Ok((k.bytes.clone(), slot.entry.resolve(&k.bytes, merge_op)?)))
+    }
+
+    // The range deletions applied to this memtable.
+    pub(crate) fn range_tombstones(&self) -> &RangeTombstones {
+        &self.range_tombstones
+    }
+
+    fn insert(&mut self, seq: u64, key: &[u8], entry: Entry) {
+        self.entries.insert(self.key(key), Slot { seq, entry });
+    }
+
+    fn key(&self, key: &[u8]) -> Key {
+        Key {
+            bytes: key.to_vec(),
+            cmp: Arc::clone(&self.cmp),
+        }
+    }
+}
+
+impl Entry {
+    // Returns this entry as a table value, folding any merge operands. The
+    // operands of `Operands` are combined into one.
+    fn resolve(&self, key: &[u8], merge_op: Option<&dyn MergeOperator>) -> io::Result<TableValue> {
+        let value = match self {
+            Entry::Value(v) => TableValue::Value(v[..].into()),
+            Entry::Deleted => TableValue::Deleted,
+            Entry::Merge { base, operands } => {
+                let op = merge_op.ok_or_else(merge::missing_operator)?;
+                let operands = operands.iter().map(|o| o.as_slice());
+                TableValue::Value(merge::fold(op, key, base.as_deref(), operands).unwrap().into())
+            }
+            Entry::Operands(operands) => {
+                let op = merge_op.ok_or_else(merge::missing_operator)?;
+                let mut operands = operands.iter().map(|o| o.as_slice());
+                let oldest = operands.next();
+                TableValue::Merge(merge::fold(op, key, oldest, operands).unwrap().into())
+            }
+        };
+        Ok(value)
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Key) -> Ordering {
+        self.cmp.compare(&self.bytes, &other.bytes)
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Key) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl Eq for Key {}
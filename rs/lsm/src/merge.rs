@@ -0,0 +1,96 @@
+use std::io;
+
+use crate::table::TableValue;
+
+// A user-supplied associative merge operator. `Agent::merge` logs operands
+// without reading the current value, and the operands are folded into the
+// value lazily when the key is read. Operands are written to tables like any
+// other value, and compaction folds them into the values below them.
+//
+// Because the operator is associative, consecutive operands may also be
+// combined with each other before the base value is known, by passing the
+// older operand as `existing`. Implementations must therefore give the same
+// result for merge(merge(v, a), b) and merge(v, merge(a, b)).
+pub trait MergeOperator: Send + Sync {
+    // Recorded alongside the data so that a store is never opened with an
+    // operator other than the one its operands were written for.
+    fn name(&self) -> &str;
+
+    // Combines `operand` into `existing`, which is `None` when the key has no
+    // value (it was never written or was deleted).
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8>;
+}
+
+// Folds `operands`, oldest first, into `base`.
+pub(crate) fn fold<'a, I>(op: &dyn MergeOperator, key: &[u8], base: Option<&[u8]>, operands: I) -> Option<Vec<u8>>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    let mut acc = base.map(|v| v.to_vec());
+    for operand in operands {
+        acc = Some(op.merge(key, acc.as_deref(), operand));
+    }
+    acc
+}
+
+// Folds the versions of a key, newest first, down to the first one that is
+// not a merge operand. The operands above it are folded into it, or, if
+// there is nothing below them, are combined into a single operand. Returns
+// `None` if there are no versions. Fails if there are operands but no merge
+// operator to fold them.
+pub(crate) fn fold_versions<I>(
+    op: Option<&dyn MergeOperator>,
+    key: &[u8],
+    versions: I,
+) -> io::Result<Option<TableValue>>
+where
+    I: IntoIterator<Item = io::Result<TableValue>>,
+{
+    // Newest first.
+    let mut operands = Vec::new();
+    let mut base = None;
+    for version in versions {
+        match version? {
+            TableValue::Merge(operand) => operands.push(operand),
+            version => {
+                base = Some(version);
+                break;
+            }
+        }
+    }
+    if operands.is_empty() {
+        return Ok(base);
+    }
+    let op = op.ok_or_else(missing_operator)?;
+    let mut operands = operands.iter().rev().map(|o| &o[..]);
+    let folded = match base {
+        Some(TableValue::Value(v)) => TableValue::Value(fold(op, key, Some(&v), operands).unwrap().into()),
+        Some(_) => TableValue::Value(fold(op, key, None, operands).unwrap().into()),
+        None => {
+            let oldest = operands.next();
+            TableValue::Merge(fold(op, key, oldest, operands).unwrap().into())
+        }
+    };
+    Ok(Some(folded))
+}
+
+// The value that a key reads as, given the result of folding its versions.
+// Operands with nothing below them apply to no value.
+pub(crate) fn read_value(
+    op: Option<&dyn MergeOperator>,
+    key: &[u8],
+    version: Option<TableValue>,
+) -> io::Result<Option<Vec<u8>>> {
+    match version {
+        Some(TableValue::Value(v)) => Ok(Some(v.to_vec())),
+        Some(TableValue::Merge(operand)) => {
+            let op = op.ok_or_else(missing_operator)?;
+            Ok(Some(op.merge(key, None, &operand)))
+        }
+        Some(TableValue::Deleted) | None => Ok(None),
+    }
+}
+
+pub(crate) fn missing_operator() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Merge operands present without a merge operator")
+}
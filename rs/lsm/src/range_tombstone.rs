@@ -0,0 +1,158 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use crate::comparator::Comparator;
+
+// A piece of one or more range deletions: every key in [start, end) is
+// deleted. `seq` is the sequence number of the newest deletion that covers
+// the piece. Tables do not record sequence numbers, so it is 0 in fragments
+// read from a table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Fragment {
+    pub(crate) start: Vec<u8>,
+    pub(crate) end: Vec<u8>,
+    pub(crate) seq: u64,
+}
+
+// Range deletions, split into fragments that do not overlap and are kept in
+// key order. Where deletions overlap, the overlap becomes a fragment of its
+// own, so finding the deletion that covers a key is a binary search.
+pub(crate) struct RangeTombstones {
+    cmp: Arc<dyn Comparator>,
+    fragments: Vec<Fragment>,
+}
+
+impl RangeTombstones {
+    pub(crate) fn new(cmp: Arc<dyn Comparator>) -> RangeTombstones {
+        RangeTombstones {
+            cmp,
+            fragments: Vec::new(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.fragments.is_empty()
+    }
+
+    // Deletes every key in [start, end). Empty ranges are ignored.
+    pub(crate) fn add(&mut self, start: &[u8], end: &[u8], seq: u64) {
+        let cmp = self.cmp.as_ref();
+        if cmp.compare(start, end) != Ordering::Less {
+            return;
+        }
+        // The fragments in [first, last) overlap the new range. They are
+        // replaced by the parts of them outside it, the parts inside it with
+        // the newer of the two sequence numbers, and the gaps between them.
+        let first = self.fragments.partition_point(|f| cmp.compare(&f.end, start) != Ordering::Greater);
+        let last = self.fragments.partition_point(|f| cmp.compare(&f.start, end) == Ordering::Less);
+        let mut pieces = Vec::with_capacity(last - first + 3);
+        let mut pos = start;
+        for f in &self.fragments[first..last] {
+            if cmp.compare(&f.start, start) == Ordering::Less {
+                pieces.push(fragment(&f.start, start, f.seq));
+            } else if cmp.compare(pos, &f.start) == Ordering::Less {
+                pieces.push(fragment(pos, &f.start, seq));
+            }
+            let inner_start = if cmp.compare(&f.start, start) == Ordering::Less { start } else { &f.start };
+            let inner_end = if cmp.compare(&f.end, end) == Ordering::Greater { end } else { &f.end };
+            pieces.push(fragment(inner_start, inner_end, seq.max(f.seq)));
+            if cmp.compare(&f.end, end) == Ordering::Greater {
+                pieces.push(fragment(end, &f.end, f.seq));
+            }
+            pos = inner_end;
+        }
+        if cmp.compare(pos, end) == Ordering::Less {
+            pieces.push(fragment(pos, end, seq));
+        }
+        self.fragments.splice(first..last, pieces);
+    }
+
+    // Returns the fragment that covers `key`, if any.
+    pub(crate) fn covering(&self, key: &[u8]) -> Option<&Fragment> {
+        let cmp = self.cmp.as_ref();
+        let idx = self.fragments.partition_point(|f| cmp.compare(&f.end, key) != Ordering::Greater);
+        self.fragments
+            .get(idx)
+            .filter(|f| cmp.compare(&f.start, key) != Ordering::Greater)
+    }
+
+    pub(crate) fn covers(&self, key: &[u8]) -> bool {
+        self.covering(key).is_some()
+    }
+
+    // Whether any deleted key lies in [smallest, largest].
+    pub(crate) fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        let cmp = self.cmp.as_ref();
+        let idx = self.fragments.partition_point(|f| cmp.compare(&f.end, smallest) != Ordering::Greater);
+        self.fragments
+            .get(idx)
+            .is_some_and(|f| cmp.compare(&f.start, largest) != Ordering::Greater)
+    }
+
+    // The fragments in key order.
+    pub(crate) fn iter(&self) -> std::slice::Iter<'_, Fragment> {
+        self.fragments.iter()
+    }
+}
+
+fn fragment(start: &[u8], end: &[u8], seq: u64) -> Fragment {
+    Fragment {
+        start: start.to_vec(),
+        end: end.to_vec(),
+        seq,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparator::BytewiseComparator;
+
+    fn fragments(tombstones: &RangeTombstones) -> Vec<(&str, &str, u64)> {
+        tombstones
+            .iter()
+            .map(|f| {
+                let start = std::str::from_utf8(&f.start).unwrap();
+                (start, std::str::from_utf8(&f.end).unwrap(), f.seq)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fragments() {
+        let mut tombstones = RangeTombstones::new(Arc::new(BytewiseComparator));
+        tombstones.add(b"c", b"f", 1);
+        tombstones.add(b"h", b"j", 2);
+        tombstones.add(b"x", b"x", 3);
+        assert_eq!(vec![("c", "f", 1), ("h", "j", 2)], fragments(&tombstones));
+
+        // A deletion over both splits them where it begins and ends, and
+        // fills the gap between them.
+        tombstones.add(b"d", b"i", 4);
+        let expected = vec![("c", "d", 1), ("d", "f", 4), ("f", "h", 4), ("h", "i", 4), ("i", "j", 2)];
+        assert_eq!(expected, fragments(&tombstones));
+
+        // An older deletion does not lower the sequence numbers.
+        tombstones.add(b"a", b"e", 0);
+        let expected = vec![
+            ("a", "c", 0),
+            ("c", "d", 1),
+            ("d", "e", 4),
+            ("e", "f", 4),
+            ("f", "h", 4),
+            ("h", "i", 4),
+            ("i", "j", 2),
+        ];
+        assert_eq!(expected, fragments(&tombstones));
+
+        assert_eq!(None, tombstones.covering(b"0"));
+        assert_eq!(Some(1), tombstones.covering(b"c").map(|f| f.seq));
+        assert_eq!(Some(4), tombstones.covering(b"d").map(|f| f.seq));
+        assert_eq!(Some(2), tombstones.covering(b"i").map(|f| f.seq));
+        assert_eq!(None, tombstones.covering(b"j"));
+
+        assert!(tombstones.overlaps(b"0", b"a"));
+        assert!(!tombstones.overlaps(b"j", b"z"));
+        assert!(!tombstones.overlaps(b"0", b"0"));
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Token bucket that paces callers to a fixed number of bytes per second.
+// The bucket holds at most one second worth of bytes, so an idle limiter
+// permits a short burst before throttling kicks in. Requests larger than
+// the bucket are admitted by borrowing against future refills, and the
+// caller sleeps off the debt.
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    state: Mutex<State>,
+}
+
+struct State {
+    available: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        assert!(bytes_per_sec > 0, "rate limit must be positive");
+        RateLimiter {
+            bytes_per_sec,
+            state: Mutex::new(State {
+                available: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    // Blocks until `bytes` may be transferred.
+    pub fn request(&self, bytes: u64) {
+        let wait = self.reserve(bytes, Instant::now());
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+
+    // Takes `bytes` out of the bucket and returns how long the caller must
+    // wait before the reservation is paid for.
+    fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let rate = self.bytes_per_sec as f64;
+        let mut state = self.state.lock().unwrap();
+
+        let elapsed = now.saturating_duration_since(state.last_refill);
+        state.available = (state.available + elapsed.as_secs_f64() * rate).min(rate);
+        state.last_refill = now;
+
+        state.available -= bytes as f64;
+        if state.available >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-state.available / rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let limiter = RateLimiter::new(1000);
+        let start = Instant::now();
+
+        // A full bucket admits a one second burst without waiting.
+        assert_eq!(Duration::from_secs(0), limiter.reserve(1000, start));
+
+        // Once empty, callers wait for the deficit to refill.
+        assert_eq!(Duration::from_millis(500), limiter.reserve(500, start));
+        assert_eq!(Duration::from_millis(1000), limiter.reserve(500, start));
+
+        // Refilling pays down the debt before any new budget is available.
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(Duration::from_secs(0), limiter.reserve(500, later));
+        assert_eq!(Duration::from_millis(100), limiter.reserve(100, later));
+    }
+}
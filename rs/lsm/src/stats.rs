@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use crate::cache::BlockCacheStats;
+
+// Latencies are bucketed by powers of two of microseconds. Bucket 0 counts
+// anything under 1us, and bucket `i` counts observations in [2^(i-1), 2^i)us.
+// The last bucket also absorbs everything larger (~35 minutes and up).
+const HISTOGRAM_BUCKETS: usize = 32;
+
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    buckets: [u64; HISTOGRAM_BUCKETS],
+    count: u64,
+    sum_micros: u64,
+    max_micros: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, d: Duration) {
+        let micros = d.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = match micros {
+            0 => 0,
+            n => ((64 - n.leading_zeros()) as usize).min(HISTOGRAM_BUCKETS - 1),
+        };
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_micros = self.sum_micros.saturating_add(micros);
+        self.max_micros = self.max_micros.max(micros);
+    }
+
+    // Adds the observations recorded in `other`.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (bucket, n) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += n;
+        }
+        self.count += other.count;
+        self.sum_micros = self.sum_micros.saturating_add(other.sum_micros);
+        self.max_micros = self.max_micros.max(other.max_micros);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::from_micros(0),
+            n => Duration::from_micros(self.sum_micros / n),
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_micros)
+    }
+
+    // Returns the upper bound of the bucket that contains the `p`th percentile
+    // observation, where `p` is in [0.0, 1.0]. Since buckets are powers of two,
+    // this overestimates by at most a factor of two.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::from_micros(0);
+        }
+        let rank = ((self.count as f64) * p.clamp(0.0, 1.0)).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                let upper = if i == 0 { 1 } else { 1u64 << i };
+                return Duration::from_micros(upper.min(self.max_micros));
+            }
+        }
+
+        self.max()
+    }
+}
+
+// Counters kept by a log segment. The agent's are added up over every
+// segment that it has written to.
+#[derive(Debug, Clone, Default)]
+pub struct LogStats {
+    pub records_appended: u64,
+    pub bytes_appended: u64,
+    pub fsync_count: u64,
+    pub fsync_latency: Histogram,
+}
+
+impl LogStats {
+    pub fn add(&mut self, other: &LogStats) {
+        self.records_appended += other.records_appended;
+        self.bytes_appended += other.bytes_appended;
+        self.fsync_count += other.fsync_count;
+        self.fsync_latency.merge(&other.fsync_latency);
+    }
+}
+
+// Counters for the tables written by flushes and compactions.
+#[derive(Debug, Clone, Default)]
+pub struct CompactionStats {
+    pub flushes: u64,
+    pub flush_bytes_written: u64,
+    pub compactions: u64,
+    // Sizes of the compactions' input and output tables.
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+// The tables in one level.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LevelStats {
+    pub tables: u64,
+    pub bytes: u64,
+}
+
+// How point lookups fared against the tables' Bloom filters. Every table that
+// a lookup reaches counts once.
+#[derive(Debug, Clone, Default)]
+pub struct FilterStats {
+    pub checks: u64,
+    // Lookups that the filter ruled out, so the table was not read.
+    pub useful: u64,
+    // Lookups that the filter let through, but that found no entry.
+    pub false_positives: u64,
+}
+
+impl FilterStats {
+    // The fraction of lookups for keys that a table does not have which its
+    // filter ruled out.
+    pub fn hit_rate(&self) -> f64 {
+        match self.useful + self.false_positives {
+            0 => 0.0,
+            misses => self.useful as f64 / misses as f64,
+        }
+    }
+}
+
+// Snapshot of engine-wide statistics, as returned by `Agent::stats()`.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub log: LogStats,
+    pub memtable_bytes: u64,
+    pub block_cache: BlockCacheStats,
+    pub compaction: CompactionStats,
+    // Indexed by level, for every level of the compaction strategy and any
+    // deeper level that still holds tables.
+    pub levels: Vec<LevelStats>,
+    pub filter: FilterStats,
+    pub get_latency: Histogram,
+    // Time spent reading each scan, not counting the time the caller takes
+    // between entries. `AsyncAgent` scans in chunks, each of which counts as
+    // a scan.
+    pub scan_latency: Histogram,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut h = Histogram::default();
+        assert_eq!(Duration::from_micros(0), h.percentile(0.5));
+
+        for micros in 1..=100 {
+            h.record(Duration::from_micros(micros));
+        }
+        h.record(Duration::from_millis(10));
+
+        assert_eq!(101, h.count());
+        assert_eq!(Duration::from_millis(10), h.max());
+        assert_eq!(Duration::from_micros((5050 + 10_000) / 101), h.mean());
+        // The median (51us) lands in the [32, 64) bucket.
+        assert_eq!(Duration::from_micros(64), h.percentile(0.5));
+        assert_eq!(Duration::from_millis(10), h.percentile(1.0));
+    }
+}
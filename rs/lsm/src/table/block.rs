@@ -0,0 +1,490 @@
+use std::cmp::Ordering;
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use super::{corrupt, TableValue};
+use crate::cache::Block;
+use crate::comparator::Comparator;
+
+// Block format:
+// +---------+-...-+---------+-----------+-...-+-----------+--------------+
+// | entry_0 | ... | entry_n | restart_0 | ... | restart_m | num_restarts |
+// +---------+-...-+---------+-----------+-...-+-----------+--------------+
+//                              4 bytes          4 bytes       4 bytes
+//
+// Entry:
+// +------------+------------+--------------+-----------+------------+-------+
+// | value_type | shared_len | unshared_len | value_len | key_suffix | value |
+// +------------+------------+--------------+-----------+------------+-------+
+//     1 byte      varint        varint         varint
+//
+// Lengths are LEB128 varints of at most 5 bytes, so that the header of an
+// entry with a short suffix and value takes 4 bytes.
+//
+// Keys are prefix compressed: an entry stores the length of the prefix its key
+// shares with the previous key, followed by the rest of the key. Every
+// `RESTART_INTERVAL` entries the key is stored in full instead, and the
+// entry's offset is recorded as a restart point. Restart points let a reader
+// binary search the block, and decode entries backwards by starting from the
+// restart point before the entry it wants.
+const RESTART_INTERVAL: usize = 16;
+const VALUE_TYPE_VALUE: u8 = 1;
+const VALUE_TYPE_DELETED: u8 = 2;
+const VALUE_TYPE_MERGE: u8 = 3;
+
+pub(super) struct BlockBuilder {
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    count: usize,
+    last_key: Vec<u8>,
+}
+
+impl BlockBuilder {
+    pub(super) fn new() -> BlockBuilder {
+        BlockBuilder {
+            buf: Vec::new(),
+            restarts: Vec::new(),
+            count: 0,
+            last_key: Vec::new(),
+        }
+    }
+
+    // Adds an entry. Keys must be added in increasing order, according to the
+    // table's comparator.
+    pub(super) fn add(&mut self, key: &[u8], value: &TableValue) {
+        let (value_type, value) = match value {
+            TableValue::Value(v) => (VALUE_TYPE_VALUE, &v[..]),
+            TableValue::Deleted => (VALUE_TYPE_DELETED, &[][..]),
+            TableValue::Merge(v) => (VALUE_TYPE_MERGE, &v[..]),
+        };
+        let shared = if self.count.is_multiple_of(RESTART_INTERVAL) {
+            self.restarts.push(self.buf.len() as u32);
+            0
+        } else {
+            self.last_key.iter().zip(key).take_while(|(a, b)| a == b).count()
+        };
+        self.buf.write_u8(value_type).unwrap();
+        put_varint(&mut self.buf, shared as u32);
+        put_varint(&mut self.buf, (key.len() - shared) as u32);
+        put_varint(&mut self.buf, value.len() as u32);
+        self.buf.extend_from_slice(&key[shared..]);
+        self.buf.extend_from_slice(value);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.count += 1;
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    // Size of the block if it were finished now.
+    pub(super) fn size(&self) -> usize {
+        self.buf.len() + 4 * (self.restarts.len() + 1)
+    }
+
+    // Returns the encoded block and resets the builder.
+    pub(super) fn finish(&mut self) -> Vec<u8> {
+        let mut block = std::mem::take(&mut self.buf);
+        for restart in &self.restarts {
+            block.write_u32::<LittleEndian>(*restart).unwrap();
+        }
+        block.write_u32::<LittleEndian>(self.restarts.len() as u32).unwrap();
+        self.restarts.clear();
+        self.count = 0;
+        self.last_key.clear();
+        block
+    }
+}
+
+// Iterates over the entries of a block in either direction. Iteration stops
+// after the first error.
+pub(super) struct BlockIter {
+    block: Block,
+    restarts: Vec<usize>,
+    // End of the entries, where the restart array begins.
+    entries_end: usize,
+    // Offset of the next entry from the front, and the key before it.
+    front: usize,
+    front_key: Vec<u8>,
+    // Offset just past the next entry from the back.
+    back: usize,
+    done: bool,
+}
+
+struct Entry {
+    key: Vec<u8>,
+    value: TableValue,
+    // Offset of the following entry.
+    next: usize,
+}
+
+impl BlockIter {
+    pub(super) fn new(block: Block) -> io::Result<BlockIter> {
+        if block.len() < 4 {
+            return Err(corrupt("block is too short"));
+        }
+        let num_restarts = LittleEndian::read_u32(&block[block.len() - 4..]) as usize;
+        let entries_end = num_restarts
+            .checked_mul(4)
+            .and_then(|n| (block.len() - 4).checked_sub(n))
+            .ok_or_else(|| corrupt("restart array extends past the start of the block"))?;
+        let restarts = block[entries_end..block.len() - 4]
+            .chunks(4)
+            .map(|b| LittleEndian::read_u32(b) as usize)
+            .collect::<Vec<_>>();
+        let valid = match restarts.first() {
+            Some(first) => {
+                *first == 0 && restarts.windows(2).all(|w| w[0] < w[1]) && restarts[num_restarts - 1] < entries_end
+            }
+            None => entries_end == 0,
+        };
+        if !valid {
+            return Err(corrupt("invalid restart points"));
+        }
+        Ok(BlockIter {
+            block,
+            restarts,
+            entries_end,
+            front: 0,
+            front_key: Vec::new(),
+            back: entries_end,
+            done: false,
+        })
+    }
+
+    // Moves the front of the iterator to the first entry with a key of at
+    // least `target`, in the order defined by `cmp`.
+    pub(super) fn seek(&mut self, target: &[u8], cmp: &dyn Comparator) -> io::Result<()> {
+        // Find the last restart point with a key below `target`. The entries
+        // before it all have smaller keys too.
+        let mut err = None;
+        let idx = self.restarts.partition_point(|&offset| match self.decode(offset, &[]) {
+            Ok(entry) => cmp.compare(&entry.key, target) == Ordering::Less,
+            Err(e) => {
+                err.get_or_insert(e);
+                false
+            }
+        });
+        if let Some(err) = err {
+            self.done = true;
+            return Err(err);
+        }
+        self.front = self.restarts.get(idx.saturating_sub(1)).copied().unwrap_or(0);
+        self.front_key.clear();
+        while self.front < self.back {
+            let entry = self.decode(self.front, &self.front_key).inspect_err(|_| self.done = true)?;
+            if cmp.compare(&entry.key, target) != Ordering::Less {
+                break;
+            }
+            self.front = entry.next;
+            self.front_key = entry.key;
+        }
+        Ok(())
+    }
+
+    fn decode(&self, offset: usize, prev_key: &[u8]) -> io::Result<Entry> {
+        let buf = &self.block[offset..self.entries_end];
+        let mut header_len = 1;
+        let mut lengths = [0usize; 3];
+        for length in &mut lengths {
+            let (n, len) = buf
+                .get(header_len..)
+                .and_then(get_varint)
+                .ok_or_else(|| corrupt("truncated block entry"))?;
+            *length = n as usize;
+            header_len += len;
+        }
+        let [shared, unshared, val_len] = lengths;
+        if shared > prev_key.len() {
+            return Err(corrupt("shared key prefix is longer than the previous key"));
+        }
+        let len = header_len as u64 + unshared as u64 + val_len as u64;
+        if (buf.len() as u64) < len {
+            return Err(corrupt("block entry extends past the end of the block"));
+        }
+        let suffix_end = header_len + unshared;
+        let mut key = Vec::with_capacity(shared + unshared);
+        key.extend_from_slice(&prev_key[..shared]);
+        key.extend_from_slice(&buf[header_len..suffix_end]);
+        let value = match buf[0] {
+            VALUE_TYPE_VALUE | VALUE_TYPE_MERGE => {
+                let start = offset + suffix_end;
+                let value = self.block.slice(start..start + val_len);
+                if buf[0] == VALUE_TYPE_VALUE {
+                    TableValue::Value(value)
+                } else {
+                    TableValue::Merge(value)
+                }
+            }
+            VALUE_TYPE_DELETED => TableValue::Deleted,
+            _ => return Err(corrupt("invalid value type")),
+        };
+        Ok(Entry {
+            key,
+            value,
+            next: offset + len as usize,
+        })
+    }
+
+    // Decodes the entry that ends at `self.back`. Keys can only be decoded
+    // forwards, so this starts from the closest restart point before it.
+    fn decode_back(&self) -> io::Result<(usize, Entry)> {
+        let idx = self.restarts.partition_point(|&offset| offset < self.back);
+        let mut offset = self.restarts[idx - 1];
+        let mut prev_key = Vec::new();
+        loop {
+            let entry = self.decode(offset, &prev_key)?;
+            if entry.next == self.back {
+                return Ok((offset, entry));
+            }
+            if entry.next > self.back {
+                return Err(corrupt("block entries overlap"));
+            }
+            offset = entry.next;
+            prev_key = entry.key;
+        }
+    }
+}
+
+impl Iterator for BlockIter {
+    type Item = io::Result<(Vec<u8>, TableValue)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.front >= self.back {
+            return None;
+        }
+        match self.decode(self.front, &self.front_key) {
+            Ok(entry) => {
+                self.front = entry.next;
+                self.front_key = entry.key.clone();
+                Some(Ok((entry.key, entry.value)))
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl DoubleEndedIterator for BlockIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.done || self.front >= self.back {
+            return None;
+        }
+        match self.decode_back() {
+            Ok((offset, entry)) => {
+                self.back = offset;
+                Some(Ok((entry.key, entry.value)))
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut n: u32) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+// Decodes a varint from the start of `buf` and returns it with its length,
+// or `None` if it is truncated or does not fit in a u32.
+fn get_varint(buf: &[u8]) -> Option<(u32, usize)> {
+    let mut n = 0u32;
+    for (i, &b) in buf.iter().take(5).enumerate() {
+        let bits = (b & 0x7f) as u32;
+        if i == 4 && bits > 0x0f {
+            return None;
+        }
+        n |= bits << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((n, i + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::collection::{btree_map, vec};
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::comparator::BytewiseComparator;
+
+    // Sorted, unique keys made of a few long shared prefixes followed by a
+    // random suffix, like the tenant/table prefixes of real keys.
+    fn random_entries(rng: &mut StdRng, n: usize) -> Vec<(Vec<u8>, TableValue)> {
+        let mut keys = (0..n)
+            .map(|_| {
+                let mut key = format!("tenant-{:04}/table-{:02}/", rng.gen_range(0, 3), rng.gen_range(0, 4)).into_bytes();
+                for _ in 0..rng.gen_range(0, 12) {
+                    key.push(rng.gen_range(0, 4));
+                }
+                key
+            })
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        keys.into_iter()
+            .map(|key| {
+                let bytes = (0..rng.gen_range(0, 8)).map(|_| rng.gen()).collect::<Vec<u8>>().into();
+                let value = match rng.gen_range(0, 6) {
+                    0 => TableValue::Deleted,
+                    1 => TableValue::Merge(bytes),
+                    _ => TableValue::Value(bytes),
+                };
+                (key, value)
+            })
+            .collect()
+    }
+
+    fn build(entries: &[(Vec<u8>, TableValue)]) -> Block {
+        let mut builder = BlockBuilder::new();
+        for (key, value) in entries {
+            builder.add(key, value);
+        }
+        let size = builder.size();
+        let block = builder.finish();
+        assert_eq!(size, block.len());
+        assert!(builder.is_empty());
+        block.into()
+    }
+
+    fn collect<I: Iterator<Item = io::Result<(Vec<u8>, TableValue)>>>(iter: I) -> Vec<(Vec<u8>, TableValue)> {
+        iter.map(|e| e.unwrap()).collect()
+    }
+
+    // Sorted, unique keys made of a few shared prefixes followed by a suffix,
+    // some long enough to need multi-byte lengths, and values of every kind.
+    fn arb_entries() -> impl Strategy<Value = Vec<(Vec<u8>, TableValue)>> {
+        let key = (0..3u8, 0..4u8, prop_oneof![4 => vec(0..4u8, 0..12), 1 => vec(any::<u8>(), 100..200)]);
+        let key = key.prop_map(|(tenant, table, suffix)| {
+            let mut key = format!("tenant-{:04}/table-{:02}/", tenant, table).into_bytes();
+            key.extend(suffix);
+            key
+        });
+        let value = (0..6u8, prop_oneof![4 => vec(any::<u8>(), 0..8), 1 => vec(any::<u8>(), 100..300)]);
+        let value = value.prop_map(|(kind, bytes)| match kind {
+            0 => TableValue::Deleted,
+            1 => TableValue::Merge(bytes.into()),
+            _ => TableValue::Value(bytes.into()),
+        });
+        btree_map(key, value, 0..200).prop_map(|entries| entries.into_iter().collect())
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(
+            entries in arb_entries(),
+            from_front in vec(any::<bool>(), 0..400),
+            targets in vec((any::<Index>(), any::<bool>()), 0..20),
+        ) {
+            let block = build(&entries);
+
+            prop_assert_eq!(&entries, &collect(BlockIter::new(block.clone()).unwrap()));
+            let mut reversed = collect(BlockIter::new(block.clone()).unwrap().rev());
+            reversed.reverse();
+            prop_assert_eq!(&entries, &reversed);
+
+            // Taking from both ends meets in the middle without repeating or
+            // skipping an entry.
+            let mut iter = BlockIter::new(block.clone()).unwrap();
+            let (mut front, mut back) = (Vec::new(), Vec::new());
+            for (i, from_front) in from_front.into_iter().chain(std::iter::repeat(true)).enumerate() {
+                let entry = if from_front {
+                    iter.next().map(|e| (&mut front, e))
+                } else {
+                    iter.next_back().map(|e| (&mut back, e))
+                };
+                match entry {
+                    Some((side, e)) => side.push(e.unwrap()),
+                    None => break,
+                }
+                prop_assert!(i <= entries.len());
+            }
+            prop_assert!(iter.next().is_none() && iter.next_back().is_none());
+            back.reverse();
+            front.extend(back);
+            prop_assert_eq!(&entries, &front);
+
+            // Seeking to a key, or just past it, starts at the first entry
+            // that is not smaller.
+            for (index, past) in targets {
+                let mut target = match entries.len() {
+                    0 => b"tenant".to_vec(),
+                    n => entries[index.index(n)].0.clone(),
+                };
+                if past {
+                    target.push(0);
+                }
+                let mut iter = BlockIter::new(block.clone()).unwrap();
+                iter.seek(&target, &BytewiseComparator).unwrap();
+                let expected = entries.iter().filter(|(k, _)| *k >= target).cloned().collect::<Vec<_>>();
+                prop_assert_eq!(expected, collect(iter));
+            }
+        }
+
+        #[test]
+        fn test_varint(n in any::<u32>(), extra in vec(any::<u8>(), 0..4)) {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, n);
+            let len = buf.len();
+            buf.extend(extra);
+            prop_assert_eq!(Some((n, len)), get_varint(&buf));
+            prop_assert_eq!(None, get_varint(&buf[..len - 1]));
+        }
+    }
+
+    #[test]
+    fn test_prefix_compression() {
+        // Whole blocks, headers and restart points included, take less than
+        // half the space of the keys and values alone.
+        let mut rng = StdRng::seed_from_u64(0);
+        let entries = random_entries(&mut rng, 500);
+        let raw_bytes = entries
+            .iter()
+            .map(|(k, v)| match v {
+                TableValue::Value(v) | TableValue::Merge(v) => k.len() + v.len(),
+                TableValue::Deleted => k.len(),
+            })
+            .sum::<usize>();
+        let block = build(&entries);
+        assert!(block.len() * 2 < raw_bytes, "{} byte block, {} bytes of keys and values", block.len(), raw_bytes);
+    }
+
+    #[test]
+    fn test_corruption() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let entries = random_entries(&mut rng, 100);
+        let block = build(&entries).to_vec();
+
+        assert!(BlockIter::new(Block::from(&block[..2])).is_err());
+        // The restart count claims more restarts than fit in the block.
+        let mut bad = block.clone();
+        let len = bad.len();
+        LittleEndian::write_u32(&mut bad[len - 4..], u32::MAX);
+        assert!(BlockIter::new(bad.into()).is_err());
+
+        // A shared prefix at a restart point cannot be decoded.
+        let mut bad = block;
+        bad[1] = 3;
+        assert!(BlockIter::new(bad.clone().into()).unwrap().next().unwrap().is_err());
+        assert!(BlockIter::new(bad.into()).unwrap().seek(b"", &BytewiseComparator).is_err());
+
+        // A length that does not fit in a u32.
+        assert_eq!(None, get_varint(&[0xff; 5]));
+        assert_eq!(Some((u32::MAX, 5)), get_varint(&[0xff, 0xff, 0xff, 0xff, 0x0f]));
+    }
+}
@@ -0,0 +1,230 @@
+use std::cmp::Ordering;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use super::block::BlockBuilder;
+use super::filter::FilterBuilder;
+use super::{block_aad, checksum, BlockHandle, TableValue, TABLE_MAGIC};
+use crate::comparator::Comparator;
+use crate::encryption::{Cipher, KeyProvider, PLAINTEXT_KEY_ID};
+use crate::fs::{FileHandle, FileSystem};
+use crate::range_tombstone::RangeTombstones;
+
+// Data blocks are cut once they reach this size.
+const BLOCK_SIZE: usize = 4096;
+
+// Writes a table. Entries must be added in strictly increasing key order,
+// as defined by the comparator that the table is built with.
+//
+// The builder does not need a running store, so tables can be built offline
+// and then loaded with `Agent::ingest_files`.
+pub struct TableBuilder {
+    file: Box<dyn FileHandle>,
+    cmp: Arc<dyn Comparator>,
+    offset: u64,
+    data_block: BlockBuilder,
+    index_block: BlockBuilder,
+    filter: FilterBuilder,
+    range_tombstones: RangeTombstones,
+    props: TableProperties,
+    cipher: Option<Cipher>,
+    // Random id that binds the sealed blocks to this table, or 0 if the
+    // table is not encrypted.
+    table_id: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableProperties {
+    pub num_entries: u64,
+    // The range of keys that the table's entries and range deletions cover.
+    // A range deletion's end is included, although it is not deleted.
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+    // Size of the table file in bytes.
+    pub size: u64,
+}
+
+impl TableProperties {
+    // Widens the key range to cover `range_tombstones`, once the range of the
+    // entries is known.
+    pub(super) fn include_range_tombstones(&mut self, range_tombstones: &RangeTombstones, cmp: &dyn Comparator) {
+        let (first, last) = match (range_tombstones.iter().next(), range_tombstones.iter().last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return,
+        };
+        if self.num_entries == 0 || cmp.compare(&first.start, &self.smallest) == Ordering::Less {
+            self.smallest = first.start.clone();
+        }
+        if self.num_entries == 0 || cmp.compare(&last.end, &self.largest) == Ordering::Greater {
+            self.largest = last.end.clone();
+        }
+    }
+}
+
+impl TableBuilder {
+    // Creates a table at `path`, which must not already exist or be empty.
+    pub fn create(fs: &dyn FileSystem, path: &Path, cmp: Arc<dyn Comparator>) -> io::Result<TableBuilder> {
+        let file = fs.open_append(path)?;
+        if file.size()? != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Table file is not empty: {}", path.display()),
+            ));
+        }
+        Ok(TableBuilder::new(file, cmp))
+    }
+
+    pub fn new(file: Box<dyn FileHandle>, cmp: Arc<dyn Comparator>) -> TableBuilder {
+        TableBuilder {
+            file,
+            range_tombstones: RangeTombstones::new(Arc::clone(&cmp)),
+            cmp,
+            offset: 0,
+            data_block: BlockBuilder::new(),
+            index_block: BlockBuilder::new(),
+            filter: FilterBuilder::new(),
+            props: TableProperties::default(),
+            cipher: None,
+            table_id: 0,
+        }
+    }
+
+    // Encrypts the table's blocks with the key provider's current key. Must
+    // be called before any entries are added.
+    pub fn with_key_provider(mut self, key_provider: &dyn KeyProvider) -> io::Result<TableBuilder> {
+        if self.props.num_entries > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Encryption must be set up before entries are added",
+            ));
+        }
+        self.cipher = Some(Cipher::for_key_id(key_provider, key_provider.current_key_id())?);
+        self.table_id = Cipher::random_id();
+        Ok(self)
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.add(key, &TableValue::Value(value.into()))
+    }
+
+    // Adds a tombstone that masks `key` in older tables.
+    pub fn delete(&mut self, key: &[u8]) -> io::Result<()> {
+        self.add(key, &TableValue::Deleted)
+    }
+
+    // Adds a tombstone that masks every key in [start, end) in older tables.
+    // Range deletions may be added in any order, and may overlap each other
+    // and the table's entries, which they do not mask.
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) {
+        self.range_tombstones.add(start, end, 0);
+    }
+
+    pub fn add(&mut self, key: &[u8], value: &TableValue) -> io::Result<()> {
+        if self.props.num_entries > 0 && self.cmp.compare(key, &self.props.largest) != Ordering::Greater {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Table keys must be added in strictly increasing order",
+            ));
+        }
+        if self.props.num_entries == 0 {
+            self.props.smallest = key.to_vec();
+        }
+        self.props.num_entries += 1;
+        self.props.largest = key.to_vec();
+        self.data_block.add(key, value);
+        self.filter.add(key);
+        if self.data_block.size() >= BLOCK_SIZE {
+            self.flush_data_block()?;
+        }
+        Ok(())
+    }
+
+    // Size of the file so far, including the data block that has not been
+    // written yet.
+    pub fn estimated_size(&self) -> u64 {
+        self.offset + self.data_block.size() as u64
+    }
+
+    // Writes the filter, range deletions, index and footer and syncs the file.
+    pub fn finish(mut self) -> io::Result<TableProperties> {
+        if self.props.num_entries == 0 && self.range_tombstones.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot build an empty table"));
+        }
+        if !self.data_block.is_empty() {
+            self.flush_data_block()?;
+        }
+        let comparator = self.cmp.name().to_string();
+        if comparator.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Comparator name is too long"));
+        }
+        let filter_handle = self.write_block(&self.filter.finish(), &[])?;
+        let mut range_block = BlockBuilder::new();
+        for fragment in self.range_tombstones.iter() {
+            range_block.add(&fragment.start, &TableValue::Value(fragment.end[..].into()));
+        }
+        let range_handle = self.write_block(&range_block.finish(), &[])?;
+        self.props.include_range_tombstones(&self.range_tombstones, self.cmp.as_ref());
+
+        // The index block is sealed with the footer's handles, so its own
+        // handle has to be worked out before it is written.
+        let index = self.index_block.finish();
+        let index_handle = BlockHandle {
+            offset: self.offset,
+            size: (index.len() + self.cipher.as_ref().map_or(0, |_| Cipher::OVERHEAD)) as u64,
+        };
+        let mut handles = index_handle.encode().to_vec();
+        handles.extend_from_slice(&range_handle.encode());
+        handles.extend_from_slice(&filter_handle.encode());
+        handles.write_u64::<LittleEndian>(self.props.num_entries).unwrap();
+        let written = self.write_block(&index, &handles)?;
+        debug_assert_eq!(index_handle, written);
+
+        let mut footer = comparator.as_bytes().to_vec();
+        footer.extend_from_slice(&handles);
+        footer.write_u64::<LittleEndian>(self.table_id).unwrap();
+        let key_id = self.cipher.as_ref().map_or(PLAINTEXT_KEY_ID, |c| c.key_id());
+        footer.write_u32::<LittleEndian>(key_id).unwrap();
+        footer.write_u16::<LittleEndian>(comparator.len() as u16).unwrap();
+        footer.write_u64::<LittleEndian>(TABLE_MAGIC).unwrap();
+        self.file.append(&footer)?;
+        self.file.sync()?;
+
+        self.props.size = self.offset + footer.len() as u64;
+        Ok(self.props)
+    }
+
+    fn flush_data_block(&mut self) -> io::Result<()> {
+        let block = self.data_block.finish();
+        let handle = self.write_block(&block, &[])?;
+        self.index_block
+            .add(&self.props.largest, &TableValue::Value(handle.encode()[..].into()));
+        Ok(())
+    }
+
+    // Writes a block, sealed with `extra` at the end of its associated data
+    // if the table is encrypted.
+    fn write_block(&mut self, block: &[u8], extra: &[u8]) -> io::Result<BlockHandle> {
+        let sealed;
+        let block = match &self.cipher {
+            Some(cipher) => {
+                let aad = block_aad(self.table_id, cipher.key_id(), self.cmp.name().as_bytes(), self.offset, extra);
+                sealed = cipher.seal(&aad, block);
+                &sealed[..]
+            }
+            None => block,
+        };
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: block.len() as u64,
+        };
+        let mut buf = Vec::with_capacity(block.len() + 4);
+        buf.extend_from_slice(block);
+        buf.write_u32::<LittleEndian>(checksum(block)).unwrap();
+        self.file.append(&buf)?;
+        self.offset += buf.len() as u64;
+        Ok(handle)
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::stats::FilterStats;
+
+// Bits of filter per key. With the matching number of probes, about 1% of
+// lookups for keys that are not in the table get through the filter.
+const BITS_PER_KEY: usize = 10;
+// Filters with more probes than this are from a newer format, and match
+// every key.
+const MAX_PROBES: u8 = 30;
+
+// Builds a Bloom filter over a table's keys.
+//
+// Filter block:
+// +----------+--------+
+// | bits     | probes |
+// +----------+--------+
+//  n bytes     1 byte
+//
+// Each key sets `probes` bits, chosen by double hashing the key's bytes. The
+// filter is over the bytes of the keys rather than their order, so keys that
+// the comparator treats as equal must have the same bytes.
+pub(super) struct FilterBuilder {
+    hashes: Vec<u32>,
+}
+
+impl FilterBuilder {
+    pub(super) fn new() -> FilterBuilder {
+        FilterBuilder { hashes: Vec::new() }
+    }
+
+    pub(super) fn add(&mut self, key: &[u8]) {
+        self.hashes.push(hash(key));
+    }
+
+    pub(super) fn finish(&self) -> Vec<u8> {
+        // ln(2) * bits per key probes give the fewest false positives.
+        let probes = ((BITS_PER_KEY as f64 * 0.69) as u8).clamp(1, MAX_PROBES);
+        let bits = (self.hashes.len() * BITS_PER_KEY).max(64);
+        let bytes = bits.div_ceil(8);
+        let bits = bytes * 8;
+        let mut filter = vec![0u8; bytes + 1];
+        for &h in &self.hashes {
+            for pos in probe_positions(h, probes, bits) {
+                filter[pos / 8] |= 1 << (pos % 8);
+            }
+        }
+        filter[bytes] = probes;
+        filter
+    }
+}
+
+// Whether `key` may be in the table that `filter` was built for. A key that
+// was added to the filter always matches.
+pub(super) fn may_contain(filter: &[u8], key: &[u8]) -> bool {
+    let (probes, bits) = match filter.split_last() {
+        Some((&probes, bits)) if !bits.is_empty() => (probes, bits),
+        _ => return true,
+    };
+    if probes > MAX_PROBES {
+        return true;
+    }
+    probe_positions(hash(key), probes, bits.len() * 8).all(|pos| bits[pos / 8] & (1 << (pos % 8)) != 0)
+}
+
+fn probe_positions(h: u32, probes: u8, bits: usize) -> impl Iterator<Item = usize> {
+    let delta = h.rotate_right(17);
+    (0..probes as u32).map(move |i| h.wrapping_add(delta.wrapping_mul(i)) as usize % bits)
+}
+
+// A fixed hash of the key's bytes, since filters are stored in tables.
+fn hash(data: &[u8]) -> u32 {
+    const SEED: u32 = 0xbc9f_1d34;
+    const M: u32 = 0xc6a4_a793;
+    let mut h = SEED ^ (data.len() as u32).wrapping_mul(M);
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        h = h.wrapping_add(LittleEndian::read_u32(chunk)).wrapping_mul(M);
+        h ^= h >> 16;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, &b) in rest.iter().enumerate() {
+            h = h.wrapping_add((b as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> 24;
+    }
+    h
+}
+
+// Counts how point lookups fare against the filters of the tables that share
+// these counters.
+#[derive(Default)]
+pub(crate) struct FilterCounters {
+    checks: AtomicU64,
+    useful: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+impl FilterCounters {
+    pub(super) fn record(&self, matched: bool, found: bool) {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        if !matched {
+            self.useful.fetch_add(1, Ordering::Relaxed);
+        } else if !found {
+            self.false_positives.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> FilterStats {
+        FilterStats {
+            checks: self.checks.load(Ordering::Relaxed),
+            useful: self.useful.load(Ordering::Relaxed),
+            false_positives: self.false_positives.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let mut builder = FilterBuilder::new();
+        let key = |i: u32| format!("key-{}", i).into_bytes();
+        for i in 0..1000 {
+            builder.add(&key(i));
+        }
+        let filter = builder.finish();
+        assert_eq!(1000 * BITS_PER_KEY / 8 + 1, filter.len());
+        assert!((0..1000).all(|i| may_contain(&filter, &key(i))));
+        let false_positives = (1000..11_000).filter(|&i| may_contain(&filter, &key(i))).count();
+        assert!(false_positives < 200, "{} false positives in 10000", false_positives);
+
+        // An empty filter matches nothing, and one that cannot be read
+        // matches everything.
+        let empty = FilterBuilder::new().finish();
+        assert!(!may_contain(&empty, b"key"));
+        assert!(may_contain(&[], b"key"));
+        assert!(may_contain(&[0, 0, 0, MAX_PROBES + 1], b"key"));
+    }
+}
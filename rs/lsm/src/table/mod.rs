@@ -0,0 +1,177 @@
+// Sorted string tables: immutable files of entries in key order.
+//
+// File format:
+// +--------------+-...-+--------------+--------------+----------------------+-------------+--------+
+// | data block 0 | ... | data block n | filter block | range deletion block | index block | footer |
+// +--------------+-...-+--------------+--------------+----------------------+-------------+--------+
+//
+// Every block is followed by a 4 byte crc32 of its contents. The index block
+// has one entry per data block, whose key is the last key in the block and
+// whose value is the block's handle. The filter block is a Bloom filter over
+// the keys of the table's entries, described in filter.rs, which lets point
+// lookups skip tables that do not have the key. The range deletion block
+// holds the table's range deletions, split into fragments that do not
+// overlap, in order. Each entry's key is the start of a fragment and its
+// value is the end, which is not itself deleted. A table's range deletions
+// mask keys in older tables, but not the table's own entries, which are
+// always newer. The data, range deletion and index blocks use the prefix
+// compressed layout described in block.rs. A table may have no data blocks
+// if it only deletes ranges.
+//
+// Block handle:
+// +--------+------+
+// | offset | size |
+// +--------+------+
+//  8 bytes  8 bytes
+//
+// Footer:
+// +------------+--------------+-----------------------+---------------+-------------+----------+--------+----------------+-------+
+// | comparator | index handle | range deletion handle | filter handle | num_entries | table_id | key_id | comparator_len | magic |
+// +------------+--------------+-----------------------+---------------+-------------+----------+--------+----------------+-------+
+//                  16 bytes           16 bytes            16 bytes        8 bytes     8 bytes    4 bytes      2 bytes     8 bytes
+//
+// `comparator` is the name of the comparator that orders the table's keys, in
+// UTF-8. It comes first so that the rest of the footer has a fixed length
+// and can be read from the end of the file. `key_id` identifies the
+// encryption key that the blocks were written with, or is `PLAINTEXT_KEY_ID`
+// if the table is not encrypted. In an encrypted table, the contents of
+// every block are sealed (see `Cipher`), and the checksum covers the sealed
+// bytes. Block handles give the size of the sealed contents.
+//
+// The associated data of a sealed block is `table_id`, which is chosen at
+// random for each encrypted table, `key_id`, `comparator` and the block's
+// offset, so that a block cannot be moved within a table or between tables.
+// The index block's associated data also includes the footer's handles and
+// `num_entries`, which are not known until the other blocks are written, so
+// every field of the footer is authenticated. `table_id` is 0 in tables that
+// are not encrypted.
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::cache::Block;
+
+mod block;
+mod builder;
+mod filter;
+mod reader;
+
+pub use builder::{TableBuilder, TableProperties};
+pub(crate) use filter::FilterCounters;
+pub use reader::{Table, TableIter};
+
+const TABLE_FILE_EXT: &str = "sst";
+const BLOCK_TRAILER_LENGTH: usize = 4;
+const BLOCK_HANDLE_LENGTH: usize = 16;
+// Length of the footer without the comparator name.
+const FOOTER_LENGTH: usize = 3 * BLOCK_HANDLE_LENGTH + 30;
+// Length of the footer's handles and `num_entries`, which the index block's
+// associated data includes.
+const FOOTER_HANDLES_LENGTH: usize = 3 * BLOCK_HANDLE_LENGTH + 8;
+const TABLE_MAGIC: u64 = 0x6b65_6e64_7275_7373;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TableValue {
+    // A value read from a table borrows the block it was read from, so that
+    // it is not copied out of the cache or the mapping.
+    Value(Block),
+    // The key was deleted. Masks any value for the key in older tables.
+    Deleted,
+    // A merge operand, to be folded into the value of the key in older
+    // tables when the key is read. Consecutive operands are combined into
+    // one when they are written to the same table.
+    Merge(Block),
+}
+
+// Opening a table with a different comparator from the one it was built with
+// fails with an `io::Error` of kind `InvalidInput` that wraps this error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComparatorMismatch {
+    // Name of the comparator that the table was built with.
+    pub table: String,
+    // Name of the comparator that it was opened with.
+    pub given: String,
+}
+
+impl ComparatorMismatch {
+    // Returns the mismatch that `err` wraps, if any.
+    pub fn from_io_error(err: &io::Error) -> Option<&ComparatorMismatch> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for ComparatorMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Table was built with comparator {}, but opened with {}", self.table, self.given)
+    }
+}
+
+impl Error for ComparatorMismatch {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockHandle {
+    offset: u64,
+    size: u64,
+}
+
+impl BlockHandle {
+    fn encode(&self) -> [u8; BLOCK_HANDLE_LENGTH] {
+        let mut buf = [0u8; BLOCK_HANDLE_LENGTH];
+        LittleEndian::write_u64(&mut buf[..8], self.offset);
+        LittleEndian::write_u64(&mut buf[8..], self.size);
+        buf
+    }
+
+    // Offset just past the block's trailer, or `None` if that overflows.
+    fn end(&self) -> Option<u64> {
+        self.offset
+            .checked_add(self.size)?
+            .checked_add(BLOCK_TRAILER_LENGTH as u64)
+    }
+
+    fn decode(buf: &[u8]) -> io::Result<BlockHandle> {
+        if buf.len() != BLOCK_HANDLE_LENGTH {
+            return Err(corrupt("invalid block handle"));
+        }
+        Ok(BlockHandle {
+            offset: LittleEndian::read_u64(&buf[..8]),
+            size: LittleEndian::read_u64(&buf[8..]),
+        })
+    }
+}
+
+// Name of the table with the given file number in a store's table directory.
+pub(crate) fn table_file_name(file_number: u64) -> String {
+    format!("{:06}.{}", file_number, TABLE_FILE_EXT)
+}
+
+// Parses the file number from a name produced by `table_file_name`.
+pub(crate) fn parse_table_file_name(name: &str) -> Option<u64> {
+    let stem = name.strip_suffix(TABLE_FILE_EXT)?.strip_suffix('.')?;
+    stem.parse().ok()
+}
+
+fn checksum(buf: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(buf);
+    hasher.finalize()
+}
+
+// Associated data of the sealed block at `offset` in an encrypted table,
+// followed by `extra`: the footer's handles for the index block, and nothing
+// for the others.
+fn block_aad(table_id: u64, key_id: u32, comparator: &[u8], offset: u64, extra: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(20 + comparator.len() + extra.len());
+    aad.extend_from_slice(&table_id.to_le_bytes());
+    aad.extend_from_slice(&key_id.to_le_bytes());
+    aad.extend_from_slice(comparator);
+    aad.extend_from_slice(&offset.to_le_bytes());
+    aad.extend_from_slice(extra);
+    aad
+}
+
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Corrupt table: {}", msg))
+}
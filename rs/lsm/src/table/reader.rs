@@ -0,0 +1,797 @@
+use std::cmp::Ordering;
+use std::io;
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::block::BlockIter;
+use super::filter::{self, FilterCounters};
+use super::{block_aad, checksum, corrupt, BlockHandle, ComparatorMismatch, TableProperties, TableValue};
+use super::{BLOCK_HANDLE_LENGTH, FOOTER_HANDLES_LENGTH, FOOTER_LENGTH, TABLE_MAGIC};
+use crate::cache::{Block, BlockCache, BlockKey};
+use crate::comparator::Comparator;
+use crate::encryption::{Cipher, KeyProvider};
+use crate::fs::{FileHandle, Mapping};
+use crate::range_tombstone::RangeTombstones;
+
+// An open table. The index is held in memory, and data blocks are read on
+// demand through the block cache, if there is one. A mapped table reads its
+// blocks straight from the mapping instead, and leaves caching to the page
+// cache. Encrypted tables are never mapped, since their blocks have to be
+// decrypted before they can be read.
+pub struct Table {
+    file: Box<dyn FileHandle>,
+    mapping: Option<Mapping>,
+    cipher: Option<Cipher>,
+    // The id that the table's blocks are sealed with.
+    table_id: u64,
+    file_number: u64,
+    cache: Option<Arc<BlockCache>>,
+    cmp: Arc<dyn Comparator>,
+    // The last key of each data block, and its handle.
+    index: Vec<(Vec<u8>, BlockHandle)>,
+    // The filter and range deletions are held in memory, like the index.
+    filter: Block,
+    range_tombstones: RangeTombstones,
+    props: TableProperties,
+    // Where to count how lookups fare against the filter, if anywhere.
+    filter_counters: Option<Arc<FilterCounters>>,
+}
+
+impl Table {
+    // Opens a table and reads its index. `file_number` identifies the table's
+    // blocks in `cache`, so it must be unique among the tables sharing the
+    // cache. `cmp` must be the comparator that the table was built with. A
+    // key provider is required if the table is encrypted.
+    //
+    // If `mmap` is set, the file is mapped into memory. A file that cannot be
+    // mapped is read with `read_at`, as if `mmap` were not set.
+    pub fn open(
+        file: Box<dyn FileHandle>,
+        file_number: u64,
+        cache: Option<Arc<BlockCache>>,
+        cmp: Arc<dyn Comparator>,
+        key_provider: Option<&dyn KeyProvider>,
+        mmap: bool,
+    ) -> io::Result<Table> {
+        let size = file.size()?;
+        if size < FOOTER_LENGTH as u64 {
+            return Err(corrupt("file is too short"));
+        }
+        let mut footer = [0u8; FOOTER_LENGTH];
+        file.read_exact_at(&mut footer, size - FOOTER_LENGTH as u64)?;
+        if LittleEndian::read_u64(&footer[FOOTER_LENGTH - 8..]) != TABLE_MAGIC {
+            return Err(corrupt("bad magic number"));
+        }
+        let index_handle = BlockHandle::decode(&footer[..BLOCK_HANDLE_LENGTH])?;
+        let range_handle = BlockHandle::decode(&footer[BLOCK_HANDLE_LENGTH..2 * BLOCK_HANDLE_LENGTH])?;
+        let filter_handle = BlockHandle::decode(&footer[2 * BLOCK_HANDLE_LENGTH..3 * BLOCK_HANDLE_LENGTH])?;
+        let fields = &footer[3 * BLOCK_HANDLE_LENGTH..];
+        let num_entries = LittleEndian::read_u64(&fields[..8]);
+        let table_id = LittleEndian::read_u64(&fields[8..]);
+        let comparator_len = LittleEndian::read_u16(&fields[20..]) as u64;
+        let data_end = (size - FOOTER_LENGTH as u64)
+            .checked_sub(comparator_len)
+            .ok_or_else(|| corrupt("file is too short"))?;
+        let mut comparator = vec![0u8; comparator_len as usize];
+        file.read_exact_at(&mut comparator, data_end)?;
+        if comparator != cmp.name().as_bytes() {
+            let mismatch = ComparatorMismatch {
+                table: String::from_utf8_lossy(&comparator).into_owned(),
+                given: cmp.name().to_string(),
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, mismatch));
+        }
+        let key_id = LittleEndian::read_u32(&fields[16..]);
+        let cipher = Cipher::for_reading(key_provider, key_id, "Table")?;
+        let mapping = if mmap && cipher.is_none() { file.map().unwrap_or(None) } else { None };
+        if mapping.as_ref().is_some_and(|m| (**m).as_ref().len() as u64 != size) {
+            return Err(corrupt("file changed size while being opened"));
+        }
+        if index_handle.end() != Some(data_end) {
+            return Err(corrupt("index block is out of bounds"));
+        }
+        if range_handle.end() != Some(index_handle.offset) {
+            return Err(corrupt("range deletion block is out of bounds"));
+        }
+        if filter_handle.end() != Some(range_handle.offset) {
+            return Err(corrupt("filter block is out of bounds"));
+        }
+
+        let mut table = Table {
+            file,
+            mapping,
+            cipher,
+            table_id,
+            file_number,
+            cache,
+            cmp: Arc::clone(&cmp),
+            index: Vec::new(),
+            filter: Vec::new().into(),
+            range_tombstones: RangeTombstones::new(Arc::clone(&cmp)),
+            props: TableProperties {
+                num_entries,
+                size,
+                ..TableProperties::default()
+            },
+            filter_counters: None,
+        };
+        let mut prev_end = 0;
+        for entry in BlockIter::new(table.read_block_uncached(index_handle, &footer[..FOOTER_HANDLES_LENGTH])?)? {
+            let (last_key, handle) = match entry? {
+                (key, TableValue::Value(v)) => (key, BlockHandle::decode(&v)?),
+                (_, TableValue::Deleted | TableValue::Merge(_)) => return Err(corrupt("invalid index entry")),
+            };
+            if handle.offset != prev_end {
+                return Err(corrupt("data blocks are not contiguous"));
+            }
+            prev_end = handle.end().ok_or_else(|| corrupt("data block is out of bounds"))?;
+            table.index.push((last_key, handle));
+        }
+        if prev_end != filter_handle.offset || table.index.is_empty() != (num_entries == 0) {
+            return Err(corrupt("index does not cover the data blocks"));
+        }
+        table.filter = table.read_block_uncached(filter_handle, &[])?;
+        table.read_range_tombstones(range_handle)?;
+        if num_entries == 0 && table.range_tombstones.is_empty() {
+            return Err(corrupt("table is empty"));
+        }
+
+        if num_entries > 0 {
+            table.props.smallest = match table.iter().next() {
+                Some(entry) => entry?.0,
+                None => return Err(corrupt("first data block is empty")),
+            };
+            table.props.largest = table.index.last().unwrap().0.clone();
+        }
+        table.props.include_range_tombstones(&table.range_tombstones, cmp.as_ref());
+        Ok(table)
+    }
+
+    // Reads the range deletion block, checking that the fragments are in
+    // order and do not overlap.
+    fn read_range_tombstones(&mut self, handle: BlockHandle) -> io::Result<()> {
+        let mut prev_end: Option<Vec<u8>> = None;
+        for entry in BlockIter::new(self.read_block_uncached(handle, &[])?)? {
+            let (start, end) = match entry? {
+                (start, TableValue::Value(end)) => (start, end.to_vec()),
+                (_, TableValue::Deleted | TableValue::Merge(_)) => return Err(corrupt("invalid range deletion")),
+            };
+            let cmp = self.cmp.as_ref();
+            let overlaps = prev_end.as_ref().is_some_and(|p| cmp.compare(p, &start) == Ordering::Greater);
+            if overlaps || cmp.compare(&start, &end) != Ordering::Less {
+                return Err(corrupt("range deletions are out of order"));
+            }
+            self.range_tombstones.add(&start, &end, 0);
+            prev_end = Some(end);
+        }
+        Ok(())
+    }
+
+    pub fn properties(&self) -> &TableProperties {
+        &self.props
+    }
+
+    // Counts each `get` in `counters`: whether the filter ruled the key out,
+    // and if not, whether the key was found.
+    pub(crate) fn count_filter_lookups(&mut self, counters: Arc<FilterCounters>) {
+        self.filter_counters = Some(counters);
+    }
+
+    // The table's range deletions. They mask keys in older tables only.
+    pub(crate) fn range_tombstones(&self) -> &RangeTombstones {
+        &self.range_tombstones
+    }
+
+    // Whether blocks are read from a mapping of the file.
+    pub fn is_mapped(&self) -> bool {
+        self.mapping.is_some()
+    }
+
+    // Returns the table's entry for `key`. The data blocks are not read if
+    // the filter rules the key out.
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<TableValue>> {
+        let matched = filter::may_contain(&self.filter, key);
+        let value = if matched {
+            self.iter_from(key).next().transpose()?.filter(|(k, _)| k == key).map(|(_, v)| v)
+        } else {
+            None
+        };
+        if let Some(counters) = &self.filter_counters {
+            counters.record(matched, value.is_some());
+        }
+        Ok(value)
+    }
+
+    pub fn iter(&self) -> TableIter<'_> {
+        self.new_iter(None, 0)
+    }
+
+    // Iterates over the entries with keys of at least `start`, in order. The
+    // iterator can also be walked backwards from the end of the table.
+    pub fn iter_from(&self, start: &[u8]) -> TableIter<'_> {
+        let first_idx = self
+            .index
+            .partition_point(|(last_key, _)| self.cmp.compare(last_key, start) == Ordering::Less);
+        self.new_iter(Some(start.to_vec()), first_idx)
+    }
+
+    fn new_iter(&self, start: Option<Vec<u8>>, first_idx: usize) -> TableIter<'_> {
+        TableIter {
+            table: self,
+            start,
+            first_idx,
+            front_idx: first_idx,
+            back_idx: self.index.len(),
+            front: None,
+            back: None,
+            done: false,
+        }
+    }
+
+    // Reads every entry and checks that the table is internally consistent:
+    // all checksums match, keys are in strictly increasing order, each block
+    // ends with the key recorded for it in the index, every key matches the
+    // filter, and the number of entries matches the footer.
+    pub fn verify(&self) -> io::Result<()> {
+        let mut count = 0;
+        let mut prev: Option<Vec<u8>> = None;
+        for (last_key, handle) in &self.index {
+            let mut block_last = None;
+            for entry in BlockIter::new(self.read_block_uncached(*handle, &[])?)? {
+                let (key, _) = entry?;
+                if prev.as_ref().is_some_and(|p| self.cmp.compare(p, &key) != Ordering::Less) {
+                    return Err(corrupt("keys are out of order"));
+                }
+                if !filter::may_contain(&self.filter, &key) {
+                    return Err(corrupt("key is missing from the filter"));
+                }
+                count += 1;
+                prev = Some(key.clone());
+                block_last = Some(key);
+            }
+            if block_last.as_ref() != Some(last_key) {
+                return Err(corrupt("index key does not match the last key of its block"));
+            }
+        }
+        if count != self.props.num_entries {
+            return Err(corrupt("entry count does not match the footer"));
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, handle: BlockHandle) -> io::Result<Block> {
+        match &self.cache {
+            Some(cache) if self.mapping.is_none() => {
+                let key = BlockKey {
+                    file_number: self.file_number,
+                    offset: handle.offset,
+                };
+                cache.get_or_load(key, || self.read_block_contents(handle, &[]))
+            }
+            _ => self.read_block_uncached(handle, &[]),
+        }
+    }
+
+    // `extra` is the end of the block's associated data, as in `block_aad`.
+    fn read_block_uncached(&self, handle: BlockHandle, extra: &[u8]) -> io::Result<Block> {
+        let mapping = match &self.mapping {
+            Some(mapping) => mapping,
+            None => return Ok(self.read_block_contents(handle, extra)?.into()),
+        };
+        let data = (**mapping).as_ref();
+        if handle.end().is_none_or(|end| end > data.len() as u64) {
+            return Err(corrupt("block is out of bounds"));
+        }
+        let start = handle.offset as usize;
+        let end = start + handle.size as usize;
+        if checksum(&data[start..end]) != LittleEndian::read_u32(&data[end..]) {
+            return Err(corrupt("block checksum mismatch"));
+        }
+        Ok(Block::mapped(mapping, start..end))
+    }
+
+    // Reads a block from the file, verifies its checksum and decrypts it.
+    fn read_block_contents(&self, handle: BlockHandle, extra: &[u8]) -> io::Result<Vec<u8>> {
+        let len = handle
+            .end()
+            .filter(|&end| end <= self.props.size)
+            .ok_or_else(|| corrupt("block is out of bounds"))?
+            - handle.offset;
+        let mut buf = vec![0u8; len as usize];
+        self.file.read_exact_at(&mut buf, handle.offset)?;
+        let expected = LittleEndian::read_u32(&buf[handle.size as usize..]);
+        buf.truncate(handle.size as usize);
+        if checksum(&buf) != expected {
+            return Err(corrupt("block checksum mismatch"));
+        }
+        match &self.cipher {
+            Some(cipher) => {
+                let aad = block_aad(self.table_id, cipher.key_id(), self.cmp.name().as_bytes(), handle.offset, extra);
+                cipher.open(&aad, &buf)
+            }
+            None => Ok(buf),
+        }
+    }
+}
+
+// Iterates over a table's entries in key order, from either end. Iteration
+// stops after the first error.
+pub struct TableIter<'a> {
+    table: &'a Table,
+    // Entries before this key are skipped. There is no key that sorts before
+    // every other under all comparators, so iterating from the first entry
+    // is `None` rather than an empty key.
+    start: Option<Vec<u8>>,
+    // Index of the block that contains `start`.
+    first_idx: usize,
+    // The blocks in [front_idx, back_idx) have not been read yet. The front
+    // and back blocks are the ones currently being read from each end; once
+    // every block has been read, both ends take from whichever is left.
+    front_idx: usize,
+    back_idx: usize,
+    front: Option<BlockIter>,
+    back: Option<BlockIter>,
+    done: bool,
+}
+
+impl<'a> TableIter<'a> {
+    fn load(&self, idx: usize) -> io::Result<BlockIter> {
+        let mut block = BlockIter::new(self.table.read_block(self.table.index[idx].1)?)?;
+        match &self.start {
+            Some(start) if idx == self.first_idx => block.seek(start, self.table.cmp.as_ref())?,
+            _ => {}
+        }
+        Ok(block)
+    }
+
+    fn check(&mut self, entry: Option<<Self as Iterator>::Item>) -> Option<<Self as Iterator>::Item> {
+        if let Some(Err(_)) = entry {
+            self.done = true;
+        }
+        entry
+    }
+}
+
+impl<'a> Iterator for TableIter<'a> {
+    type Item = io::Result<(Vec<u8>, TableValue)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let Some(entry) = self.front.as_mut().and_then(|b| b.next()) {
+                return self.check(Some(entry));
+            }
+            if self.front_idx == self.back_idx {
+                let entry = self.back.as_mut().and_then(|b| b.next());
+                return self.check(entry);
+            }
+            match self.load(self.front_idx) {
+                Ok(block) => self.front = Some(block),
+                Err(err) => return self.check(Some(Err(err))),
+            }
+            self.front_idx += 1;
+        }
+        None
+    }
+}
+
+impl<'a> DoubleEndedIterator for TableIter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let Some(entry) = self.back.as_mut().and_then(|b| b.next_back()) {
+                return self.check(Some(entry));
+            }
+            if self.front_idx == self.back_idx {
+                let entry = self.front.as_mut().and_then(|b| b.next_back());
+                return self.check(entry);
+            }
+            match self.load(self.back_idx - 1) {
+                Ok(block) => self.back = Some(block),
+                Err(err) => return self.check(Some(Err(err))),
+            }
+            self.back_idx -= 1;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::comparator::{BytewiseComparator, ReverseBytewiseComparator};
+    use crate::encryption::{StaticKeyProvider, KEY_LENGTH, PLAINTEXT_KEY_ID};
+    use crate::fs::{FileSystem, FsOp, MemFileSystem, OsFileSystem};
+    use crate::table::TableBuilder;
+    use crate::test_util::TmpDir;
+
+    fn build(fs: &dyn FileSystem, path: &Path, n: u32) -> TableProperties {
+        let mut builder = TableBuilder::create(fs, path, Arc::new(BytewiseComparator)).unwrap();
+        for i in 0..n {
+            let key = format!("key{:06}", i);
+            if i % 10 == 3 {
+                builder.delete(key.as_bytes()).unwrap();
+            } else {
+                builder.put(key.as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+            }
+        }
+        builder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_build_and_read() {
+        let fs = MemFileSystem::new();
+        let path = Path::new("/t/000001.sst");
+        fs.create_dir_all(Path::new("/t")).unwrap();
+        let props = build(&fs, path, 2000);
+        assert_eq!(2000, props.num_entries);
+        assert_eq!(b"key000000".to_vec(), props.smallest);
+        assert_eq!(b"key001999".to_vec(), props.largest);
+
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let table = Table::open(fs.open(path).unwrap(), 1, Some(cache.clone()), Arc::new(BytewiseComparator), None, false).unwrap();
+        assert_eq!(&props, table.properties());
+        assert!(table.index.len() > 1);
+        table.verify().unwrap();
+
+        assert_eq!(Some(TableValue::Value(b"value1500"[..].into())), table.get(b"key001500").unwrap());
+        assert_eq!(Some(TableValue::Deleted), table.get(b"key001503").unwrap());
+        assert_eq!(None, table.get(b"key0015000").unwrap());
+        assert_eq!(None, table.get(b"zzz").unwrap());
+        assert!(cache.stats().hits > 0);
+
+        // The filter keeps most lookups for missing keys from reading blocks.
+        let before = cache.stats();
+        for i in 0..100 {
+            assert_eq!(None, table.get(format!("key{:06}x", i).as_bytes()).unwrap());
+        }
+        let after = cache.stats();
+        assert!(after.hits + after.misses - before.hits - before.misses < 10);
+
+        let keys = table
+            .iter_from(b"key001995")
+            .map(|e| e.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(5, keys.len());
+        assert_eq!(2000, table.iter().count());
+    }
+
+    #[test]
+    fn test_reverse_iteration() {
+        let fs = MemFileSystem::new();
+        let path = Path::new("/t/000001.sst");
+        fs.create_dir_all(Path::new("/t")).unwrap();
+        build(&fs, path, 2000);
+        let table = Table::open(fs.open(path).unwrap(), 1, None, Arc::new(BytewiseComparator), None, false).unwrap();
+
+        let forward = table.iter().map(|e| e.unwrap()).collect::<Vec<_>>();
+        let mut backward = table.iter().rev().map(|e| e.unwrap()).collect::<Vec<_>>();
+        backward.reverse();
+        assert_eq!(forward, backward);
+
+        // Reverse iteration stops at the start key, even part way through a
+        // block.
+        let keys = table
+            .iter_from(b"key001234")
+            .rev()
+            .map(|e| e.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(766, keys.len());
+        assert_eq!(b"key001234".to_vec(), *keys.last().unwrap());
+
+        // Both ends meet without repeating an entry.
+        let mut iter = table.iter_from(b"key001990");
+        assert_eq!(b"key001990".to_vec(), iter.next().unwrap().unwrap().0);
+        assert_eq!(b"key001999".to_vec(), iter.next_back().unwrap().unwrap().0);
+        assert_eq!(8, iter.count());
+    }
+
+    #[test]
+    fn test_keys_out_of_order() {
+        let fs = MemFileSystem::new();
+        fs.create_dir_all(Path::new("/t")).unwrap();
+        let mut builder = TableBuilder::create(&fs, Path::new("/t/1.sst"), Arc::new(BytewiseComparator)).unwrap();
+        builder.put(b"b", b"").unwrap();
+        assert!(builder.put(b"b", b"").is_err());
+        assert!(builder.put(b"a", b"").is_err());
+    }
+
+    #[test]
+    fn test_range_deletions() {
+        let fs = MemFileSystem::new();
+        fs.create_dir_all(Path::new("/t")).unwrap();
+        let open = |path: &str| {
+            Table::open(fs.open(Path::new(path)).unwrap(), 1, None, Arc::new(BytewiseComparator), None, false).unwrap()
+        };
+        let mut builder = TableBuilder::create(&fs, Path::new("/t/1.sst"), Arc::new(BytewiseComparator)).unwrap();
+        builder.delete_range(b"f", b"h");
+        builder.put(b"b", b"1").unwrap();
+        builder.delete_range(b"a", b"c");
+        builder.delete_range(b"g", b"k");
+        builder.put(b"d", b"2").unwrap();
+        let props = builder.finish().unwrap();
+        assert_eq!((b"a".to_vec(), b"k".to_vec()), (props.smallest.clone(), props.largest.clone()));
+
+        let table = open("/t/1.sst");
+        assert_eq!(&props, table.properties());
+        table.verify().unwrap();
+        let fragments = table
+            .range_tombstones()
+            .iter()
+            .map(|f| (f.start.clone(), f.end.clone()))
+            .collect::<Vec<_>>();
+        let expected = [("a", "c"), ("f", "g"), ("g", "h"), ("h", "k")]
+            .iter()
+            .map(|(start, end)| (start.as_bytes().to_vec(), end.as_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(expected, fragments);
+        // The table's own entries are not masked.
+        assert_eq!(Some(TableValue::Value(b"1"[..].into())), table.get(b"b").unwrap());
+        assert!(table.range_tombstones().covers(b"b"));
+        assert!(!table.range_tombstones().covers(b"k"));
+
+        // A table may hold nothing but range deletions.
+        let mut builder = TableBuilder::create(&fs, Path::new("/t/2.sst"), Arc::new(BytewiseComparator)).unwrap();
+        builder.delete_range(b"x", b"z");
+        builder.finish().unwrap();
+        let table = open("/t/2.sst");
+        assert_eq!(0, table.iter().count());
+        assert_eq!(None, table.get(b"y").unwrap());
+        assert!(table.range_tombstones().covers(b"y"));
+        assert_eq!((b"x".to_vec(), b"z".to_vec()), (table.props.smallest.clone(), table.props.largest.clone()));
+    }
+
+    #[test]
+    fn test_comparator_is_checked_on_open() {
+        let fs = MemFileSystem::new();
+        let path = Path::new("/t/1.sst");
+        fs.create_dir_all(Path::new("/t")).unwrap();
+        build(&fs, path, 10);
+        let open = |cmp: Arc<dyn Comparator>| Table::open(fs.open(path).unwrap(), 1, None, cmp, None, false);
+
+        let err = open(Arc::new(ReverseBytewiseComparator)).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let expected = ComparatorMismatch {
+            table: BytewiseComparator.name().to_string(),
+            given: ReverseBytewiseComparator.name().to_string(),
+        };
+        assert_eq!(Some(&expected), ComparatorMismatch::from_io_error(&err));
+        assert_eq!(10, open(Arc::new(BytewiseComparator)).unwrap().iter().count());
+    }
+
+    #[test]
+    fn test_corruption_is_detected() {
+        let fs = MemFileSystem::new();
+        let path = Path::new("/t/000001.sst");
+        fs.create_dir_all(Path::new("/t")).unwrap();
+        build(&fs, path, 2000);
+
+        // Flip a byte in the middle of a data block.
+        let mut contents = fs.contents(path).unwrap();
+        contents[5000] ^= 0xff;
+        let corrupted = Path::new("/t/000002.sst");
+        let mut file = fs.open_append(corrupted).unwrap();
+        file.append(&contents).unwrap();
+        let truncated = Path::new("/t/000003.sst");
+        let mut file = fs.open_append(truncated).unwrap();
+        file.append(&contents[..contents.len() - 1]).unwrap();
+
+        for mmap in [false, true] {
+            let table = Table::open(fs.open(corrupted).unwrap(), 2, None, Arc::new(BytewiseComparator), None, mmap).unwrap();
+            assert_eq!(mmap, table.is_mapped());
+            let err = table.verify().unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            assert!(table.iter().any(|e| e.is_err()));
+
+            // A truncated file is rejected when it is opened.
+            assert!(Table::open(fs.open(truncated).unwrap(), 3, None, Arc::new(BytewiseComparator), None, mmap).is_err());
+        }
+    }
+
+    #[test]
+    fn test_block_handle_overflow() {
+        let fs = MemFileSystem::new();
+        fs.create_dir_all(Path::new("/t")).unwrap();
+        let write = |path: &Path, contents: &[u8]| {
+            fs.open_append(path).unwrap().append(contents).unwrap();
+            fs.open(path).unwrap()
+        };
+        let footer = |index: BlockHandle, range: BlockHandle, filter: BlockHandle| {
+            let name = BytewiseComparator.name();
+            let mut footer = name.as_bytes().to_vec();
+            footer.extend_from_slice(&index.encode());
+            footer.extend_from_slice(&range.encode());
+            footer.extend_from_slice(&filter.encode());
+            footer.extend_from_slice(&1u64.to_le_bytes());
+            footer.extend_from_slice(&0u64.to_le_bytes());
+            footer.extend_from_slice(&PLAINTEXT_KEY_ID.to_le_bytes());
+            footer.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            footer.extend_from_slice(&TABLE_MAGIC.to_le_bytes());
+            footer
+        };
+
+        // The footer's index handle runs past the end of the address space.
+        let contents = footer(
+            BlockHandle {
+                offset: 1,
+                size: u64::MAX,
+            },
+            BlockHandle { offset: 0, size: 0 },
+            BlockHandle { offset: 0, size: 0 },
+        );
+        let err = Table::open(write(Path::new("/t/1.sst"), &contents), 1, None, Arc::new(BytewiseComparator), None, false);
+        assert_eq!(io::ErrorKind::InvalidData, err.err().unwrap().kind());
+
+        // So does the handle of a data block in the index.
+        let mut index = super::super::block::BlockBuilder::new();
+        let handle = BlockHandle {
+            offset: 0,
+            size: u64::MAX - 1,
+        };
+        index.add(b"a", &TableValue::Value(handle.encode()[..].into()));
+        let index = index.finish();
+        let range = super::super::block::BlockBuilder::new().finish();
+        let mut contents = checksum(&[]).to_le_bytes().to_vec();
+        contents.extend_from_slice(&range);
+        contents.extend_from_slice(&checksum(&range).to_le_bytes());
+        contents.extend_from_slice(&index);
+        contents.extend_from_slice(&checksum(&index).to_le_bytes());
+        contents.extend(footer(
+            BlockHandle {
+                offset: (range.len() + 8) as u64,
+                size: index.len() as u64,
+            },
+            BlockHandle {
+                offset: 4,
+                size: range.len() as u64,
+            },
+            BlockHandle { offset: 0, size: 0 },
+        ));
+        for mmap in [false, true] {
+            let file = write(Path::new(&format!("/t/{}.sst", 2 + mmap as u8)), &contents);
+            let err = Table::open(file, 2, None, Arc::new(BytewiseComparator), None, mmap);
+            assert_eq!(io::ErrorKind::InvalidData, err.err().unwrap().kind());
+        }
+    }
+
+    #[test]
+    fn test_mapped_reads() {
+        let dir = TmpDir::new();
+        let path = dir.as_ref().join("000001.sst");
+        build(&OsFileSystem, &path, 2000);
+
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let open = |mmap| {
+            let file = OsFileSystem.open(&path).unwrap();
+            Table::open(file, 1, Some(cache.clone()), Arc::new(BytewiseComparator), None, mmap).unwrap()
+        };
+        let mapped = open(true);
+        assert!(mapped.is_mapped());
+        mapped.verify().unwrap();
+        assert_eq!(Some(TableValue::Value(b"value1500"[..].into())), mapped.get(b"key001500").unwrap());
+        let read = open(false).iter().map(|e| e.unwrap()).collect::<Vec<_>>();
+        assert_eq!(read, mapped.iter().map(|e| e.unwrap()).collect::<Vec<_>>());
+
+        // Mapped blocks bypass the cache, so only the unmapped table used it.
+        let stats = cache.stats();
+        assert_eq!(mapped.index.len() as u64, stats.misses);
+
+        // Values point into the mapping rather than being copied out of it.
+        let mapping = (**mapped.mapping.as_ref().unwrap()).as_ref().as_ptr_range();
+        match mapped.get(b"key001500").unwrap() {
+            Some(TableValue::Value(v)) => assert!(mapping.contains(&v.as_ptr())),
+            other => panic!("unexpected value {:?}", other),
+        }
+
+        // A block keeps the mapping alive after the table is closed.
+        let block = mapped.read_block(mapped.index[0].1).unwrap();
+        drop(mapped);
+        let first = BlockIter::new(block).unwrap().next().unwrap().unwrap();
+        assert_eq!(read[0], first);
+    }
+
+    #[test]
+    fn test_encrypted_table() {
+        let fs = MemFileSystem::new();
+        let path = Path::new("/t/000001.sst");
+        fs.create_dir_all(Path::new("/t")).unwrap();
+        let provider = StaticKeyProvider::new(42, [9u8; KEY_LENGTH]);
+        // Values of more than a block, so that every block holds one entry
+        // and they all have the same size.
+        let value = |i: u32| format!("value{:06}", i).repeat(500);
+        let mut builder = TableBuilder::create(&fs, path, Arc::new(BytewiseComparator))
+            .unwrap()
+            .with_key_provider(&provider)
+            .unwrap();
+        for i in 0..20 {
+            builder.put(format!("key{:06}", i).as_bytes(), value(i).as_bytes()).unwrap();
+        }
+        builder.finish().unwrap();
+
+        // Neither keys nor values are readable from the file, and the footer
+        // records the key.
+        let contents = fs.contents(path).unwrap();
+        assert!(!contents.windows(6).any(|w| w == b"key000"));
+        assert!(!contents.windows(5).any(|w| w == b"value"));
+        let key_id = &contents[contents.len() - 14..contents.len() - 10];
+        assert_eq!(42u32.to_le_bytes(), key_id);
+
+        let open = |path: &Path, provider: Option<&dyn KeyProvider>, mmap| {
+            Table::open(fs.open(path).unwrap(), 1, None, Arc::new(BytewiseComparator), provider, mmap)
+        };
+        for mmap in [false, true] {
+            let table = open(path, Some(&provider), mmap).unwrap();
+            assert!(!table.is_mapped());
+            table.verify().unwrap();
+            assert_eq!(Some(TableValue::Value(value(15).as_bytes().into())), table.get(b"key000015").unwrap());
+            assert_eq!(20, table.iter().count());
+        }
+        assert_eq!(io::ErrorKind::InvalidInput, open(path, None, false).err().unwrap().kind());
+        let wrong_key = StaticKeyProvider::new(43, [9u8; KEY_LENGTH]);
+        assert!(open(path, Some(&wrong_key), false).is_err());
+
+        // A block copied over another one fails authentication, even though
+        // its checksum matches.
+        let table = open(path, Some(&provider), false).unwrap();
+        let (first, second) = (table.index[0].1, table.index[1].1);
+        assert_eq!(first.size, second.size);
+        let mut moved = contents.clone();
+        let block = &contents[first.offset as usize..first.end().unwrap() as usize];
+        moved[second.offset as usize..second.end().unwrap() as usize].copy_from_slice(block);
+        let moved_path = Path::new("/t/000002.sst");
+        fs.open_append(moved_path).unwrap().append(&moved).unwrap();
+        let table = open(moved_path, Some(&provider), false).unwrap();
+        let err = table.get(b"key000001").unwrap_err();
+        assert_eq!("Encrypted data failed authentication", err.to_string());
+
+        // So does a block at the same offset in another table with the same
+        // key and contents.
+        let other_path = Path::new("/t/000003.sst");
+        let mut builder = TableBuilder::create(&fs, other_path, Arc::new(BytewiseComparator))
+            .unwrap()
+            .with_key_provider(&provider)
+            .unwrap();
+        for i in 0..20 {
+            builder.put(format!("key{:06}", i).as_bytes(), value(i).as_bytes()).unwrap();
+        }
+        builder.finish().unwrap();
+        let other = fs.contents(other_path).unwrap();
+        assert_eq!(contents.len(), other.len());
+        let mut swapped = contents.clone();
+        swapped[second.offset as usize..second.end().unwrap() as usize]
+            .copy_from_slice(&other[second.offset as usize..second.end().unwrap() as usize]);
+        let swapped_path = Path::new("/t/000004.sst");
+        fs.open_append(swapped_path).unwrap().append(&swapped).unwrap();
+        let table = open(swapped_path, Some(&provider), false).unwrap();
+        assert_eq!(Some(TableValue::Value(value(0).as_bytes().into())), table.get(b"key000000").unwrap());
+        let err = table.get(b"key000001").unwrap_err();
+        assert_eq!("Encrypted data failed authentication", err.to_string());
+
+        // Every field of the footer is authenticated: the entry count and
+        // the table id here.
+        for pos in [contents.len() - 30, contents.len() - 22] {
+            let mut tampered = contents.clone();
+            tampered[pos] ^= 1;
+            let tampered_path = Path::new("/t/000005.sst");
+            fs.remove(tampered_path).ok();
+            fs.open_append(tampered_path).unwrap().append(&tampered).unwrap();
+            let err = open(tampered_path, Some(&provider), false).err().unwrap();
+            assert_eq!("Encrypted data failed authentication", err.to_string());
+        }
+    }
+
+    #[test]
+    fn test_mmap_falls_back_to_read_at() {
+        let fs = MemFileSystem::new();
+        let path = Path::new("/t/000001.sst");
+        fs.create_dir_all(Path::new("/t")).unwrap();
+        build(&fs, path, 100);
+
+        fs.fail_nth(FsOp::Map, 0);
+        let table = Table::open(fs.open(path).unwrap(), 1, None, Arc::new(BytewiseComparator), None, true).unwrap();
+        assert!(!table.is_mapped());
+        table.verify().unwrap();
+        assert_eq!(100, table.iter().count());
+    }
+}
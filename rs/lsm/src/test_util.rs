@@ -1,4 +1,3 @@
-use rand;
 use std::path::{Path, PathBuf};
 use std::fs;
 